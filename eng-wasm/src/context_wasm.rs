@@ -0,0 +1,38 @@
+use super::*;
+
+/// Information about the task the contract is running in.
+/// This is what a contract should use for access control instead of trusting its arguments.
+pub struct Context;
+
+impl Context {
+    /// The 64 bytes public key of the user that submitted the task.
+    pub fn sender_pubkey() -> [u8; 64] {
+        let pubkey = [0u8; 64];
+        unsafe { external::fetch_sender_pubkey(pubkey.as_ptr()) };
+        pubkey
+    }
+
+    /// The Ethereum address of the user that submitted the task.
+    pub fn sender() -> Address {
+        let address = [0u8; 20];
+        unsafe { external::fetch_sender_address(address.as_ptr()) };
+        Address::from(address)
+    }
+
+    /// The address of this contract.
+    pub fn contract_address() -> H256 {
+        let address = [0u8; 32];
+        unsafe { external::fetch_contract_address(address.as_ptr()) };
+        H256::from(address)
+    }
+
+    /// The block number the task was submitted in.
+    pub fn block_number() -> u64 { unsafe { external::fetch_block_number() as u64 } }
+
+    /// A unique id of the task, the same for every run of the same task.
+    pub fn task_id() -> H256 {
+        let id = [0u8; 32];
+        unsafe { external::fetch_task_id(id.as_ptr()) };
+        H256::from(id)
+    }
+}
@@ -8,15 +8,19 @@
 #[macro_use]
 extern crate serde_json;
 extern crate serde;
+extern crate enigma_types;
 #[macro_use]
 mod internal_std;
 mod rand_wasm;
+mod context_wasm;
 pub extern crate eng_pwasm_abi;
 
 pub use internal_std::*;
 pub use rand_wasm::*;
+pub use context_wasm::*;
 pub use serde_json::Value;
 pub use eng_pwasm_abi::types::*;
+pub use enigma_types::wasm::SEALED_OVERHEAD;
 
 
 pub mod external {
@@ -36,6 +40,12 @@ pub mod external {
         pub fn gas(amount: u32);
         pub fn ret(payload: *const u8, payload_len: u32);
         pub fn rand(payload: *const u8, payload_len: u32);
+        pub fn seal_for_user(pubkey: *const u8, data: *const u8, data_len: u32, sealed_holder: *const u8);
+        pub fn fetch_sender_pubkey(pubkey_holder: *const u8);
+        pub fn fetch_sender_address(address_holder: *const u8);
+        pub fn fetch_contract_address(address_holder: *const u8);
+        pub fn fetch_block_number() -> i64;
+        pub fn fetch_task_id(id_holder: *const u8);
     }
 }
 
@@ -86,6 +96,31 @@ pub fn write_ethereum_bridge(payload: &[u8], address: &Address){
     };
 }
 
+/// Encrypt `data` so that only the owner of `user_pubkey` (a 64 bytes uncompressed secp256k1 public key) can decrypt it.
+/// The sealed value can be returned from a function or kept in the state, other users and the contract
+/// state key can't open it.
+pub fn seal_for_user(user_pubkey: &[u8], data: &[u8]) -> Vec<u8> {
+    assert_eq!(user_pubkey.len(), 64, "seal_for_user expects a 64 bytes public key");
+    let sealed: Vec<u8> = iter::repeat(0).take(data.len() + SEALED_OVERHEAD).collect();
+    unsafe { external::seal_for_user(user_pubkey.as_ptr(), data.as_ptr(), data.len() as u32, sealed.as_ptr()) };
+    sealed
+}
+
+/// Declare the resources this contract needs, e.g. `contract_limits!(memory_pages: 16, stack_height: 16384);`
+/// The enclave enforces these instead of its defaults; a deployment that asks for more than the enclave allows fails.
+/// The limits are kept in a custom wasm section of the deployed bytecode.
+#[macro_export]
+macro_rules! contract_limits {
+    (memory_pages: $pages: expr, stack_height: $stack: expr) => {
+        #[link_section = "enigma_limits"]
+        #[no_mangle]
+        pub static ENIGMA_CONTRACT_LIMITS: [u8; 8] = [
+            ($pages as u32 >> 24) as u8, ($pages as u32 >> 16) as u8, ($pages as u32 >> 8) as u8, $pages as u32 as u8,
+            ($stack as u32 >> 24) as u8, ($stack as u32 >> 16) as u8, ($stack as u32 >> 8) as u8, $stack as u32 as u8,
+        ];
+    }
+}
+
 #[macro_export]
  macro_rules! write_state {
      ( $($key: expr => $val: expr),+ ) => {
@@ -1,5 +1,6 @@
 //pub use pwasm_abi::types::*;
 use super::*;
+use std::ops::Range;
 
 pub struct Rand;
 
@@ -8,8 +9,43 @@ impl Rand {
     pub fn gen_slice(slice: &mut [u8]) {
         unsafe { external::rand(slice.as_ptr(), slice.len() as u32)};
     }
+
+    /// generate a random number in `range`, e.g. `Rand::gen_range(1..7u8)` for a dice roll.
+    /// Uses rejection sampling so every value in the range is equally likely.
+    pub fn gen_range<T: RandRange>(range: Range<T>) -> T {
+        let (low, high) = (range.start.into_u64(), range.end.into_u64());
+        assert!(low < high, "Rand::gen_range called with an empty range");
+        let span = high - low;
+        // Values above `zone` would make the low end of the range more likely, so they are redrawn.
+        let zone = u64::max_value() - (u64::max_value() - span + 1) % span;
+        loop {
+            let r: u64 = Self::gen();
+            if r <= zone {
+                return T::from_u64(low + r % span);
+            }
+        }
+    }
+}
+
+/// Integer types that `Rand::gen_range` can sample.
+pub trait RandRange: Copy {
+    fn into_u64(self) -> u64;
+    fn from_u64(v: u64) -> Self;
 }
 
+macro_rules! impl_rand_range {
+    ( $($t:ty),+ ) => {
+        $(
+            impl RandRange for $t {
+                fn into_u64(self) -> u64 { self as u64 }
+                fn from_u64(v: u64) -> Self { v as $t }
+            }
+        )+
+    }
+}
+
+impl_rand_range!(u8, u16, u32, u64, usize);
+
 pub trait RandTypes<T> {
     /// generate a random number on the trusted side.
     fn gen() -> T;
@@ -54,3 +90,10 @@ impl RandTypes<u64> for Rand {
         u64::from_be_bytes(r)
     }
 }
+
+impl RandTypes<bool> for Rand {
+    fn gen() -> bool {
+        let r: u8 = Self::gen();
+        r & 1 == 1
+    }
+}
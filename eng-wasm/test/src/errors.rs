@@ -0,0 +1,87 @@
+use enigma_types::limits::LimitsError;
+use std::fmt;
+
+/// Errors returned to the test writer by `TestContract`.
+#[derive(Fail, Debug)]
+pub enum HarnessError {
+    #[fail(display = "Failed compiling the contract in {}: {}", path, err)]
+    BuildError { path: String, err: String },
+    #[fail(display = "Error while creating the WASM module: {}", err)]
+    ModuleCreationError { err: String },
+    #[fail(display = "Error in the task inputs: {}", message)]
+    InputError { message: String },
+    #[fail(display = "The contract requested {} {}, the limit is {}", requested, resource, limit)]
+    ResourceLimitError { resource: String, requested: u32, limit: u32 },
+    #[fail(display = "Invocation resulted in gas limit violated, used gas: {}", used_gas)]
+    GasLimitError { used_gas: u64 },
+    #[fail(display = "Error in execution of the contract: {}, used gas: {}", err, used_gas)]
+    ExecutionError { used_gas: u64, err: String },
+}
+
+/// Errors raised by the host functions while the contract is running.
+/// These travel through wasmi as a `Trap` and are converted to a `HarnessError` afterwards.
+#[derive(Debug)]
+pub enum RuntimeError {
+    GasLimit,
+    WasmiError(String),
+    StateError(String),
+    CryptoError(String),
+    MemoryLimit { requested: u32, limit: u32 },
+}
+
+impl wasmi::HostError for RuntimeError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::GasLimit => write!(f, "Invocation resulted in gas limit violated"),
+            RuntimeError::WasmiError(ref e) => write!(f, "{}", e),
+            RuntimeError::StateError(ref e) => write!(f, "State error: {}", e),
+            RuntimeError::CryptoError(ref e) => write!(f, "Crypto error: {}", e),
+            RuntimeError::MemoryLimit { requested, limit } => write!(f, "The contract requested {} memory pages, the limit is {}", requested, limit),
+        }
+    }
+}
+
+impl From<wasmi::Error> for RuntimeError {
+    fn from(e: wasmi::Error) -> Self { RuntimeError::WasmiError(e.to_string()) }
+}
+
+impl From<wasmi::Trap> for RuntimeError {
+    fn from(trap: wasmi::Trap) -> Self { RuntimeError::WasmiError(format!("{:?}", trap)) }
+}
+
+impl From<serde_json::Error> for RuntimeError {
+    fn from(e: serde_json::Error) -> Self { RuntimeError::StateError(e.to_string()) }
+}
+
+impl From<enigma_crypto::CryptoError> for RuntimeError {
+    fn from(e: enigma_crypto::CryptoError) -> Self { RuntimeError::CryptoError(e.to_string()) }
+}
+
+impl From<parity_wasm::elements::Error> for HarnessError {
+    fn from(err: parity_wasm::elements::Error) -> HarnessError {
+        HarnessError::ModuleCreationError { err: format!("deserialization into WASM module: {}", err) }
+    }
+}
+
+impl From<parity_wasm::elements::Module> for HarnessError {
+    fn from(_: parity_wasm::elements::Module) -> HarnessError {
+        HarnessError::ModuleCreationError { err: "injecting gas counter".to_string() }
+    }
+}
+
+impl From<LimitsError> for HarnessError {
+    fn from(err: LimitsError) -> HarnessError {
+        match err {
+            LimitsError::Malformed(err) => HarnessError::ModuleCreationError { err: format!("Malformed limits section: {}", err) },
+            LimitsError::ResourceLimit { resource, requested, limit } => HarnessError::ResourceLimitError { resource: resource.to_string(), requested, limit },
+        }
+    }
+}
+
+impl From<pwasm_utils::stack_height::Error> for HarnessError {
+    fn from(err: pwasm_utils::stack_height::Error) -> HarnessError {
+        HarnessError::ModuleCreationError { err: format!("injecting stack height limiter: {:?}", err) }
+    }
+}
@@ -0,0 +1,305 @@
+#![warn(unused_extern_crates)]
+
+//! A host-side harness for unit testing Secret Contracts written with `eng-wasm`.
+//!
+//! It implements the same `eng_wasm::external` imports the enclave runtime does, on top of `wasmi`,
+//! with a plaintext in-memory `ContractState`, so a contract can be deployed and called from a
+//! regular `cargo test` without SGX:
+//!
+//! ```ignore
+//! let mut contract = TestContract::from_path("../examples/eng_wasm_contracts/simplest")?;
+//! contract.deploy(&ethabi::encode(&[Token::Uint(1.into())]))?;
+//! let res = contract.call("addition(uint256,uint256)", &ethabi::encode(&[Token::Uint(1.into()), Token::Uint(2.into())]))?;
+//! assert_eq!(contract.state().read_key::<u64>("curr_sum")?, 3);
+//! ```
+//!
+//! Nothing is encrypted or signed here; the harness is meant for the contract logic only.
+
+#[macro_use]
+extern crate failure;
+
+mod errors;
+mod resolver;
+mod runtime;
+mod state;
+
+pub use crate::errors::HarnessError;
+pub use enigma_types::limits::{ContractLimits, MAX_MEMORY_PAGES, MAX_STACK_HEIGHT};
+pub use crate::runtime::EthereumData;
+pub use crate::state::ContractState;
+pub use enigma_types::{wasm::{RuntimeWasmCosts, WasmCosts}, ContractAddress};
+
+use crate::errors::RuntimeError;
+use crate::runtime::{TaskContext, TestRuntime};
+use enigma_types::wasm::gas_rules;
+use parity_wasm::elements::{self, Deserialize};
+use parity_wasm::io::Cursor;
+use enigma_crypto::rand::SeededRng;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Command;
+use wasmi::{ImportsBuilder, Module, ModuleInstance, ModuleRef, RuntimeValue, TrapKind};
+
+fn create_module(code: &[u8], limits: &ContractLimits) -> Result<Module, HarnessError> {
+    let mut cursor = Cursor::new(&code[..]);
+    let deserialized_module = elements::Module::deserialize(&mut cursor)?;
+    if deserialized_module.memory_section().map_or(false, |ms| !ms.entries().is_empty()) {
+        return Err(HarnessError::ModuleCreationError { err: "Malformed wasm module: internal memory".to_string() });
+    }
+    let wasm_costs = WasmCosts::default();
+    let contract_module = pwasm_utils::inject_gas_counter(deserialized_module, &gas_rules(&wasm_costs))?;
+    let limited_module = pwasm_utils::stack_height::inject_limiter(contract_module, limits.stack_height)?;
+    wasmi::Module::from_parity_wasm_module(limited_module).map_err(|e| HarnessError::ModuleCreationError { err: e.to_string() })
+}
+
+/// Compiles the contract crate in `contract_path` to `wasm32-unknown-unknown` and returns the bytecode.
+/// The crate is expected to have a `.cargo/config` with the wasm target, like the examples do.
+pub fn build_contract(contract_path: &str) -> Result<Vec<u8>, HarnessError> {
+    let build_error = |err: String| HarnessError::BuildError { path: contract_path.to_string(), err };
+    let mut dir = PathBuf::new();
+    dir.push(contract_path);
+    let status = Command::new("cargo")
+        .current_dir(&dir)
+        .args(&["build", "--release"])
+        .status()
+        .map_err(|e| build_error(e.to_string()))?;
+    if !status.success() {
+        return Err(build_error(format!("cargo exited with {}", status)));
+    }
+    dir.push("target/wasm32-unknown-unknown/release/contract.wasm");
+
+    let mut f = File::open(&dir).map_err(|e| build_error(format!("{:?}: {}", &dir, e)))?;
+    let mut wasm_code = Vec::new();
+    f.read_to_end(&mut wasm_code).map_err(|e| build_error(e.to_string()))?;
+    Ok(wasm_code)
+}
+
+/// Splits a callable like `addition(uint256,uint256)` into the function name and the types.
+fn split_callable(callable: &str) -> Result<(String, String), HarnessError> {
+    let illegal = || HarnessError::InputError { message: format!("'callable' signature is illegal: {}", callable) };
+    let start = callable.find('(').ok_or_else(illegal)?;
+    let end = callable.rfind(')').ok_or_else(illegal)?;
+    if end < start {
+        return Err(illegal());
+    }
+    Ok((callable[..start].to_string(), callable[start + 1..end].to_string()))
+}
+
+fn instantiation_error(e: wasmi::Error) -> HarnessError {
+    if let wasmi::Error::Host(ref host) = e {
+        if let Some(RuntimeError::MemoryLimit { requested, limit }) = host.downcast_ref::<RuntimeError>() {
+            return HarnessError::ResourceLimitError { resource: "memory pages".to_string(), requested: *requested, limit: *limit };
+        }
+    }
+    HarnessError::ModuleCreationError { err: e.to_string() }
+}
+
+/// Same as `enigma_runtime_t::limits::stack_limit_error`: the stack height limiter keeps the height in the
+/// last global and traps with `unreachable` once it passes the limit, which the height tells apart from a panic.
+fn stack_limit_error(instance: &ModuleRef, limits: &ContractLimits, err: &wasmi::Error) -> Option<HarnessError> {
+    let trap = match err {
+        wasmi::Error::Trap(trap) => trap,
+        _ => return None,
+    };
+    let height = match instance.globals().last().map(|global| global.get()) {
+        Some(RuntimeValue::I32(height)) => height as u32,
+        _ => return None,
+    };
+    match trap.kind() {
+        TrapKind::Unreachable if height > limits.stack_height => Some(limits.stack_exceeded(height).into()),
+        TrapKind::StackOverflow => Some(limits.stack_exceeded(height).into()),
+        _ => None,
+    }
+}
+
+/// Everything a single deployment or execution produced.
+#[derive(Debug, Clone)]
+pub struct TaskResult {
+    /// The value returned with `ret` (on deploy this is the deployed bytecode).
+    pub output: Vec<u8>,
+    /// The JSON patch between the state before and after the task, `None` if nothing changed.
+    pub delta: Option<json_patch::Patch>,
+    /// The index the delta would have been stored under in the enclave.
+    pub delta_index: u32,
+    pub ethereum_bridge: Option<EthereumData>,
+    pub used_gas: u64,
+    /// Everything the contract printed with `eprint!`.
+    pub logs: Vec<String>,
+}
+
+/// A Secret Contract loaded into the harness together with its state.
+pub struct TestContract {
+    code: Vec<u8>,
+    module: Module,
+    limits: ContractLimits,
+    state: ContractState,
+    gas_limit: u64,
+    costs: RuntimeWasmCosts,
+    rng: SeededRng,
+    sender: [u8; 64],
+    block_number: u64,
+    deployed: bool,
+}
+
+impl TestContract {
+    /// Load a contract from its compiled wasm bytecode.
+    pub fn new(code: &[u8]) -> Result<TestContract, HarnessError> {
+        let limits = ContractLimits::from_code(code)?;
+        let module = create_module(code, &limits)?;
+        Ok(TestContract {
+            code: code.to_vec(),
+            module,
+            limits,
+            state: ContractState::new(ContractAddress::default()),
+            gas_limit: 100_000_000,
+            costs: RuntimeWasmCosts::default(),
+            rng: SeededRng::new(rand::random()),
+            sender: [0u8; 64],
+            block_number: 0,
+            deployed: false,
+        })
+    }
+
+    /// Compile the contract crate in `contract_path` and load it.
+    pub fn from_path(contract_path: &str) -> Result<TestContract, HarnessError> { Self::new(&build_contract(contract_path)?) }
+
+    pub fn with_address(mut self, contract_address: ContractAddress) -> Self {
+        self.state.contract_address = contract_address;
+        self
+    }
+
+    pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    pub fn with_costs(mut self, costs: RuntimeWasmCosts) -> Self {
+        self.costs = costs;
+        self
+    }
+
+    /// Make `Rand::gen` reproducible across runs.
+    pub fn with_rand_seed(mut self, seed: [u8; 32]) -> Self {
+        self.set_rand_seed(seed);
+        self
+    }
+
+    /// Restart the random stream from `seed`. This is the same stream the enclave uses, so setting
+    /// the `randSeed` of a signed task result before the next call replays the values the contract saw in the enclave.
+    pub fn set_rand_seed(&mut self, seed: [u8; 32]) { self.rng = SeededRng::new(seed); }
+
+    /// The public key of the user the next tasks are sent by, see `eng_wasm::Context::sender`.
+    pub fn set_sender(&mut self, user_pubkey: [u8; 64]) { self.sender = user_pubkey; }
+
+    pub fn with_sender(mut self, user_pubkey: [u8; 64]) -> Self {
+        self.set_sender(user_pubkey);
+        self
+    }
+
+    /// The block number the next tasks are submitted in, see `eng_wasm::Context::block_number`.
+    pub fn set_block_number(&mut self, block_number: u64) { self.block_number = block_number; }
+
+    pub fn with_block_number(mut self, block_number: u64) -> Self {
+        self.set_block_number(block_number);
+        self
+    }
+
+    /// Start from an existing state instead of deploying the contract.
+    pub fn with_state(mut self, json: serde_json::Value) -> Self {
+        self.state.json = json;
+        self.deployed = true;
+        self
+    }
+
+    pub fn state(&self) -> &ContractState { &self.state }
+
+    pub fn code(&self) -> &[u8] { &self.code }
+
+    /// The limits the contract runs with, the ones it declared or the enclave defaults.
+    pub fn limits(&self) -> ContractLimits { self.limits }
+
+    /// Run the contract constructor (`construct` in the `pub_interface`) with the ABI encoded `args`.
+    /// Deploy is charged per byte of the deployed code, like in the enclave.
+    pub fn deploy(&mut self, args: &[u8]) -> Result<TaskResult, HarnessError> {
+        let code_len = self.code.len();
+        let mut result = self.run("deploy", String::new(), String::new(), args, |runtime| runtime.charge_deployment(code_len))?;
+        result.output = self.code.clone();
+        self.deployed = true;
+        Ok(result)
+    }
+
+    /// Call a function of the contract, `callable` is the function signature (e.g. `addition(uint256,uint256)`)
+    /// and `args` are the ABI encoded arguments.
+    pub fn call(&mut self, callable: &str, args: &[u8]) -> Result<TaskResult, HarnessError> {
+        if !self.deployed {
+            return Err(HarnessError::InputError { message: "The contract must be deployed before it is called".to_string() });
+        }
+        let (function_name, types) = split_callable(callable)?;
+        self.run("call", function_name, types, args, |runtime| runtime.charge_execution())
+    }
+
+    fn run<F>(&mut self, export: &str, function_name: String, types: String, args: &[u8], charge: F) -> Result<TaskResult, HarnessError>
+    where F: FnOnce(&mut TestRuntime) -> Result<(), RuntimeError> {
+        let resolver = resolver::ImportResolver::with_limit(self.limits.memory_pages);
+        let imports = ImportsBuilder::new().with_resolver("env", &resolver);
+        let instance = ModuleInstance::new(&self.module, &imports).map_err(instantiation_error)?.assert_no_start();
+
+        // In the enclave the task id is the inputs hash, here any value that's unique per task will do.
+        let context = TaskContext { user_pubkey: self.sender, block_number: self.block_number, task_id: rand::random() };
+
+        let pre_execution_state = self.state.clone();
+        let mut runtime = TestRuntime::new(self.gas_limit, resolver.memory_ref(), args.to_vec(), self.state.clone(),
+                                           function_name, types, &self.costs, &mut self.rng, context);
+
+        if let Err(e) = instance.invoke_export(export, &[], &mut runtime) {
+            if let Some(err) = stack_limit_error(&instance, &self.limits, &e) {
+                return Err(err);
+            }
+            let used_gas = runtime.get_used_gas();
+            if let wasmi::Error::Trap(ref trap) = e {
+                if let TrapKind::Host(ref host) = trap.kind() {
+                    if let Some(RuntimeError::GasLimit) = host.downcast_ref::<RuntimeError>() {
+                        return Err(HarnessError::GasLimitError { used_gas });
+                    }
+                }
+            }
+            return Err(HarnessError::ExecutionError { used_gas, err: e.to_string() });
+        }
+        if charge(&mut runtime).is_err() {
+            return Err(HarnessError::GasLimitError { used_gas: runtime.get_used_gas() });
+        }
+
+        let used_gas = runtime.get_used_gas();
+        let mut post_execution_state = runtime.state;
+        // Same rule as the enclave: a delta is always generated on deploy, and on execution only if the state changed.
+        let delta = if !self.deployed || pre_execution_state.json != post_execution_state.json {
+            if self.deployed {
+                post_execution_state.delta_index += 1;
+            }
+            Some(json_patch::diff(&pre_execution_state.json, &post_execution_state.json))
+        } else {
+            None
+        };
+        let delta_index = post_execution_state.delta_index;
+        self.state = post_execution_state;
+
+        Ok(TaskResult { output: runtime.result, delta, delta_index, ethereum_bridge: runtime.ethereum_bridge, used_gas, logs: runtime.logs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_callable;
+
+    #[test]
+    fn test_split_callable() {
+        let (name, types) = split_callable("addition(uint256,uint256)").unwrap();
+        assert_eq!(name, "addition");
+        assert_eq!(types, "uint256,uint256");
+        let (name, types) = split_callable("flip()").unwrap();
+        assert_eq!(name, "flip");
+        assert_eq!(types, "");
+        assert!(split_callable("flip").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+/// This is the host-side twin of `enigma-runtime-t::eng_resolver`.
+/// The ids and signatures come from `enigma_types::wasm`, and the memory rules must stay in sync
+/// with the enclave so that a contract that instantiates here also instantiates inside the enclave.
+use std::cell::RefCell;
+
+use crate::errors::RuntimeError;
+use enigma_types::wasm::{self, signatures::{StaticSignature, ValueType}};
+
+use wasmi::{memory_units, Error, FuncInstance, FuncRef, MemoryDescriptor, MemoryInstance, MemoryRef, ModuleImportResolver, Signature};
+
+fn signature(sig: StaticSignature) -> Signature {
+    let value_type = |value: ValueType| match value {
+        ValueType::I32 => wasmi::ValueType::I32,
+        ValueType::I64 => wasmi::ValueType::I64,
+    };
+    Signature::new(sig.0.iter().cloned().map(value_type).collect::<Vec<_>>(), sig.1.map(value_type))
+}
+
+/// Import resolver for wasmi
+/// Maps all the `eng_wasm::external` functions to the host functions of `TestRuntime`.
+/// Also manages initial memory request from the contract.
+#[derive(Default, Debug)]
+pub struct ImportResolver {
+    max_memory: u32,
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl ImportResolver {
+    /// New import resolver with specifed maximum amount of inital memory (in wasm pages = 64kb)
+    pub fn with_limit(max_memory: u32) -> ImportResolver { ImportResolver { max_memory, memory: RefCell::new(None) } }
+
+    /// Returns memory that was instantiated during the contract module
+    /// start. If contract does not use memory at all, the dummy memory of length (0, 0)
+    /// will be created instead.
+    pub fn memory_ref(&self) -> MemoryRef {
+        {
+            let mut mem_ref = self.memory.borrow_mut();
+            if mem_ref.is_none() {
+                *mem_ref = Some(
+                    MemoryInstance::alloc(memory_units::Pages(0), Some(memory_units::Pages(0)))
+                        .expect("Memory allocation (0, 0) should not fail; qed"),
+                );
+            }
+        }
+
+        self.memory.borrow().clone().expect("it is either existed or was created as (0, 0) above; qed")
+    }
+}
+
+impl ModuleImportResolver for ImportResolver {
+    fn resolve_func(&self, field_name: &str, _signature: &Signature) -> Result<FuncRef, Error> {
+        match wasm::resolve_func(field_name) {
+            Some((sig, id)) => Ok(FuncInstance::alloc_host(signature(sig), id)),
+            None => Err(wasmi::Error::Instantiation(format!("Export {} not found", field_name))),
+        }
+    }
+
+    fn resolve_memory(&self, field_name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+        if field_name == "memory" {
+            // A memory without a maximum can grow without bound, so it's never within the limit.
+            let requested = descriptor.maximum().unwrap_or(u32::max_value()).max(descriptor.initial());
+            if requested > self.max_memory {
+                Err(Error::Host(Box::new(RuntimeError::MemoryLimit { requested, limit: self.max_memory })))
+            } else {
+                let mem = MemoryInstance::alloc(
+                    memory_units::Pages(descriptor.initial() as usize),
+                    descriptor.maximum().map(|x| memory_units::Pages(x as usize)),
+                )?;
+                *self.memory.borrow_mut() = Some(mem.clone());
+                Ok(mem)
+            }
+        } else {
+            Err(Error::Instantiation("Memory imported under unknown name".to_owned()))
+        }
+    }
+}
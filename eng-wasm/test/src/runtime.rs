@@ -0,0 +1,293 @@
+use crate::errors::RuntimeError;
+use crate::state::ContractState;
+use enigma_crypto::rand::SeededRng;
+use enigma_crypto::hash::Keccak256;
+use enigma_crypto::KeyPair;
+use enigma_types::wasm::RuntimeWasmCosts;
+use std::str;
+use wasmi::{MemoryRef, RuntimeArgs, RuntimeValue};
+
+/// A call that the contract made to an Ethereum contract through `write_ethereum_bridge`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EthereumData {
+    pub ethereum_payload: Vec<u8>,
+    pub ethereum_contract_addr: [u8; 20],
+}
+
+/// What the contract sees through `eng_wasm::Context`.
+#[derive(Clone, Copy)]
+pub(crate) struct TaskContext {
+    pub user_pubkey: [u8; 64],
+    pub block_number: u64,
+    pub task_id: [u8; 32],
+}
+
+type Result<T> = ::std::result::Result<T, RuntimeError>;
+
+/// Host side implementation of the `eng_wasm::external` functions.
+/// Behaves like `enigma_runtime_t::Runtime`, but keeps the state in plaintext and
+/// records everything the test may want to assert on.
+pub struct TestRuntime<'a> {
+    gas_counter: u64,
+    gas_limit: u64,
+    gas_return: u64,
+    memory: MemoryRef,
+    function_name: String,
+    args_types: String,
+    args: Vec<u8>,
+    pub(crate) result: Vec<u8>,
+    pub(crate) ethereum_bridge: Option<EthereumData>,
+    pub(crate) logs: Vec<String>,
+    pub(crate) state: ContractState,
+    gas_costs: &'a RuntimeWasmCosts,
+    rng: &'a mut SeededRng,
+    context: TaskContext,
+}
+
+impl<'a> TestRuntime<'a> {
+    pub fn new(gas_limit: u64, memory: MemoryRef, args: Vec<u8>, state: ContractState, function_name: String,
+               args_types: String, gas_costs: &'a RuntimeWasmCosts, rng: &'a mut SeededRng, context: TaskContext) -> TestRuntime<'a> {
+        TestRuntime {
+            gas_counter: 0,
+            gas_limit,
+            gas_return: 0,
+            memory,
+            function_name,
+            args_types,
+            args,
+            result: Vec::new(),
+            ethereum_bridge: None,
+            logs: Vec::new(),
+            state,
+            gas_costs,
+            rng,
+            context,
+        }
+    }
+
+    /// The gas used so far, after returning the gas of the bytes removed from the state.
+    pub fn get_used_gas(&self) -> u64 { self.gas_counter.saturating_sub(self.gas_return) }
+
+    fn fetch_args(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, &self.args)?;
+        Ok(())
+    }
+
+    fn fetch_function_name(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, self.function_name.as_bytes())?;
+        Ok(())
+    }
+
+    fn fetch_types(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, self.args_types.as_bytes())?;
+        Ok(())
+    }
+
+    fn read_state_key_from_memory(&self, args: &RuntimeArgs, arg_index: usize, arg_len_index: usize) -> Result<String> {
+        let key: u32 = args.nth_checked(arg_index)?;
+        let key_len: u32 = args.nth_checked(arg_len_index)?;
+        let mut buf = vec![0u8; key_len as usize];
+        self.memory.get_into(key, &mut buf[..])?;
+        let key_str = str::from_utf8(&buf).map_err(|e| RuntimeError::StateError(e.to_string()))?;
+        Ok(key_str.to_string())
+    }
+
+    fn read_state_len(&mut self, args: RuntimeArgs) -> Result<i32> {
+        let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        let value_vec = serde_json::to_vec(&self.state.json[&key])?;
+        Ok(value_vec.len() as i32)
+    }
+
+    fn read_state(&mut self, args: RuntimeArgs) -> Result<()> {
+        let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        let value_holder: u32 = args.nth_checked(2)?;
+        let value_vec = serde_json::to_vec(&self.state.json[&key])?;
+        self.memory.set(value_holder, &value_vec)?;
+        Ok(())
+    }
+
+    fn remove_from_state(&mut self, args: RuntimeArgs) -> Result<()> {
+        let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        self.state.remove_key(&key);
+        Ok(())
+    }
+
+    fn write_state(&mut self, args: RuntimeArgs) -> Result<()> {
+        let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        let value: u32 = args.nth_checked(2)?;
+        let value_len: u32 = args.nth_checked(3)?;
+
+        let gas_amount = self.calculate_gas_for_writing(u64::from(value_len), &key)?;
+        self.charge_gas(gas_amount)?;
+        let mut val = vec![0u8; value_len as usize];
+        self.memory.get_into(value, &mut val[..])?;
+
+        let value: serde_json::Value = serde_json::from_slice(&val)?;
+        self.state.write_key(&key, &value);
+        Ok(())
+    }
+
+    /// Same pricing as the enclave: a constant for every write, plus a charge for every new byte.
+    /// Shrinking a value returns the gas of the removed bytes at the end of the execution.
+    fn calculate_gas_for_writing(&mut self, new_value_len: u64, key: &str) -> Result<u64> {
+        let val = &self.state.json[key];
+        let old_value_len = if val.is_null() { 0 } else { serde_json::to_vec(val)?.len() as u64 };
+
+        if new_value_len >= old_value_len {
+            (new_value_len - old_value_len)
+                .checked_mul(self.gas_costs.write_additional_byte)
+                .and_then(|additional| additional.checked_add(self.gas_costs.write_value))
+                .ok_or(RuntimeError::GasLimit)
+        } else {
+            let returned = (old_value_len - new_value_len).saturating_mul(self.gas_costs.write_additional_byte);
+            self.gas_return = self.gas_return.saturating_add(returned);
+            Ok(0)
+        }
+    }
+
+    fn write_eth_bridge(&mut self, args: RuntimeArgs) -> Result<()> {
+        let payload: u32 = args.nth_checked(0)?;
+        let payload_len: u32 = args.nth_checked(1)?;
+        let address: u32 = args.nth_checked(2)?;
+
+        let mut bridge = EthereumData { ethereum_payload: vec![0u8; payload_len as usize], ethereum_contract_addr: Default::default() };
+        self.memory.get_into(payload, &mut bridge.ethereum_payload[..])?;
+        self.memory.get_into(address, &mut bridge.ethereum_contract_addr[..])?;
+        self.ethereum_bridge = Some(bridge);
+        Ok(())
+    }
+
+    fn ret(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let len: u32 = args.nth_checked(1)?;
+        self.result = self.memory.get(ptr, len as usize)?;
+        Ok(())
+    }
+
+    fn rand(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let len: u32 = args.nth_checked(1)?;
+        let mut buf = vec![0u8; len as usize];
+        self.rng.fill(&mut buf);
+        self.memory.set(ptr, &buf)?;
+        Ok(())
+    }
+
+    fn fetch_sender_pubkey(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, &self.context.user_pubkey[..])?;
+        Ok(())
+    }
+
+    fn fetch_sender_address(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let hash = self.context.user_pubkey[..].keccak256();
+        self.memory.set(ptr, &hash[12..])?;
+        Ok(())
+    }
+
+    fn fetch_contract_address(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, &self.state.contract_address[..])?;
+        Ok(())
+    }
+
+    fn fetch_task_id(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        self.memory.set(ptr, &self.context.task_id[..])?;
+        Ok(())
+    }
+
+    /// Seals with a real ephemeral key like the enclave does, so the test can open the result with the user's `KeyPair`.
+    fn seal_for_user(&mut self, args: RuntimeArgs) -> Result<()> {
+        let pubkey_ptr: u32 = args.nth_checked(0)?;
+        let data_ptr: u32 = args.nth_checked(1)?;
+        let data_len: u32 = args.nth_checked(2)?;
+        let sealed_holder: u32 = args.nth_checked(3)?;
+
+        let cost = self.gas_costs.seal_for_user;
+        self.charge_gas(cost)?;
+        let mut pubkey = [0u8; 64];
+        self.memory.get_into(pubkey_ptr, &mut pubkey[..])?;
+        let data = self.memory.get(data_ptr, data_len as usize)?;
+        let sealed = KeyPair::seal_for(&pubkey, &data)?;
+        self.memory.set(sealed_holder, &sealed)?;
+        Ok(())
+    }
+
+    fn eprint(&mut self, args: RuntimeArgs) -> Result<()> {
+        let msg_ptr: u32 = args.nth_checked(0)?;
+        let msg_len: u32 = args.nth_checked(1)?;
+        let res = self.memory.get(msg_ptr, msg_len as usize)?;
+        let st = String::from_utf8_lossy(&res).into_owned();
+        self.logs.push(st);
+        Ok(())
+    }
+
+    fn gas(&mut self, args: RuntimeArgs) -> Result<()> {
+        let amount: u32 = args.nth_checked(0)?;
+        self.charge_gas(u64::from(amount))
+    }
+
+    pub fn charge_deployment(&mut self, code_len: usize) -> Result<()> {
+        let amount = (code_len as u64).checked_mul(self.gas_costs.deploy_byte).ok_or(RuntimeError::GasLimit)?;
+        self.charge_gas(amount)
+    }
+
+    pub fn charge_execution(&mut self) -> Result<()> {
+        let amount = self.gas_costs.execution;
+        self.charge_gas(amount)
+    }
+
+    fn charge_gas(&mut self, amount: u64) -> Result<()> {
+        match self.gas_counter.checked_add(amount) {
+            Some(val) if val <= self.gas_limit => {
+                self.gas_counter = val;
+                Ok(())
+            }
+            _ => {
+                self.gas_counter = self.gas_limit;
+                Err(RuntimeError::GasLimit)
+            }
+        }
+    }
+}
+
+mod ext_impl {
+    use super::TestRuntime;
+    use enigma_types::wasm::ids;
+    use wasmi::{Externals, RuntimeArgs, RuntimeValue, Trap};
+
+    impl<'a> Externals for TestRuntime<'a> {
+        fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+            match index {
+                ids::RET_FUNC => self.ret(args)?,
+                ids::WRITE_STATE_FUNC => self.write_state(args)?,
+                ids::READ_STATE_LEN_FUNC => return Ok(Some(RuntimeValue::I32(self.read_state_len(args)?))),
+                ids::READ_STATE_FUNC => self.read_state(args)?,
+                ids::REMOVE_STATE_FUNC => self.remove_from_state(args)?,
+                ids::EPRINT_FUNC => self.eprint(args)?,
+                ids::NAME_LENGTH_FUNC => return Ok(Some(RuntimeValue::I32(self.function_name.len() as i32))),
+                ids::NAME_FUNC => self.fetch_function_name(args)?,
+                ids::ARGS_LENGTH_FUNC => return Ok(Some(RuntimeValue::I32(self.args.len() as i32))),
+                ids::ARGS_FUNC => self.fetch_args(args)?,
+                ids::TYPES_LENGTH_FUNC => return Ok(Some(RuntimeValue::I32(self.args_types.len() as i32))),
+                ids::TYPES_FUNC => self.fetch_types(args)?,
+                ids::WRITE_ETH_BRIDGE_FUNC => self.write_eth_bridge(args)?,
+                ids::GAS_FUNC => self.gas(args)?,
+                ids::RAND_FUNC => self.rand(args)?,
+                ids::SEAL_FOR_USER_FUNC => self.seal_for_user(args)?,
+                ids::SENDER_PUBKEY_FUNC => self.fetch_sender_pubkey(args)?,
+                ids::SENDER_ADDRESS_FUNC => self.fetch_sender_address(args)?,
+                ids::CONTRACT_ADDRESS_FUNC => self.fetch_contract_address(args)?,
+                ids::BLOCK_NUMBER_FUNC => return Ok(Some(RuntimeValue::I64(self.context.block_number as i64))),
+                ids::TASK_ID_FUNC => self.fetch_task_id(args)?,
+                _ => unimplemented!("Unimplemented function at {}", index),
+            }
+            Ok(None)
+        }
+    }
+}
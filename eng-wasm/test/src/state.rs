@@ -0,0 +1,32 @@
+use enigma_types::ContractAddress;
+use serde::de::DeserializeOwned;
+use serde_json::{self, json, Value};
+
+/// An in-memory, unencrypted version of the enclave's `ContractState`.
+/// The state is committed only after a successful task, exactly like the enclave does.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ContractState {
+    pub contract_address: ContractAddress,
+    pub json: Value,
+    pub delta_index: u32,
+}
+
+impl ContractState {
+    pub fn new(contract_address: ContractAddress) -> ContractState {
+        ContractState { contract_address, json: json!({}), delta_index: 0 }
+    }
+
+    /// Read a value from the state and deserialize it, like `eng_wasm::read_state!` does.
+    pub fn read_key<T>(&self, key: &str) -> Result<T, serde_json::Error>
+    where T: DeserializeOwned {
+        serde_json::from_value(self.json[key].clone())
+    }
+
+    pub fn write_key(&mut self, key: &str, value: &Value) { self.json[key] = value.clone(); }
+
+    pub fn remove_key(&mut self, key: &str) {
+        if let Some(ref mut v) = self.json.as_object_mut() {
+            v.remove(key);
+        }
+    }
+}
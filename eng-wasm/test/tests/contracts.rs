@@ -0,0 +1,117 @@
+extern crate eng_wasm_test;
+extern crate enigma_crypto;
+extern crate ethabi;
+
+use eng_wasm_test::{HarnessError, TestContract};
+use enigma_crypto::{asymmetric::SEALED_OVERHEAD, hash::Keccak256, KeyPair};
+use ethabi::{ParamType, Token};
+
+const SIMPLEST: &str = "../../examples/eng_wasm_contracts/simplest";
+const FLIP_COIN: &str = "../../examples/eng_wasm_contracts/flip_coin";
+const ETH_CALLS: &str = "../../examples/eng_wasm_contracts/contract_with_eth_calls";
+const MILLIONAIRES: &str = "../../examples/eng_wasm_contracts/millionaires_problem_demo";
+
+#[test]
+fn test_deploy_writes_initial_state() {
+    let mut contract = TestContract::from_path(SIMPLEST).unwrap();
+    let res = contract.deploy(&ethabi::encode(&[Token::Uint(17.into())])).unwrap();
+
+    assert_eq!(contract.state().read_key::<u64>("1").unwrap(), 17);
+    assert!(res.delta.is_some());
+    assert_eq!(res.delta_index, 0);
+    assert!(res.used_gas > contract.code().len() as u64);
+}
+
+#[test]
+fn test_call_updates_state_and_returns_output() {
+    let mut contract = TestContract::from_path(SIMPLEST).unwrap();
+    contract.deploy(&ethabi::encode(&[Token::Uint(1.into())])).unwrap();
+
+    let args = ethabi::encode(&[Token::Uint(76.into()), Token::Uint(17.into())]);
+    let res = contract.call("addition(uint256,uint256)", &args).unwrap();
+    assert_eq!(res.output, ethabi::encode(&[Token::Uint(93.into())]));
+    assert_eq!(contract.state().read_key::<u64>("curr_sum").unwrap(), 93);
+    assert_eq!(res.delta_index, 1);
+    assert!(res.used_gas > 10_000);
+
+    // The same value again doesn't change the state, so there's no delta.
+    let res = contract.call("addition(uint256,uint256)", &args).unwrap();
+    assert!(res.delta.is_none());
+    assert_eq!(contract.state().delta_index, 1);
+}
+
+#[test]
+fn test_print_is_recorded() {
+    let mut contract = TestContract::from_path(SIMPLEST).unwrap();
+    contract.deploy(&ethabi::encode(&[Token::Uint(1.into())])).unwrap();
+    let res = contract.call("print_test(uint256,uint256)", &ethabi::encode(&[Token::Uint(3.into()), Token::Uint(4.into())])).unwrap();
+    assert_eq!(res.logs, vec!["3 4".to_string()]);
+}
+
+#[test]
+fn test_task_context() {
+    let user = KeyPair::new().unwrap();
+    let mut contract = TestContract::from_path(SIMPLEST).unwrap().with_sender(user.get_pubkey()).with_block_number(42);
+    contract.deploy(&ethabi::encode(&[Token::Uint(1.into())])).unwrap();
+
+    let sender = contract.call("get_sender()", &[]).unwrap().output;
+    assert_eq!(sender[12..], user.get_pubkey()[..].keccak256()[12..]);
+    let block_number = contract.call("get_block_number()", &[]).unwrap().output;
+    assert_eq!(block_number, ethabi::encode(&[Token::Uint(42.into())]));
+    let first_id = contract.call("get_task_id()", &[]).unwrap().output;
+    let second_id = contract.call("get_task_id()", &[]).unwrap().output;
+    assert_ne!(first_id, second_id);
+}
+
+#[test]
+fn test_seeded_rand_is_reproducible() {
+    let flips = |seed| {
+        let mut contract = TestContract::from_path(FLIP_COIN).unwrap().with_rand_seed(seed);
+        contract.deploy(&[]).unwrap();
+        (0..10).map(|_| contract.call("flip()", &[]).unwrap().output).collect::<Vec<_>>()
+    };
+    assert_eq!(flips([7u8; 32]), flips([7u8; 32]));
+}
+
+#[test]
+fn test_eth_bridge_payload() {
+    let mut contract = TestContract::from_path(ETH_CALLS).unwrap();
+    contract.deploy(&[]).unwrap();
+    let res = contract.call("test()", &[]).unwrap();
+    let bridge = res.ethereum_bridge.unwrap();
+    assert_eq!(bridge.ethereum_contract_addr[..2], [0x12, 0x3f]);
+    assert!(!bridge.ethereum_payload.is_empty());
+}
+
+#[test]
+fn test_gas_limit() {
+    let mut contract = TestContract::from_path(SIMPLEST).unwrap().with_gas_limit(1_000);
+    match contract.deploy(&ethabi::encode(&[Token::Uint(1.into())])) {
+        Err(HarnessError::GasLimitError { used_gas }) => assert_eq!(used_gas, 1_000),
+        other => panic!("Expected a gas limit error, got: {:?}", other),
+    }
+    // A failed task doesn't touch the state.
+    assert!(contract.state().read_key::<Option<u64>>("1").unwrap().is_none());
+}
+
+#[test]
+fn test_millionaires_private_results() {
+    let mut contract = TestContract::from_path(MILLIONAIRES).unwrap();
+    contract.deploy(&[]).unwrap();
+    let users: Vec<KeyPair> = (0..3).map(|_| KeyPair::new().unwrap()).collect();
+    for (i, user) in users.iter().enumerate() {
+        let args = [Token::FixedBytes(vec![i as u8; 32]), Token::Uint((i * 1_000_000).into()), Token::Bytes(user.get_pubkey().to_vec())];
+        contract.call("add_millionaire_with_key(bytes32,uint256,bytes)", &ethabi::encode(&args)).unwrap();
+    }
+
+    let res = contract.call("compute_private_results()", &[]).unwrap();
+    let sealed = ethabi::decode(&[ParamType::Bytes], &res.output).unwrap().pop().unwrap().to_bytes().unwrap();
+    let results: Vec<_> = sealed.chunks(1 + SEALED_OVERHEAD).collect();
+    assert_eq!(results.len(), users.len());
+    for (i, (user, result)) in users.iter().zip(results.iter()).enumerate() {
+        let is_richest = i == users.len() - 1;
+        assert_eq!(user.open_sealed(result).unwrap(), vec![is_richest as u8]);
+    }
+    // Every result is sealed to its own user only.
+    assert!(users[0].open_sealed(results[1]).is_err());
+}
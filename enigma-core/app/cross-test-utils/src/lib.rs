@@ -13,7 +13,7 @@ use std::io::Read;
 use std::path::PathBuf;
 use std::process::Command;
 pub use enigma_types::{ContractAddress, StateKey, Hash256};
-use enigma_crypto::{KeyPair, symmetric, rand};
+use enigma_crypto::{KeyPair, kdf::KeyPurpose, symmetric, rand};
 use enigma_crypto::hash::{Sha256, Keccak256};
 use serde_json::{*, Value};
 use rmp_serde::{Serializer};
@@ -35,6 +35,21 @@ pub fn sign_message(key: KeyPair, address: ERC20UserAddress, amount: u64) -> [u8
     key.sign_multiple(&to_sign).unwrap()
 }
 
+// A fixed key standing in for the signing key of the principal enclave
+pub fn get_principal_keys() -> KeyPair {
+    KeyPair::from_slice(&b"Enigma Principal Test Key".sha256()).unwrap()
+}
+
+pub fn get_principal_address() -> [u8; 20] {
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&get_principal_keys().get_pubkey().keccak256()[12..32]);
+    address
+}
+
+pub fn sign_principal_response(response: &[u8]) -> [u8; 65] {
+    get_principal_keys().sign(response).unwrap()
+}
+
 pub fn get_bytecode_from_path(contract_path: &str) -> Vec<u8> {
     let mut dir = PathBuf::new();
     dir.push(contract_path);
@@ -71,7 +86,8 @@ pub fn make_encrypted_response(req: &Value, addresses: Vec<ContractAddress>, key
     } else {
         addresses.into_iter().map(|addr| (addr, get_fake_state_key(addr))).collect()
     };
-    let mut response_data = Vec::new();
+    // Tagged as an unversioned response, see `RESPONSE_TAG` in `enigma_tools_m::primitives::km_primitives`
+    let mut response_data = vec![0u8];
     _response_data.serialize(&mut Serializer::new(&mut response_data)).unwrap();
 
     // Getting the node DH Public Key
@@ -82,8 +98,8 @@ pub fn make_encrypted_response(req: &Value, addresses: Vec<ContractAddress>, key
     // Generating a second pair of priv-pub keys for the DH
     let keys = KeyPair::new().unwrap();
 
-    // Generating the ECDH key for AES
-    let shared_key = keys.derive_key(&node_pubkey).unwrap();
+    // Deriving the key of the response from the ECDH secret
+    let shared_key = keys.derive_key_for(&node_pubkey, KeyPurpose::PrincipalResponse).unwrap();
     // Encrypting the response
     let response_data = symmetric::encrypt(&response_data, &shared_key).unwrap();
 
@@ -100,6 +116,8 @@ pub fn make_encrypted_response(req: &Value, addresses: Vec<ContractAddress>, key
     enc_template["data"]["EncryptedResponse"] = json!(response_data);
     enc_template["id"] = req["id"].clone();
     enc_template["pubkey"] = json!(&keys.get_pubkey()[..]);
+    // The keys are issued for the first epoch, so any block number is valid for the tasks that use them
+    enc_template["epoch"] = json!(0);
 
     enc_template
 }
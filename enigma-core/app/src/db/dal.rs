@@ -1,6 +1,7 @@
+use enigma_types::KeyVersion;
 use failure::Error;
 use rocksdb::DB as rocks_db;
-use rocksdb::{Options, SliceTransform, WriteOptions};
+use rocksdb::{IteratorMode, Options, SliceTransform, WriteBatch, WriteOptions};
 use std::path::{Path, PathBuf};
 
 use common_u::errors::{DBErr, DBErrKind};
@@ -8,6 +9,12 @@ use db::primitives::SplitKey;
 
 // These are global variables for Reade/Write/Create Options
 const SYNC: bool = true;
+/// The key of the DB format version, in the default column family.
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+/// Version 1 prefixes the encrypted states and deltas with the version of the state key that encrypts them.
+pub const FORMAT_VERSION: u32 = 1;
+/// The index keys of the encrypted values, deltas (1) and states (2), see `DeltaKey`.
+const ENCRYPTED_KEY_TYPES: &[u8] = &[1, 2];
 
 pub struct DB {
     pub location: PathBuf,
@@ -54,8 +61,51 @@ impl DB {
         let database = rocks_db::open_cf(&options, &location, &cf_list_burrowed[..])?;
         let location = location.as_ref().to_path_buf();
         let db_par = DB { location, database, options };
+        db_par.migrate(&cf_list)?;
         Ok(db_par)
     }
+
+    /// Bring a DB written by an older version to the current `FORMAT_VERSION`.
+    /// Before version 1 the states and deltas were stored without a key version prefix,
+    /// they were all encrypted with the first state key so version 0 is prepended to them.
+    fn migrate(&self, cf_list: &[String]) -> Result<(), Error> {
+        let format_version = match self.database.get(FORMAT_VERSION_KEY)? {
+            Some(version) => {
+                if version.len() != 4 {
+                    bail!("Invalid DB format version: {:?}", &*version);
+                }
+                let mut be_bytes = [0u8; 4];
+                be_bytes.copy_from_slice(&version);
+                u32::from_be_bytes(be_bytes)
+            }
+            None => 0,
+        };
+        if format_version > FORMAT_VERSION {
+            bail!("The DB format version {} is newer than the supported {}", format_version, FORMAT_VERSION);
+        }
+        if format_version == FORMAT_VERSION {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        // list_cf returns the default CF too, which doesn't hold any contract
+        for cf_name in cf_list.iter().filter(|name| name.as_str() != "default") {
+            let cf = self.database.cf_handle(cf_name).ok_or(DBErr { command: "migrate".to_string(), kind: DBErrKind::MissingKey })?;
+            for (key, value) in self.database.iterator_cf(cf, IteratorMode::Start)? {
+                if key.first().map_or(false, |key_type| ENCRYPTED_KEY_TYPES.contains(key_type)) {
+                    let mut prefixed = KeyVersion::default().to_be_bytes().to_vec();
+                    prefixed.extend_from_slice(&value);
+                    batch.put_cf(cf, &key, &prefixed)?;
+                }
+            }
+        }
+        // The values and the new format version are written at once, so a DB is never migrated twice
+        batch.put(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_be_bytes())?;
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(SYNC);
+        self.database.write_opt(batch, &write_options)?;
+        info!("Migrated the DB from format version {} to {}", format_version, FORMAT_VERSION);
+        Ok(())
+    }
 }
 
 pub trait CRUDInterface<E, K, T, V> {
@@ -238,7 +288,7 @@ impl<'a, K: SplitKey> CRUDInterface<Error, &'a K, Vec<u8>, &'a [u8]> for DB {
 #[cfg(test)]
 mod test {
 
-    use crate::db::{tests::create_test_db, dal::CRUDInterface, primitives::{Array32u8, DeltaKey, Stype}};
+    use crate::db::{tests::create_test_db, dal::{CRUDInterface, DB, FORMAT_VERSION_KEY}, primitives::{Array32u8, DeltaKey, Stype}};
     use hex::ToHex;
 
     #[test]
@@ -246,6 +296,31 @@ mod test {
         let (_db, _dir) = create_test_db();
     }
 
+    #[test]
+    fn test_migrate_legacy_db() {
+        let (mut db, dir) = create_test_db();
+        let contract_address = [6u8; 32].into();
+        let delta = DeltaKey::new(contract_address, Stype::Delta(0));
+        let state = DeltaKey::new(contract_address, Stype::State);
+        let bytecode = DeltaKey::new(contract_address, Stype::ByteCode);
+        for key in &[delta, state, bytecode] {
+            db.create(key, &b"Enigma"[..]).unwrap();
+        }
+        // A DB written before the format version existed
+        db.database.delete(FORMAT_VERSION_KEY).unwrap();
+        drop(db);
+
+        let db = DB::new(dir.path(), false).unwrap();
+        assert_eq!(db.read(&delta).unwrap(), b"\0\0\0\0Enigma".to_vec());
+        assert_eq!(db.read(&state).unwrap(), b"\0\0\0\0Enigma".to_vec());
+        assert_eq!(db.read(&bytecode).unwrap(), b"Enigma".to_vec());
+
+        // The migration runs only once
+        drop(db);
+        let db = DB::new(dir.path(), false).unwrap();
+        assert_eq!(db.read(&delta).unwrap(), b"\0\0\0\0Enigma".to_vec());
+    }
+
     #[test]
     fn test_create_read() {
         let (mut db, _dir) = create_test_db();
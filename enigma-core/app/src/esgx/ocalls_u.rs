@@ -1,7 +1,9 @@
-use crate::db::{CRUDInterface, DeltaKey, P2PCalls, ResultType, ResultTypeVec, Stype, DB};
+use crate::common_u::errors::{DBErr, DBErrKind};
+use crate::db::{CRUDInterface, DeltaKey, P2PCalls, ResultType, Stype, DB};
 use enigma_crypto::hash::Sha256;
 use enigma_tools_u::common_u::LockExpectMutex;
 use enigma_types::{ContractAddress, EnclaveReturn, Hash256, RawPointer};
+use failure::Error;
 use lru_cache::LruCache;
 use std::sync::Mutex;
 use std::{ptr, slice};
@@ -131,15 +133,12 @@ pub unsafe extern "C" fn ocall_get_deltas_sizes(db_ptr: *const RawPointer, addr:
     let mut deltas_vec = Vec::with_capacity(len);
     let mut sizes = Vec::with_capacity(len);
     match get_deltas(db, *addr, *start, *end) {
-        Ok(deltas_type) => match deltas_type {
-            ResultType::None => return EnclaveReturn::OcallDBError,
-            ResultType::Full(deltas) | ResultType::Partial(deltas) => {
-                for delta in deltas {
-                    sizes.push(delta.1.len());
-                    deltas_vec.push(delta.1);
-                }
+        Ok(deltas) => {
+            for delta in deltas {
+                sizes.push(delta.1.len());
+                deltas_vec.push(delta.1);
             }
-        },
+        }
         Err(_) => return EnclaveReturn::OcallDBError,
     };
     DELTAS_CACHE.lock_expect("DeltaCache").insert(cache_id.sha256(), deltas_vec);
@@ -175,25 +174,30 @@ pub unsafe extern "C" fn ocall_get_deltas(db_ptr: *const RawPointer, addr: &Cont
         None => {
             // If the data doesn't exist in the cache I need to pull it from the DB
             match get_deltas(db, *addr, *start, *end) {
-                Ok(deltas_type) => match deltas_type {
-                    ResultType::None => EnclaveReturn::OcallDBError,
-                    ResultType::Full(deltas) | ResultType::Partial(deltas) => {
-                        let res = deltas.iter().map(|(_, val)| val.clone()).flatten().collect::<Vec<u8>>();
-                        println!("res: {:?}", res);
-                        enigma_types::write_ptr(&res[..], res_ptr, res_len);
-                        EnclaveReturn::Success
-                    }
-                },
+                Ok(deltas) => {
+                    let res = deltas.iter().map(|(_, val)| val.clone()).flatten().collect::<Vec<u8>>();
+                    println!("res: {:?}", res);
+                    enigma_types::write_ptr(&res[..], res_ptr, res_len);
+                    EnclaveReturn::Success
+                }
                 Err(_) => EnclaveReturn::OcallDBError,
             }
         }
     }
 }
 
-fn get_deltas(db: &mut DB, addr: ContractAddress, start: u32, end: u32) -> ResultTypeVec<(DeltaKey, Vec<u8>)> {
+/// The deltas of the contract in the range, a range without deltas (or a contract without any) is empty
+/// so that the enclave can tell the tip of the deltas from a failure to read them.
+fn get_deltas(db: &mut DB, addr: ContractAddress, start: u32, end: u32) -> Result<Vec<(DeltaKey, Vec<u8>)>, Error> {
     let key_start = DeltaKey::new(addr, Stype::Delta(start));
     let key_end = DeltaKey::new(addr, Stype::Delta(end));
 
-
-    db.get_deltas(key_start, key_end)
+    match db.get_deltas(key_start, key_end) {
+        Ok(ResultType::Full(deltas)) | Ok(ResultType::Partial(deltas)) => Ok(deltas),
+        Ok(ResultType::None) => Ok(Vec::new()),
+        Err(e) => match e.downcast_ref::<DBErr>() {
+            Some(DBErr { kind: DBErrKind::MissingKey, .. }) => Ok(Vec::new()),
+            _ => Err(e),
+        },
+    }
 }
@@ -2,8 +2,9 @@
 
 use crate::common_u::errors::EnclaveFailError;
 use crate::db::DB;
+use enigma_tools_m::primitives::km_primitives::BuildStateResult;
 use enigma_types::traits::SliceCPtr;
-use enigma_types::{EnclaveReturn, ContractAddress, PubKey, RawPointer};
+use enigma_types::{DhKeysMetrics, EnclaveReturn, ContractAddress, PubKey, RawPointer, SessionId};
 use failure::Error;
 use sgx_types::{sgx_enclave_id_t, sgx_status_t};
 use std::mem;
@@ -12,10 +13,18 @@ use std::mem;
 extern "C" {
     fn ecall_ptt_req(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, addresses: *const ContractAddress, len: usize,
                      signature: &mut [u8; 65], serialized_ptr: *mut u64) -> sgx_status_t;
-    fn ecall_ptt_res(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg_ptr: *const u8, msg_len: usize) -> sgx_status_t;
+    fn ecall_ptt_res(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg_ptr: *const u8, msg_len: usize,
+                     sig: &[u8; 65]) -> sgx_status_t;
+    fn ecall_set_principal_address(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, address: &[u8; 20]) -> sgx_status_t;
+    fn ecall_verify_principal_sig(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg_ptr: *const u8, msg_len: usize,
+                                  sig: &[u8; 65]) -> sgx_status_t;
     fn ecall_build_state(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, db_ptr: *const RawPointer, failed_ptr: *mut u64) -> sgx_status_t;
-    fn ecall_get_user_key(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
-                          signature: &mut [u8; 65], user_pubkey: &PubKey, serialized_ptr: *mut u64) -> sgx_status_t;
+    fn ecall_rebuild_state(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, db_ptr: *const RawPointer,
+                           addresses: *const ContractAddress, len: usize, result_ptr: *mut u64) -> sgx_status_t;
+    fn ecall_get_user_key(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, signature: &mut [u8; 65], user_pubkey: &PubKey,
+                          session: &SessionId, uses: u32, serialized_ptr: *mut u64) -> sgx_status_t;
+    fn ecall_forget_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey, session: &SessionId) -> sgx_status_t;
+    fn ecall_get_dh_keys_metrics(eid: sgx_enclave_id_t, metrics: &mut DhKeysMetrics) -> sgx_status_t;
 
 }
 
@@ -50,9 +59,58 @@ pub fn ptt_build_state(db: &mut DB, eid: sgx_enclave_id_t) -> Result<Vec<Contrac
     Ok(part)
 }
 
-pub fn ptt_res(eid: sgx_enclave_id_t, msg: &[u8]) -> Result<(), Error> {
+/// Rebuilds the states of the given contracts from the deltas written since their saved states,
+/// or of every contract the enclave has keys for if `addresses` is empty.
+/// Returns the status of each contract, in the order of the addresses.
+#[logfn(INFO)]
+pub fn rebuild_state(db: &mut DB, eid: sgx_enclave_id_t, addresses: &[ContractAddress]) -> Result<Vec<BuildStateResult>, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut result_ptr = 0u64;
+    let db_ptr = unsafe { RawPointer::new_mut(db) };
+
+    let status = unsafe {
+        ecall_rebuild_state(eid,
+                            &mut ret as *mut EnclaveReturn,
+                            &db_ptr as *const RawPointer,
+                            addresses.as_c_ptr() as *const ContractAddress,
+                            addresses.len() * mem::size_of::<ContractAddress>(),
+                            &mut result_ptr as *mut u64) };
+
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = result_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    part.chunks(BuildStateResult::SIZE).map(|res| BuildStateResult::from_bytes(res).map_err(Error::from)).collect()
+}
+
+/// Passes the principal's response to the enclave, which accepts it only if `sig` is the signature
+/// of the principal address set by `set_principal_address`.
+/// The keys are sealed for the epoch the principal signed into the response, and the keys of an earlier epoch are dropped.
+pub fn ptt_res(eid: sgx_enclave_id_t, msg: &[u8], sig: &[u8; 65]) -> Result<(), Error> {
     let mut ret = EnclaveReturn::Success;
-    let status = unsafe { ecall_ptt_res(eid, &mut ret as *mut EnclaveReturn, msg.as_c_ptr(), msg.len()) };
+    let status = unsafe { ecall_ptt_res(eid, &mut ret as *mut EnclaveReturn, msg.as_c_ptr(), msg.len(), sig) };
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+/// Sets the signing address of the principal, as registered in the Enigma contract.
+/// The enclave seals the first address it's given and refuses to change it afterwards.
+pub fn set_principal_address(eid: sgx_enclave_id_t, address: &[u8; 20]) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = unsafe { ecall_set_principal_address(eid, &mut ret as *mut EnclaveReturn, address) };
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+/// Checks in the enclave that `sig` is the signature of `msg` by the principal address set by `set_principal_address`
+pub fn verify_principal_sig(eid: sgx_enclave_id_t, msg: &[u8], sig: &[u8; 65]) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = unsafe { ecall_verify_principal_sig(eid, &mut ret as *mut EnclaveReturn, msg.as_c_ptr(), msg.len(), sig) };
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
     }
@@ -81,13 +139,14 @@ pub fn ptt_req(eid: sgx_enclave_id_t, addresses: &[ContractAddress]) -> Result<(
     Ok((*part, sig))
 }
 
-pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey) -> Result<(Box<[u8]>, [u8; 65]), Error> {
+/// Opens a session of the user for `uses` tasks, requesting an open session again replaces its key.
+pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey, session: &SessionId, uses: u32) -> Result<(Box<[u8]>, [u8; 65]), Error> {
     let mut sig = [0u8; 65];
     let mut ret = EnclaveReturn::Success;
     let mut serialized_ptr = 0u64;
 
     let status = unsafe {
-        ecall_get_user_key(eid, &mut ret as *mut EnclaveReturn, &mut sig, &user_pubkey, &mut serialized_ptr as *mut u64)
+        ecall_get_user_key(eid, &mut ret as *mut EnclaveReturn, &mut sig, &user_pubkey, session, uses, &mut serialized_ptr as *mut u64)
     };
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
@@ -97,19 +156,39 @@ pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey) -> Result<(Box<
     Ok((*part, sig))
 }
 
+/// Drops a session of the user, so a task can no longer be sent with it
+pub fn forget_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey, session: &SessionId) -> Result<(), Error> {
+    let status = unsafe { ecall_forget_user_key(eid, user_pubkey, session) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+    }
+    Ok(())
+}
+
+pub fn get_dh_keys_metrics(eid: sgx_enclave_id_t) -> Result<DhKeysMetrics, Error> {
+    let mut metrics = DhKeysMetrics::default();
+    let status = unsafe { ecall_get_dh_keys_metrics(eid, &mut metrics) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+    }
+    Ok(metrics)
+}
+
 #[cfg(test)]
 pub mod tests {
     extern crate ethabi;
     extern crate cross_test_utils;
     extern crate itertools;
 
-    use super::{ptt_build_state, ptt_req, ptt_res};
+    use super::{forget_user_key, get_dh_keys_metrics, get_user_key, ptt_build_state, ptt_req, ptt_res, rebuild_state, set_principal_address};
+    use enigma_tools_m::primitives::km_primitives::{BuildStateStatus, DEFAULT_SESSION};
+    use crate::common_u::errors::EnclaveFailError;
     use crate::db::{CRUDInterface, DeltaKey, DB,
                     Stype::{Delta, State}, tests::create_test_db};
     use crate::esgx::{general::init_enclave_wrapper, equote};
     use self::cross_test_utils::*;
-    use enigma_types::{ContractAddress, DhKey};
-    use enigma_crypto::{KeyPair, symmetric, hash::{self, Sha256, Keccak256}};
+    use enigma_types::{ContractAddress, DhKey, EnclaveReturn};
+    use enigma_crypto::{KeyPair, kdf::KeyPurpose, symmetric, hash::{self, Sha256, Keccak256}};
     use rmp_serde::{Deserializer, Serializer};
     use serde::{Deserialize, Serialize};
     use serde_json::{self, Value};
@@ -121,7 +200,7 @@ pub mod tests {
 
     pub fn exchange_keys(id: sgx_enclave_id_t) -> (KeyPair, DhKey, Box<[u8]>, [u8; 65]) {
         let keys = KeyPair::new().unwrap();
-        let (data, sig) = super::get_user_key(id, &keys.get_pubkey()).unwrap();
+        let (data, sig) = super::get_user_key(id, &keys.get_pubkey(), &DEFAULT_SESSION, 1).unwrap();
         let data_borrowed = data.clone();
 
         let mut des = Deserializer::new(&data_borrowed[..]);
@@ -131,7 +210,7 @@ pub mod tests {
         let mut node_pubkey = [0u8; 64];
         node_pubkey.copy_from_slice(&_node_pubkey);
 
-        let shared_bytes = keys.derive_key(&node_pubkey).unwrap();
+        let shared_bytes = keys.derive_key_for(&node_pubkey, KeyPurpose::UserIo).unwrap();
         (keys, shared_bytes, data, sig)
     }
 
@@ -171,11 +250,36 @@ pub mod tests {
         let res: Value = Deserialize::deserialize(&mut des).unwrap();
         let prefix = b"Enigma User Message";
         let pubkey = serde_json::from_value::<Vec<u8>>(res["pubkey"].clone()).unwrap();
-        let msg = hash::prepare_hash_multiple(&[&prefix[..], &pubkey]);
+        let session = serde_json::from_value::<Vec<u8>>(res["session"].clone()).unwrap();
+        assert_eq!(session, DEFAULT_SESSION.to_vec());
+        assert_eq!(res["uses"], 1);
+        assert_eq!(res["version"], 1);
+        let msg = hash::prepare_hash_multiple(&[&prefix[..], &[1u8][..], &pubkey[..], &session[..], &1u32.to_be_bytes()[..]]);
         let recovered = KeyPair::recover(&msg, sig).unwrap();
         assert_eq!(recovered.keccak256()[12..32], signing_key);
     }
 
+    #[test]
+    fn test_forget_user_key() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let eid = enclave.geteid();
+        let users: Vec<KeyPair> = (0..3).map(|_| KeyPair::new().unwrap()).collect();
+        for user in &users {
+            get_user_key(eid, &user.get_pubkey(), &DEFAULT_SESSION, 1).unwrap();
+        }
+        // Requesting a session again replaces it, a user can hold several sessions
+        get_user_key(eid, &users[0].get_pubkey(), &DEFAULT_SESSION, 1).unwrap();
+        get_user_key(eid, &users[0].get_pubkey(), &[1u8; 32], 2).unwrap();
+        assert_eq!(get_dh_keys_metrics(eid).unwrap().user_keys, 4);
+
+        forget_user_key(eid, &users[1].get_pubkey(), &DEFAULT_SESSION).unwrap();
+        forget_user_key(eid, &users[1].get_pubkey(), &DEFAULT_SESSION).unwrap();
+        forget_user_key(eid, &users[0].get_pubkey(), &[2u8; 32]).unwrap();
+        let metrics = get_dh_keys_metrics(eid).unwrap();
+        assert_eq!(metrics.user_keys, 3);
+        assert_eq!(metrics.evicted_user_keys, 0);
+    }
+
     #[test]
     fn test_ptt_req() {
         let enclave = init_enclave_wrapper().unwrap();
@@ -195,7 +299,33 @@ pub mod tests {
         let mut serialized_enc_response = Vec::new();
         enc_response.serialize(&mut Serializer::new(&mut serialized_enc_response)).unwrap();
 
-        ptt_res(eid, &serialized_enc_response).unwrap();
+        set_principal_address(eid, &get_principal_address()).unwrap();
+        ptt_res(eid, &serialized_enc_response, &sign_principal_response(&serialized_enc_response)).unwrap();
+    }
+
+    #[test]
+    fn test_ptt_res_principal_signature() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let eid = enclave.geteid();
+        let address = generate_contract_address();
+        let req = ptt_req(eid, &[address]).unwrap();
+        let mut des = Deserializer::new(&req.0[..]);
+        let req_val: Value = Deserialize::deserialize(&mut des).unwrap();
+        let mut response = Vec::new();
+        make_encrypted_response(&req_val, vec![address], None).serialize(&mut Serializer::new(&mut response)).unwrap();
+        let sig = sign_principal_response(&response);
+
+        let assert_auth_err = |sig: &[u8; 65]| {
+            let err = ptt_res(eid, &response, sig).unwrap_err();
+            assert_eq!(err.downcast_ref::<EnclaveFailError>().unwrap().err, EnclaveReturn::PrincipalAuthError);
+        };
+        // The principal address wasn't provisioned yet
+        assert_auth_err(&sig);
+        set_principal_address(eid, &get_principal_address()).unwrap();
+        assert_auth_err(&[0u8; 65]);
+        assert_auth_err(&KeyPair::new().unwrap().sign(&response).unwrap());
+
+        ptt_res(eid, &response, &sig).unwrap();
     }
 
     #[test]
@@ -225,11 +355,20 @@ pub mod tests {
         let mut serialized_enc_response = Vec::new();
         enc_response.serialize(&mut Serializer::new(&mut serialized_enc_response)).unwrap();
 
-        ptt_res(enclave.geteid(), &serialized_enc_response).unwrap();
+        set_principal_address(enclave.geteid(), &get_principal_address()).unwrap();
+        ptt_res(enclave.geteid(), &serialized_enc_response, &sign_principal_response(&serialized_enc_response)).unwrap();
 
         let address_result = ptt_build_state(&mut db, enclave.geteid()).unwrap();
         assert_eq!(address_result, vec![addresses[2]]);
 
+        // Nothing was written since, so only the broken state and the unknown contract fail again
+        let unknown = b"unknown".sha256();
+        let results = rebuild_state(&mut db, enclave.geteid(), &[addresses[0], addresses[2], unknown]).unwrap();
+        assert_eq!(results.iter().map(|res| res.address).collect::<Vec<_>>(), vec![addresses[0], addresses[2], unknown]);
+        assert!(!results[0].status.is_failure());
+        assert_eq!(results[1].status, BuildStateStatus::DecryptFailure);
+        assert_eq!(results[2].status, BuildStateStatus::MissingKey);
+
         // Testing equality while ignoring order.
 //        assert_eq!(address_result.len(), address.len());
 //        let address_set: HashSet<&ContractAddress> = address.iter().collect();
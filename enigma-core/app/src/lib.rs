@@ -19,6 +19,8 @@ extern crate zmq;
 extern crate failure;
 pub extern crate enigma_tools_u;
 extern crate enigma_crypto;
+extern crate enigma_tools_m;
+extern crate ethereum_types;
 extern crate enigma_types;
 extern crate rustc_hex as hex;
 extern crate lru_cache;
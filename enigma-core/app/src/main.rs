@@ -15,6 +15,7 @@ use cli::Opt;
 use structopt::StructOpt;
 use futures::Future;
 use simplelog::CombinedLogger;
+use std::sync::{Arc, Mutex};
 
 fn main() {
     let opt: Opt = Opt::from_args();
@@ -28,11 +29,11 @@ fn main() {
     let eid = enclave.geteid();
     info!("[+] Init Enclave Successful {}!", eid);
 
-    let mut db = DB::new(datadir, true).expect("Failed initializing the DB");
+    let db = Arc::new(Mutex::new(DB::new(datadir, true).expect("Failed initializing the DB")));
     let server = IpcListener::new(&format!("tcp://*:{}", opt.port));
 
     server
-        .run(move |multi| ipc_listener::handle_message(&mut db, multi, &opt.spid, eid))
+        .run(move |multi| ipc_listener::handle_message(&db, multi, &opt.spid, eid))
         .wait()
         .unwrap();
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 use crate::networking::messages::*;
 use crate::db::DB;
+use enigma_tools_u::common_u::utils_u::LockExpectMutex;
 use futures::{Future, Stream};
 use sgx_types::sgx_enclave_id_t;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio_zmq::prelude::*;
 use tokio_zmq::{Error, Multipart, Rep};
 
@@ -28,11 +29,14 @@ impl IpcListener {
     }
 }
 
-pub fn handle_message(db: &mut DB, request: Multipart, spid: &str, eid: sgx_enclave_id_t) -> Multipart {
+/// The DB is shared with the background state rebuild, it's locked for the duration of each message.
+pub fn handle_message(shared_db: &Arc<Mutex<DB>>, request: Multipart, spid: &str, eid: sgx_enclave_id_t) -> Multipart {
     let mut responses = Multipart::new();
     for msg in request {
         let msg: IpcMessageRequest = msg.into();
         let id = msg.id.clone();
+        let mut db_guard = shared_db.lock_expect("DB");
+        let db = &mut *db_guard;
         let response_msg = match msg.request {
             IpcRequest::GetRegistrationParams => handling::get_registration_params(eid, spid),
             IpcRequest::GetTip { input } => handling::get_tip(db, &input),
@@ -44,11 +48,19 @@ pub fn handle_message(db: &mut DB, request: Multipart, spid: &str, eid: sgx_encl
             IpcRequest::GetContract { input } => handling::get_contract(db, &input),
             IpcRequest::UpdateNewContract { address, bytecode } => handling::update_new_contract(db, address, &bytecode),
             IpcRequest::UpdateDeltas { deltas } => handling::update_deltas(db, deltas),
-            IpcRequest::NewTaskEncryptionKey { user_pubkey } => handling::get_dh_user_key( &user_pubkey, eid),
+            IpcRequest::NewTaskEncryptionKey { user_pubkey, session_id, uses } =>
+                handling::get_dh_user_key(&user_pubkey, &session_id, uses, eid),
             IpcRequest::DeploySecretContract { input } => handling::deploy_contract(db, input, eid),
             IpcRequest::ComputeTask { input } => handling::compute_task(db, input, eid),
             IpcRequest::GetPTTRequest { input } => handling::get_ptt_req(&input, eid),
             IpcRequest::PTTResponse { input } => handling::ptt_response(db, &input, eid),
+            IpcRequest::GetWorkerGroup { input } => handling::get_worker_group(&input, eid),
+            IpcRequest::SetPrincipalAddress { input } => handling::set_principal_address(&input, eid),
+            IpcRequest::ForgetTaskEncryptionKey { user_pubkey, session_id } =>
+                handling::forget_dh_user_key(&user_pubkey, &session_id, eid),
+            IpcRequest::GetDHKeysMetrics => handling::get_dh_keys_metrics(eid),
+            IpcRequest::StartStateRebuild { input } => handling::start_state_rebuild(shared_db, &input, eid),
+            IpcRequest::GetStateRebuildProgress => handling::get_state_rebuild_progress(),
         };
         let msg = IpcMessageResponse::from_response(response_msg.unwrap_or_error(), id);
         responses.push_back(msg.into());
@@ -63,13 +75,16 @@ pub(self) mod handling {
     use crate::common_u::errors::P2PErr;
     use crate::db::{CRUDInterface, DeltaKey, P2PCalls, Stype, DB};
     use crate::km_u;
-    use crate::networking::messages::*;
+    use crate::networking::{messages::*, state_rebuild};
     use crate::esgx::equote;
     use crate::wasm_u::*;
     use enigma_crypto::hash::Keccak256;
+    use enigma_tools_m::keeper_types::InputWorkerParams;
     use enigma_tools_u::esgx::equote as equote_tools;
     use enigma_tools_u::attestation_service::{service::AttestationService, constants::ATTESTATION_SERVICE_URL};
-    use enigma_types::ContractAddress;
+    use enigma_tools_m::primitives::km_primitives::DEFAULT_SESSION;
+    use enigma_types::{ContractAddress, SessionId};
+    use ethereum_types::{H160, U256};
     use failure::Error;
     use hex::{FromHex, ToHex};
     use rmp_serde::Deserializer;
@@ -77,6 +92,7 @@ pub(self) mod handling {
     use serde_json::Value;
     use sgx_types::sgx_enclave_id_t;
     use std::str;
+    use std::sync::{Arc, Mutex};
 
     type ResponseResult = Result<IpcResponse, Error>;
 
@@ -99,6 +115,7 @@ pub(self) mod handling {
                 delta: self.delta.into(),
                 ethereum_address: self.eth_contract_addr.to_hex(),
                 ethereum_payload: self.eth_payload.to_hex(),
+                rand_seed: self.rand_seed.to_hex(),
                 signature: self.signature.to_hex(),
             };
             IpcResponse::ComputeTask { result }
@@ -112,6 +129,7 @@ pub(self) mod handling {
                 delta: self.delta.into(),
                 ethereum_address: self.eth_contract_addr.to_hex(),
                 ethereum_payload: self.eth_payload.to_hex(),
+                rand_seed: self.rand_seed.to_hex(),
                 signature: self.signature.to_hex(),
             };
             IpcResponse::DeploySecretContract { result }
@@ -259,22 +277,63 @@ pub(self) mod handling {
         Ok(IpcResponse::UpdateDeltas {result})
     }
 
+    /// A missing session id refers to the default session, which keeps clients unaware of sessions working.
+    fn parse_session(cmd: &str, session_id: &Option<String>) -> Result<SessionId, Error> {
+        let session_id = match session_id {
+            Some(session_id) => session_id.from_hex()?,
+            None => return Ok(DEFAULT_SESSION),
+        };
+        if session_id.len() != DEFAULT_SESSION.len() {
+            return Err(P2PErr { cmd: cmd.to_string(), msg: "Invalid Session ID".to_string() }.into());
+        }
+        let mut session = DEFAULT_SESSION;
+        session.copy_from_slice(&session_id);
+        Ok(session)
+    }
+
     #[logfn(INFO)]
-    pub fn get_dh_user_key(_user_pubkey: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+    pub fn get_dh_user_key(_user_pubkey: &str, session_id: &Option<String>, uses: u32, eid: sgx_enclave_id_t) -> ResponseResult {
         let mut user_pubkey = [0u8; 64];
         user_pubkey.clone_from_slice(&_user_pubkey.from_hex().unwrap());
+        let session = parse_session("NewTaskEncryptionKey", session_id)?;
 
-        let (msg, sig) = km_u::get_user_key(eid, &user_pubkey)?;
+        let (msg, sig) = km_u::get_user_key(eid, &user_pubkey, &session, uses)?;
 
         let mut des = Deserializer::new(&msg[..]);
         let res: Value = Deserialize::deserialize(&mut des).unwrap();
         let pubkey = serde_json::from_value::<Vec<u8>>(res["pubkey"].clone())?;
+        let version = serde_json::from_value::<u8>(res["version"].clone())?;
 
-        let result = IpcResults::DHKey {dh_key: pubkey.to_hex(), sig: sig.to_hex() };
+        let result = IpcResults::DHKey {dh_key: pubkey.to_hex(), sig: sig.to_hex(), version };
 
         Ok(IpcResponse::NewTaskEncryptionKey {result})
     }
 
+    #[logfn(INFO)]
+    pub fn forget_dh_user_key(user_pubkey: &str, session_id: &Option<String>, eid: sgx_enclave_id_t) -> ResponseResult {
+        let bytes = user_pubkey.from_hex()?;
+        if bytes.len() != 64 {
+            return Err(P2PErr { cmd: "ForgetTaskEncryptionKey".to_string(), msg: "Invalid User Public Key".to_string() }.into());
+        }
+        let mut pubkey = [0u8; 64];
+        pubkey.copy_from_slice(&bytes);
+        let session = parse_session("ForgetTaskEncryptionKey", session_id)?;
+        km_u::forget_user_key(eid, &pubkey, &session)?;
+        Ok(IpcResponse::ForgetTaskEncryptionKey { result: IpcResults::Status(0) })
+    }
+
+    #[logfn(INFO)]
+    pub fn get_dh_keys_metrics(eid: sgx_enclave_id_t) -> ResponseResult {
+        let metrics = km_u::get_dh_keys_metrics(eid)?;
+        let result = IpcResults::DHKeysMetrics {
+            user_keys: metrics.user_keys,
+            evicted_user_keys: metrics.evicted_user_keys,
+            ptt_keys: metrics.ptt_keys,
+            evicted_ptt_keys: metrics.evicted_ptt_keys,
+        };
+        Ok(IpcResponse::GetDHKeysMetrics { result })
+    }
+
     #[logfn(INFO)]
     pub fn get_ptt_req(addresses: &Option<Addresses>, eid: sgx_enclave_id_t) -> ResponseResult {
         let mut addresses_arr: Vec<ContractAddress> = Vec::new();
@@ -293,7 +352,15 @@ pub(self) mod handling {
     #[logfn(INFO)]
     pub fn ptt_response(db: &mut DB, response: &PrincipalResponse, eid: sgx_enclave_id_t) -> ResponseResult {
         let msg = response.response.from_hex()?;
-        km_u::ptt_res(eid, &msg)?;
+        let mut sig = [0u8; 65];
+        if let Some(response_sig) = &response.sig {
+            let bytes = response_sig.from_hex()?;
+            if bytes.len() != sig.len() {
+                return Err(P2PErr { cmd: "PTTResponse".to_string(), msg: "Invalid Signature Length".to_string() }.into());
+            }
+            sig.copy_from_slice(&bytes);
+        }
+        km_u::ptt_res(eid, &msg, &sig)?;
         let res = km_u::ptt_build_state(db, eid)?;
         let result: Vec<_> = res
             .into_iter()
@@ -304,6 +371,92 @@ pub(self) mod handling {
         Ok(IpcResponse::PTTResponse {result})
     }
 
+    /// Starts rebuilding the states in the background, the progress is polled with `GetStateRebuildProgress`
+    #[logfn(INFO)]
+    pub fn start_state_rebuild(db: &Arc<Mutex<DB>>, addresses: &Option<Addresses>, eid: sgx_enclave_id_t) -> ResponseResult {
+        let mut addresses_arr: Vec<ContractAddress> = Vec::new();
+        if let Some(addresses) = addresses {
+            addresses_arr.reserve_exact(addresses.len());
+            for a in addresses.iter() {
+                addresses_arr.push(ContractAddress::from_hex(a)?);
+            }
+        }
+        state_rebuild::start(Arc::clone(db), eid, addresses_arr)
+            .map_err(|e| P2PErr { cmd: "StartStateRebuild".to_string(), msg: e.to_string() })?;
+        Ok(IpcResponse::StartStateRebuild { result: IpcResults::Status(0) })
+    }
+
+    pub fn get_state_rebuild_progress() -> ResponseResult {
+        let progress = state_rebuild::progress();
+        let result = IpcResults::StateRebuildProgress {
+            running: progress.running,
+            total: progress.total as u64,
+            done: progress.results.len() as u64,
+            error: progress.error,
+            contracts: progress.results.into_iter().map(IpcRebuildStatus::from).collect(),
+        };
+        Ok(IpcResponse::GetStateRebuildProgress { result })
+    }
+
+    #[logfn(INFO)]
+    pub fn set_principal_address(input: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+        let bytes = input.from_hex()?;
+        if bytes.len() != 20 {
+            return Err(P2PErr { cmd: "SetPrincipalAddress".to_string(), msg: "Invalid Principal Address".to_string() }.into());
+        }
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&bytes);
+        km_u::set_principal_address(eid, &address)?;
+        Ok(IpcResponse::SetPrincipalAddress { result: IpcResults::Status(0) })
+    }
+
+    #[logfn(INFO)]
+    pub fn get_worker_group(input: &IpcWorkerGroupRequest, eid: sgx_enclave_id_t) -> ResponseResult {
+        let err = |msg: &str| P2PErr { cmd: "GetWorkerGroup".to_string(), msg: msg.to_string() };
+        let to_uint = |hex: &str| -> Result<U256, Error> {
+            let bytes = hex.from_hex()?;
+            if bytes.len() > 32 {
+                return Err(err("Uint Longer Than 32 Bytes").into());
+            }
+            Ok(U256::from_big_endian(&bytes))
+        };
+        let mut workers = Vec::with_capacity(input.workers.len());
+        for worker in &input.workers {
+            let bytes = worker.from_hex()?;
+            if bytes.len() != 20 {
+                return Err(err("Invalid Worker Address").into());
+            }
+            workers.push(H160::from_slice(&bytes));
+        }
+        let stakes = input.stakes.iter().map(|stake| to_uint(stake)).collect::<Result<Vec<U256>, Error>>()?;
+        if workers.len() != stakes.len() {
+            return Err(err("Workers And Stakes Mismatch").into());
+        }
+        let seed = to_uint(&input.seed)?;
+        let nonce = to_uint(&input.nonce)?;
+        let mut sig = [0u8; 65];
+        let sig_bytes = input.sig.from_hex()?;
+        if sig_bytes.len() != sig.len() {
+            return Err(err("Invalid Signature Length").into());
+        }
+        sig.copy_from_slice(&sig_bytes);
+        // The block number is not part of the selection
+        let worker_params = InputWorkerParams { block_number: U256::zero(), workers, stakes };
+        // The selection is computed only for an epoch signed by the principal
+        km_u::verify_principal_sig(eid, &worker_params.encode_epoch(seed, nonce, input.group_size), &sig)?;
+        let signing_address = H160(equote::get_register_signing_address(eid)?);
+
+        let mut groups = Vec::with_capacity(input.addresses.len());
+        for address in &input.addresses {
+            let contract_address = ContractAddress::from_hex(address)?;
+            let group = worker_params.get_selected_workers(contract_address, seed, Some(input.group_size));
+            let member = group.contains(&signing_address);
+            let workers = group.iter().map(|worker| worker.to_hex()).collect();
+            groups.push(IpcWorkerGroup { address: address.clone(), workers, member });
+        }
+        Ok(IpcResponse::GetWorkerGroup { result: IpcResults::WorkerGroups(groups) })
+    }
+
     #[logfn(INFO)]
     pub fn deploy_contract(db: &mut DB, input: IpcTask, eid: sgx_enclave_id_t) -> ResponseResult {
         let bytecode = input.pre_code.expect("Bytecode Missing");
@@ -312,6 +465,7 @@ pub(self) mod handling {
         let constructor = input.encrypted_fn.from_hex()?;
         let mut user_pubkey = [0u8; 64];
         user_pubkey.clone_from_slice(&input.user_dhkey.from_hex()?);
+        let session = parse_session("DeploySecretContract", &input.session_id)?;
         let result = wasm::deploy(
             db,
             eid,
@@ -320,7 +474,9 @@ pub(self) mod handling {
             &enc_args,
             &contract_address,
             &user_pubkey,
-            input.gas_limit)?;
+            &session,
+            input.gas_limit,
+            input.block_number)?;
 
         match result {
             WasmResult::WasmTaskResult(v) => {
@@ -340,6 +496,7 @@ pub(self) mod handling {
         let callable = input.encrypted_fn.from_hex()?;
         let mut user_pubkey = [0u8; 64];
         user_pubkey.clone_from_slice(&input.user_dhkey.from_hex()?);
+        let session = parse_session("ComputeTask", &input.session_id)?;
 
         let bytecode = db.get_contract(address)?;
 
@@ -351,8 +508,10 @@ pub(self) mod handling {
             &callable,
             &enc_args,
             &user_pubkey,
+            &session,
             &address,
-            input.gas_limit)?;
+            input.gas_limit,
+            input.block_number)?;
 
         match result {
             WasmResult::WasmTaskResult(v) => Ok(v.into_execute_response()),
@@ -413,9 +572,10 @@ mod test {
             res.unwrap();
         }
 
+        let db = Arc::new(Mutex::new(db));
         let conn = "tcp://*:2456";
         let server = IpcListener::new(conn);
-        server.run(|multi| handle_message(&mut db, multi,  SPID, enclave.geteid())).wait().unwrap();
+        server.run(|multi| handle_message(&db, multi,  SPID, enclave.geteid())).wait().unwrap();
     }
 
 }
@@ -2,6 +2,8 @@ use serde_json;
 use zmq::Message;
 use crate::db::{Delta, Stype, DeltaKey};
 use hex::ToHex;
+use enigma_tools_m::keeper_types::DEFAULT_GROUP_SIZE;
+use enigma_tools_m::primitives::km_primitives::{BuildStateResult, BuildStateStatus};
 use failure::Error;
 
 type Status = i8;
@@ -40,6 +42,12 @@ pub enum IpcResponse {
     FailedTask { #[serde(flatten)] result: IpcResults },
     GetPTTRequest { #[serde(flatten)] result: IpcResults },
     PTTResponse { result: IpcResults },
+    GetWorkerGroup { result: IpcResults },
+    SetPrincipalAddress { result: IpcResults },
+    ForgetTaskEncryptionKey { result: IpcResults },
+    GetDHKeysMetrics { #[serde(flatten)] result: IpcResults },
+    StartStateRebuild { result: IpcResults },
+    GetStateRebuildProgress { #[serde(flatten)] result: IpcResults },
     Error { msg: String },
 }
 
@@ -55,10 +63,39 @@ pub enum IpcResults {
     Bytecode(String),
     Status(Status),
     Tips(Vec<IpcDelta>),
+    WorkerGroups(Vec<IpcWorkerGroup>),
     #[serde(rename = "result")]
     UpdateDeltasResult { status: Status, errors: Vec<IpcStatusResult> },
     #[serde(rename = "result")]
-    DHKey { #[serde(rename = "workerEncryptionKey")] dh_key: String, #[serde(rename = "workerSig")] sig: String },
+    DHKey {
+        #[serde(rename = "workerEncryptionKey")]
+        dh_key: String,
+        #[serde(rename = "workerSig")]
+        sig: String,
+        /// The version of the key exchange, which tells the client how to derive the session key.
+        #[serde(rename = "keyExchangeVersion")]
+        version: u8,
+    },
+    #[serde(rename = "result")]
+    DHKeysMetrics {
+        #[serde(rename = "userKeys")]
+        user_keys: u64,
+        #[serde(rename = "evictedUserKeys")]
+        evicted_user_keys: u64,
+        #[serde(rename = "pttKeys")]
+        ptt_keys: u64,
+        #[serde(rename = "evictedPttKeys")]
+        evicted_ptt_keys: u64,
+    },
+    #[serde(rename = "result")]
+    StateRebuildProgress {
+        running: bool,
+        total: u64,
+        done: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        contracts: Vec<IpcRebuildStatus>,
+    },
     #[serde(rename = "result")]
     RegistrationParams { #[serde(rename = "signingKey")] signing_key: String, report: String, signature: String },
     #[serde(rename = "result")]
@@ -71,6 +108,8 @@ pub enum IpcResults {
         ethereum_address: String,
         #[serde(rename = "ethereumPayload")]
         ethereum_payload: String,
+        #[serde(rename = "randSeed")]
+        rand_seed: String,
         signature: String,
     },
     #[serde(rename = "result")]
@@ -85,6 +124,8 @@ pub enum IpcResults {
         ethereum_address: String,
         #[serde(rename = "ethereumPayload")]
         ethereum_payload: String,
+        #[serde(rename = "randSeed")]
+        rand_seed: String,
         signature: String,
     },
     #[serde(rename = "result")]
@@ -109,11 +150,24 @@ pub enum IpcRequest {
     GetContract { input: String },
     UpdateNewContract { address: String, bytecode: String },
     UpdateDeltas { deltas: Vec<IpcDelta> },
-    NewTaskEncryptionKey { #[serde(rename = "userPubKey")] user_pubkey: String },
+    NewTaskEncryptionKey {
+        #[serde(rename = "userPubKey")] user_pubkey: String,
+        #[serde(rename = "sessionId", default)] session_id: Option<String>,
+        #[serde(default = "default_session_uses")] uses: u32,
+    },
     DeploySecretContract { input: IpcTask},
     ComputeTask { input: IpcTask },
     GetPTTRequest { input: Option<Addresses> },
     PTTResponse {  input: PrincipalResponse },
+    GetWorkerGroup { input: IpcWorkerGroupRequest },
+    SetPrincipalAddress { input: String },
+    ForgetTaskEncryptionKey {
+        #[serde(rename = "userPubKey")] user_pubkey: String,
+        #[serde(rename = "sessionId", default)] session_id: Option<String>,
+    },
+    GetDHKeysMetrics,
+    StartStateRebuild { input: Option<Addresses> },
+    GetStateRebuildProgress,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -127,8 +181,15 @@ pub struct IpcTask {
     pub encrypted_fn: String,
     #[serde(rename = "userDHKey")]
     pub user_dhkey: String,
+    /// The encryption session the task was encrypted under, the default one if missing
+    #[serde(rename = "sessionId", default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
     #[serde(rename = "gasLimit")]
     pub gas_limit: u64,
+    /// The block number the task was submitted in, it's required and the enclave rejects 0
+    #[serde(rename = "blockNumber")]
+    pub block_number: u64,
     #[serde(rename = "contractAddress")]
     pub address: String,
 }
@@ -141,6 +202,15 @@ pub struct IpcStatusResult {
     pub status: Status,
 }
 
+/// The outcome of rebuilding a contract's state, `index` is the delta index the status refers to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcRebuildStatus {
+    pub address: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct IpcDelta {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -158,9 +228,40 @@ pub struct IpcGetDeltas {
     pub to: u32,
 }
 
+/// The worker parameters of an epoch, as published by the Enigma contract, used to run the
+/// worker selection for a group of contracts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IpcWorkerGroupRequest {
+    pub seed: String,
+    pub nonce: String,
+    /// The principal's signature of the epoch, as submitted to the Enigma contract
+    pub sig: String,
+    pub workers: Vec<String>,
+    pub stakes: Vec<String>,
+    #[serde(default = "default_group_size")]
+    pub group_size: u64,
+    pub addresses: Vec<String>,
+}
+
+fn default_group_size() -> u64 { DEFAULT_GROUP_SIZE }
+
+/// A session without an explicit use count serves a single task.
+fn default_session_uses() -> u32 { 1 }
+
+/// The group of workers selected for a contract, and whether this worker is one of them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcWorkerGroup {
+    pub address: String,
+    pub workers: Vec<String>,
+    pub member: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PrincipalResponse {
     pub response: String,
+    /// The principal's signature of the response, an unsigned response is rejected by the enclave
+    pub sig: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -207,6 +308,21 @@ impl From<Delta> for IpcDelta {
     }
 }
 
+impl From<BuildStateResult> for IpcRebuildStatus {
+    fn from(res: BuildStateResult) -> Self {
+        let (status, index) = match res.status {
+            BuildStateStatus::Rebuilt(index) => ("rebuilt", Some(index)),
+            BuildStateStatus::UpToDate(index) => ("upToDate", Some(index)),
+            BuildStateStatus::DecryptFailure => ("decryptFailure", None),
+            BuildStateStatus::MissingDelta(index) => ("missingDelta", Some(index)),
+            BuildStateStatus::MissingKey => ("missingKey", None),
+            BuildStateStatus::SaveFailure => ("saveFailure", None),
+            BuildStateStatus::ReadFailure(index) => ("readFailure", Some(index)),
+        };
+        IpcRebuildStatus { address: res.address.to_hex(), status: status.to_string(), index }
+    }
+}
+
 impl From<Message> for IpcMessageRequest {
     fn from(msg: Message) -> Self {
         let msg_str = msg.as_str().unwrap();
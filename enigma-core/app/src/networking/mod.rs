@@ -1,5 +1,6 @@
 pub mod constants;
 pub mod ipc_listener;
 pub mod messages;
+pub mod state_rebuild;
 
 pub use self::ipc_listener::IpcListener;
@@ -0,0 +1,58 @@
+use crate::db::{P2PCalls, DB};
+use crate::km_u;
+use enigma_tools_m::primitives::km_primitives::BuildStateResult;
+use enigma_tools_u::common_u::utils_u::LockExpectMutex;
+use enigma_types::ContractAddress;
+use failure::Error;
+use sgx_types::sgx_enclave_id_t;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many contracts are rebuilt in a single ecall, the DB is locked only for the duration of one,
+/// so the listener keeps serving requests in between.
+const CONTRACTS_BATCH: usize = 16;
+
+lazy_static! { static ref STATE_REBUILD: Mutex<RebuildProgress> = Mutex::new(RebuildProgress::default()); }
+
+/// The progress of the latest state rebuild, there's at most one running at a time.
+#[derive(Debug, Default, Clone)]
+pub struct RebuildProgress {
+    pub running: bool,
+    /// How many contracts the rebuild covers, set once the addresses are known
+    pub total: usize,
+    /// The status of every contract rebuilt so far
+    pub results: Vec<BuildStateResult>,
+    /// Why the rebuild stopped before covering all the contracts
+    pub error: Option<String>,
+}
+
+/// Starts rebuilding the states of the given contracts in the background, or of all the contracts in the DB if none are given.
+/// Fails if a rebuild is already running, otherwise the progress of the previous one is dropped.
+pub fn start(db: Arc<Mutex<DB>>, eid: sgx_enclave_id_t, addresses: Vec<ContractAddress>) -> Result<(), Error> {
+    {
+        let mut progress = STATE_REBUILD.lock_expect("State Rebuild");
+        if progress.running {
+            bail!("A state rebuild is already running");
+        }
+        *progress = RebuildProgress { running: true, ..Default::default() };
+    }
+    thread::spawn(move || {
+        let error = run(&db, eid, addresses).err();
+        let mut progress = STATE_REBUILD.lock_expect("State Rebuild");
+        progress.running = false;
+        progress.error = error.map(|e| e.to_string());
+    });
+    Ok(())
+}
+
+pub fn progress() -> RebuildProgress { STATE_REBUILD.lock_expect("State Rebuild").clone() }
+
+fn run(db: &Mutex<DB>, eid: sgx_enclave_id_t, addresses: Vec<ContractAddress>) -> Result<(), Error> {
+    let addresses = if addresses.is_empty() { db.lock_expect("DB").get_all_addresses()? } else { addresses };
+    STATE_REBUILD.lock_expect("State Rebuild").total = addresses.len();
+    for batch in addresses.chunks(CONTRACTS_BATCH) {
+        let results = km_u::rebuild_state(&mut db.lock_expect("DB"), eid, batch)?;
+        STATE_REBUILD.lock_expect("State Rebuild").results.extend(results);
+    }
+    Ok(())
+}
@@ -16,6 +16,7 @@ pub struct WasmTaskResult {
     pub eth_contract_addr: [u8; 20],
     pub signature: [u8; 65],
     pub used_gas: u64,
+    pub rand_seed: [u8; 32],
 }
 
 pub struct WasmTaskFailure {
@@ -39,7 +40,8 @@ impl Default for WasmTaskResult {
             eth_payload: Default::default(),
             eth_contract_addr: Default::default(),
             signature: [0u8; 65],
-            used_gas: Default::default()
+            used_gas: Default::default(),
+            rand_seed: Default::default(),
         }
     }
 }
@@ -64,6 +66,7 @@ impl fmt::Debug for WasmTaskResult {
         debug_builder.field("eth_contract_addr", &self.eth_contract_addr);
         debug_builder.field("signature", &(&self.signature[..]));
         debug_builder.field("used_gas", &self.used_gas);
+        debug_builder.field("rand_seed", &self.rand_seed);
         debug_builder.finish()
     }
 }
@@ -109,6 +112,7 @@ impl TryFrom<(ExecuteResult, ContractAddress, EnclaveReturn, sgx_status_t)> for
             result.output = get_output(exec.0)?;
             result.signature = exec.0.signature;
             result.used_gas = exec.0.used_gas;
+            result.rand_seed = exec.0.rand_seed;
 
             // If there is no call to any ethereum contract in the execution, then
             // `eth_contract_addr` is all zeros
@@ -1,6 +1,6 @@
 
 
-use enigma_types::{ContractAddress, EnclaveReturn, ExecuteResult, PubKey, RawPointer, traits::SliceCPtr};
+use enigma_types::{ContractAddress, EnclaveReturn, ExecuteResult, PubKey, RawPointer, SessionId, traits::SliceCPtr};
 use super::WasmResult;
 use crate::db::DB;
 use std::convert::TryInto;
@@ -12,21 +12,23 @@ extern "C" {
                     bytecode: *const u8, bytecode_len: usize,
                     constructor: *const u8, constructor_len: usize,
                     args: *const u8, args_len: usize,
-                    address: &ContractAddress, user_key: &PubKey,
-                    gas_limit: *const u64, db_ptr: *const RawPointer,
+                    address: &ContractAddress, user_key: &PubKey, session: &SessionId,
+                    gas_limit: *const u64, block_number: *const u64, db_ptr: *const RawPointer,
                     result: &mut ExecuteResult) -> sgx_status_t;
 
     fn ecall_execute(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
                      bytecode: *const u8, bytecode_len: usize,
                      callable: *const u8, callable_len: usize,
                      args: *const u8, args_len: usize,
-                     user_key: &[u8; 64], contract_address: &ContractAddress,
-                     gas_limit: *const u64, db_ptr: *const RawPointer, result: &mut ExecuteResult ) -> sgx_status_t;
+                     user_key: &[u8; 64], session: &SessionId, contract_address: &ContractAddress,
+                     gas_limit: *const u64, block_number: *const u64, db_ptr: *const RawPointer,
+                     result: &mut ExecuteResult ) -> sgx_status_t;
 }
 
 #[logfn(DEBUG)]
 pub fn deploy(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], constructor: &[u8], args: &[u8],
-              contract_address: &ContractAddress, user_pubkey: &PubKey, gas_limit: u64)-> Result<WasmResult, Error> {
+              contract_address: &ContractAddress, user_pubkey: &PubKey, session: &SessionId, gas_limit: u64,
+              block_number: u64)-> Result<WasmResult, Error> {
     let mut retval = EnclaveReturn::Success;
     let mut result = ExecuteResult::default();
     let db_ptr = unsafe { RawPointer::new_mut(db) };
@@ -42,7 +44,9 @@ pub fn deploy(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], constructor:
                      args.len(),
                      contract_address,
                      &user_pubkey,
+                     session,
                      &gas_limit as *const u64,
+                     &block_number as *const u64,
                      &db_ptr as *const RawPointer,
                      &mut result)
     };
@@ -51,7 +55,8 @@ pub fn deploy(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], constructor:
 
 #[logfn(DEBUG)]
 pub fn execute(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], callable: &[u8], args: &[u8],
-               user_pubkey: &PubKey, contract_address: &ContractAddress, gas_limit: u64)-> Result<WasmResult,Error> {
+               user_pubkey: &PubKey, session: &SessionId, contract_address: &ContractAddress, gas_limit: u64,
+               block_number: u64)-> Result<WasmResult,Error> {
     let mut retval = EnclaveReturn::Success;
     let mut result = ExecuteResult::default();
     let db_ptr = unsafe { RawPointer::new_mut(db) };
@@ -66,8 +71,10 @@ pub fn execute(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], callable: &
                       args.as_c_ptr() as *const u8,
                       args.len(),
                       &user_pubkey,
+                      session,
                       contract_address,
                       &gas_limit as *const u64,
+                      &block_number as *const u64,
                       &db_ptr as *const RawPointer,
                       &mut result)
     };
@@ -86,14 +93,17 @@ mod tests {
     use crate::km_u::tests::instantiate_encryption_key;
     use crate::db::{DB, tests::create_test_db};
     use crate::wasm_u::wasm;
-    use self::ethabi::{Token};
+    use self::ethabi::{Token, ParamType};
     use enigma_types::{ContractAddress, DhKey, PubKey};
-    use enigma_crypto::symmetric;
+    use enigma_crypto::{symmetric, KeyPair, asymmetric::SEALED_OVERHEAD, hash::Keccak256};
     use sgx_types::*;
     use wasm_u::{WasmResult, WasmTaskResult};
+    use enigma_tools_m::primitives::{km_primitives::DEFAULT_SESSION, receipts::TaskReceipt};
+    use crate::esgx::equote::get_register_signing_address;
     use self::ethabi::Uint;
 
     pub const GAS_LIMIT: u64 = 100_000_000;
+    pub const BLOCK_NUMBER: u64 = 1_000;
 
     impl WasmResult {
         fn unwrap_result(self) -> WasmTaskResult {
@@ -108,7 +118,7 @@ mod tests {
         let wasm_code = get_bytecode_from_path(test_path);
         println!("Bytecode size: {}KB\n", wasm_code.len() / 1024);
 
-        wasm::deploy(db, eid, &wasm_code, constructor, args, &contract_address, &user_pubkey, GAS_LIMIT).expect("Deploy Failed")
+        wasm::deploy(db, eid, &wasm_code, constructor, args, &contract_address, &user_pubkey, &DEFAULT_SESSION, GAS_LIMIT, BLOCK_NUMBER).expect("Deploy Failed")
     }
 
     fn compile_deploy_execute(db: &mut DB,
@@ -147,8 +157,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &contract_address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         (enclave, exe_code, result, shared_key)
@@ -187,14 +199,71 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &contract_address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         assert!(result.used_gas > 10_000);
 
     }
 
+    #[test]
+    fn test_verify_task_receipts() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let enclave = init_enclave_wrapper().unwrap();
+        instantiate_encryption_key(vec![contract_address], enclave.geteid());
+        let worker = get_register_signing_address(enclave.geteid()).unwrap();
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_construct = symmetric::encrypt(b"construct()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let pre_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/flip_coin");
+        let deploy_res = wasm::deploy(&mut db, enclave.geteid(), &pre_code, &encrypted_construct, &encrypted_args, &contract_address,
+                                      &keys.get_pubkey(), &DEFAULT_SESSION, GAS_LIMIT, BLOCK_NUMBER).unwrap().unwrap_result();
+
+        let deploy_receipt = TaskReceipt::Deploy {
+            inputs_hash: TaskReceipt::deploy_inputs_hash(&encrypted_construct, &encrypted_args, &pre_code.keccak256(), &keys.get_pubkey()),
+            exe_code_hash: deploy_res.output.keccak256(),
+            delta_hash: TaskReceipt::stored_delta_hash(&deploy_res.delta.value),
+            used_gas: deploy_res.used_gas,
+            ethereum_payload: deploy_res.eth_payload.to_vec(),
+            ethereum_address: deploy_res.eth_contract_addr,
+            block_number: BLOCK_NUMBER,
+            rand_seed: deploy_res.rand_seed.into(),
+        };
+        deploy_receipt.verify(&deploy_res.signature, &worker).unwrap();
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"flip()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let result = wasm::execute(&mut db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                   &keys.get_pubkey(), &DEFAULT_SESSION, &contract_address, GAS_LIMIT, BLOCK_NUMBER)
+            .unwrap().unwrap_result();
+
+        let mut compute_receipt = TaskReceipt::Compute {
+            exe_code_hash: deploy_res.output.keccak256(),
+            inputs_hash: TaskReceipt::compute_inputs_hash(&encrypted_callable, &encrypted_args, &contract_address, &keys.get_pubkey()),
+            prev_delta_hash: TaskReceipt::stored_delta_hash(&deploy_res.delta.value),
+            delta_hash: TaskReceipt::stored_delta_hash(&result.delta.value),
+            output_hash: result.output.keccak256(),
+            used_gas: result.used_gas,
+            ethereum_payload: result.eth_payload.to_vec(),
+            ethereum_address: result.eth_contract_addr,
+            block_number: BLOCK_NUMBER,
+            rand_seed: result.rand_seed.into(),
+        };
+        compute_receipt.verify(&result.signature, &worker).unwrap();
+
+        // A receipt which doesn't match the result recovers to some other signer
+        if let TaskReceipt::Compute { ref mut block_number, .. } = compute_receipt {
+            *block_number += 1;
+        }
+        assert!(compute_receipt.verify(&result.signature, &worker).is_err());
+    }
+
     #[test]
     fn test_charge_for_write() {
         let (mut db, _dir) = create_test_db();
@@ -222,8 +291,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         assert_eq!(used_gas_for_write_new_value - result.used_gas, 3);
@@ -240,8 +311,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         assert!(used_gas_for_write_new_value - result.used_gas >= 1);
@@ -275,8 +348,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
@@ -289,8 +364,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         encoded_output = symmetric::decrypt(&result.output, &shared_key).unwrap();
@@ -348,8 +425,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &contract_address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed");
 
         if let WasmResult::WasmTaskResult(v) = result {
@@ -394,6 +473,43 @@ mod tests {
         assert_eq!(&(decoded_output.clone().to_bytes().unwrap())[..], b"157");
     }
 
+    #[test]
+    fn test_task_context() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(17.into())],
+        );
+
+        let mut call = |func: &str, param: ParamType| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(func.as_bytes(), &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&[], &shared_key).unwrap();
+            let result = wasm::execute(&mut db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                       &keys.get_pubkey(), &DEFAULT_SESSION, &contract_address, GAS_LIMIT, BLOCK_NUMBER)
+                .expect("Execution failed").unwrap_result();
+            let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+            (keys, ethabi::decode(&[param], &output).unwrap().pop().unwrap())
+        };
+
+        let (keys, sender) = call("get_sender()", ParamType::Address);
+        assert_eq!(sender.to_address().unwrap().0, keys.get_pubkey()[..].keccak256()[12..]);
+
+        let (_, address) = call("get_contract_address()", ParamType::FixedBytes(32));
+        assert_eq!(address.to_fixed_bytes().unwrap(), contract_address.to_vec());
+
+        let (_, block_number) = call("get_block_number()", ParamType::Uint(256));
+        assert_eq!(block_number.to_uint().unwrap(), BLOCK_NUMBER.into());
+
+        let (_, first_id) = call("get_task_id()", ParamType::FixedBytes(32));
+        let (_, second_id) = call("get_task_id()", ParamType::FixedBytes(32));
+        assert_ne!(first_id, second_id);
+    }
+
     // address is defined in our protocol as ethereum's H256/bytes32
     #[test]
     fn test_single_address() {
@@ -530,8 +646,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         // deserialization of result
@@ -572,8 +690,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         let result_balance_decrypted = symmetric::decrypt(&result_balance.output, &shared_key).unwrap();
@@ -620,8 +740,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed");
 
         let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
@@ -634,8 +756,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         let result_balance_decrypted = symmetric::decrypt(&result_balance.output, &shared_key).unwrap();
@@ -651,8 +775,10 @@ mod tests {
             &encrypted_callable,
             &encrypted_args,
             &keys.get_pubkey(),
+            &DEFAULT_SESSION,
             &address,
-            GAS_LIMIT
+            GAS_LIMIT,
+            BLOCK_NUMBER
         ).expect("Execution failed").unwrap_result();
 
         let result_allowance_decrypted = symmetric::decrypt(&result_allowance.output, &shared_key).unwrap();
@@ -878,4 +1004,45 @@ mod tests {
         );
         assert_eq!(symmetric::decrypt(&result.output, &shared_key).unwrap(), *millionaire_two_addr);
     }
+
+    #[test]
+    fn test_millionaires_private_results(){
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/millionaires_problem_demo",
+            contract_address,
+            "construct()",
+            &[],
+        );
+
+        let millionaires: Vec<(KeyPair, u64)> = vec![(KeyPair::new().unwrap(), 2_000_000), (KeyPair::new().unwrap(), 1_000_000)];
+        for (keys, net_worth) in &millionaires {
+            let (_, _) = compile_compute_task_execute(
+                &mut db,
+                &enclave,
+                &deploy_res,
+                "add_millionaire_with_key(bytes32,uint256,bytes)",
+                &[Token::FixedBytes(generate_user_address().0.to_vec()), Token::Uint((*net_worth).into()), Token::Bytes(keys.get_pubkey().to_vec())],
+                contract_address,
+            );
+        }
+
+        let (result, shared_key) = compile_compute_task_execute(
+            &mut db,
+            &enclave,
+            &deploy_res,
+            "compute_private_results()",
+            &[],
+            contract_address,
+        );
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        let sealed = ethabi::decode(&[ParamType::Bytes], &output).unwrap().pop().unwrap().to_bytes().unwrap();
+        let results: Vec<&[u8]> = sealed.chunks(1 + SEALED_OVERHEAD).collect();
+        assert_eq!(millionaires[0].0.open_sealed(results[0]).unwrap(), vec![1]);
+        assert_eq!(millionaires[1].0.open_sealed(results[1]).unwrap(), vec![0]);
+        // The task sender can decrypt the output, but not the results sealed to the millionaires.
+        assert!(symmetric::decrypt(&results[0][64..], &shared_key).is_err());
+    }
 }
@@ -15,8 +15,8 @@ extern crate rand;
 extern crate tempfile;
 
 use self::cross_test_utils::{generate_contract_address, generate_user_address, make_encrypted_response,
-                             get_fake_state_key, get_bytecode_from_path, ContractAddress,
-                             ERC20UserAddress, sign_message};
+                             get_fake_state_key, get_bytecode_from_path, get_principal_address, sign_principal_response,
+                             ContractAddress, ERC20UserAddress, sign_message};
 use self::app::*;
 use self::futures::Future;
 use self::app::networking::*;
@@ -24,16 +24,20 @@ use self::serde::{Deserialize, Serialize};
 use self::rmps::{Deserializer, Serializer};
 use self::app::serde_json;
 use app::serde_json::*;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use self::regex::Regex;
 use self::hex::{ToHex, FromHex};
-use self::ethabi::{Token};
-use self::enigma_crypto::{asymmetric::KeyPair, symmetric};
+use self::ethabi::{Address, Token};
+use self::enigma_crypto::{asymmetric::KeyPair, kdf::KeyPurpose, symmetric};
 use self::enigma_types::Hash256;
 use self::rand::{thread_rng, Rng};
 use app::db::DB;
 use self::tempfile::TempDir;
 
+/// The block number the tasks are submitted in.
+pub const BLOCK_NUMBER: u64 = 1_000;
+
 /// It's important to save TempDir too, because when it gets dropped the directory will be removed.
 pub fn create_test_db() -> (DB, TempDir) {
     let tempdir = tempfile::tempdir().unwrap();
@@ -46,11 +50,12 @@ pub fn run_core(port: &'static str) {
         let enclave = esgx::general::init_enclave_wrapper().expect("[-] Init Enclave Failed");
         let eid = enclave.geteid();
 
-        let (mut db, _datadir) = create_test_db();
+        let (db, _datadir) = create_test_db();
+        let db = Arc::new(Mutex::new(db));
         let server = IpcListener::new(&format!("tcp://*:{}", port));
         let spid = "B0335FD3BC1CCA8F804EB98A6420592D";
         server
-            .run(move |multi| ipc_listener::handle_message(&mut db, multi, spid, eid))
+            .run(move |multi| ipc_listener::handle_message(&db, multi, spid, eid))
             .wait()
             .unwrap();
 
@@ -93,6 +98,15 @@ pub fn get_encryption_msg(user_pubkey: [u8; 64]) -> Value {
     json!({"id" : &generate_job_id(), "type" : "NewTaskEncryptionKey", "userPubKey": user_pubkey.to_hex()})
 }
 
+pub fn get_session_encryption_msg(user_pubkey: [u8; 64], session_id: &str, uses: u32) -> Value {
+    json!({"id" : &generate_job_id(), "type" : "NewTaskEncryptionKey", "userPubKey": user_pubkey.to_hex(),
+           "sessionId": session_id, "uses": uses})
+}
+
+pub fn get_forget_encryption_key_msg(user_pubkey: [u8; 64]) -> Value {
+    json!({"id" : &generate_job_id(), "type" : "ForgetTaskEncryptionKey", "userPubKey": user_pubkey.to_hex()})
+}
+
 pub fn get_ptt_req_msg(addresses: Option<Vec<String>>) -> Value {
     match addresses {
         None => json!({"id" : &generate_job_id(), "type" : "GetPTTRequest"}),
@@ -100,21 +114,56 @@ pub fn get_ptt_req_msg(addresses: Option<Vec<String>>) -> Value {
     }
 }
 
+pub fn get_start_state_rebuild_msg(addresses: Option<Vec<String>>) -> Value {
+    match addresses {
+        None => json!({"id" : &generate_job_id(), "type" : "StartStateRebuild"}),
+        Some(addrs) =>  json!({"id" : &generate_job_id(), "type" : "StartStateRebuild", "input": { "addresses": addrs} }),
+    }
+}
+
 pub fn get_ptt_res_msg(response: &[u8]) -> Value {
-    json!({"id" : &generate_job_id(), "type" : "PTTResponse", "input": {"response": response.to_hex() }})
+    let sig = sign_principal_response(response);
+    json!({"id" : &generate_job_id(), "type" : "PTTResponse", "input": {"response": response.to_hex(), "sig": sig.to_hex() }})
+}
+
+pub fn get_set_principal_address_msg(address: &[u8; 20]) -> Value {
+    json!({"id" : &generate_job_id(), "type" : "SetPrincipalAddress", "input": address.to_hex()})
+}
+
+pub fn set_principal_address(port: &'static str) -> Value {
+    let msg = get_set_principal_address_msg(&get_principal_address());
+    conn_and_call_ipc(&msg.to_string(), port)
+}
+
+/// A `GetWorkerGroup` request for an epoch signed by the test principal key
+pub fn get_worker_group_msg(seed: u64, nonce: u64, workers: &[String], stakes: &[u64], group_size: u64, addrs: &[String]) -> Value {
+    let to_hex = |value: u64| format!("{:016x}", value);
+    let epoch = ethabi::encode(&[
+        Token::Uint(seed.into()),
+        Token::Uint(nonce.into()),
+        Token::Array(workers.iter().map(|worker| Token::Address(Address::from_slice(&worker.from_hex::<Vec<u8>>().unwrap()))).collect()),
+        Token::Array(stakes.iter().map(|&stake| Token::Uint(stake.into())).collect()),
+        Token::Uint(group_size.into()),
+    ]);
+    let sig = sign_principal_response(&epoch);
+    let stakes: Vec<String> = stakes.iter().map(|&stake| to_hex(stake)).collect();
+    json!({"id" : &generate_job_id(), "type" : "GetWorkerGroup", "input":
+            {"seed": to_hex(seed), "nonce": to_hex(nonce), "sig": sig.to_hex(), "workers": workers, "stakes": stakes,
+            "groupSize": group_size, "addresses": addrs}})
 }
 
 pub fn get_deploy_msg(pre_code: &[u8], args: &str, callable: &str, usr_pubkey: &str, gas_limit: u64, addr: &str) -> Value {
     json!({"id" : &generate_job_id(), "type" : "DeploySecretContract", "input":
             {"preCode": &pre_code, "encryptedArgs": args,
             "encryptedFn": callable, "userDHKey": usr_pubkey,
-            "gasLimit": gas_limit, "contractAddress": addr}
+            "gasLimit": gas_limit, "blockNumber": BLOCK_NUMBER, "contractAddress": addr}
             })
 }
 
 pub fn get_compute_msg(task_id: &str, callable: &str, args: &str, user_pubkey: &str, gas_limit: u64, con_addr: &str) -> Value {
     json!({"id": &generate_job_id(), "type": "ComputeTask", "input": { "taskID": task_id, "encryptedArgs": args,
-    "encryptedFn": callable, "userDHKey": user_pubkey, "gasLimit": gas_limit, "contractAddress": con_addr}})
+    "encryptedFn": callable, "userDHKey": user_pubkey, "gasLimit": gas_limit, "blockNumber": BLOCK_NUMBER,
+    "contractAddress": con_addr}})
 }
 
 pub fn get_get_tips_msg(input: &[String]) -> Value {
@@ -155,6 +204,7 @@ pub fn mock_principal_res(msg: &str, addrs: Vec<ContractAddress>) -> Vec<u8> {
 }
 
 pub fn run_ptt_round(port: &'static str, addrs: Vec<ContractAddress>) -> Value {
+    set_principal_address(port);
 
     // set encrypted request message to send to the principal node
     let msg_req = get_ptt_req_msg(None);
@@ -177,10 +227,20 @@ pub fn produce_shared_key(port: &'static str) -> ([u8; 32], [u8; 64]) {
     let mut pubkey_arr = [0u8; 64];
     pubkey_arr.copy_from_slice(&_pubkey_vec);
 
-    let shared_key = keys.derive_key(&pubkey_arr).unwrap();
+    let shared_key = keys.derive_key_for(&pubkey_arr, KeyPurpose::UserIo).unwrap();
     (shared_key, keys.get_pubkey())
 }
 
+/// Opens an encryption session of `keys` that serves `uses` tasks, returns the key the tasks are encrypted with.
+pub fn produce_session_key(port: &'static str, keys: &KeyPair, session_id: &str, uses: u32) -> [u8; 32] {
+    let msg = get_session_encryption_msg(keys.get_pubkey(), session_id, uses);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    let core_pubkey: Vec<u8> = v["result"]["workerEncryptionKey"].as_str().unwrap().from_hex().unwrap();
+    let mut pubkey_arr = [0u8; 64];
+    pubkey_arr.copy_from_slice(&core_pubkey);
+    keys.derive_key_for(&pubkey_arr, KeyPurpose::UserIo).unwrap()
+}
+
 pub fn full_erc20_deployment(port: &'static str, owner: ERC20UserAddress, total_supply: Option<u64>, gas_limit: Option<u64>) -> (Value, [u8; 32], [u8; 32]) {
     // address generation and ptt
     let address = generate_contract_address();
@@ -271,23 +331,41 @@ pub fn contract_compute(port: &'static str,  contract_addr: [u8; 32], args: &[To
     (conn_and_call_ipc(&msg.to_string(), port), shared_key)
 }
 
-fn encrypt_args( args:&[Token], callable: &str, key: [u8;32]) -> (Vec<u8>, Vec<u8>) {
+pub fn encrypt_args( args:&[Token], callable: &str, key: [u8;32]) -> (Vec<u8>, Vec<u8>) {
     (symmetric::encrypt(callable.as_bytes(), &key).unwrap(),
      symmetric::encrypt(&ethabi::encode(args), &key).unwrap())
 }
 
-pub fn encrypt_addr_delta(addr: [u8; 32], delta: &[u8]) -> Vec<u8> {
+/// The top bit of a stored delta's key version, set when the ciphertext is bound to its contract and index.
+const AAD_BOUND_FLAG: u32 = 1 << 31;
+
+pub fn delta_aad(addr: [u8; 32], index: u32) -> Vec<u8> {
+    let mut aad = vec![1u8];
+    aad.extend_from_slice(&addr);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+/// Encrypts a delta the way the enclave stores it, the key version followed by the bound ciphertext.
+pub fn encrypt_addr_delta(addr: [u8; 32], index: u32, delta: &[u8]) -> Vec<u8> {
     let state_key = get_fake_state_key(addr.into());
-    symmetric::encrypt(delta, &state_key).unwrap()
+    let mut stored = AAD_BOUND_FLAG.to_be_bytes().to_vec();
+    stored.extend(symmetric::encrypt_with_aad(delta, &state_key, &delta_aad(addr, index), None).unwrap());
+    stored
 }
 
-pub fn decrypt_addr_delta(addr: [u8; 32], delta: &[u8]) -> Vec<u8> {
+pub fn decrypt_addr_delta(addr: [u8; 32], index: u32, stored: &[u8]) -> Vec<u8> {
     let state_key = get_fake_state_key(addr.into());
-    symmetric::decrypt(delta, &state_key).unwrap()
+    let (version, ciphertext) = stored.split_at(4);
+    if u32::from_be_bytes([version[0], version[1], version[2], version[3]]) & AAD_BOUND_FLAG != 0 {
+        symmetric::decrypt_with_aad(ciphertext, &state_key, &delta_aad(addr, index)).unwrap()
+    } else {
+        symmetric::decrypt(ciphertext, &state_key).unwrap()
+    }
 }
 
-pub fn decrypt_delta_to_value(addr: [u8; 32], delta: &[u8]) -> Value {
-    let dec = decrypt_addr_delta(addr, delta);
+pub fn decrypt_delta_to_value(addr: [u8; 32], index: u32, delta: &[u8]) -> Value {
+    let dec = decrypt_addr_delta(addr, index, delta);
     let mut des = Deserializer::new(&dec[..]);
     Deserialize::deserialize(&mut des).unwrap()
 }
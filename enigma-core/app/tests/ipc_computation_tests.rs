@@ -30,6 +30,7 @@ fn test_new_task_encryption_key(){
 
     assert!(is_hex(result_key));
     assert!(is_hex(result_sig));
+    assert_eq!(v["result"]["keyExchangeVersion"].as_u64(), Some(1));
 }
 
 #[test]
@@ -135,15 +136,17 @@ fn test_execute_on_existing_contract_with_constructor() {
     let _msg = get_msg_format_update_contract(&new_addr.to_hex(), deployed_bytecode);
     let _res_a = send_update_contract(port, &new_addr.to_hex(), deployed_bytecode);
 
-    let decrypted_delta0_data = decrypt_addr_delta(_old_addr, &delta0_data);
-    let encrypted_delta0_data_new = encrypt_addr_delta(new_addr.into(), &decrypted_delta0_data);
-    let decrypted_delta1_data = decrypt_addr_delta(_old_addr, &computed_data);
-    let decrypted_delta1_data = replace_previous_hash_in_delta_data(&decrypted_delta1_data, encrypted_delta0_data_new.keccak256());
+    let (delta0_key, delta1_key) = (delta0["key"].as_u64().unwrap(), add_delta["key"].as_u64().unwrap());
+    let decrypted_delta0_data = decrypt_addr_delta(_old_addr, delta0_key as u32, &delta0_data);
+    let encrypted_delta0_data_new = encrypt_addr_delta(new_addr.into(), delta0_key as u32, &decrypted_delta0_data);
+    let decrypted_delta1_data = decrypt_addr_delta(_old_addr, delta1_key as u32, &computed_data);
+    // The delta hash doesn't include the key version prefix
+    let decrypted_delta1_data = replace_previous_hash_in_delta_data(&decrypted_delta1_data, encrypted_delta0_data_new[4..].keccak256());
 
-    let encrypted_delta1_data_new = encrypt_addr_delta(new_addr.into(), &decrypted_delta1_data);
+    let encrypted_delta1_data_new = encrypt_addr_delta(new_addr.into(), delta1_key as u32, &decrypted_delta1_data);
     let deltas = vec![
-        (new_addr.to_hex(), delta0["key"].as_u64().unwrap(), encrypted_delta0_data_new),
-        (new_addr.to_hex(),add_delta["key"].as_u64().unwrap(), encrypted_delta1_data_new)
+        (new_addr.to_hex(), delta0_key, encrypted_delta0_data_new),
+        (new_addr.to_hex(), delta1_key, encrypted_delta1_data_new)
     ];
     let msg = get_update_deltas_msg(&deltas);
     let _update_deltas_res: Value = conn_and_call_ipc(&msg.to_string(), port);
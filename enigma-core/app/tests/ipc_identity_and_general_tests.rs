@@ -34,6 +34,41 @@ fn test_registration_params() {
     assert!(is_hex(result_sig));
 }
 
+#[test]
+fn test_get_worker_group() {
+    use integration_utils::{get_worker_group_msg, set_principal_address};
+    let port = "5581";
+    run_core(port);
+    let msg = get_simple_msg_format("GetRegistrationParams");
+    let signing_key = conn_and_call_ipc(&msg.to_string(), port)["result"]["signingKey"].as_str().unwrap().to_string();
+
+    set_principal_address(port);
+
+    let workers = vec![[1u8; 20].to_hex(), signing_key.clone()];
+    let stakes = vec![1, 1];
+    let addresses = vec![generate_contract_address().to_hex(), generate_contract_address().to_hex()];
+    let msg = get_worker_group_msg(42, 3, &workers, &stakes, 2, &addresses);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["type"].as_str().unwrap(), "GetWorkerGroup");
+    let groups = v["result"]["workerGroups"].as_array().unwrap();
+    assert_eq!(groups.len(), addresses.len());
+    for (group, address) in groups.iter().zip(addresses.iter()) {
+        assert_eq!(group["address"].as_str().unwrap(), address);
+        assert_eq!(group["workers"].as_array().unwrap().len(), 2);
+        assert!(group["member"].as_bool().unwrap());
+    }
+
+    let msg = get_worker_group_msg(42, 3, &workers, &stakes[..1], 2, &addresses);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["type"].as_str().unwrap(), "Error");
+
+    // An epoch that doesn't match the principal's signature is rejected
+    let mut msg = get_worker_group_msg(42, 3, &workers, &stakes, 2, &addresses);
+    msg["input"]["seed"] = json!(format!("{:016x}", 43));
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["type"].as_str().unwrap(), "Error");
+}
+
 #[test]
 fn test_deploy_with_no_ptt() {
     let port = "5575";
@@ -74,6 +109,7 @@ fn test_run_ptt_with_addresses() {
     run_core(port);
     let addresses = vec![generate_contract_address(), generate_contract_address()];
     let address_string: Vec<_> = addresses.iter().map(|a| a.to_hex()).collect();
+    set_principal_address(port);
     let msg_req = get_ptt_req_msg(Some(address_string));
     let req_val: Value = conn_and_call_ipc(&msg_req.to_string(), port);
     let packed_msg = req_val["result"]["request"].as_str().unwrap();
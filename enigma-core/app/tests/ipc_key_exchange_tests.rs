@@ -3,11 +3,17 @@ pub extern crate enigma_core_app as app;
 pub extern crate cross_test_utils;
 extern crate rustc_hex as hex;
 
-use integration_utils::{conn_and_call_ipc, is_hex, run_core, run_ptt_round,
-                        get_ptt_req_msg, parse_packed_msg};
+use integration_utils::{conn_and_call_ipc, is_hex, run_core, run_ptt_round, set_principal_address, produce_shared_key,
+                        get_simple_msg_format, get_forget_encryption_key_msg, get_ptt_req_msg, get_ptt_res_msg,
+                        get_start_state_rebuild_msg, mock_principal_res, parse_packed_msg, full_simple_deployment,
+                        produce_session_key, get_compute_msg, encrypt_args};
 use self::cross_test_utils::{generate_contract_address};
+use integration_utils::enigma_crypto::asymmetric::KeyPair;
+use integration_utils::ethabi::Token;
 use self::app::serde_json;
 use app::serde_json::*;
+use self::hex::ToHex;
+use std::{thread, time::Duration};
 
 #[test]
 fn test_get_ptt_request() {
@@ -34,4 +40,115 @@ fn test_ptt_response() {
 
     let errors: Vec<u8> = serde_json::from_value(res_val["result"]["errors"].clone()).unwrap();
     assert_eq!(errors.len(), 0);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_ptt_response_unsigned() {
+    let port = "5582";
+    run_core(port);
+    let addresses = vec![generate_contract_address()];
+    let res = set_principal_address(port);
+    assert_eq!(res["type"].as_str().unwrap(), "SetPrincipalAddress");
+
+    let req_val: Value = conn_and_call_ipc(&get_ptt_req_msg(None).to_string(), port);
+    let packed_msg = req_val["result"]["request"].as_str().unwrap();
+    let enc_response = mock_principal_res(packed_msg, addresses);
+    let mut msg = get_ptt_res_msg(&enc_response);
+
+    msg["input"].as_object_mut().unwrap().remove("sig");
+    let res = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains("PrincipalAuthError"));
+
+    // The rejected response can still be delivered with the principal's signature
+    let res = conn_and_call_ipc(&get_ptt_res_msg(&enc_response).to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "PTTResponse");
+}
+
+#[test]
+fn test_forget_task_encryption_key() {
+    let port = "5583";
+    run_core(port);
+    let (_, first_user) = produce_shared_key(port);
+    let (_, _second_user) = produce_shared_key(port);
+    let metrics_msg = get_simple_msg_format("GetDHKeysMetrics");
+    let metrics = conn_and_call_ipc(&metrics_msg.to_string(), port);
+    assert_eq!(metrics["result"]["userKeys"].as_u64().unwrap(), 2);
+
+    let res = conn_and_call_ipc(&get_forget_encryption_key_msg(first_user).to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "ForgetTaskEncryptionKey");
+    let metrics = conn_and_call_ipc(&metrics_msg.to_string(), port);
+    assert_eq!(metrics["result"]["userKeys"].as_u64().unwrap(), 1);
+    assert_eq!(metrics["result"]["evictedUserKeys"].as_u64().unwrap(), 0);
+}
+
+#[test]
+fn test_state_rebuild_in_background() {
+    let port = "5584";
+    run_core(port);
+    let addresses = vec![generate_contract_address(), generate_contract_address()];
+    run_ptt_round(port, addresses.clone());
+
+    let mut hex_addresses: Vec<String> = addresses.iter().map(|a| a.to_hex()).collect();
+    hex_addresses.push(generate_contract_address().to_hex());
+    let res = conn_and_call_ipc(&get_start_state_rebuild_msg(Some(hex_addresses.clone())).to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "StartStateRebuild");
+
+    let progress_msg = get_simple_msg_format("GetStateRebuildProgress");
+    let mut progress = conn_and_call_ipc(&progress_msg.to_string(), port);
+    for _ in 0..100 {
+        if !progress["result"]["running"].as_bool().unwrap() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+        progress = conn_and_call_ipc(&progress_msg.to_string(), port);
+    }
+    assert!(!progress["result"]["running"].as_bool().unwrap());
+    assert_eq!(progress["result"]["total"].as_u64().unwrap(), 3);
+    assert_eq!(progress["result"]["done"].as_u64().unwrap(), 3);
+
+    // Nothing was written to the contracts yet, the last one has no keys
+    let contracts = progress["result"]["contracts"].as_array().unwrap();
+    let statuses: Vec<&str> = contracts.iter().map(|c| c["status"].as_str().unwrap()).collect();
+    assert_eq!(statuses, vec!["upToDate", "upToDate", "missingKey"]);
+    assert_eq!(contracts[2]["address"].as_str().unwrap(), hex_addresses[2]);
+}
+
+#[test]
+fn test_task_encryption_sessions() {
+    let port = "5585";
+    run_core(port);
+    let (_, contract_addr): (_, [u8; 32]) = full_simple_deployment(port);
+    let user = KeyPair::new().unwrap();
+    let (first_session, second_session) = ([1u8; 32].to_hex(), [2u8; 32].to_hex());
+    let first_key = produce_session_key(port, &user, &first_session, 2);
+    let second_key = produce_session_key(port, &user, &second_session, 1);
+
+    let compute_msg = |key: [u8; 32], session: &str| {
+        let args = [Token::Uint(2.into()), Token::Uint(3.into())];
+        let (callable, args) = encrypt_args(&args, "addition(uint,uint)", key);
+        let mut msg = get_compute_msg(&generate_contract_address().to_hex(), &callable.to_hex(), &args.to_hex(),
+                                      &user.get_pubkey().to_hex(), 100_000_000, &contract_addr.to_hex());
+        msg["input"]["sessionId"] = Value::from(session);
+        msg
+    };
+    let compute = |key: [u8; 32], session: &str| conn_and_call_ipc(&compute_msg(key, session).to_string(), port);
+
+    // A task that's rejected before it's validated doesn't use up the session
+    let mut invalid = compute_msg(second_key, &second_session);
+    invalid["input"]["blockNumber"] = Value::from(0);
+    assert_ne!(conn_and_call_ipc(&invalid.to_string(), port)["type"].as_str().unwrap(), "ComputeTask");
+
+    // Both sessions are outstanding at the same time, the first one serves two tasks
+    assert_eq!(compute(second_key, &second_session)["type"].as_str().unwrap(), "ComputeTask");
+    assert_eq!(compute(first_key, &first_session)["type"].as_str().unwrap(), "ComputeTask");
+    assert_eq!(compute(first_key, &first_session)["type"].as_str().unwrap(), "ComputeTask");
+
+    let res = compute(first_key, &first_session);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains("ConsumedSessionError"));
+
+    let res = compute(first_key, &[3u8; 32].to_hex());
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains("UnknownSessionError"));
+}
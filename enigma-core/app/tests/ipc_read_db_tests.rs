@@ -96,7 +96,7 @@ fn test_ipc_get_delta() {
     let msg = get_delta_msg(&addresses[1].to_hex(), 1);
     let res: Value = conn_and_call_ipc(&msg.to_string(), port);
     let delta_accepted = res["result"]["delta"].as_str().unwrap();
-    let decrypted_delta = decrypt_delta_to_value(addresses[1], &delta_accepted.from_hex().unwrap());
+    let decrypted_delta = decrypt_delta_to_value(addresses[1], 1, &delta_accepted.from_hex().unwrap());
     let add_result = decrypted_delta[0][0][2].as_u64().unwrap();
     // values that were sent in deploy_and_compute_few_contracts in the second contract
     assert_eq!(add_result, 75 + 43);
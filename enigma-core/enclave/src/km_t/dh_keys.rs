@@ -0,0 +1,96 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// DH keys waiting to be used, bounded in number so they can't exhaust the enclave's memory.
+/// The enclave has no trusted clock, so keys expire by count: once the store is full
+/// the oldest key is evicted to make room for a new one.
+#[derive(Debug)]
+pub struct DhKeyStore<K: Hash + Eq + Clone, V> {
+    keys: HashMap<K, V>,
+    /// The keys from the oldest to the newest
+    order: VecDeque<K>,
+    capacity: usize,
+    evicted: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> DhKeyStore<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        DhKeyStore { keys: HashMap::new(), order: VecDeque::new(), capacity, evicted: 0 }
+    }
+
+    /// Inserts the key as the newest one, replacing the previous key of the same id.
+    pub fn insert(&mut self, id: K, key: V) {
+        if self.keys.insert(id.clone(), key).is_some() {
+            self.order.retain(|other| *other != id);
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+                self.evicted += 1;
+            }
+        }
+    }
+
+    /// Inserts the key like `insert`, but holds at most `group_capacity` keys of the group `in_group` matches,
+    /// evicting the oldest keys of the group first, so a single group can't evict the keys of all the others.
+    pub fn insert_capped<F: Fn(&K) -> bool>(&mut self, id: K, key: V, in_group: F, group_capacity: usize) {
+        let group: Vec<K> = self.order.iter().filter(|other| **other != id && in_group(other)).cloned().collect();
+        let excess = (group.len() + 1).saturating_sub(group_capacity);
+        for oldest in &group[..excess.min(group.len())] {
+            self.remove(oldest);
+            self.evicted += 1;
+        }
+        self.insert(id, key);
+    }
+
+    pub fn get<Q: ?Sized>(&self, id: &Q) -> Option<&V>
+    where K: Borrow<Q>, Q: Hash + Eq {
+        self.keys.get(id)
+    }
+
+    pub fn get_mut<Q: ?Sized>(&mut self, id: &Q) -> Option<&mut V>
+    where K: Borrow<Q>, Q: Hash + Eq {
+        self.keys.get_mut(id)
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, id: &Q) -> Option<V>
+    where K: Borrow<Q>, Q: Hash + Eq {
+        let key = self.keys.remove(id)?;
+        self.order.retain(|other| other.borrow() != id);
+        Some(key)
+    }
+
+    pub fn len(&self) -> usize { self.keys.len() }
+
+    pub fn is_empty(&self) -> bool { self.keys.is_empty() }
+
+    /// How many keys were evicted since the enclave started
+    pub fn evicted(&self) -> u64 { self.evicted }
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+
+    pub fn test_dh_key_store_eviction() {
+        let mut store = DhKeyStore::new(3);
+        for i in 0..5u8 {
+            store.insert(vec![i], i);
+        }
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.evicted(), 2);
+        assert!(store.get(&[0u8][..]).is_none());
+        assert_eq!(store.get(&[4u8][..]), Some(&4));
+
+        // Replacing a key makes it the newest, so the next eviction skips it
+        store.insert(vec![2], 20);
+        store.insert(vec![5], 5);
+        assert!(store.get(&[3u8][..]).is_none());
+        assert_eq!(store.remove(&[2u8][..]), Some(20));
+        assert!(store.remove(&[2u8][..]).is_none());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.evicted(), 3);
+    }
+}
@@ -1,40 +1,290 @@
+pub(crate) mod dh_keys;
 pub(crate) mod principal;
 pub(crate) mod users;
 
-pub(crate) use self::principal::{ecall_build_state_internal, ecall_ptt_req_internal, ecall_ptt_res_internal};
-pub(crate) use self::users::ecall_get_user_key_internal;
+pub(crate) use self::principal::{ecall_build_state_internal, ecall_ptt_req_internal, ecall_ptt_res_internal, ecall_rebuild_state_internal,
+                                 ecall_set_principal_address_internal, ecall_verify_principal_sig_internal};
+pub(crate) use self::users::{ecall_forget_user_key_internal, ecall_get_user_key_internal};
 
 use enigma_runtime_t::data::{ContractState, EncryptedContractState, EncryptedPatch, StatePatch};
-use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::SystemError, EnclaveSystemError::KeyProvisionError};
 use enigma_tools_t::common::LockExpectMutex;
+use enigma_tools_t::document_storage_t::{load_document, save_document, SealedDocumentType};
+use enigma_tools_t::esgx::ocalls_t;
+use enigma_tools_m::primitives::km_primitives::KEY_VERSIONS_WINDOW;
 use enigma_crypto::{Encryption, CryptoError};
-use enigma_types::{ContractAddress, StateKey};
+use enigma_types::{ContractAddress, DhKeysMetrics, KeyVersion, StateKey};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::SgxMutex;
+use std::vec::Vec;
+
+const STATE_KEYS_FILE: &str = "state-keys.sealed";
+const STATE_KEYS_DOCUMENT: SealedDocumentType = 1;
+pub(crate) const PRINCIPAL_ADDRESS_DOCUMENT: SealedDocumentType = 2;
 
 lazy_static! {
-    pub static ref STATE_KEYS: SgxMutex<HashMap<ContractAddress, StateKey>> = SgxMutex::new(HashMap::new());
+    /// Loaded from the sealed document on first use, so the keys survive a restart of the worker.
+    pub static ref STATE_KEYS: SgxMutex<StateKeyStore> = SgxMutex::new(StateKeyStore::load());
+    /// The directory the key management documents are sealed in, the home directory unless the tests point it elsewhere.
+    static ref SEALING_DIR: SgxMutex<Option<PathBuf>> = SgxMutex::new(None);
+}
+
+pub(crate) fn get_sealing_dir() -> Result<PathBuf, EnclaveError> {
+    match *SEALING_DIR.lock_expect("Sealing Dir") {
+        Some(ref dir) => Ok(dir.clone()),
+        None => ocalls_t::get_home_path(),
+    }
+}
+
+/// The state keys provisioned by the principal, tied to the epoch they were issued for.
+/// The epoch is identified by the block number of its worker parameters.
+#[derive(Debug, Default, Clone)]
+pub struct StateKeyStore {
+    pub epoch: u64,
+    pub keys: HashMap<ContractAddress, VersionedStateKeys>,
+}
+
+impl StateKeyStore {
+    /// Moves the store to the epoch of newly received keys, dropping the keys of a previous epoch.
+    /// Keys issued for an epoch older than the current one are refused.
+    pub fn set_epoch(&mut self, epoch: u64) -> Result<(), EnclaveError> {
+        if epoch < self.epoch {
+            return Err(SystemError(KeyProvisionError {
+                err: format!("The keys were issued for epoch {}, the current epoch is {}", epoch, self.epoch),
+            }));
+        }
+        if epoch > self.epoch {
+            debug_println!("Dropping the state keys of {} contracts from epoch {}", self.keys.len(), self.epoch);
+            self.keys.clear();
+            self.epoch = epoch;
+        }
+        Ok(())
+    }
+
+    /// epoch (u64) || for every contract: address (32 bytes) || number of versions (u32) || (version (u32) || key (32 bytes))*
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(&self.epoch.to_le_bytes());
+        for (address, versions) in self.keys.iter() {
+            res.extend_from_slice(&address[..]);
+            res.extend_from_slice(&(versions.keys.len() as u32).to_le_bytes());
+            for (version, key) in versions.keys.iter() {
+                res.extend_from_slice(&version.to_le_bytes());
+                res.extend_from_slice(key);
+            }
+        }
+        res
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, EnclaveError> {
+        let invalid = || SystemError(KeyProvisionError { err: format!("Invalid sealed state keys of {} bytes", bytes.len()) });
+        if bytes.len() < 8 {
+            return Err(invalid());
+        }
+        let (mut epoch, mut number) = ([0u8; 8], [0u8; 4]);
+        epoch.copy_from_slice(&bytes[..8]);
+        let mut store = StateKeyStore { epoch: u64::from_le_bytes(epoch), keys: HashMap::new() };
+        let mut rest = &bytes[8..];
+        while !rest.is_empty() {
+            if rest.len() < 36 {
+                return Err(invalid());
+            }
+            let mut address = ContractAddress::default();
+            address.copy_from_slice(&rest[..32]);
+            number.copy_from_slice(&rest[32..36]);
+            // Every version is the version number followed by the key
+            let versions_len = u32::from_le_bytes(number) as usize * 36;
+            rest = &rest[36..];
+            if rest.len() < versions_len {
+                return Err(invalid());
+            }
+            let versions = store.keys.entry(address).or_insert_with(VersionedStateKeys::default);
+            for chunk in rest[..versions_len].chunks(36) {
+                let mut key = [0u8; 32];
+                number.copy_from_slice(&chunk[..4]);
+                key.copy_from_slice(&chunk[4..]);
+                versions.insert(KeyVersion::from_le_bytes(number), key);
+            }
+            rest = &rest[versions_len..];
+        }
+        Ok(store)
+    }
+
+    fn get_path() -> Result<PathBuf, EnclaveError> { Ok(get_sealing_dir()?.join(STATE_KEYS_FILE)) }
+
+    pub fn seal(&self) -> Result<(), EnclaveError> { self.seal_to(&Self::get_path()?) }
+
+    fn seal_to(&self, path: &PathBuf) -> Result<(), EnclaveError> { save_document(path, STATE_KEYS_DOCUMENT, &self.to_bytes()) }
+
+    fn unseal_from(path: &PathBuf) -> Result<Option<Self>, EnclaveError> {
+        match load_document(path, STATE_KEYS_DOCUMENT, None)? {
+            Some(payload) => Ok(Some(Self::from_bytes(&payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The sealed store, or an empty one if there is none or it can't be read,
+    /// in which case the keys are requested from the principal again.
+    fn load() -> Self {
+        match Self::get_path().and_then(|path| Self::unseal_from(&path)) {
+            Ok(Some(store)) => {
+                debug_println!("Loaded the state keys of {} contracts from epoch {}", store.keys.len(), store.epoch);
+                store
+            }
+            Ok(None) => StateKeyStore::default(),
+            Err(e) => {
+                println!("Unable to load the sealed state keys: {}", e);
+                StateKeyStore::default()
+            }
+        }
+    }
 }
 
-pub fn get_state_key(address: ContractAddress) -> Result<StateKey, EnclaveError> {
+/// The versions of a contract's state key, ordered from the oldest to the latest.
+/// New data is always encrypted with the latest version, the older ones are kept only to decrypt
+/// what was written before the key was rotated.
+#[derive(Debug, Default, Clone)]
+pub struct VersionedStateKeys {
+    keys: Vec<(KeyVersion, StateKey)>,
+}
+
+impl VersionedStateKeys {
+    pub fn insert(&mut self, version: KeyVersion, key: StateKey) {
+        match self.keys.binary_search_by_key(&version, |&(v, _)| v) {
+            Ok(i) => self.keys[i].1 = key,
+            Err(i) => self.keys.insert(i, (version, key)),
+        }
+        if self.keys.len() > KEY_VERSIONS_WINDOW {
+            let expired = self.keys.len() - KEY_VERSIONS_WINDOW;
+            self.keys.drain(..expired);
+        }
+    }
+
+    pub fn latest(&self) -> Result<(KeyVersion, StateKey), EnclaveError> {
+        Ok(*self.keys.last().ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?)
+    }
+
+    pub fn get(&self, version: KeyVersion) -> Result<StateKey, EnclaveError> {
+        let i = self.keys
+            .binary_search_by_key(&version, |&(v, _)| v)
+            .map_err(|_| CryptoError::MissingKeyError { key_type: "State Key Version" })?;
+        Ok(self.keys[i].1)
+    }
+
+    pub fn encrypt_state(&self, state: ContractState) -> Result<EncryptedContractState<u8>, EnclaveError> {
+        let (key_version, key) = self.latest()?;
+        let mut enc = state.encrypt(&key)?;
+        enc.key_version = key_version;
+        Ok(enc)
+    }
+
+    pub fn decrypt_state(&self, enc: EncryptedContractState<u8>) -> Result<ContractState, EnclaveError> {
+        let key = self.get(enc.key_version)?;
+        ContractState::decrypt(enc, &key)
+    }
+}
+
+pub(crate) fn ecall_get_dh_keys_metrics_internal() -> DhKeysMetrics {
+    let user_keys = users::DH_KEYS.lock_expect("User DH Keys");
+    let ptt_keys = principal::DH_KEYS.lock_expect("DH Keys");
+    DhKeysMetrics {
+        user_keys: user_keys.len() as u64,
+        evicted_user_keys: user_keys.evicted(),
+        ptt_keys: ptt_keys.len() as u64,
+        evicted_ptt_keys: ptt_keys.evicted(),
+    }
+}
+
+/// Returns the latest version of the contract's state key.
+pub fn get_state_key(address: ContractAddress) -> Result<(KeyVersion, StateKey), EnclaveError> {
     let statekeys_guard = STATE_KEYS.lock_expect("State Keys");
-    Ok(*statekeys_guard
+    statekeys_guard
+        .keys
         .get(&address)
-        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?)
+        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?
+        .latest()
 }
 
 pub fn encrypt_delta(del: StatePatch) -> Result<EncryptedPatch, EnclaveError> {
     let statekeys_guard = STATE_KEYS.lock_expect("State Keys");
-    let key = statekeys_guard
+    let (key_version, key) = statekeys_guard
+        .keys
         .get(&del.contract_address)
-        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?;
-    del.encrypt(&key)
+        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?
+        .latest()?;
+    let mut enc = del.encrypt(&key)?;
+    enc.key_version = key_version;
+    Ok(enc)
 }
 
 pub fn encrypt_state(state: ContractState) -> Result<EncryptedContractState<u8>, EnclaveError> {
     let statekeys_guard = STATE_KEYS.lock_expect("State Keys");
-    let key = statekeys_guard
+    statekeys_guard
+        .keys
         .get(&state.contract_address)
-        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?;
-    state.encrypt(&key)
+        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?
+        .encrypt_state(state)
+}
+
+pub fn decrypt_state(enc: EncryptedContractState<u8>) -> Result<ContractState, EnclaveError> {
+    let statekeys_guard = STATE_KEYS.lock_expect("State Keys");
+    statekeys_guard
+        .keys
+        .get(&enc.contract_address)
+        .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?
+        .decrypt_state(enc)
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use std::untrusted::fs::{create_dir_all, remove_file};
+
+    /// Seals the key management documents in a directory of their own, so the tests leave the documents of the worker alone.
+    pub fn use_test_sealing_dir() {
+        let dir = ocalls_t::get_home_path().unwrap().join("km-tests");
+        create_dir_all(&dir).unwrap();
+        *SEALING_DIR.lock_expect("Sealing Dir") = Some(dir);
+    }
+
+    pub fn test_versioned_state_keys() {
+        let mut keys = VersionedStateKeys::default();
+        assert!(keys.latest().is_err());
+        keys.insert(1, [1u8; 32]);
+        keys.insert(0, [0u8; 32]);
+        assert_eq!(keys.latest().unwrap(), (1, [1u8; 32]));
+        assert_eq!(keys.get(0).unwrap(), [0u8; 32]);
+
+        // Versions that fall out of the window are dropped
+        keys.insert(KEY_VERSIONS_WINDOW as KeyVersion + 1, [2u8; 32]);
+        assert_eq!(keys.latest().unwrap(), (KEY_VERSIONS_WINDOW as KeyVersion + 1, [2u8; 32]));
+        assert!(keys.get(0).is_err());
+        assert_eq!(keys.keys.len(), KEY_VERSIONS_WINDOW);
+    }
+
+    pub fn test_state_key_store() {
+        let (first, second) = (ContractAddress::from([1u8; 32]), ContractAddress::from([2u8; 32]));
+        let mut store = StateKeyStore { epoch: 7, keys: HashMap::new() };
+        store.keys.entry(first).or_insert_with(VersionedStateKeys::default).insert(0, [1u8; 32]);
+        store.keys.entry(first).or_insert_with(VersionedStateKeys::default).insert(1, [2u8; 32]);
+        store.keys.entry(second).or_insert_with(VersionedStateKeys::default).insert(3, [3u8; 32]);
+
+        let path = ocalls_t::get_home_path().unwrap().join("test-state-keys.sealed");
+        store.seal_to(&path).unwrap();
+        let unsealed = StateKeyStore::unseal_from(&path).unwrap().unwrap();
+        remove_file(&path).unwrap();
+        assert_eq!(unsealed.epoch, 7);
+        assert_eq!(unsealed.keys[&first].keys, store.keys[&first].keys);
+        assert_eq!(unsealed.keys[&second].keys, store.keys[&second].keys);
+        assert!(StateKeyStore::from_bytes(&store.to_bytes()[..20]).is_err());
+
+        // Keys of an older epoch are refused, a newer epoch drops the current keys
+        assert!(store.set_epoch(6).is_err());
+        store.set_epoch(7).unwrap();
+        assert_eq!(store.keys.len(), 2);
+        store.set_epoch(8).unwrap();
+        assert!(store.keys.is_empty());
+        assert_eq!(store.epoch, 8);
+    }
 }
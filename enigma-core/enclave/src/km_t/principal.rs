@@ -1,21 +1,89 @@
-use super::STATE_KEYS;
+use super::{dh_keys::DhKeyStore, get_sealing_dir, VersionedStateKeys, PRINCIPAL_ADDRESS_DOCUMENT, STATE_KEYS};
 use crate::SIGNING_KEY;
 use enigma_runtime_t::data::{ContractState, DeltasInterface};
 use enigma_runtime_t::ocalls_t as runtime_ocalls_t;
-use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::SystemError, EnclaveSystemError::{KeyProvisionError, PrincipalAuthError}};
 use enigma_tools_t::common::utils_t::LockExpectMutex;
+use enigma_tools_t::document_storage_t::{load_document, save_document};
 use enigma_crypto::asymmetric::KeyPair;
 use enigma_crypto::{Encryption, CryptoError};
-use enigma_tools_m::primitives::km_primitives::MsgID;
-use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType};
+use enigma_tools_m::primitives::km_primitives::{BuildStateResult, BuildStateStatus, MsgID};
+use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType, PrincipalResponseKey};
 use enigma_types::{ContractAddress, StateKey, RawPointer};
-use std::collections::HashMap;
+use std::path::PathBuf;
+use std::string::ToString;
 use std::sync::SgxMutex;
 use std::u32;
 use std::vec::Vec;
 
+/// The most PTT requests waiting for a response, the oldest request is abandoned when a new one exceeds it.
+pub const MAX_PTT_DH_KEYS: usize = 16;
+const PRINCIPAL_ADDRESS_FILE: &str = "principal-address.sealed";
+
 lazy_static! {
-    pub static ref DH_KEYS: SgxMutex<HashMap<MsgID, KeyPair>> = SgxMutex::new(HashMap::new());
+    pub static ref DH_KEYS: SgxMutex<DhKeyStore<MsgID, KeyPair>> = SgxMutex::new(DhKeyStore::new(MAX_PTT_DH_KEYS));
+    /// The signing address of the principal enclave as registered in the Enigma contract,
+    /// the state keys are accepted only from responses signed by it.
+    /// It's sealed when it's set, and loaded from the sealed document on first use.
+    pub static ref PRINCIPAL_ADDRESS: SgxMutex<Option<[u8; 20]>> = SgxMutex::new(load_principal_address());
+}
+
+fn get_principal_address_path() -> Result<PathBuf, EnclaveError> { Ok(get_sealing_dir()?.join(PRINCIPAL_ADDRESS_FILE)) }
+
+fn load_principal_address() -> Option<[u8; 20]> {
+    match get_principal_address_path().and_then(|path| load_document(&path, PRINCIPAL_ADDRESS_DOCUMENT, None)) {
+        Ok(Some(ref payload)) if payload.len() == 20 => {
+            let mut address = [0u8; 20];
+            address.copy_from_slice(payload);
+            Some(address)
+        }
+        Ok(Some(payload)) => {
+            println!("Invalid sealed principal address of {} bytes", payload.len());
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            println!("Unable to load the sealed principal address: {}", e);
+            None
+        }
+    }
+}
+
+/// The principal address can be set only once, setting the same address again (e.g. after a restart) does nothing.
+pub(crate) fn ecall_set_principal_address_internal(address: [u8; 20]) -> Result<(), EnclaveError> {
+    if address == [0u8; 20] {
+        return Err(SystemError(PrincipalAuthError { err: "The principal address is empty".to_string() }));
+    }
+    let mut guard = PRINCIPAL_ADDRESS.lock_expect("Principal Address");
+    match *guard {
+        Some(current) if current == address => Ok(()),
+        Some(current) => Err(SystemError(PrincipalAuthError {
+            err: format!("The principal address is already set to {:?} and can't be changed to {:?}", current, address),
+        })),
+        None => {
+            save_document(&get_principal_address_path()?, PRINCIPAL_ADDRESS_DOCUMENT, &address)?;
+            *guard = Some(address);
+            Ok(())
+        }
+    }
+}
+
+/// Checks that the response was signed by the provisioned principal,
+/// since it may be delivered by any intermediary.
+fn verify_principal_signature(msg_slice: &[u8], sig: [u8; 65]) -> Result<(), EnclaveError> {
+    let auth_err = |err: String| SystemError(PrincipalAuthError { err });
+    let principal = PRINCIPAL_ADDRESS.lock_expect("Principal Address")
+        .ok_or_else(|| auth_err("The principal address was not provisioned".to_string()))?;
+    let signer = KeyPair::recover_address(msg_slice, sig).map_err(|e| auth_err(format!("Unable to recover the signer: {:?}", e)))?;
+    if signer != principal {
+        return Err(auth_err(format!("The response was signed by {:?} instead of the principal {:?}", signer, principal)));
+    }
+    Ok(())
+}
+
+/// Checks that the message, e.g. an epoch, was signed by the provisioned principal.
+pub(crate) fn ecall_verify_principal_sig_internal(msg_slice: &[u8], sig: [u8; 65]) -> Result<(), EnclaveError> {
+    verify_principal_signature(msg_slice, sig)
 }
 
 pub(crate) unsafe fn ecall_ptt_req_internal(addresses: &[ContractAddress], sig: &mut [u8; 65]) -> Result<Vec<u8>, EnclaveError> {
@@ -32,107 +100,144 @@ pub(crate) unsafe fn ecall_ptt_req_internal(addresses: &[ContractAddress], sig:
     Ok(msg)
 }
 
-pub(crate) fn ecall_ptt_res_internal(msg_slice: &[u8]) -> Result<(), EnclaveError> {
+/// The keys are tied to the epoch the principal signed into the response, a response without one is refused.
+pub(crate) fn ecall_ptt_res_internal(msg_slice: &[u8], sig: [u8; 65]) -> Result<(), EnclaveError> {
+    // Verified before the DH key is used, so a foreign response can't consume it.
+    verify_principal_signature(msg_slice, sig)?;
     let res = PrincipalMessage::from_message(msg_slice)?;
+    let epoch = res.get_epoch().ok_or_else(|| SystemError(KeyProvisionError {
+        err: "The response doesn't say which epoch the keys were issued for".to_string(),
+    }))?;
 
     let mut guard = DH_KEYS.lock_expect("DH Keys");
     let id = res.get_id();
     let msg;
     {
         let keys = guard.get(&id).ok_or(CryptoError::MissingKeyError { key_type: "DH Keys" })?;
-        let aes = keys.derive_key(&res.get_pubkey())?;
+        let aes = PrincipalResponseKey::derive(keys, &res.get_pubkey())?;
         msg = PrincipalMessage::decrypt(res, &aes)?;
     }
-    if let PrincipalMessageType::Response(v) = msg.data {
-        for (addr, key) in v {
-            STATE_KEYS.lock_expect("state keys").insert(addr, key);
+    let mut state_keys = STATE_KEYS.lock_expect("state keys");
+    state_keys.set_epoch(epoch)?;
+    match msg.data {
+        // An unversioned response comes from a principal that never rotated keys, so these are the first version.
+        PrincipalMessageType::Response(v) => {
+            for (addr, key) in v {
+                state_keys.keys.entry(addr).or_insert_with(VersionedStateKeys::default).insert(0, key);
+            }
         }
-    } else {
-        unreachable!() // This should never execute. // TODO: Replace with an error.
+        PrincipalMessageType::VersionedResponse(v) => {
+            for (addr, version, key) in v {
+                state_keys.keys.entry(addr).or_insert_with(VersionedStateKeys::default).insert(version, key);
+            }
+        }
+        _ => unreachable!(), // This should never execute. // TODO: Replace with an error.
     }
     guard.remove(&id);
-    Ok(())
+    state_keys.seal()
 }
 
+/// How many deltas are fetched from the untrusted DB at once
+const DELTAS_BATCH: u32 = 500;
+
+/// Rebuilds the state of every contract the enclave has keys for, returns the contracts that failed.
 pub(crate) fn ecall_build_state_internal(db_ptr: *const RawPointer) -> Result<Vec<ContractAddress>, EnclaveError> {
-    let guard = STATE_KEYS.lock_expect("State Keys");
-    let mut failed_contracts = Vec::with_capacity(guard.len());
-    debug_println!("building state for {} contracts", guard.len());
-
-    'contract: for (addrs, key) in guard.iter() {
-        // Get the state and decrypt it.
-        // if no state exist create new one and if failed decrypting push to failed_contracts and move on.
-        let (mut start, mut state ) = match runtime_ocalls_t::get_state(db_ptr, *addrs) {
-            Ok(enc_state) => match ContractState::decrypt(enc_state, &key) {
-                Ok(state) => (state.delta_index+1, state),
-                Err(_) => {
-                    failed_contracts.push(*addrs);
-                    continue 'contract;
-                }
-            }, // don't throw error if only one failed, somehow tell that but continue
-            Err(_) => (0, ContractState::new(*addrs)),
+    let addresses: Vec<ContractAddress> = STATE_KEYS.lock_expect("State Keys").keys.keys().cloned().collect();
+    debug_println!("building state for {} contracts", addresses.len());
+    let results = ecall_rebuild_state_internal(db_ptr, &addresses)?;
+    Ok(results.into_iter().filter(|res| res.status.is_failure()).map(|res| res.address).collect())
+}
+
+/// Rebuilds the state of the given contracts, or of every contract the enclave has keys for if none are given.
+/// Only the deltas written since the saved state are applied, a state that is up to date isn't saved again.
+pub(crate) fn ecall_rebuild_state_internal(db_ptr: *const RawPointer, addresses: &[ContractAddress]) -> Result<Vec<BuildStateResult>, EnclaveError> {
+    let addresses = if addresses.is_empty() {
+        STATE_KEYS.lock_expect("State Keys").keys.keys().cloned().collect()
+    } else {
+        addresses.to_vec()
+    };
+    let mut results = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        // The keys are locked only while they're copied, so that tasks can run between the contracts.
+        let keys = STATE_KEYS.lock_expect("State Keys").keys.get(&address).cloned();
+        let status = match keys {
+            Some(keys) => build_contract_state(db_ptr, address, &keys),
+            None => BuildStateStatus::MissingKey,
         };
+        results.push(BuildStateResult { address, status });
+    }
+    Ok(results)
+}
 
-        'deltas: while start < u32::MAX {
-            let mut end = start + 500;
-            // Get deltas from start to end, if fails save the latest state and move on.
-            let deltas = match runtime_ocalls_t::get_deltas(db_ptr, *addrs, start, end) {
-                Ok(deltas) => deltas,
-                Err(_) => {
-                    // If it failed to get deltas, encrypt the latest state and save it
-                    let enc = match state.encrypt(key) {
-                        Ok(s) => s,
-                        Err(_) => {
-                            // If Failed to encrypt the latest state push to failed_contracts and move on.
-                            failed_contracts.push(*addrs);
-                            continue 'contract;
-                        }
-                    };
-                    runtime_ocalls_t::save_state(db_ptr, &enc)?;
-                    continue 'contract;
-                }
-            };
-            let deltas_len = deltas.len();
-            // decrypt the deltas and apply them to the state.
-            // If failed, encrypt the latest state and move on.
-            for delta in deltas {
-                match state.apply_delta(delta, key) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        debug_println!("Failed applying delta: {:?}", e);
-                        let enc = match state.encrypt(key) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                // If Failed to encrypt the latest state push to failed_contracts and move on.
-                                debug_println!("Failed encrypting the state: {:?}", e);
-                                failed_contracts.push(*addrs);
-                                continue 'contract;
-                            }
-                        };
-                        failed_contracts.push(*addrs);
-                        runtime_ocalls_t::save_state(db_ptr, &enc)?;
-                        continue 'contract;
-                    }
-                };
-            }
-            if deltas_len == (end - start) as usize {
-                start = end;
-            } else {
-                start = u32::MAX;
+fn build_contract_state(db_ptr: *const RawPointer, address: ContractAddress, keys: &VersionedStateKeys) -> BuildStateStatus {
+    // If no state exists start from a new one, which the first delta is applied to.
+    let mut saved = None;
+    let mut state = match runtime_ocalls_t::get_state(db_ptr, address) {
+        Ok(enc_state) => {
+            saved = Some((enc_state.key_version, enc_state.aad_bound));
+            match keys.decrypt_state(enc_state) {
+                Ok(state) => state,
+                Err(_) => return BuildStateStatus::DecryptFailure,
             }
         }
+        Err(_) => ContractState::new(address),
+    };
+    let saved_index = state.delta_index;
+    let mut next = if state.is_initial() { 0 } else { state.delta_index + 1 };
+    let mut applied = false;
+    let mut failure = None;
 
-        let enc = match state.encrypt(key) {
-            Ok(d) => d,
-            Err(_) => {
-                // If Failed to encrypt the latest state push to failed_contracts and move on.
-                failed_contracts.push(*addrs);
-                continue 'contract;
+    'deltas: while next < u32::MAX {
+        let end = next.saturating_add(DELTAS_BATCH);
+        // There are no deltas in the range once the state is at the tip, failing to read them stops the rebuild.
+        let deltas = match runtime_ocalls_t::get_deltas(db_ptr, address, next, end) {
+            Ok(deltas) => deltas,
+            Err(e) => {
+                debug_println!("Failed reading the deltas from {}: {:?}", next, e);
+                failure = Some(BuildStateStatus::ReadFailure(next));
+                break;
             }
         };
-        runtime_ocalls_t::save_state(db_ptr, &enc)?;
+        // Every delta depends on the one before it, so the first one that's missing or can't be applied stops the rebuild.
+        for delta in deltas {
+            if delta.index != next {
+                failure = Some(BuildStateStatus::MissingDelta(next));
+                break 'deltas;
+            }
+            let key_version = delta.key_version;
+            if let Err(e) = keys.get(key_version).and_then(|key| state.apply_delta(delta, (key_version, &key))) {
+                debug_println!("Failed applying delta {}: {:?}", next, e);
+                failure = Some(BuildStateStatus::MissingDelta(next));
+                break 'deltas;
+            }
+            applied = true;
+            next += 1;
+        }
+        if next < end {
+            break;
+        }
+    }
+
+    // The deltas that were applied are kept even if a later one failed.
+    // The state is saved with the latest key version and bound through the AAD, so a saved state that's encrypted
+    // with an older version or written before the binding is saved again even without new deltas.
+    let outdated = match (saved, keys.latest()) {
+        (Some((saved_version, aad_bound)), Ok((latest, _))) => saved_version != latest || !aad_bound,
+        _ => false,
+    };
+    let index = state.delta_index;
+    if applied || outdated {
+        let saved = keys.encrypt_state(state).and_then(|enc| runtime_ocalls_t::save_state(db_ptr, &enc));
+        if let Err(e) = saved {
+            debug_println!("Failed saving the state: {:?}", e);
+            return BuildStateStatus::SaveFailure;
+        }
+    }
+    match failure {
+        Some(status) => status,
+        None if applied => BuildStateStatus::Rebuilt(index),
+        None => BuildStateStatus::UpToDate(saved_index),
     }
-    Ok(failed_contracts)
 }
 
 #[cfg(debug_assertions)]
@@ -143,6 +248,7 @@ pub mod tests {
     use enigma_crypto::hash::Sha256;
     use enigma_crypto::asymmetric::KeyPair;
     use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType};
+    use enigma_tools_m::utils::EthereumAddress;
     use enigma_types::{ContractAddress, RawPointer};
     use std::string::ToString;
 
@@ -159,7 +265,7 @@ pub mod tests {
                 runtime_ocalls_t::save_delta(db_ptr, &delta).unwrap();
             }
         }
-        let gibrish_state = EncryptedContractState { contract_address: address[2], json: vec![8u8; 65] };
+        let gibrish_state = EncryptedContractState { contract_address: address[2], json: vec![8u8; 65], key_version: 0, aad_bound: false, delta_index: 0 };
         runtime_ocalls_t::save_state(db_ptr, &gibrish_state).unwrap();
         // Generating the request
         let mut _sig = [0u8; 65];
@@ -170,19 +276,174 @@ pub mod tests {
         let km_node_keys = KeyPair::new().unwrap();
         let restype: Vec<(ContractAddress, StateKey)> = address.clone().into_iter().zip(state_keys.into_iter()).collect();
 
-        let res_obj = PrincipalMessage::new_id(PrincipalMessageType::Response(restype), req_obj.get_id(), km_node_keys.get_pubkey());
-        let dh_key = km_node_keys.derive_key(&req_obj.get_pubkey()).unwrap();
+        let res_obj = PrincipalMessage::new_id(PrincipalMessageType::Response(restype), req_obj.get_id(), km_node_keys.get_pubkey()).with_epoch(0);
+        let dh_key = PrincipalResponseKey::derive(&km_node_keys, &req_obj.get_pubkey()).unwrap();
         let enc_req = res_obj.encrypt(&dh_key).unwrap();
 
         let enc_res_slice = enc_req.into_message().unwrap();
+        let sig = sign_as_principal(&enc_res_slice);
 
         // Enclave Process Response
-        ecall_ptt_res_internal(&enc_res_slice).unwrap();
+        ecall_ptt_res_internal(&enc_res_slice, sig).unwrap();
 
         // Initiate the building
         assert_eq!(ecall_build_state_internal(db_ptr).unwrap(), vec![address[2]])
     }
 
+    pub fn test_state_internal_rotated_keys(db_ptr: *const RawPointer) {
+        let address = b"rotated".sha256();
+        let (old_key, new_key) = (*b"old_key".sha256(), *b"new_key".sha256());
+
+        // Half of the deltas were written before the key was rotated.
+        let mut state = ContractState::new(address);
+        for i in 0..10u32 {
+            let (key_version, key) = if i < 5 { (0, &old_key) } else { (1, &new_key) };
+            let old_state = state.clone();
+            state.write_key(&i.to_string(), &json!(i)).unwrap();
+            let delta = ContractState::generate_delta_and_update_state(&old_state, &mut state, (key_version, key)).unwrap();
+            runtime_ocalls_t::save_delta(db_ptr, &delta).unwrap();
+        }
+
+        let mut _sig = [0u8; 65];
+        let req_msg = unsafe { ecall_ptt_req_internal(&[address], &mut _sig).unwrap() };
+        let req_obj = PrincipalMessage::from_message(&req_msg).unwrap();
+        let km_node_keys = KeyPair::new().unwrap();
+        let restype = PrincipalMessageType::VersionedResponse(vec![(address, 0, old_key), (address, 1, new_key)]);
+        let res_obj = PrincipalMessage::new_id(restype, req_obj.get_id(), km_node_keys.get_pubkey()).with_epoch(0);
+        let dh_key = PrincipalResponseKey::derive(&km_node_keys, &req_obj.get_pubkey()).unwrap();
+        let enc_res_slice = res_obj.encrypt(&dh_key).unwrap().into_message().unwrap();
+        let sig = sign_as_principal(&enc_res_slice);
+        ecall_ptt_res_internal(&enc_res_slice, sig).unwrap();
+
+        assert!(!ecall_build_state_internal(db_ptr).unwrap().contains(&address));
+
+        // The built state is re-encrypted with the new key only.
+        let enc_state = runtime_ocalls_t::get_state(db_ptr, address).unwrap();
+        assert_eq!(enc_state.key_version, 1);
+        assert!(ContractState::decrypt(enc_state.clone(), &old_key).is_err());
+        assert_eq!(ContractState::decrypt(enc_state, &new_key).unwrap(), state);
+        STATE_KEYS.lock_expect("State Keys").keys.remove(&address);
+    }
+
+    pub fn test_rebuild_state_incremental(db_ptr: *const RawPointer) {
+        let (address, gap_address, unknown) = (b"incremental".sha256(), b"incremental_gap".sha256(), b"incremental_unknown".sha256());
+        let key = *b"incremental_key".sha256();
+        let mut keys = VersionedStateKeys::default();
+        keys.insert(0, key);
+        {
+            let mut guard = STATE_KEYS.lock_expect("State Keys");
+            guard.keys.insert(address, keys.clone());
+            guard.keys.insert(gap_address, keys);
+        }
+        let write_deltas = |state: &mut ContractState, range: ::std::ops::Range<u32>, skip: Option<u32>| {
+            for i in range {
+                let old_state = state.clone();
+                state.write_key(&i.to_string(), &json!(i)).unwrap();
+                let delta = ContractState::generate_delta_and_update_state(&old_state, state, (0, &key)).unwrap();
+                if Some(i) != skip {
+                    runtime_ocalls_t::save_delta(db_ptr, &delta).unwrap();
+                }
+            }
+        };
+        let status = |addresses: &[ContractAddress]| -> Vec<BuildStateStatus> {
+            ecall_rebuild_state_internal(db_ptr, addresses).unwrap().into_iter().map(|res| res.status).collect()
+        };
+
+        let mut state = ContractState::new(address);
+        write_deltas(&mut state, 0..5, None);
+        assert_eq!(status(&[address, unknown]), vec![BuildStateStatus::Rebuilt(4), BuildStateStatus::MissingKey]);
+        assert_eq!(status(&[address]), vec![BuildStateStatus::UpToDate(4)]);
+
+        // Only the new deltas are applied on top of the saved state
+        write_deltas(&mut state, 5..8, None);
+        assert_eq!(status(&[address]), vec![BuildStateStatus::Rebuilt(7)]);
+        let enc_state = runtime_ocalls_t::get_state(db_ptr, address).unwrap();
+        assert_eq!(ContractState::decrypt(enc_state, &key).unwrap(), state);
+
+        // The deltas before the gap are kept
+        let mut gap_state = ContractState::new(gap_address);
+        write_deltas(&mut gap_state, 0..6, Some(3));
+        assert_eq!(status(&[gap_address]), vec![BuildStateStatus::MissingDelta(3)]);
+        let enc_state = runtime_ocalls_t::get_state(db_ptr, gap_address).unwrap();
+        assert_eq!(ContractState::decrypt(enc_state, &key).unwrap().delta_index, 2);
+
+        let gibrish_state = EncryptedContractState { contract_address: gap_address, json: vec![8u8; 65], key_version: 0, aad_bound: false, delta_index: 0 };
+        runtime_ocalls_t::save_state(db_ptr, &gibrish_state).unwrap();
+        assert_eq!(status(&[gap_address, address]), vec![BuildStateStatus::DecryptFailure, BuildStateStatus::UpToDate(7)]);
+        assert_eq!(ecall_build_state_internal(db_ptr).unwrap().iter().filter(|a| **a == gap_address).count(), 1);
+
+        // After a rotation the saved state is re-encrypted with the new key even without new deltas
+        let new_key = *b"incremental_new_key".sha256();
+        STATE_KEYS.lock_expect("State Keys").keys.get_mut(&address).unwrap().insert(1, new_key);
+        assert_eq!(status(&[address]), vec![BuildStateStatus::UpToDate(7)]);
+        let enc_state = runtime_ocalls_t::get_state(db_ptr, address).unwrap();
+        assert_eq!(enc_state.key_version, 1);
+        assert_eq!(ContractState::decrypt(enc_state, &new_key).unwrap(), state);
+
+        let mut guard = STATE_KEYS.lock_expect("State Keys");
+        guard.keys.remove(&address);
+        guard.keys.remove(&gap_address);
+    }
+
+    pub fn test_ptt_res_principal_auth() {
+        let address = b"principal_auth".sha256();
+        let mut _sig = [0u8; 65];
+        let req_msg = unsafe { ecall_ptt_req_internal(&[address], &mut _sig).unwrap() };
+        let req_obj = PrincipalMessage::from_message(&req_msg).unwrap();
+        let km_node_keys = KeyPair::new().unwrap();
+        let restype = PrincipalMessageType::VersionedResponse(vec![(address, 0, *b"principal_key".sha256())]);
+        let res_obj = PrincipalMessage::new_id(restype, req_obj.get_id(), km_node_keys.get_pubkey());
+        let dh_key = PrincipalResponseKey::derive(&km_node_keys, &req_obj.get_pubkey()).unwrap();
+        let no_epoch_slice = res_obj.clone().encrypt(&dh_key).unwrap().into_message().unwrap();
+        let enc_res_slice = res_obj.with_epoch(0).encrypt(&dh_key).unwrap().into_message().unwrap();
+        let sig = sign_as_principal(&enc_res_slice);
+
+        // A response has to say which epoch its keys were issued for, even if it's signed
+        match ecall_ptt_res_internal(&no_epoch_slice, sign_as_principal(&no_epoch_slice)) {
+            Err(SystemError(KeyProvisionError { .. })) => (),
+            other => panic!("Expected a KeyProvisionError, got: {:?}", other),
+        }
+
+        let assert_auth_err = |res: Result<(), EnclaveError>| match res {
+            Err(SystemError(PrincipalAuthError { .. })) => (),
+            other => panic!("Expected a PrincipalAuthError, got: {:?}", other),
+        };
+        // Unsigned, signed by someone else, or signed over a different message
+        assert_auth_err(ecall_ptt_res_internal(&enc_res_slice, [0u8; 65]));
+        let foreign_sig = KeyPair::new().unwrap().sign(&enc_res_slice).unwrap();
+        assert_auth_err(ecall_ptt_res_internal(&enc_res_slice, foreign_sig));
+        assert_auth_err(ecall_ptt_res_internal(&req_msg, sig));
+        assert!(STATE_KEYS.lock_expect("State Keys").keys.get(&address).is_none());
+
+        // The rejected responses didn't consume the DH key
+        ecall_ptt_res_internal(&enc_res_slice, sig).unwrap();
+        assert!(STATE_KEYS.lock_expect("State Keys").keys.remove(&address).is_some());
+    }
+
+    /// A fixed key standing in for the principal, as its address can be set only once
+    fn get_principal_keys() -> KeyPair { KeyPair::from_slice(&b"Enigma Principal Test Key".sha256()).unwrap() }
+
+    /// Signs the response with the principal key, and provisions its address to the enclave
+    fn sign_as_principal(msg: &[u8]) -> [u8; 65] {
+        let principal_keys = get_principal_keys();
+        ecall_set_principal_address_internal(principal_keys.get_pubkey().address()).unwrap();
+        principal_keys.sign(msg).unwrap()
+    }
+
+    pub fn test_principal_address_write_once() {
+        let address = get_principal_keys().get_pubkey().address();
+        ecall_set_principal_address_internal(address).unwrap();
+        // Setting the same address again is fine, but it can't be changed
+        ecall_set_principal_address_internal(address).unwrap();
+        match ecall_set_principal_address_internal(KeyPair::new().unwrap().get_pubkey().address()) {
+            Err(SystemError(PrincipalAuthError { .. })) => (),
+            other => panic!("Expected a PrincipalAuthError, got: {:?}", other),
+        }
+        assert_eq!(*PRINCIPAL_ADDRESS.lock_expect("Principal Address"), Some(address));
+        // It's sealed for the next time the enclave starts
+        assert_eq!(load_principal_address(), Some(address));
+    }
+
     fn get_states_deltas(address: &[ContractAddress], keys: &[StateKey]) -> Vec<Vec<EncryptedPatch>> {
         let jsons: Vec<serde_json::Value> = vec![
             json!({"widget":{"debug":"on","window":{"title":"Sample Konfabulator Widget","name":"main_window","width":500,"height":500},"image":{"src":"Images/Sun.png","name":"sun1","hOffset":250,"vOffset":250,"alignment":"center"},"text":{"data":"Click Here","size":36,"style":"bold","name":"text1","hOffset":250,"vOffset":100,"alignment":"center","onMouseUp":"sun1.opacity = (sun1.opacity / 100) * 90;"}}}),
@@ -198,12 +459,12 @@ pub mod tests {
             let mut patches = Vec::with_capacity(15);
             let original_state = state.clone();
             state.json = json;
-            let delta0 = ContractState::generate_delta_and_update_state(&original_state, &mut state, key).unwrap();
+            let delta0 = ContractState::generate_delta_and_update_state(&original_state, &mut state, (0, key)).unwrap();
             patches.push(delta0);
             for i in 1..15 {
                 let old_state = state.clone();
                 state.write_key(&i.to_string(), &json!(i)).unwrap();
-                let delta = ContractState::generate_delta_and_update_state(&old_state, &mut state, key).unwrap();
+                let delta = ContractState::generate_delta_and_update_state(&old_state, &mut state, (0, key)).unwrap();
                 patches.push(delta);
             }
             result.push(patches);
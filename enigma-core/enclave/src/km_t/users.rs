@@ -1,20 +1,174 @@
+use super::dh_keys::DhKeyStore;
 use crate::SIGNING_KEY;
-use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::SystemError, EnclaveSystemError::*};
 use enigma_tools_t::common::utils_t::LockExpectMutex;
-use enigma_crypto::asymmetric::KeyPair;
+use enigma_crypto::{asymmetric::KeyPair, kdf::KeyPurpose};
 use enigma_tools_m::primitives::km_primitives::UserMessage;
-use enigma_types::{DhKey, PubKey};
-use std::collections::HashMap;
-use std::{sync::SgxMutex, vec::Vec};
+use enigma_types::{DhKey, PubKey, SessionId};
+use std::{string::ToString, sync::SgxMutex, vec::Vec};
 
-lazy_static! { pub static ref DH_KEYS: SgxMutex<HashMap<Vec<u8>, DhKey>> = SgxMutex::new(HashMap::new()); }
+/// The most user sessions waiting for tasks, a user can hold several sessions, each with its own key.
+pub const MAX_USER_DH_KEYS: usize = 4096;
+/// The most sessions a single user can hold, and the most of its used up sessions that are remembered,
+/// so that one user requesting new session ids can't evict the sessions of all the others.
+pub const MAX_SESSIONS_PER_USER: usize = 32;
 
-pub(crate) unsafe fn ecall_get_user_key_internal(sig: &mut [u8; 65], user_pubkey: &PubKey) -> Result<Vec<u8>, EnclaveError> {
+/// The DH key of a user's session and how many more tasks can use it
+#[derive(Debug)]
+pub struct Session {
+    key: DhKey,
+    uses_left: u32,
+}
+
+lazy_static! {
+    pub static ref DH_KEYS: SgxMutex<DhKeyStore<Vec<u8>, Session>> = SgxMutex::new(DhKeyStore::new(MAX_USER_DH_KEYS));
+    /// The sessions that were used up, kept only to tell them apart from unknown ones.
+    static ref CONSUMED_SESSIONS: SgxMutex<DhKeyStore<Vec<u8>, ()>> = SgxMutex::new(DhKeyStore::new(MAX_USER_DH_KEYS));
+}
+
+/// Sessions are identified by the user's pubkey followed by the session id
+fn session_id(user_pubkey: &PubKey, session: &SessionId) -> Vec<u8> { [&user_pubkey[..], &session[..]].concat() }
+
+fn is_user_session<'a>(user_pubkey: &'a PubKey) -> impl Fn(&Vec<u8>) -> bool + 'a { move |id| id.starts_with(&user_pubkey[..]) }
+
+pub(crate) unsafe fn ecall_get_user_key_internal(sig: &mut [u8; 65], user_pubkey: &PubKey, session: &SessionId, uses: u32) -> Result<Vec<u8>, EnclaveError> {
+    if uses == 0 {
+        return Err(SystemError(MessagingError { err: "A session must allow at least one task".to_string() }));
+    }
     let keys = KeyPair::new()?;
-    let req = UserMessage::new(keys.get_pubkey());
+    let req = UserMessage::new(keys.get_pubkey(), *session, uses);
     *sig = SIGNING_KEY.sign(&req.to_sign())?;
     let msg = req.into_message()?;
-    let enc_key = keys.derive_key(&user_pubkey)?;
-    DH_KEYS.lock_expect("DH Keys").insert(user_pubkey.to_vec(), enc_key);
+    let enc_key = keys.derive_key_for(&user_pubkey, KeyPurpose::UserIo)?;
+    // Requesting a session again replaces its key, so the session can be used again
+    let id = session_id(user_pubkey, session);
+    CONSUMED_SESSIONS.lock_expect("Consumed Sessions").remove(&id);
+    DH_KEYS.lock_expect("DH Keys").insert_capped(id, Session { key: enc_key, uses_left: uses }, is_user_session(user_pubkey), MAX_SESSIONS_PER_USER);
     Ok(msg)
 }
+
+/// Drops a session the user won't send tasks with, it's a no-op if there is no such session.
+pub(crate) fn ecall_forget_user_key_internal(user_pubkey: &PubKey, session: &SessionId) {
+    DH_KEYS.lock_expect("DH Keys").remove(&session_id(user_pubkey, session)[..]);
+}
+
+/// Returns the key of the session a task references, without using up any of the session's tasks,
+/// so that a task that's rejected before it's validated doesn't cost the user one.
+pub(crate) fn get_session_key(user_pubkey: &PubKey, session: &SessionId) -> Result<DhKey, EnclaveError> {
+    let id = session_id(user_pubkey, session);
+    let sessions = DH_KEYS.lock_expect("DH Keys");
+    match sessions.get(&id[..]) {
+        Some(user_session) => Ok(user_session.key),
+        None => Err(missing_session_error(&id)),
+    }
+}
+
+/// Uses up one of the tasks of the session, once the task that references it was validated.
+/// Fails if the session was used up in the meantime, e.g. by a task that ran concurrently.
+pub(crate) fn use_session(user_pubkey: &PubKey, session: &SessionId) -> Result<(), EnclaveError> {
+    let id = session_id(user_pubkey, session);
+    let mut sessions = DH_KEYS.lock_expect("DH Keys");
+    let uses_left = match sessions.get_mut(&id[..]) {
+        Some(user_session) => {
+            user_session.uses_left -= 1;
+            user_session.uses_left
+        }
+        None => return Err(missing_session_error(&id)),
+    };
+    if uses_left == 0 {
+        sessions.remove(&id[..]);
+        CONSUMED_SESSIONS.lock_expect("Consumed Sessions").insert_capped(id, (), is_user_session(user_pubkey), MAX_SESSIONS_PER_USER);
+    }
+    Ok(())
+}
+
+fn missing_session_error(id: &[u8]) -> EnclaveError {
+    if CONSUMED_SESSIONS.lock_expect("Consumed Sessions").get(id).is_some() {
+        SystemError(ConsumedSessionError { err: "All the tasks of the session were sent".to_string() })
+    } else {
+        SystemError(UnknownSessionError { err: "The session was never requested, or was forgotten or evicted".to_string() })
+    }
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+
+    fn use_session_key(user_pubkey: &PubKey, session: &SessionId) -> Result<DhKey, EnclaveError> {
+        let key = get_session_key(user_pubkey, session)?;
+        use_session(user_pubkey, session)?;
+        Ok(key)
+    }
+
+    pub fn test_user_sessions() {
+        let user = KeyPair::new().unwrap().get_pubkey();
+        let (first, second) = ([1u8; 32], [2u8; 32]);
+        let mut sig = [0u8; 65];
+        unsafe {
+            ecall_get_user_key_internal(&mut sig, &user, &first, 2).unwrap();
+            ecall_get_user_key_internal(&mut sig, &user, &second, 1).unwrap();
+            assert!(ecall_get_user_key_internal(&mut sig, &user, &[3u8; 32], 0).is_err());
+        }
+        // Both sessions are outstanding at once, with their own keys
+        let first_key = use_session_key(&user, &first).unwrap();
+        assert_ne!(use_session_key(&user, &second).unwrap(), first_key);
+        assert_eq!(use_session_key(&user, &first).unwrap(), first_key);
+
+        let assert_consumed = |res: Result<DhKey, EnclaveError>| match res {
+            Err(SystemError(ConsumedSessionError { .. })) => (),
+            other => panic!("Expected a ConsumedSessionError, got: {:?}", other),
+        };
+        assert_consumed(use_session_key(&user, &first));
+        assert_consumed(use_session_key(&user, &second));
+        match use_session_key(&user, &[3u8; 32]) {
+            Err(SystemError(UnknownSessionError { .. })) => (),
+            other => panic!("Expected an UnknownSessionError, got: {:?}", other),
+        }
+
+        // Getting the key of a session doesn't use it up, only a validated task does
+        unsafe { ecall_get_user_key_internal(&mut sig, &user, &second, 1).unwrap() };
+        let second_key = get_session_key(&user, &second).unwrap();
+        assert_eq!(get_session_key(&user, &second).unwrap(), second_key);
+        assert_eq!(use_session_key(&user, &second).unwrap(), second_key);
+        assert_consumed(get_session_key(&user, &second));
+
+        // A consumed session can be requested again
+        unsafe { ecall_get_user_key_internal(&mut sig, &user, &first, 1).unwrap() };
+        ecall_forget_user_key_internal(&user, &first);
+        match use_session_key(&user, &first) {
+            Err(SystemError(UnknownSessionError { .. })) => (),
+            other => panic!("Expected an UnknownSessionError, got: {:?}", other),
+        }
+    }
+
+    pub fn test_user_sessions_cap() {
+        let (user, other) = (KeyPair::new().unwrap().get_pubkey(), KeyPair::new().unwrap().get_pubkey());
+        let mut sig = [0u8; 65];
+        unsafe { ecall_get_user_key_internal(&mut sig, &other, &[0u8; 32], 1).unwrap() };
+        for i in 0..=MAX_SESSIONS_PER_USER {
+            unsafe { ecall_get_user_key_internal(&mut sig, &user, &[i as u8; 32], 1).unwrap() };
+        }
+        // Only the user's own oldest session is evicted
+        match use_session_key(&user, &[0u8; 32]) {
+            Err(SystemError(UnknownSessionError { .. })) => (),
+            other => panic!("Expected an UnknownSessionError, got: {:?}", other),
+        }
+        use_session_key(&other, &[0u8; 32]).unwrap();
+        for i in 1..=MAX_SESSIONS_PER_USER {
+            use_session_key(&user, &[i as u8; 32]).unwrap();
+        }
+
+        // The used up sessions are capped per user as well
+        let last = [MAX_SESSIONS_PER_USER as u8 + 1; 32];
+        unsafe { ecall_get_user_key_internal(&mut sig, &user, &last, 1).unwrap() };
+        use_session_key(&user, &last).unwrap();
+        match use_session_key(&user, &[1u8; 32]) {
+            Err(SystemError(UnknownSessionError { .. })) => (),
+            other => panic!("Expected an UnknownSessionError, got: {:?}", other),
+        }
+        match use_session_key(&other, &[0u8; 32]) {
+            Err(SystemError(ConsumedSessionError { .. })) => (),
+            other => panic!("Expected a ConsumedSessionError, got: {:?}", other),
+        }
+    }
+}
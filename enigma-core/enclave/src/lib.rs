@@ -49,23 +49,27 @@ mod wasm_g;
 
 use crate::evm_t::{abi::{create_callback, prepare_evm_input},
                    evm::call_sputnikvm};
-use crate::km_t::{ecall_build_state_internal, ecall_get_user_key_internal, ecall_ptt_req_internal, ecall_ptt_res_internal};
+use crate::km_t::{ecall_build_state_internal, ecall_forget_user_key_internal, ecall_get_dh_keys_metrics_internal,
+                  ecall_get_user_key_internal, ecall_ptt_req_internal, ecall_ptt_res_internal, ecall_rebuild_state_internal,
+                  ecall_set_principal_address_internal, ecall_verify_principal_sig_internal};
 use crate::wasm_g::execution;
 use enigma_runtime_t::data::{ContractState, EncryptedPatch};
-use enigma_runtime_t::EthereumData;
-use enigma_crypto::hash::Keccak256;
-use enigma_crypto::{asymmetric, CryptoError, symmetric};
+use enigma_runtime_t::{EthereumData, TaskContext, limits::{self, ContractLimits}};
+use enigma_crypto::hash::{Keccak256, Sha256};
+use enigma_crypto::{asymmetric, symmetric};
 use enigma_tools_t::common::{errors_t::{EnclaveError, EnclaveError::*, FailedTaskError::*}, LockExpectMutex};
-use enigma_tools_m::utils::EthereumAddress;
+use enigma_tools_m::{primitives::receipts::TaskReceipt, utils::EthereumAddress};
 use enigma_tools_t::{build_arguments_g::*, quote_t, storage_t, esgx::ocalls_t};
-use enigma_types::{traits::SliceCPtr, EnclaveReturn, ExecuteResult, Hash256, ContractAddress, PubKey, ResultStatus, RawPointer, DhKey};
+use enigma_types::{traits::SliceCPtr, EnclaveReturn, ExecuteResult, Hash256, ContractAddress, PubKey, RawPointer, DhKey,
+                   DhKeysMetrics, SessionId};
 use wasm_utils::{build, SourceTarget};
 
 use sgx_types::*;
 use std::{mem, ptr, slice, str};
-use std::{boxed::Box, string::{String, ToString}, vec::Vec};
+use std::{string::{String, ToString}, vec::Vec};
 
 lazy_static! { pub(crate) static ref SIGNING_KEY: asymmetric::KeyPair = get_sealed_keys_wrapper(); }
+lazy_static! { static ref RAND_SECRET: [u8; 32] = get_sealed_rand_secret(); }
 
 #[no_mangle]
 pub extern "C" fn ecall_get_registration_quote(target_info: &sgx_target_info_t, real_report: &mut sgx_report_t) -> sgx_status_t {
@@ -109,21 +113,24 @@ pub unsafe extern "C" fn ecall_evm(bytecode: *const u8, bytecode_len: usize, cal
 /// * `args` - the encrypted arguments for the function
 /// * `args_len` - the length of the `args`
 /// * `user_key` - the DH key of the user to decrypt `callable` and `args`
+/// * `session` - the user's session `user_key` was derived for
 /// * `contract_address` - the address of the deployed contract with code `bytecode`
 /// * `gas_limit` - the gas limit for the function execution
+/// * `block_number` - the block number the task was submitted in
 /// * `result` - the result of the function invocation
 // TODO: add arguments of callable.
 pub unsafe extern "C" fn ecall_execute(bytecode: *const u8, bytecode_len: usize,
                                        callable: *const u8, callable_len: usize,
                                        args: *const u8, args_len: usize,
-                                       user_key: &[u8; 64], contract_address: &ContractAddress,
-                                       gas_limit: *const u64, db_ptr: *const RawPointer, result: &mut ExecuteResult) -> EnclaveReturn {
+                                       user_key: &[u8; 64], session: &SessionId, contract_address: &ContractAddress,
+                                       gas_limit: *const u64, block_number: *const u64,
+                                       db_ptr: *const RawPointer, result: &mut ExecuteResult) -> EnclaveReturn {
     let bytecode = slice::from_raw_parts(bytecode, bytecode_len);
     let callable = slice::from_raw_parts(callable, callable_len);
     let args = slice::from_raw_parts(args, args_len);
 
     let mut pre_execution_data = vec![];
-    let io_key = match get_io_key(user_key) {
+    let io_key = match get_io_key(user_key, session) {
         Ok(v) => v,
         Err(e) => return e.into(),
     };
@@ -131,10 +138,11 @@ pub unsafe extern "C" fn ecall_execute(bytecode: *const u8, bytecode_len: usize,
 // in order to view the specific error print out the result of the function
     let mut internal_result = ecall_execute_internal(&mut pre_execution_data, bytecode,
                            callable,
-                           args, user_key,
+                           args, user_key, session,
                          &io_key,
                            (*contract_address).into(),
                            *gas_limit,
+                           *block_number,
                            db_ptr,
                            result);
     if let Err(e) = internal_result.clone() {
@@ -155,24 +163,26 @@ pub unsafe extern "C" fn ecall_execute(bytecode: *const u8, bytecode_len: usize,
 /// * `args_len` - the length of `args`
 /// * `address` - the address of the contract to be deployed
 /// * `user_key` - the DH key of the user to decrypt `constructor` and `args`
+/// * `session` - the user's session `user_key` was derived for
 /// * `gas_limit` - the gas limit for the constructor execution
+/// * `block_number` - the block number the deployment was submitted in
 /// * `result` - the result of the deployment
 pub unsafe extern "C" fn ecall_deploy(bytecode: *const u8, bytecode_len: usize,
                                       constructor: *const u8, constructor_len: usize,
                                       args: *const u8, args_len: usize,
-                                      address: &ContractAddress, user_key: &PubKey,
-                                      gas_limit: *const u64, db_ptr: *const RawPointer,
+                                      address: &ContractAddress, user_key: &PubKey, session: &SessionId,
+                                      gas_limit: *const u64, block_number: *const u64, db_ptr: *const RawPointer,
                                       result: &mut ExecuteResult) -> EnclaveReturn {
     let args = slice::from_raw_parts(args, args_len);
     let bytecode = slice::from_raw_parts(bytecode, bytecode_len);
     let constructor = slice::from_raw_parts(constructor, constructor_len);
     let mut pre_execution_data = vec![];
     let io_key;
-    match get_io_key(user_key){
+    match get_io_key(user_key, session){
         Ok(v) => io_key  = v,
         Err(e) => return e.into(),
     }
-    let mut internal_result = ecall_deploy_internal(&mut pre_execution_data, bytecode, constructor, args, (*address).into(), user_key, &io_key, *gas_limit, db_ptr, result);
+    let mut internal_result = ecall_deploy_internal(&mut pre_execution_data, bytecode, constructor, args, (*address).into(), user_key, session, &io_key, *gas_limit, *block_number, db_ptr, result);
     if let Err(e) = internal_result.clone() {
         println!("Error in deployment of smart contract function: {}", e);
         internal_result = output_task_failure(&pre_execution_data, e, result, &io_key);
@@ -195,9 +205,20 @@ pub unsafe extern "C" fn ecall_ptt_req(address: *const ContractAddress, len: usi
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_ptt_res(msg_ptr: *const u8, msg_len: usize) -> EnclaveReturn {
+pub unsafe extern "C" fn ecall_ptt_res(msg_ptr: *const u8, msg_len: usize, sig: &[u8; 65]) -> EnclaveReturn {
     let msg_slice = slice::from_raw_parts(msg_ptr, msg_len);
-    ecall_ptt_res_internal(msg_slice).into()
+    ecall_ptt_res_internal(msg_slice, *sig).into()
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_set_principal_address(address: &[u8; 20]) -> EnclaveReturn {
+    ecall_set_principal_address_internal(*address).into()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_verify_principal_sig(msg_ptr: *const u8, msg_len: usize, sig: &[u8; 65]) -> EnclaveReturn {
+    let msg_slice = slice::from_raw_parts(msg_ptr, msg_len);
+    ecall_verify_principal_sig_internal(msg_slice, *sig).into()
 }
 
 #[no_mangle]
@@ -215,8 +236,25 @@ pub unsafe extern "C" fn ecall_build_state(db_ptr: *const RawPointer, failed_ptr
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_get_user_key(sig: &mut [u8; 65], user_pubkey: &PubKey, serialized_ptr: *mut u64) -> EnclaveReturn {
-    let msg = match ecall_get_user_key_internal(sig, user_pubkey) {
+pub unsafe extern "C" fn ecall_rebuild_state(db_ptr: *const RawPointer, address: *const ContractAddress, len: usize,
+                                             result_ptr: *mut u64) -> EnclaveReturn {
+    let address_list = slice::from_raw_parts(address, len/mem::size_of::<ContractAddress>());
+    let results = match ecall_rebuild_state_internal(db_ptr, address_list) {
+        Ok(res) => res,
+        Err(e) => return e.into(),
+    };
+    let flatten = results.iter().flat_map(|res| res.to_bytes().to_vec()).collect::<Vec<u8>>();
+    *result_ptr = match ocalls_t::save_to_untrusted_memory(&flatten) {
+        Ok(ptr) => ptr,
+        Err(e) => return e.into(),
+    };
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_user_key(sig: &mut [u8; 65], user_pubkey: &PubKey, session: &SessionId, uses: u32,
+                                            serialized_ptr: *mut u64) -> EnclaveReturn {
+    let msg = match ecall_get_user_key_internal(sig, user_pubkey, session, uses) {
         Ok(msg) => msg,
         Err(e) => return e.into(),
     };
@@ -227,6 +265,15 @@ pub unsafe extern "C" fn ecall_get_user_key(sig: &mut [u8; 65], user_pubkey: &Pu
     EnclaveReturn::Success
 }
 
+#[no_mangle]
+pub extern "C" fn ecall_forget_user_key(user_pubkey: &PubKey, session: &SessionId) {
+    ecall_forget_user_key_internal(user_pubkey, session)
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_get_dh_keys_metrics(metrics: &mut DhKeysMetrics) {
+    *metrics = ecall_get_dh_keys_metrics_internal();
+}
 
 unsafe fn ecall_evm_internal(bytecode_slice: &[u8], callable_slice: &[u8], callable_args_slice: &[u8],
                              preprocessor_slice: &[u8], callback_slice: &[u8], output: *mut u8,
@@ -259,11 +306,27 @@ unsafe fn ecall_evm_internal(bytecode_slice: &[u8], callable_slice: &[u8], calla
     }
 }
 
-fn get_io_key(user_key: &PubKey) -> Result<DhKey, EnclaveError> {
-    let io_key = km_t::users::DH_KEYS.lock_expect("User DH Key")
-        .remove(&user_key[..])
-        .ok_or(CryptoError::MissingKeyError { key_type: "DH Key" })?;
-        Ok(io_key)
+/// The session is used up by the task only once its inputs were validated, see `km_t::users::use_session`.
+fn get_io_key(user_key: &PubKey, session: &SessionId) -> Result<DhKey, EnclaveError> {
+    km_t::users::get_session_key(user_key, session)
+}
+
+/// The block number is signed into the receipt, so the Enigma contract holds the worker to it,
+/// but it's still checked against what the enclave knows: a task can't come before the epoch
+/// the principal issued the state keys for.
+fn check_block_number(block_number: u64) -> Result<(), EnclaveError> {
+    let epoch = km_t::STATE_KEYS.lock_expect("State Keys").epoch;
+    if block_number == 0 || block_number < epoch {
+        return Err(FailedTaskError(InputError { message: format!("Invalid block number {}, the current epoch is {}", block_number, epoch) }));
+    }
+    Ok(())
+}
+
+/// The seed of the randomness a task can use through `Rand`.
+/// It's bound to the task inputs so the worker can't re-roll it, and to a sealed secret of the enclave so it can't be
+/// predicted before the task is executed. The seed is signed as part of the result so the task can be replayed.
+fn derive_rand_seed(inputs_hash: &Hash256) -> Hash256 {
+    enigma_crypto::hash::prepare_hash_multiple(&[&b"Enigma Task Randomness"[..], &RAND_SECRET[..], &inputs_hash[..]]).sha256()
 }
 
 fn decrypt_inputs(callable: &[u8], args: &[u8], inputs_key: &DhKey) -> Result<(Vec<u8>, Vec<u8>, String, String), EnclaveError>{
@@ -300,8 +363,7 @@ fn create_eth_data_to_sign(input: Option<EthereumData>) -> (Vec<u8>, [u8;20]){
     }
 }
 
-fn output_task_failure (pre_execution_data: &[Box<[u8]>], err: EnclaveError, result: &mut ExecuteResult, key: &DhKey) -> Result<(), EnclaveError>{
-    // Signing: S(pre-execution data, usedGas, Failure)
+fn output_task_failure (pre_execution_data: &[Hash256], err: EnclaveError, result: &mut ExecuteResult, key: &DhKey) -> Result<(), EnclaveError>{
     result.used_gas = 0;
     let mut return_error = err.clone();
     match err {
@@ -312,39 +374,38 @@ fn output_task_failure (pre_execution_data: &[Box<[u8]>], err: EnclaveError, res
         },
         SystemError(e) => return Err(SystemError(e)),
     }
-    let used_gas = result.used_gas.to_be_bytes();
-    let failure = [ResultStatus::Failure as u8];
-    let mut to_sign: Vec<&[u8]> = Vec::with_capacity(pre_execution_data.len()+2);
-    pre_execution_data.into_iter().for_each(|x| { to_sign.push(&x) });
-    to_sign.push(&used_gas);
-    to_sign.push(&failure);
-    result.signature = SIGNING_KEY.sign_multiple(&to_sign)?;
+    let receipt = TaskReceipt::Failure { pre_execution_data: pre_execution_data.to_vec(), used_gas: result.used_gas };
+    result.signature = SIGNING_KEY.sign(&receipt.to_sign())?;
     let error_text = format!("{}", return_error);
     let encrypted_result = symmetric::encrypt(error_text.as_bytes(), &key)?;
     result.output = ocalls_t::save_to_untrusted_memory(&encrypted_result)? as *const u8;
     Err(return_error)
 }
 
-unsafe fn ecall_execute_internal(pre_execution_data: &mut Vec<Box<[u8]>>, bytecode: &[u8], callable: &[u8],
-                                 args: &[u8], user_key: &PubKey, io_key: &DhKey,
-                                 address: ContractAddress, gas_limit: u64,
+unsafe fn ecall_execute_internal(pre_execution_data: &mut Vec<Hash256>, bytecode: &[u8], callable: &[u8],
+                                 args: &[u8], user_key: &PubKey, session: &SessionId, io_key: &DhKey,
+                                 address: ContractAddress, gas_limit: u64, block_number: u64,
                                  db_ptr: *const RawPointer, result: &mut ExecuteResult) -> Result<(), EnclaveError> {
 
     // TODO: make sure the state is up to date.
     // TODO: Should this be here or on the untrusted side via build_state?;
 
-    let inputs_hash = enigma_crypto::hash::prepare_hash_multiple(&[callable, args, &*address, user_key]).keccak256();
+    check_block_number(block_number)?;
+    let inputs_hash = TaskReceipt::compute_inputs_hash(callable, args, &address, user_key);
     let exe_code_hash = bytecode.keccak256();
-    pre_execution_data.push(Box::new(*inputs_hash));
-    pre_execution_data.push(Box::new(*exe_code_hash));
+    pre_execution_data.push(inputs_hash);
+    pre_execution_data.push(exe_code_hash);
     let pre_execution_state = execution::get_state(db_ptr, address)?;
 
     let (decrypted_args, _decrypted_callable, types, function_name) =
         decrypt_inputs(callable, args, io_key).
              map_err(|e| {FailedTaskError(InputError{ message: format!("{}", e) })})?;
+    km_t::users::use_session(user_key, session)?;
 
     let state_key = km_t::get_state_key(address)?;
-    let exec_res = execution::execute_call(&bytecode, gas_limit, pre_execution_state.clone(), function_name, types, decrypted_args.clone(), state_key)?;
+    let rand_seed = derive_rand_seed(&inputs_hash);
+    let context = TaskContext { user_pubkey: *user_key, block_number, task_id: inputs_hash };
+    let exec_res = execution::execute_call(&bytecode, gas_limit, pre_execution_state.clone(), function_name, types, decrypted_args.clone(), state_key, rand_seed, context)?;
 
     let delta_hash = save_enc_delta(db_ptr, &exec_res.state_delta)?;
     if exec_res.state_delta.is_some() {
@@ -355,23 +416,23 @@ unsafe fn ecall_execute_internal(pre_execution_data: &mut Vec<Box<[u8]>>, byteco
                         &encrypted_output,
                         exec_res.ethereum_bridge.clone(),
                         exec_res.used_gas,
+                        rand_seed,
                         result)?;
 
     let (ethereum_payload, ethereum_address) = create_eth_data_to_sign(exec_res.ethereum_bridge);
-    // Signing: S(exeCodeHash, inputsHash, delta(X-1)Hash, deltaXHash, outputHash, usedGas, optionalEthereumData, Success)
-    let used_gas = result.used_gas.to_be_bytes();
-    let output_hash = encrypted_output.keccak256();
-    let to_sign = [
-        &*exe_code_hash,
-        &*inputs_hash,
-        &*pre_execution_state.delta_hash,
-        &*delta_hash,
-        &*output_hash,
-        &used_gas[..],
-        &ethereum_payload[..],
-        &ethereum_address[..],
-        &[ResultStatus::Ok as u8]];
-    result.signature = SIGNING_KEY.sign_multiple(&to_sign)?;
+    let receipt = TaskReceipt::Compute {
+        exe_code_hash,
+        inputs_hash,
+        prev_delta_hash: pre_execution_state.delta_hash,
+        delta_hash,
+        output_hash: encrypted_output.keccak256(),
+        used_gas: result.used_gas,
+        ethereum_payload,
+        ethereum_address,
+        block_number,
+        rand_seed,
+    };
+    result.signature = SIGNING_KEY.sign(&receipt.to_sign())?;
     Ok(())
 }
 
@@ -412,63 +473,74 @@ pub fn build_constructor(wasm_code: &[u8]) -> Result<Vec<u8>, EnclaveError> {
     }
 }
 
-unsafe fn ecall_deploy_internal(pre_execution_data: &mut Vec<Box<[u8]>>, bytecode: &[u8], constructor: &[u8], args: &[u8],
-                                address: ContractAddress, user_key: &PubKey, io_key: &DhKey,
-                                gas_limit: u64, db_ptr: *const RawPointer,
+unsafe fn ecall_deploy_internal(pre_execution_data: &mut Vec<Hash256>, bytecode: &[u8], constructor: &[u8], args: &[u8],
+                                address: ContractAddress, user_key: &PubKey, session: &SessionId, io_key: &DhKey,
+                                gas_limit: u64, block_number: u64, db_ptr: *const RawPointer,
                                 result: &mut ExecuteResult) -> Result<(), EnclaveError> {
 
+    check_block_number(block_number)?;
     let pre_code_hash = bytecode.keccak256();
-    let inputs_hash = enigma_crypto::hash::prepare_hash_multiple(&[constructor, args, &pre_code_hash[..], user_key][..]).keccak256();
-    pre_execution_data.push(Box::new(*inputs_hash));
+    let inputs_hash = TaskReceipt::deploy_inputs_hash(constructor, args, &pre_code_hash, user_key);
+    pre_execution_data.push(inputs_hash);
 
+    let declared_limits = ContractLimits::declared_in(bytecode)?;
+    let limits = declared_limits.unwrap_or_default();
     let deploy_bytecode = build_constructor(bytecode)?;
     let (decrypted_args, _, _types, _) = decrypt_inputs(constructor, args, io_key).
         map_err(|e| {FailedTaskError(InputError{ message: format!("{}", e) })})?;
+    km_t::users::use_session(user_key, session)?;
 
     let state = ContractState::new(address);
 
     let state_key = km_t::get_state_key(address)?;
-    let exec_res = execution::execute_constructor(&deploy_bytecode, gas_limit, state, decrypted_args.clone(), state_key)?;
-
-    let exe_code = &exec_res.result[..];
+    let rand_seed = derive_rand_seed(&inputs_hash);
+    let context = TaskContext { user_pubkey: *user_key, block_number, task_id: inputs_hash };
+    let exec_res = execution::execute_constructor(&deploy_bytecode, &limits, gas_limit, state, decrypted_args.clone(), state_key, rand_seed, context)?;
+
+    // Building the constructor drops custom sections, so the declared limits are put back into the deployed code.
+    let mut exe_code = exec_res.result.clone();
+    if let Some(limits) = declared_limits {
+        limits::append_to(&limits, &mut exe_code)?;
+    }
 
     let delta_hash = save_enc_delta(db_ptr, &exec_res.state_delta)?;
     encrypt_and_save_state(db_ptr, &exec_res.updated_state)?;
 
     prepare_wasm_result(exec_res.state_delta,
-                        exe_code,
+                        &exe_code,
                         exec_res.ethereum_bridge.clone(),
                         exec_res.used_gas,
+                        rand_seed,
                         result)?;
 
 //    let exe_code = &exec_res.result[..];
 //    *output_ptr = ocalls_t::save_to_untrusted_memory(&exe_code)?;
 
-    // Signing: S(inputsHash, exeCodeHash, delta0Hash, usedGas, optionalEthereumData, Success)
-    let used_gas = result.used_gas.to_be_bytes();
     let (ethereum_payload, ethereum_address) = create_eth_data_to_sign(exec_res.ethereum_bridge);
-    let to_sign = [
-        &*(inputs_hash),
-        &*(exec_res.result.keccak256()),
-        &*delta_hash,
-        &used_gas[..],
-        &ethereum_payload[..],
-        &ethereum_address[..],
-        &[ResultStatus::Ok as u8]
-    ];
-    result.signature = SIGNING_KEY.sign_multiple(&to_sign)?;
+    let receipt = TaskReceipt::Deploy {
+        inputs_hash,
+        exe_code_hash: exe_code.keccak256(),
+        delta_hash,
+        used_gas: result.used_gas,
+        ethereum_payload,
+        ethereum_address,
+        block_number,
+        rand_seed,
+    };
+    result.signature = SIGNING_KEY.sign(&receipt.to_sign())?;
     Ok(())
 }
 
 unsafe fn prepare_wasm_result(delta_option: Option<EncryptedPatch>, execute_result: &[u8],
-                              ethereum_bridge: Option<EthereumData>, used_gas: u64,
+                              ethereum_bridge: Option<EthereumData>, used_gas: u64, rand_seed: Hash256,
                               result: &mut ExecuteResult ) -> Result<(), EnclaveError>
 {
     result.output = ocalls_t::save_to_untrusted_memory(&execute_result)? as *const u8;
     result.used_gas = used_gas;
+    result.rand_seed = rand_seed.into();
     match delta_option {
         Some(enc_delta) => {
-            result.delta_ptr = ocalls_t::save_to_untrusted_memory(&enc_delta.data)? as *const u8;
+            result.delta_ptr = ocalls_t::save_to_untrusted_memory(&enc_delta.to_stored_bytes())? as *const u8;
             result.delta_index = enc_delta.index;
         }
         None => {
@@ -504,6 +576,18 @@ fn get_sealed_keys_wrapper() -> asymmetric::KeyPair {
     }
 }
 
+fn get_sealed_rand_secret() -> [u8; 32] {
+    let mut path_buf = ocalls_t::get_home_path().unwrap();
+    // add the filename to the path: `rand-secret.sealed`
+    path_buf.push("rand-secret.sealed");
+    let sealed_path = path_buf.to_str().unwrap();
+
+    match storage_t::get_sealed_secret(&sealed_path) {
+        Ok(secret) => secret,
+        Err(err) => panic!("Failed obtaining the randomness secret: {:?}", err),
+    }
+}
+
 pub mod tests {
     use enigma_types::RawPointer;
     use enigma_types::ResultStatus;
@@ -513,10 +597,15 @@ pub mod tests {
         extern crate sgx_tstd as std;
         extern crate sgx_tunittest;
 
+        use crate::km_t::dh_keys::tests::*;
         use crate::km_t::principal::tests::*;
+        use crate::km_t::tests::*;
+        use crate::km_t::users::tests::*;
         use crate::wasm_g::execution::tests::*;
         use enigma_runtime_t::data::tests::*;
         use enigma_runtime_t::ocalls_t::tests::*;
+        use enigma_runtime_t::tests::*;
+        use enigma_runtime_t::limits::tests::*;
         use enigma_tools_t::storage_t::tests::*;
         use self::sgx_tunittest::*;
         use std::{vec::Vec, string::String};
@@ -527,6 +616,7 @@ pub mod tests {
             let mut ctr = 0u64;
             let mut failures = Vec::new();
             rsgx_unit_test_start();
+            use_test_sealing_dir();
 
             // The reason I had to make our own tests is because baidu's unittest lib supports only static functions that get no inputs.
             core_unitests(&mut ctr, &mut failures, test_full_sealing_storage, "test_full_sealing_storage" );
@@ -534,19 +624,38 @@ pub mod tests {
             core_unitests(&mut ctr, &mut failures, test_encrypt_state, "test_encrypt_state" );
             core_unitests(&mut ctr, &mut failures, test_decrypt_state, "test_decrypt_state" );
             core_unitests(&mut ctr, &mut failures, test_encrypt_decrypt_state, "test_encrypt_decrypt_state" );
+            core_unitests(&mut ctr, &mut failures, test_bound_state_swap, "test_bound_state_swap" );
             core_unitests(&mut ctr, &mut failures, test_write_state, "test_write_state" );
             core_unitests(&mut ctr, &mut failures, test_read_state, "test_read_state" );
             core_unitests(&mut ctr, &mut failures, test_diff_patch, "test_diff_patch" );
             core_unitests(&mut ctr, &mut failures, test_encrypt_patch, "test_encrypt_patch" );
             core_unitests(&mut ctr, &mut failures, test_decrypt_patch, "test_decrypt_patch" );
             core_unitests(&mut ctr, &mut failures, test_encrypt_decrypt_patch, "test_encrypt_decrypt_patch" );
+            core_unitests(&mut ctr, &mut failures, test_bound_patch_swap, "test_bound_patch_swap" );
             core_unitests(&mut ctr, &mut failures, test_apply_delta, "test_apply_delta" );
             core_unitests(&mut ctr, &mut failures, test_generate_delta, "test_generate_delta" );
+            core_unitests(&mut ctr, &mut failures, test_delta_hash_key_version, "test_delta_hash_key_version" );
+            core_unitests(&mut ctr, &mut failures, test_seeded_rand, "test_seeded_rand" );
+            core_unitests(&mut ctr, &mut failures, test_unseeded_rand, "test_unseeded_rand" );
+            core_unitests(&mut ctr, &mut failures, test_seal_for_user, "test_seal_for_user" );
+            core_unitests(&mut ctr, &mut failures, test_task_context, "test_task_context" );
+            core_unitests(&mut ctr, &mut failures, test_limits_roundtrip, "test_limits_roundtrip" );
+            core_unitests(&mut ctr, &mut failures, test_limits_above_maximum, "test_limits_above_maximum" );
             core_unitests(&mut ctr, &mut failures, ||test_me(db_ptr), "test_me" );
             core_unitests(&mut ctr, &mut failures, test_execute_contract, "test_execute_contract" );
             core_unitests(&mut ctr, &mut failures, ||test_get_deltas(db_ptr), "test_get_deltas" );
             core_unitests(&mut ctr, &mut failures, ||test_get_deltas_more(db_ptr), "test_get_deltas_more" );
+            core_unitests(&mut ctr, &mut failures, ||test_get_deltas_key_versions(db_ptr), "test_get_deltas_key_versions" );
             core_unitests(&mut ctr, &mut failures, ||test_state_internal(db_ptr), "test_state_internal" );
+            core_unitests(&mut ctr, &mut failures, ||test_state_internal_rotated_keys(db_ptr), "test_state_internal_rotated_keys" );
+            core_unitests(&mut ctr, &mut failures, ||test_rebuild_state_incremental(db_ptr), "test_rebuild_state_incremental" );
+            core_unitests(&mut ctr, &mut failures, test_ptt_res_principal_auth, "test_ptt_res_principal_auth" );
+            core_unitests(&mut ctr, &mut failures, test_principal_address_write_once, "test_principal_address_write_once" );
+            core_unitests(&mut ctr, &mut failures, test_versioned_state_keys, "test_versioned_state_keys" );
+            core_unitests(&mut ctr, &mut failures, test_state_key_store, "test_state_key_store" );
+            core_unitests(&mut ctr, &mut failures, test_dh_key_store_eviction, "test_dh_key_store_eviction" );
+            core_unitests(&mut ctr, &mut failures, test_user_sessions, "test_user_sessions" );
+            core_unitests(&mut ctr, &mut failures, test_user_sessions_cap, "test_user_sessions_cap" );
             core_unitests(&mut ctr, &mut failures, || {test_state(db_ptr)}, "test_state" );
 
 
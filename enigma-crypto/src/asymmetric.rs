@@ -1,7 +1,10 @@
 use crate::error::CryptoError;
 use secp256k1::{PublicKey, SecretKey, SharedSecret,  RecoveryId, Signature};
-use crate::hash::Keccak256;
-use enigma_types::{DhKey, PubKey};
+use crate::hash::{self, Keccak256};
+use crate::kdf::{self, KeyPurpose};
+use enigma_types::{DhKey, Hash256, PubKey, SymmetricKey};
+#[cfg(any(feature = "sgx", feature = "std"))]
+use crate::localstd::vec::Vec;
 
 #[derive(Debug)]
 pub struct KeyPair {
@@ -31,6 +34,7 @@ impl KeyPair {
         Ok(KeyPair { privkey, pubkey })
     }
 
+    /// The raw ECDH shared secret, use `derive_key_for` to get a key to encrypt with.
     pub fn derive_key(&self, _pubarr: &PubKey) -> Result<DhKey, CryptoError> {
         let mut pubarr: [u8; 65] = [0; 65];
         pubarr[0] = 4;
@@ -47,6 +51,11 @@ impl KeyPair {
         Ok(result)
     }
 
+    /// The key of `purpose` derived from the ECDH shared secret with `pubkey`.
+    pub fn derive_key_for(&self, pubkey: &PubKey, purpose: KeyPurpose) -> Result<SymmetricKey, CryptoError> {
+        Ok(kdf::derive_key(&self.derive_key(pubkey)?, purpose))
+    }
+
     pub fn get_privkey(&self) -> [u8; 32] { self.privkey.serialize() }
 
     /// Get the Public Key and slice the first byte
@@ -60,6 +69,17 @@ impl KeyPair {
         KeyPair::pubkey_object_to_pubkey(&self.pubkey)
     }
 
+    /// The Ethereum address of the key pair.
+    pub fn get_address(&self) -> [u8; 20] { KeyPair::pubkey_to_address(&self.get_pubkey()) }
+
+    /// The Ethereum address of a public key, the last 20 bytes of the keccak256 of the key (without the `0x04` prefix).
+    /// It's the one implementation of the address, `EthereumAddress::address` in enigma-tools-m uses it too.
+    pub fn pubkey_to_address(pubkey: &PubKey) -> [u8; 20] {
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&pubkey.keccak256()[12..]);
+        address
+    }
+
     fn pubkey_object_to_pubkey(key: &PublicKey) -> PubKey {
         let mut sliced_pubkey: [u8; 64] = [0; 64];
         sliced_pubkey.clone_from_slice(&key.serialize()[1..65]);
@@ -81,11 +101,20 @@ impl KeyPair {
     /// 2. 32 Bytes ECDSA `s` variable.
     /// 3. 1 Bytes ECDSA `v` variable aligned to the right for Ethereum compatibility
     pub fn sign(&self, message: &[u8]) -> Result<[u8; 65], CryptoError> {
-        let hashed_msg = message.keccak256();
-        let message_to_sign = secp256k1::Message::parse(&hashed_msg);
+        self.sign_hashed(&message.keccak256())
+    }
+
+    /// Sign a message the way Ethereum wallets do with `personal_sign` (EIP-191 version `0x45`),
+    /// so the signature can be checked with `ecrecover` against `hash::personal_message_hash(message)`.
+    pub fn sign_personal(&self, message: &[u8]) -> Result<[u8; 65], CryptoError> {
+        self.sign_hashed(&hash::personal_message_hash(message))
+    }
+
+    fn sign_hashed(&self, hashed_msg: &Hash256) -> Result<[u8; 65], CryptoError> {
+        let message_to_sign = secp256k1::Message::parse(hashed_msg);
 
         let (sig, recovery) = secp256k1::sign(&message_to_sign, &self.privkey)
-            .map_err(|_| CryptoError::SigningError { hashed_msg: *hashed_msg })?;
+            .map_err(|_| CryptoError::SigningError { hashed_msg: **hashed_msg })?;
 
         let v: u8 = recovery.into();
         let mut returnvalue = [0u8; 65];
@@ -105,15 +134,63 @@ impl KeyPair {
     /// let recovered_pubkey = KeyPair::recover(msg, sig).unwrap();
     /// ```
     pub fn recover(message: &[u8], sig: [u8;65]) -> Result<[u8; 64], CryptoError> {
-        let recovery = RecoveryId::parse(sig[64] -27)
-            .map_err(|_| CryptoError::ParsingError { sig })?;
+        let recovered_pub = KeyPair::recover_hashed(&message.keccak256(), sig)?;
+        Ok(KeyPair::pubkey_object_to_pubkey(&recovered_pub))
+    }
+
+    /// Recover the pubkey of a message signed with `sign_personal` (or by an Ethereum wallet).
+    pub fn recover_personal(message: &[u8], sig: [u8; 65]) -> Result<[u8; 64], CryptoError> {
+        let recovered_pub = KeyPair::recover_hashed(&hash::personal_message_hash(message), sig)?;
+        Ok(KeyPair::pubkey_object_to_pubkey(&recovered_pub))
+    }
+
+    /// Recover the Ethereum address of the signer of a message signed with `sign`.
+    pub fn recover_address(message: &[u8], sig: [u8; 65]) -> Result<[u8; 20], CryptoError> {
+        let recovered_pub = KeyPair::recover_hashed(&message.keccak256(), sig)?;
+        Ok(KeyPair::pubkey_to_address(&KeyPair::pubkey_object_to_pubkey(&recovered_pub)))
+    }
+
+    /// Checks that `message` was signed with `sign` by the owner of `address`,
+    /// an error means the signature itself is malformed.
+    pub fn verify_address(message: &[u8], sig: [u8; 65], address: &[u8; 20]) -> Result<bool, CryptoError> {
+        Ok(&KeyPair::recover_address(message, sig)? == address)
+    }
+
+    /// Recover the signers of several messages, fails on the first signature that can't be recovered.
+    #[cfg(any(feature = "sgx", feature = "std"))]
+    pub fn recover_batch<B: AsRef<[u8]>>(signed: &[(B, [u8; 65])]) -> Result<Vec<[u8; 64]>, CryptoError> {
+        signed.iter().map(|(message, sig)| KeyPair::recover(message.as_ref(), *sig)).collect()
+    }
+
+    /// The same as `recover_batch` but returns the Ethereum addresses of the signers.
+    #[cfg(any(feature = "sgx", feature = "std"))]
+    pub fn recover_addresses<B: AsRef<[u8]>>(signed: &[(B, [u8; 65])]) -> Result<Vec<[u8; 20]>, CryptoError> {
+        signed.iter().map(|(message, sig)| KeyPair::recover_address(message.as_ref(), *sig)).collect()
+    }
+
+    /// Checks that every message was signed by the address at the same position,
+    /// it's false if the number of messages and addresses differ.
+    pub fn verify_batch<B: AsRef<[u8]>>(signed: &[(B, [u8; 65])], addresses: &[[u8; 20]]) -> Result<bool, CryptoError> {
+        if signed.len() != addresses.len() {
+            return Ok(false);
+        }
+        for ((message, sig), address) in signed.iter().zip(addresses.iter()) {
+            if !KeyPair::verify_address(message.as_ref(), *sig, address)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn recover_hashed(hashed_msg: &Hash256, sig: [u8; 65]) -> Result<PublicKey, CryptoError> {
+        // `v` is 27 or 28 as in Ethereum, anything lower isn't a signature.
+        let recovery = sig[64].checked_sub(27).and_then(|v| RecoveryId::parse(v).ok())
+            .ok_or(CryptoError::ParsingError { sig })?;
         let signature = Signature::parse_slice(&sig[..64])
             .map_err(|_| CryptoError::ParsingError { sig } )?;
-        let hashed_msg = message.keccak256();
-        let signed_message = secp256k1::Message::parse(&hashed_msg);
-        let recovered_pub = secp256k1::recover(&signed_message, &signature, &recovery)
-            .map_err(|_| CryptoError::RecoveryError { sig } )?;
-        Ok(KeyPair::pubkey_object_to_pubkey(&recovered_pub))
+        let signed_message = secp256k1::Message::parse(hashed_msg);
+        secp256k1::recover(&signed_message, &signature, &recovery)
+            .map_err(|_| CryptoError::RecoveryError { sig } )
     }
 
     /// The same as sign() but for multiple arguments.
@@ -132,11 +209,48 @@ impl KeyPair {
         let ready = crate::hash::prepare_hash_multiple(messages);
         self.sign(&ready)
     }
+
+    /// Encrypt `message` so that only the owner of `pubkey` can decrypt it.
+    /// A fresh ephemeral key is used for every message, its public key is prepended to the ciphertext
+    /// so the receiver can derive the same key: `version (1) || ephemeral pubkey (64) || ciphertext || tag (16) || iv (12)`.
+    #[cfg(any(feature = "sgx", feature = "std"))]
+    pub fn seal_for(pubkey: &PubKey, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let ephemeral = KeyPair::new()?;
+        let key = ephemeral.derive_key_for(pubkey, KeyPurpose::SealedMessage)?;
+        let mut sealed = Vec::with_capacity(message.len() + SEALED_OVERHEAD);
+        sealed.push(SEALED_VERSION);
+        sealed.extend_from_slice(&ephemeral.get_pubkey());
+        sealed.extend_from_slice(&crate::symmetric::encrypt(message, &key)?);
+        Ok(sealed)
+    }
+
+    /// Decrypt a message that was sealed to this key with `KeyPair::seal_for`.
+    #[cfg(any(feature = "sgx", feature = "std"))]
+    pub fn open_sealed(&self, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < SEALED_OVERHEAD || sealed[0] != SEALED_VERSION {
+            return Err(CryptoError::ImproperEncryption);
+        }
+        let mut ephemeral_pubkey = [0u8; 64];
+        ephemeral_pubkey.copy_from_slice(&sealed[1..65]);
+        let key = self.derive_key_for(&ephemeral_pubkey, KeyPurpose::SealedMessage)?;
+        crate::symmetric::decrypt(&sealed[65..], &key)
+    }
 }
 
+/// The version of the `KeyPair::seal_for` format, version 1 keys the ciphertext with the HKDF
+/// `KeyPurpose::SealedMessage` key of the ephemeral DH secret.
+#[cfg(any(feature = "sgx", feature = "std"))]
+const SEALED_VERSION: u8 = 1;
+
+/// How much longer a message sealed with `KeyPair::seal_for` is than the plaintext.
+pub use enigma_types::wasm::SEALED_OVERHEAD;
+
 #[cfg(test)]
 mod tests {
-    use super::KeyPair;
+    use super::{KeyPair, SEALED_OVERHEAD};
+    use crate::hash::{personal_message_hash, Keccak256};
+    use crate::kdf::KeyPurpose;
+    use rustc_hex::{FromHex, ToHex};
 
     #[test]
     fn test_signing() {
@@ -160,6 +274,143 @@ mod tests {
         assert_eq!(&k1.get_pubkey()[..], &recover_pub[..]);
     }
 
+    #[test]
+    fn test_personal_sign() {
+        // The example of `web3.eth.accounts.sign` in the web3.js documentation
+        let mut privkey = [0u8; 32];
+        privkey.copy_from_slice(&"4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318".from_hex::<Vec<u8>>().unwrap());
+        let keys = KeyPair::from_slice(&privkey).unwrap();
+        let address: Vec<u8> = "2c7536e3605d9c16a7a3d7b1898e529396a65c23".from_hex().unwrap();
+        let msg = b"Some data";
+
+        assert_eq!(personal_message_hash(msg).to_hex::<String>(), "1da44b586eb0729ff70a73c326926f6ed5a25f5b056e7f47fbc6e58d86871655");
+        let sig = keys.sign_personal(msg).unwrap();
+        assert_eq!(
+            sig.to_hex::<String>(),
+            "b91467e570a6466aa9e9876cbcd013baba02900b8979d43fe208a4a4f339f5fd6007e74cd82e037b800186422fc2da167c747ef045e5d18a5f5d4300f8e1a0291c"
+        );
+        assert_eq!(KeyPair::recover_personal(msg, sig).unwrap(), keys.get_pubkey());
+        // A personal signature isn't a signature over the message itself
+        assert!(!KeyPair::verify_address(msg, sig, &keys.get_address()).unwrap());
+        assert_eq!(&keys.get_address()[..], &address[..]);
+
+        // ethers.js `hashMessage`
+        assert_eq!(personal_message_hash(b"Hello World").to_hex::<String>(), "a1de988600a42c4b4ab089b619297c17d53cffae5d5120d82d8a92d0bb3b78f2");
+        assert_eq!(personal_message_hash(b"").to_hex::<String>(), "5f35dce98ba4fba25530a026ed80b2cecdaa31091ba4958b99b52ea1d068adad");
+    }
+
+    #[test]
+    fn test_ethereum_vectors() {
+        // (private key, address, message, signature, signed with `sign_personal`)
+        let vectors = [
+            // The transaction signed in the EIP-155 example, `v` is 27 here because the chain id isn't mixed in
+            (
+                "4646464646464646464646464646464646464646464646464646464646464646",
+                "9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f",
+                "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080",
+                "28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa63627667cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d831b",
+                false,
+            ),
+            // `b"EnigmaMPC"` signed with the key of `test_signing`
+            (
+                "cdbd854f10463bf67be34240f4bcbc93e9fcd5852c9dad8d325d28822c632bcd",
+                "565bb98965acd6dd393e0327646b9e998b1b43e0",
+                "456e69676d614d5043",
+                "6774d0d2c223be51aea201a26068aaf3d802f15d95d02ed288b65d3fb2a14b8b0310a289b883d6af310b3689e858ea4b026721f49e51a2f11f9e881e26bf7c5d1c",
+                false,
+            ),
+            // The example of `web3.eth.accounts.sign`, `b"Some data"`
+            (
+                "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318",
+                "2c7536e3605d9c16a7a3d7b1898e529396a65c23",
+                "536f6d652064617461",
+                "b91467e570a6466aa9e9876cbcd013baba02900b8979d43fe208a4a4f339f5fd6007e74cd82e037b800186422fc2da167c747ef045e5d18a5f5d4300f8e1a0291c",
+                true,
+            ),
+        ];
+
+        let mut signed = Vec::new();
+        let mut addresses = Vec::new();
+        for &(privkey_hex, address_hex, msg_hex, sig_hex, personal) in vectors.iter() {
+            let mut privkey = [0u8; 32];
+            privkey.copy_from_slice(&privkey_hex.from_hex::<Vec<u8>>().unwrap());
+            let mut address = [0u8; 20];
+            address.copy_from_slice(&address_hex.from_hex::<Vec<u8>>().unwrap());
+            let mut msg: Vec<u8> = msg_hex.from_hex().unwrap();
+            let keys = KeyPair::from_slice(&privkey).unwrap();
+
+            assert_eq!(keys.get_address(), address);
+            assert_eq!(KeyPair::pubkey_to_address(&keys.get_pubkey()), address);
+            assert_eq!(keys.get_pubkey().keccak256()[12..], address[..]);
+
+            if personal {
+                let sig = keys.sign_personal(&msg).unwrap();
+                assert_eq!(sig.to_hex::<String>(), sig_hex);
+                assert_eq!(KeyPair::recover_personal(&msg, sig).unwrap(), keys.get_pubkey());
+                continue;
+            }
+            let sig = keys.sign(&msg).unwrap();
+            assert_eq!(sig.to_hex::<String>(), sig_hex);
+            assert_eq!(KeyPair::recover(&msg, sig).unwrap(), keys.get_pubkey());
+            assert_eq!(KeyPair::recover_address(&msg, sig).unwrap(), address);
+            assert!(KeyPair::verify_address(&msg, sig, &address).unwrap());
+            signed.push((msg.clone(), sig));
+            addresses.push(address);
+
+            msg[0] ^= 1;
+            // A different message recovers some other key, or none at all
+            assert!(!KeyPair::verify_address(&msg, sig, &address).unwrap_or(false));
+        }
+
+        assert!(KeyPair::verify_batch(&signed, &addresses).unwrap());
+        assert_eq!(KeyPair::recover_addresses(&signed).unwrap(), addresses);
+        addresses.reverse();
+        assert!(!KeyPair::verify_batch(&signed, &addresses).unwrap());
+    }
+
+    #[test]
+    fn test_batch_recover_and_verify() {
+        let keys: Vec<KeyPair> = (0..5).map(|_| KeyPair::new().unwrap()).collect();
+        let signed: Vec<(Vec<u8>, [u8; 65])> = keys.iter().enumerate().map(|(i, key)| {
+            let msg = vec![i as u8; i + 1];
+            let sig = key.sign(&msg).unwrap();
+            (msg, sig)
+        }).collect();
+        let addresses: Vec<[u8; 20]> = keys.iter().map(|key| key.get_address()).collect();
+
+        assert_eq!(KeyPair::recover_addresses(&signed).unwrap(), addresses);
+        assert_eq!(KeyPair::recover_batch(&signed).unwrap(), keys.iter().map(|key| key.get_pubkey()).collect::<Vec<_>>());
+        assert!(KeyPair::verify_batch(&signed, &addresses).unwrap());
+        assert!(!KeyPair::verify_batch(&signed[1..], &addresses).unwrap());
+
+        let mut swapped = addresses.clone();
+        swapped.swap(0, 1);
+        assert!(!KeyPair::verify_batch(&signed, &swapped).unwrap());
+
+        let mut unsigned = signed.clone();
+        unsigned[2].1 = [0u8; 65];
+        assert!(KeyPair::recover_addresses(&unsigned).is_err());
+        assert!(KeyPair::verify_batch(&unsigned, &addresses).is_err());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let receiver = KeyPair::new().unwrap();
+        let msg = b"Only for the receiver";
+        let sealed = KeyPair::seal_for(&receiver.get_pubkey(), msg).unwrap();
+        assert_eq!(sealed.len(), msg.len() + SEALED_OVERHEAD);
+        assert_eq!(receiver.open_sealed(&sealed).unwrap(), msg.to_vec());
+
+        let other = KeyPair::new().unwrap();
+        assert!(other.open_sealed(&sealed).is_err());
+        assert!(receiver.open_sealed(&sealed[..SEALED_OVERHEAD - 1]).is_err());
+
+        // A message sealed in another version of the format isn't opened
+        let mut other_version = sealed.clone();
+        other_version[0] = 0;
+        assert!(receiver.open_sealed(&other_version).is_err());
+    }
+
     #[test]
     fn test_ecdh() {
         let _priv1: [u8; 32] = [205, 189, 133, 79, 16, 70, 59, 246, 123, 227, 66, 64, 244, 188, 188, 147, 233, 252, 213, 133, 44, 157, 173, 141, 50, 93, 40, 130, 44, 99, 43, 205];
@@ -174,4 +425,13 @@ mod tests {
             [139, 184, 212, 39, 0, 146, 97, 243, 63, 65, 81, 130, 96, 208, 43, 150, 229, 90, 132, 202, 235, 168, 86, 59, 141, 19, 200, 38, 242, 55, 203, 15]
         );
     }
+
+    #[test]
+    fn test_derive_key_for() {
+        let (k1, k2) = (KeyPair::new().unwrap(), KeyPair::new().unwrap());
+        let user_io = k1.derive_key_for(&k2.get_pubkey(), KeyPurpose::UserIo).unwrap();
+        assert_eq!(user_io, k2.derive_key_for(&k1.get_pubkey(), KeyPurpose::UserIo).unwrap());
+        assert_ne!(user_io, k1.derive_key(&k2.get_pubkey()).unwrap());
+        assert_ne!(user_io, k1.derive_key_for(&k2.get_pubkey(), KeyPurpose::PrincipalResponse).unwrap());
+    }
 }
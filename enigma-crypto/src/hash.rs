@@ -25,6 +25,29 @@ pub fn prepare_hash_multiple<B: AsRef<[u8]>>(messages: &[B]) -> crate::localstd:
     res
 }
 
+/// The hash an Ethereum `personal_sign` signature is over (EIP-191 version `0x45`):
+/// `keccak256("\x19Ethereum Signed Message:\n" || len(message) in decimal || message)`.
+pub fn personal_message_hash(message: &[u8]) -> Hash256 {
+    let mut len_digits = [0u8; 20];
+    let mut start = len_digits.len();
+    let mut len = message.len();
+    loop {
+        start -= 1;
+        len_digits[start] = b'0' + (len % 10) as u8;
+        len /= 10;
+        if len == 0 {
+            break;
+        }
+    }
+    let mut keccak = Keccak::new_keccak256();
+    let mut result = Hash256::default();
+    keccak.update(b"\x19Ethereum Signed Message:\n");
+    keccak.update(&len_digits[start..]);
+    keccak.update(message);
+    keccak.finalize(result.as_mut());
+    result
+}
+
 // Hash a byte array into keccak256.
 pub trait Keccak256<T> {
     fn keccak256(&self) -> T where T: Sized;
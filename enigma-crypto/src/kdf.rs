@@ -0,0 +1,103 @@
+//! Derivation of purpose bound keys from a shared secret, with HKDF-SHA256 (RFC 5869).
+//!
+//! A DH secret is never used as a key directly, every use of it derives its own key with a
+//! distinct `KeyPurpose` label, so a key of one purpose can't decrypt the messages of another.
+use enigma_types::SymmetricKey;
+use sha2::{Digest, Sha256};
+
+const BLOCK_SIZE: usize = 64;
+const HASH_SIZE: usize = 32;
+
+/// What a derived key is used for, the label is the HKDF `info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// Encrypting the inputs a user sends to a worker and the outputs it gets back.
+    UserIo,
+    /// Encrypting the response of the principal node to a worker's request for state keys.
+    PrincipalResponse,
+    /// Encrypting a message sealed to a public key with an ephemeral key, see `KeyPair::seal_for`.
+    SealedMessage,
+    /// Wrapping a state key for another enclave. Reserved: the state keys are only sent in a principal response
+    /// (`PrincipalResponse`) and sealed with the enclave key at rest, so nothing derives it yet.
+    StateKeyWrap,
+}
+
+impl KeyPurpose {
+    pub fn label(self) -> &'static [u8] {
+        match self {
+            KeyPurpose::UserIo => b"enigma user-io",
+            KeyPurpose::PrincipalResponse => b"enigma principal-response",
+            KeyPurpose::SealedMessage => b"enigma sealed-message",
+            KeyPurpose::StateKeyWrap => b"enigma state-key-wrap",
+        }
+    }
+}
+
+/// Derives the key of `purpose` from a shared secret (i.e. the output of `KeyPair::derive_key`).
+pub fn derive_key(secret: &[u8], purpose: KeyPurpose) -> SymmetricKey {
+    hkdf_sha256(&[], secret, purpose.label())
+}
+
+/// HKDF-SHA256 with a 32 bytes output, which is a single expand block: `HMAC(HMAC(salt, ikm), info || 0x01)`.
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> SymmetricKey {
+    let prk = hmac_sha256(salt, &[ikm]);
+    hmac_sha256(&prk, &[info, &[1u8]])
+}
+
+fn hmac_sha256(key: &[u8], messages: &[&[u8]]) -> [u8; HASH_SIZE] {
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..HASH_SIZE].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let (mut ipad, mut opad) = ([0x36u8; BLOCK_SIZE], [0x5cu8; BLOCK_SIZE]);
+    for ((i, o), k) in ipad.iter_mut().zip(opad.iter_mut()).zip(block_key.iter()) {
+        *i ^= k;
+        *o ^= k;
+    }
+
+    let mut inner = Sha256::new();
+    inner.input(&ipad[..]);
+    for msg in messages {
+        inner.input(msg);
+    }
+    let mut outer = Sha256::new();
+    outer.input(&opad[..]);
+    outer.input(&inner.result());
+
+    let mut res = [0u8; HASH_SIZE];
+    res.copy_from_slice(&outer.result());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_key, hkdf_sha256, KeyPurpose};
+    use rustc_hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_hkdf_sha256() {
+        // RFC 5869, test case 1, the first 32 bytes of the OKM
+        let ikm = [0x0bu8; 22];
+        let salt: Vec<u8> = "000102030405060708090a0b0c".from_hex().unwrap();
+        let info: Vec<u8> = "f0f1f2f3f4f5f6f7f8f9".from_hex().unwrap();
+        assert_eq!(hkdf_sha256(&salt, &ikm, &info).to_hex::<String>(), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf");
+
+        // RFC 5869, test case 3, an empty salt and info
+        assert_eq!(hkdf_sha256(&[], &ikm, &[]).to_hex::<String>(), "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d");
+    }
+
+    #[test]
+    fn test_purposes_derive_different_keys() {
+        let secret = [7u8; 32];
+        let purposes = [KeyPurpose::UserIo, KeyPurpose::PrincipalResponse, KeyPurpose::SealedMessage, KeyPurpose::StateKeyWrap];
+        let keys: Vec<_> = purposes.iter().map(|&purpose| derive_key(&secret, purpose)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_ne!(key, &secret);
+            assert!(keys[i + 1..].iter().all(|other| other != key));
+        }
+        assert_eq!(KeyPurpose::StateKeyWrap.label(), b"enigma state-key-wrap");
+    }
+}
@@ -6,6 +6,8 @@
 pub mod asymmetric;
 #[cfg(feature = "hash")]
 pub mod hash;
+#[cfg(feature = "hash")]
+pub mod kdf;
 pub mod error;
 pub mod rand;
 
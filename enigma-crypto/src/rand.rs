@@ -14,4 +14,75 @@ pub fn random(rand: &mut [u8]) -> Result<(), CryptoError> {
     use sgx_trts::trts::rsgx_read_rand;
     rsgx_read_rand(rand)
         .map_err(|e| CryptoError::RandomError { err: e } )
-}
\ No newline at end of file
+}
+
+/// A deterministic stream of random bytes derived from a 32 bytes seed.
+/// Block `i` of the stream is `sha256(seed || i)` where `i` is a big endian u64,
+/// so the same seed always produces the same stream, on any machine.
+/// This is what makes a task that uses randomness replayable and verifiable.
+#[cfg(feature = "hash")]
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    seed: [u8; 32],
+    counter: u64,
+    block: [u8; 32],
+    offset: usize,
+}
+
+#[cfg(feature = "hash")]
+impl SeededRng {
+    pub fn new(seed: [u8; 32]) -> SeededRng {
+        SeededRng { seed, counter: 0, block: [0u8; 32], offset: 32 }
+    }
+
+    pub fn seed(&self) -> [u8; 32] { self.seed }
+
+    /// Fill `dest` with the next bytes of the stream.
+    pub fn fill(&mut self, dest: &mut [u8]) {
+        for byte in dest.iter_mut() {
+            if self.offset == self.block.len() {
+                self.next_block();
+            }
+            *byte = self.block[self.offset];
+            self.offset += 1;
+        }
+    }
+
+    fn next_block(&mut self) {
+        use crate::hash::Sha256;
+        let mut input = [0u8; 40];
+        input[..32].copy_from_slice(&self.seed);
+        input[32..].copy_from_slice(&self.counter.to_be_bytes());
+        self.block = *input[..].sha256();
+        self.counter += 1;
+        self.offset = 0;
+    }
+}
+
+#[cfg(all(test, feature = "hash"))]
+mod tests {
+    use super::SeededRng;
+
+    #[test]
+    fn test_seeded_rng_stream() {
+        let mut rng = SeededRng::new([0u8; 32]);
+        let mut first = [0u8; 32];
+        rng.fill(&mut first);
+        assert_eq!(first, [44, 52, 206, 29, 242, 59, 131, 140, 90, 191, 42, 127, 100, 55, 204, 163, 211, 6, 126, 213, 9, 255, 37, 241, 29, 246, 177, 27, 88, 43, 81, 235]);
+        let mut next = [0u8; 8];
+        rng.fill(&mut next);
+        assert_eq!(next, [8, 224, 2, 102, 255, 240, 170, 204]);
+    }
+
+    #[test]
+    fn test_seeded_rng_is_split_independent() {
+        let (mut a, mut b) = (SeededRng::new([7u8; 32]), SeededRng::new([7u8; 32]));
+        let mut whole = [0u8; 100];
+        a.fill(&mut whole);
+        let mut parts = [0u8; 100];
+        b.fill(&mut parts[..3]);
+        b.fill(&mut parts[3..61]);
+        b.fill(&mut parts[61..]);
+        assert_eq!(&whole[..], &parts[..]);
+    }
+}
@@ -1,3 +1,9 @@
+//! Authenticated symmetric encryption.
+//!
+//! `encrypt` produces the original format, `AES-256-GCM ciphertext || tag || 96 bit nonce`, which clients rely on.
+//! `seal` produces `HEADER_MAGIC || cipher id || ciphertext || tag || nonce` with any of the `Cipher`s,
+//! the default one is AES-256-GCM unless the `xchacha20-default` feature is enabled.
+//! `decrypt` accepts all of the formats.
 use enigma_types::SymmetricKey;
 use crate::error::CryptoError;
 use ring::aead::{self, Nonce, Aad};
@@ -8,13 +14,78 @@ use crate::localstd::vec;
 use crate::rand;
 
 const IV_SIZE: usize = 96/8;
+const XNONCE_SIZE: usize = 192/8;
 static AES_MODE: &aead::Algorithm = &aead::AES_256_GCM;
 type IV = [u8; IV_SIZE];
 
+/// The first byte of a ciphertext that carries a header, followed by the `Cipher` id.
+const HEADER_MAGIC: u8 = 0xEC;
+const HEADER_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+    /// ChaCha20-Poly1305 with a 192 bit nonce, safe to pick at random for any number of messages under one key.
+    XChaCha20Poly1305 = 3,
+}
+
+#[cfg(not(feature = "xchacha20-default"))]
+pub const DEFAULT_CIPHER: Cipher = Cipher::Aes256Gcm;
+#[cfg(feature = "xchacha20-default")]
+pub const DEFAULT_CIPHER: Cipher = Cipher::XChaCha20Poly1305;
+
+impl Cipher {
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            3 => Some(Cipher::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => IV_SIZE,
+            Cipher::XChaCha20Poly1305 => XNONCE_SIZE,
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 | Cipher::XChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// The key and 96 bit nonce the underlying AEAD is called with.
+    fn aead_params(self, key: &SymmetricKey, nonce: &[u8]) -> (SymmetricKey, IV) {
+        let mut iv = [0u8; IV_SIZE];
+        match self {
+            Cipher::XChaCha20Poly1305 => {
+                let mut hnonce = [0u8; 16];
+                hnonce.copy_from_slice(&nonce[..16]);
+                iv[4..].copy_from_slice(&nonce[16..]);
+                (hchacha20(key, &hnonce), iv)
+            }
+            _ => {
+                iv.copy_from_slice(nonce);
+                (*key, iv)
+            }
+        }
+    }
+}
 
 pub fn encrypt(message: &[u8], key: &SymmetricKey) -> Result<Vec<u8>, CryptoError> { encrypt_with_nonce(message, key, None) }
 
 pub fn encrypt_with_nonce(message: &[u8], key: &SymmetricKey, _iv: Option<IV>) -> Result<Vec<u8>, CryptoError> {
+    encrypt_with_aad(message, key, &[], _iv)
+}
+
+/// Encrypts `message` authenticating `aad` along with it, the ciphertext will only decrypt with the same `aad`.
+/// The `aad` itself isn't part of the ciphertext, so the decrypting side has to know it.
+pub fn encrypt_with_aad(message: &[u8], key: &SymmetricKey, aad: &[u8], _iv: Option<IV>) -> Result<Vec<u8>, CryptoError> {
     let iv = match _iv {
         Some(x) => x,
         None => {
@@ -23,46 +94,128 @@ pub fn encrypt_with_nonce(message: &[u8], key: &SymmetricKey, _iv: Option<IV>) -
             _tmp_iv
         }
     };
-    let aes_encrypt = aead::SealingKey::new(&AES_MODE, key)
+    let mut in_out = seal_in_place(AES_MODE, key, &iv, aad, message)?;
+    in_out.extend_from_slice(&iv);
+    Ok(in_out)
+}
+
+/// Encrypts `message` with the `DEFAULT_CIPHER` into the format with a header, see `seal_with_cipher`.
+pub fn seal(message: &[u8], key: &SymmetricKey, aad: &[u8], nonce: Option<&[u8]>) -> Result<Vec<u8>, CryptoError> {
+    seal_with_cipher(DEFAULT_CIPHER, message, key, aad, nonce)
+}
+
+/// Encrypts `message` authenticating `aad` along with it, a random nonce is used if none is given.
+/// The nonce has to be `cipher.nonce_len()` bytes long.
+pub fn seal_with_cipher(cipher: Cipher, message: &[u8], key: &SymmetricKey, aad: &[u8], nonce: Option<&[u8]>) -> Result<Vec<u8>, CryptoError> {
+    let mut random_nonce = [0u8; XNONCE_SIZE];
+    let nonce = match nonce {
+        Some(nonce) if nonce.len() == cipher.nonce_len() => nonce,
+        Some(_) => return Err(CryptoError::EncryptionError),
+        None => {
+            rand::random(&mut random_nonce[..cipher.nonce_len()])?;
+            &random_nonce[..cipher.nonce_len()]
+        }
+    };
+    let (key, iv) = cipher.aead_params(key, nonce);
+    let mut res = vec![HEADER_MAGIC, cipher as u8];
+    res.extend(seal_in_place(cipher.algorithm(), &key, &iv, aad, message)?);
+    res.extend_from_slice(nonce);
+    Ok(res)
+}
+
+fn seal_in_place(algorithm: &'static aead::Algorithm, key: &SymmetricKey, iv: &IV, aad: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let sealing_key = aead::SealingKey::new(algorithm, key)
         .map_err(|_| CryptoError::KeyError{ key_type: "Encryption", err: None })?;
 
     let mut in_out = message.to_owned();
-    let tag_size = AES_MODE.tag_len();
+    let tag_size = algorithm.tag_len();
     in_out.extend(vec![0u8; tag_size]);
     let seal_size = {
-        let iv = Nonce::assume_unique_for_key(iv);
-        aead::seal_in_place(&aes_encrypt, iv, Aad::empty(), &mut in_out, tag_size)
+        let iv = Nonce::assume_unique_for_key(*iv);
+        aead::seal_in_place(&sealing_key, iv, Aad::from(aad), &mut in_out, tag_size)
             .map_err(|_| CryptoError::EncryptionError)
     }?;
 
     in_out.truncate(seal_size);
-    in_out.extend_from_slice(&iv);
     Ok(in_out)
 }
 
 
-pub fn decrypt(cipheriv: &[u8], key: &SymmetricKey) -> Result<Vec<u8>, CryptoError> {
-    if cipheriv.len() < IV_SIZE {
+pub fn decrypt(cipheriv: &[u8], key: &SymmetricKey) -> Result<Vec<u8>, CryptoError> { decrypt_with_aad(cipheriv, key, &[]) }
+
+/// Decrypts a ciphertext of `encrypt_with_aad` or `seal`, fails if `aad` isn't the one it was encrypted with.
+pub fn decrypt_with_aad(cipheriv: &[u8], key: &SymmetricKey, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    // The original format has no header, so a ciphertext of it can start with what looks like one.
+    // Authentication fails if it's read the wrong way, in which case it's read as the original format.
+    if cipheriv.len() >= HEADER_SIZE && cipheriv[0] == HEADER_MAGIC {
+        if let Some(cipher) = Cipher::from_id(cipheriv[1]) {
+            if let Ok(res) = open(cipher, &cipheriv[HEADER_SIZE..], key, aad) {
+                return Ok(res);
+            }
+        }
+    }
+    open(Cipher::Aes256Gcm, cipheriv, key, aad)
+}
+
+fn open(cipher: Cipher, cipheriv: &[u8], key: &SymmetricKey, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if cipheriv.len() < cipher.nonce_len() {
         return Err(CryptoError::ImproperEncryption);
     }
-    let aes_decrypt = aead::OpeningKey::new(&AES_MODE, key)
+    let (ciphertext, nonce) = cipheriv.split_at(cipheriv.len() - cipher.nonce_len());
+    let (key, iv) = cipher.aead_params(key, nonce);
+    let opening_key = aead::OpeningKey::new(cipher.algorithm(), &key)
         .map_err(|_| CryptoError::KeyError { key_type: "Decryption", err: None })?;
 
-    let (ciphertext, iv) = cipheriv.split_at(cipheriv.len()-12);
-    let nonce = aead::Nonce::try_assume_unique_for_key(&iv).unwrap(); // This Cannot fail because split_at promises that iv.len()==12
+    let nonce = Nonce::assume_unique_for_key(iv);
     let mut ciphertext = ciphertext.to_owned();
-    let decrypted_data = aead::open_in_place(&aes_decrypt, nonce, Aad::empty(), 0, &mut ciphertext);
+    let decrypted_data = aead::open_in_place(&opening_key, nonce, Aad::from(aad), 0, &mut ciphertext);
     let decrypted_data = decrypted_data.map_err(|_| CryptoError::DecryptionError)?;
 
     Ok(decrypted_data.to_vec())
 }
 
+/// HChaCha20 (draft-irtf-cfrg-xchacha), derives the subkey XChaCha20 encrypts with from the first 128 bits of its nonce.
+fn hchacha20(key: &SymmetricKey, nonce: &[u8; 16]) -> SymmetricKey {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+    let le_word = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        state[4 + i] = le_word(&key[i * 4..]);
+    }
+    for i in 0..4 {
+        state[12 + i] = le_word(&nonce[i * 4..]);
+    }
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut subkey = [0u8; 32];
+    for (i, word) in state[..4].iter().chain(state[12..].iter()).enumerate() {
+        subkey[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    subkey
+}
+
 #[cfg(test)]
 mod tests {
     use crate::rand;
     use rustc_hex::{ToHex, FromHex};
     use crate::hash::Sha256;
-    use super::{decrypt, encrypt_with_nonce};
+    use super::{decrypt, decrypt_with_aad, encrypt_with_aad, encrypt_with_nonce, hchacha20, seal, seal_with_cipher, Cipher};
 
     #[test]
     fn test_rand_encrypt_decrypt() {
@@ -86,6 +239,60 @@ mod tests {
         assert_eq!(result.to_hex::<String>(), "02dc75395859faa78a598e11945c7165db9a16d16ada1b026c9434b134ae000102030405060708090a0b");
     }
 
+    #[test]
+    fn test_encryption_with_aad() {
+        let key = b"EnigmaMPC".sha256();
+        let msg = b"This Is Enigma".to_vec();
+        let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let result = encrypt_with_aad(&msg, &key, b"Enigma", Some(iv)).unwrap();
+        assert_eq!(decrypt_with_aad(&result, &key, b"Enigma").unwrap(), msg);
+        assert!(decrypt_with_aad(&result, &key, b"Enigmb").is_err());
+        assert!(decrypt(&result, &key).is_err());
+
+        // An empty AAD is the same as none
+        assert_eq!(encrypt_with_aad(&msg, &key, &[], Some(iv)).unwrap(), encrypt_with_nonce(&msg, &key, Some(iv)).unwrap());
+    }
+
+    #[test]
+    fn test_hchacha20() {
+        // draft-irtf-cfrg-xchacha-03, section 2.2.1
+        let mut key = [0u8; 32];
+        key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let mut nonce = [0u8; 16];
+        nonce.copy_from_slice(&"000000090000004a0000000031415927".from_hex::<Vec<u8>>().unwrap());
+        assert_eq!(hchacha20(&key, &nonce).to_hex::<String>(), "82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc");
+    }
+
+    #[test]
+    fn test_seal_ciphers() {
+        let key = b"EnigmaMPC".sha256();
+        let msg = b"This Is Enigma".to_vec();
+        let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let xnonce: Vec<u8> = (0..24).collect();
+        let vectors = [
+            (Cipher::Aes256Gcm, &iv[..], "ec0102dc75395859faa78a598e11945c5f7d3828b6a519d546de30eab421f720000102030405060708090a0b"),
+            (Cipher::ChaCha20Poly1305, &iv[..], "ec029c09091f69bf667af03734bb77154920d89b5aaf8ec7c7427b83640932e7000102030405060708090a0b"),
+            (Cipher::XChaCha20Poly1305, &xnonce[..], "ec0328e09e34a539d851b947808dd80c0cd48ccc9174f2d74c4740a38803d49a000102030405060708090a0b0c0d0e0f1011121314151617"),
+        ];
+        for (cipher, nonce, expected) in vectors.iter() {
+            let sealed = seal_with_cipher(*cipher, &msg, &key, b"Enigma", Some(*nonce)).unwrap();
+            assert_eq!(sealed.to_hex::<String>(), *expected);
+            assert_eq!(decrypt_with_aad(&sealed, &key, b"Enigma").unwrap(), msg);
+            assert!(decrypt_with_aad(&sealed, &key, b"Enigmb").is_err());
+            assert!(seal_with_cipher(*cipher, &msg, &key, &[], Some(&xnonce[..nonce.len() - 1])).is_err());
+        }
+        let sealed = seal(&msg, &key, &[], None).unwrap();
+        assert_eq!(decrypt(&sealed, &key).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_decrypt_headerless_lookalike() {
+        // An original format ciphertext which happens to start with a valid header
+        let encrypted_data: Vec<u8> = "ec037c50f92865a790750ce290cb521b53697aacb7a1458485229b91a6980000000000000000000035d1".from_hex().unwrap();
+        let key = b"EnigmaMPC".sha256();
+        assert_eq!(decrypt(&encrypted_data, &key).unwrap(), b"This Is Enigma".to_vec());
+    }
+
     #[test]
     fn test_decryption() {
         let encrypted_data: Vec<u8> = "02dc75395859faa78a598e11945c7165db9a16d16ada1b026c9434b134ae000102030405060708090a0b".from_hex().unwrap();
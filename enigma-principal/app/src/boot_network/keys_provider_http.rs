@@ -1,21 +1,55 @@
 use enigma_crypto::KeyPair;
-use enigma_tools_m::{
-    primitives::km_primitives::{PrincipalMessage, PrincipalMessageType},
-    utils::EthereumAddress,
-};
+use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType};
+use enigma_tools_m::utils::EthereumAddress;
 use enigma_types::ContractAddress;
 use epoch_u::{epoch_provider::EpochProvider, epoch_types::EpochState};
-use esgx::keys_keeper_u::get_enc_state_keys;
+use esgx::{equote::get_register_signing_address, keys_keeper_u::get_enc_state_keys};
 use failure::Error;
 use jsonrpc_http_server::{
     jsonrpc_core::{Error as ServerError, ErrorCode, IoHandler, Params, Value},
     ServerBuilder,
 };
 use rustc_hex::{FromHex, ToHex};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{convert::TryInto, sync::Arc};
+use web3::types::{H160, U256};
 
 const METHOD_GET_STATE_KEYS: &str = "getStateKeys";
+const METHOD_GET_STATE_KEYS_BATCH: &str = "getStateKeysBatch";
+const METHOD_GET_EPOCH: &str = "getEpoch";
+const METHOD_GET_SELECTED_WORKER: &str = "getSelectedWorker";
+const METHOD_GET_WORKER_GROUP: &str = "getWorkerGroup";
+const METHOD_GET_SIGNING_ADDRESS: &str = "getSigningAddress";
+const METHOD_HEALTH: &str = "health";
+
+/// The errors returned by the JSON-RPC methods, each with its own error code
+#[derive(Fail, Debug)]
+pub enum PrincipalRpcError {
+    #[fail(display = "Invalid request: {}", _0)]
+    InvalidRequest(String),
+    #[fail(display = "Epoch not found: {}", _0)]
+    EpochNotFound(String),
+    #[fail(display = "No worker selected for contract: {:?}", _0)]
+    WorkerNotFound(ContractAddress),
+    #[fail(display = "Unable to process the request: {}", _0)]
+    Internal(String),
+}
+
+impl PrincipalRpcError {
+    /// The JSON-RPC error code, the range reserved for server errors is used for the application errors
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PrincipalRpcError::InvalidRequest(_) => ErrorCode::InvalidParams,
+            PrincipalRpcError::Internal(_) => ErrorCode::ServerError(-32000),
+            PrincipalRpcError::EpochNotFound(_) => ErrorCode::ServerError(-32001),
+            PrincipalRpcError::WorkerNotFound(_) => ErrorCode::ServerError(-32002),
+        }
+    }
+}
+
+impl From<PrincipalRpcError> for ServerError {
+    fn from(err: PrincipalRpcError) -> Self { ServerError { code: err.code(), message: err.to_string(), data: None } }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StringWrapper(pub String);
@@ -44,6 +78,10 @@ impl TryInto<[u8; 65]> for StringWrapper {
 pub struct StateKeyRequest {
     pub data: StringWrapper,
     pub sig: StringWrapper,
+    /// The block number of the task, the keys are provisioned according to the epoch active at that block.
+    /// The latest epoch is used if missing.
+    #[serde(default, rename = "blockNumber")]
+    pub block_number: Option<u64>,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -52,6 +90,44 @@ pub struct StateKeyResponse {
     pub sig: StringWrapper,
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct EpochRequest {
+    /// The epoch active at the block number is returned, the latest confirmed epoch if missing
+    #[serde(default, rename = "blockNumber")]
+    pub block_number: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EpochResponse {
+    pub nonce: U256,
+    pub seed: U256,
+    pub first_block_number: U256,
+    /// Missing while the epoch is active
+    pub last_block_number: Option<U256>,
+    pub workers: Vec<H160>,
+    pub stakes: Vec<U256>,
+    pub group_size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SelectedWorkerRequest {
+    #[serde(rename = "contractAddress")]
+    pub contract_address: StringWrapper,
+    /// The worker is selected in the epoch active at the block number, the latest confirmed epoch if missing
+    #[serde(default, rename = "blockNumber")]
+    pub block_number: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthResponse {
+    pub status: String,
+    /// The nonce of the latest epoch, confirmed or not
+    pub epoch_nonce: Option<U256>,
+    pub epoch_confirmed: bool,
+}
+
 impl<H: ToHex> From<H> for StringWrapper {
     fn from(bytes: H) -> Self { StringWrapper(bytes.to_hex()) }
 }
@@ -64,67 +140,211 @@ pub struct PrincipalHttpServer {
 impl StateKeyRequest {
     pub fn get_data(&self) -> Result<Vec<u8>, Error> { Ok(self.data.0.from_hex()?) }
 
-    pub fn get_sig(&self) -> Result<[u8; 65], Error> {
-        let mut sig = [0u8; 65];
-        sig.copy_from_slice(&self.sig.0.from_hex()?);
-        Ok(sig)
+    pub fn get_sig(&self) -> Result<[u8; 65], Error> { self.sig.clone().try_into() }
+
+    /// The hash the worker signed, the `to_sign` of the request and not the serialized message.
+    pub fn get_signed_hash(&self) -> Result<Vec<u8>, Error> {
+        let msg = PrincipalMessage::from_message(&self.get_data()?)?;
+        Ok(msg.to_sign()?)
     }
 }
 
 impl PrincipalHttpServer {
     pub fn new(epoch_provider: Arc<EpochProvider>, port: u16) -> PrincipalHttpServer { PrincipalHttpServer { epoch_provider, port } }
 
-    fn find_epoch_contract_addresses(request: &StateKeyRequest, epoch_state: &EpochState) -> Result<Vec<ContractAddress>, Error> {
-        let msg_slice = request.get_data()?;
-        let sig = request.get_sig()?;
-        let worker = KeyPair::recover(&msg_slice, sig)?.address();
+    fn find_epoch_contract_addresses(worker: [u8; 20], epoch_state: &EpochState) -> Result<Vec<ContractAddress>, Error> {
         let addrs = epoch_state.get_contract_addresses(&worker.into())?;
         Ok(addrs)
     }
 
+    /// Recovers the workers that signed the requests, in the order of the requests.
+    /// It fails if any of the signatures can't be recovered, so a batch is rejected before any of it reaches the enclave.
+    fn recover_workers(requests: &[StateKeyRequest]) -> Result<Vec<[u8; 20]>, Error> {
+        let signed = requests.iter().map(|request| Ok((request.get_signed_hash()?, request.get_sig()?))).collect::<Result<Vec<_>, Error>>()?;
+        Ok(KeyPair::recover_batch(&signed)?.iter().map(|pubkey| pubkey.address()).collect())
+    }
+
     #[logfn(DEBUG)]
-    pub fn get_state_keys(epoch_provider: Arc<EpochProvider>, request: StateKeyRequest) -> Result<Value, Error> {
+    pub fn get_state_keys(epoch_provider: Arc<EpochProvider>, request: StateKeyRequest) -> Result<Value, PrincipalRpcError> {
         println!("Got get_state_keys request: {:?}", request);
-        let msg_slice = request.get_data()?;
-        let msg = PrincipalMessage::from_message(&msg_slice)?;
+        let response = Self::get_state_keys_response(&epoch_provider, request, None)?;
+        Self::to_value(&response)
+    }
+
+    /// The responses to several requests, possibly from different workers, in the order of the requests.
+    /// The batch fails as a whole if any of the requests fails.
+    #[logfn(DEBUG)]
+    pub fn get_state_keys_batch(epoch_provider: Arc<EpochProvider>, requests: Vec<StateKeyRequest>) -> Result<Value, PrincipalRpcError> {
+        println!("Got get_state_keys batch of {} requests", requests.len());
+        let workers = Self::recover_workers(&requests).map_err(|err| PrincipalRpcError::InvalidRequest(err.to_string()))?;
+        let responses = requests
+            .into_iter()
+            .zip(workers.into_iter())
+            .map(|(request, worker)| Self::get_state_keys_response(&epoch_provider, request, Some(worker)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::to_value(&responses)
+    }
+
+    /// `worker` is the signer of the request if it was already recovered.
+    fn get_state_keys_response(epoch_provider: &EpochProvider, request: StateKeyRequest, worker: Option<[u8; 20]>)
+                               -> Result<StateKeyResponse, PrincipalRpcError> {
+        let invalid = |err: Error| PrincipalRpcError::InvalidRequest(err.to_string());
+        let internal = |err: Error| PrincipalRpcError::Internal(err.to_string());
+        let msg_slice = request.get_data().map_err(invalid)?;
+        let msg = PrincipalMessage::from_message(&msg_slice).map_err(|err| PrincipalRpcError::InvalidRequest(format!("{:?}", err)))?;
         let response = match msg.data {
             PrincipalMessageType::Request(Some(addrs)) => {
                 println!("Found addresses in message: {:?}", addrs);
-                get_enc_state_keys(*epoch_provider.eid, request, None)?
+                get_enc_state_keys(*epoch_provider.eid, request, None).map_err(internal)?
             }
             PrincipalMessageType::Request(None) => {
                 println!("No addresses in message, reading from epoch state...");
-                let epoch_state = epoch_provider.get_state()?;
-                let epoch_addrs = Self::find_epoch_contract_addresses(&request, &epoch_state)?;
-                get_enc_state_keys(*epoch_provider.eid, request, Some(&epoch_addrs))?
+                let epoch_state = match request.block_number {
+                    Some(block_number) => epoch_provider.get_state_for_block(block_number.into()),
+                    None => epoch_provider.get_state(),
+                };
+                let epoch_state = epoch_state.map_err(|err| PrincipalRpcError::EpochNotFound(err.to_string()))?;
+                if epoch_state.confirmed_state.is_none() {
+                    return Err(PrincipalRpcError::EpochNotFound("The latest epoch is not confirmed yet".to_string()));
+                }
+                let worker = match worker {
+                    Some(worker) => worker,
+                    None => KeyPair::recover_address(&request.get_signed_hash().map_err(invalid)?, request.get_sig().map_err(invalid)?).map_err(|err| invalid(err.into()))?,
+                };
+                let epoch_addrs = Self::find_epoch_contract_addresses(worker, &epoch_state).map_err(invalid)?;
+                get_enc_state_keys(*epoch_provider.eid, request, Some(&epoch_addrs)).map_err(internal)?
             }
-            _ => bail!("Invalid Principal message request"),
+            _ => return Err(PrincipalRpcError::InvalidRequest("Invalid Principal message request".to_string())),
+        };
+        Ok(response)
+    }
+
+    fn find_confirmed_epoch(epoch_provider: &EpochProvider, block_number: Option<u64>)
+                            -> Result<(EpochState, Option<U256>), PrincipalRpcError> {
+        epoch_provider.get_epoch_range(block_number.map(U256::from)).map_err(|err| PrincipalRpcError::EpochNotFound(err.to_string()))
+    }
+
+    /// Returns the confirmed epoch with its block range and worker parameters
+    pub fn get_epoch(epoch_provider: Arc<EpochProvider>, request: EpochRequest) -> Result<Value, PrincipalRpcError> {
+        let (epoch_state, next_block_number) = Self::find_confirmed_epoch(&epoch_provider, request.block_number)?;
+        let confirmed_state = match epoch_state.confirmed_state {
+            Some(confirmed_state) => confirmed_state,
+            None => return Err(PrincipalRpcError::EpochNotFound("The epoch is not confirmed".to_string())),
+        };
+        let response = EpochResponse {
+            nonce: epoch_state.nonce,
+            seed: epoch_state.seed,
+            first_block_number: confirmed_state.block_number,
+            last_block_number: next_block_number.map(|block_number| block_number - U256::from(1)),
+            workers: confirmed_state.workers,
+            stakes: confirmed_state.stakes,
+            group_size: epoch_state.group_size,
+        };
+        Self::to_value(&response)
+    }
+
+    fn parse_contract_address(request: &SelectedWorkerRequest) -> Result<ContractAddress, PrincipalRpcError> {
+        let addr = request.contract_address.0.trim_start_matches("0x");
+        ContractAddress::from_hex(addr)
+            .map_err(|err| PrincipalRpcError::InvalidRequest(format!("Invalid contract address {}: {:?}", addr, err)))
+    }
+
+    /// Returns the address of the worker selected for the secret contract
+    pub fn get_selected_worker(epoch_provider: Arc<EpochProvider>, request: SelectedWorkerRequest) -> Result<Value, PrincipalRpcError> {
+        let contract_address = Self::parse_contract_address(&request)?;
+        let (epoch_state, _) = Self::find_confirmed_epoch(&epoch_provider, request.block_number)?;
+        match epoch_state.get_selected_worker(contract_address) {
+            Ok(Some(worker)) => Self::to_value(&worker),
+            Ok(None) => Err(PrincipalRpcError::WorkerNotFound(contract_address)),
+            Err(err) => Err(PrincipalRpcError::EpochNotFound(err.to_string())),
+        }
+    }
+
+    /// Returns the addresses of the group of workers selected for the secret contract,
+    /// starting with the worker returned by `getSelectedWorker`
+    pub fn get_worker_group(epoch_provider: Arc<EpochProvider>, request: SelectedWorkerRequest) -> Result<Value, PrincipalRpcError> {
+        let contract_address = Self::parse_contract_address(&request)?;
+        let (epoch_state, _) = Self::find_confirmed_epoch(&epoch_provider, request.block_number)?;
+        match epoch_state.get_selected_workers(contract_address) {
+            Ok(ref workers) if workers.is_empty() => Err(PrincipalRpcError::WorkerNotFound(contract_address)),
+            Ok(workers) => Self::to_value(&workers),
+            Err(err) => Err(PrincipalRpcError::EpochNotFound(err.to_string())),
+        }
+    }
+
+    /// Returns the signing address of the Principal enclave, registered in the Enigma contract
+    pub fn get_signing_address(epoch_provider: Arc<EpochProvider>) -> Result<Value, PrincipalRpcError> {
+        let address = get_register_signing_address(*epoch_provider.eid).map_err(|err| PrincipalRpcError::Internal(err.to_string()))?;
+        Self::to_value(&H160(address))
+    }
+
+    pub fn health(epoch_provider: Arc<EpochProvider>) -> Result<Value, PrincipalRpcError> {
+        let epoch_state = epoch_provider.get_state().ok();
+        let response = HealthResponse {
+            status: "ok".to_string(),
+            epoch_nonce: epoch_state.as_ref().map(|epoch_state| epoch_state.nonce),
+            epoch_confirmed: epoch_state.map_or(false, |epoch_state| epoch_state.confirmed_state.is_some()),
         };
-        let response_data = serde_json::to_value(&response)?;
-        Ok(response_data)
+        Self::to_value(&response)
+    }
+
+    fn to_value<T: Serialize>(response: &T) -> Result<Value, PrincipalRpcError> {
+        serde_json::to_value(response).map_err(|err| PrincipalRpcError::Internal(err.to_string()))
+    }
+
+    /// Parse the parameters of a method accepting no parameters at all
+    fn parse_optional<T: DeserializeOwned + Default>(params: Params) -> Result<T, ServerError> {
+        match params {
+            Params::None => Ok(T::default()),
+            params => params.parse(),
+        }
     }
 
     /// Endpoint for the get_state_keys method
+    /// The optional third parameter is the block number of the task (the latest epoch is used if missing).
     ///
     /// Example:
     /// curl -X POST --data '{"jsonrpc": "2.0", "method": "get_state_keys", "params": ["84a46461746181a75265717565737493dc0020cca7cc937b64ccb8cccacca5cc8f03721bccb6ccbacccf5c78cccb235fccebcce0cce70b1bcc84cccdcc99541461cca0cc8edc002016367accacccb67a4a017ccc8dcca8ccabcc95682ccccb390863780f7114ccddcca0cca0cce0ccc55644ccc7ccc4dc0020ccb1cce9cc9324505bccd32dcca0cce1ccf85dcccf5e19cca0cc9dccb0481ecc8a15ccf62c41cceb320304cca8cce927a269649c1363ccb3301c101f33cce1cc9a0524a67072656669789e456e69676d61204d657373616765a67075626b6579dc0040cce5ccbe28cc9dcc9a2eccbd08ccc0457a5f16ccdfcc9fccdc256c5d5f6c3514cccdcc95ccb47c11ccc4cccd3e31ccf0cce4ccefccc83ccc80cce8121c3939ccbb2561cc80ccec48ccbecca8ccc569ccd2cca3ccda6bcce415ccfa20cc9bcc98ccda", "43f19586b0a0ae626b9418fe8355888013be1c9b4263a4b3a27953de641991e936ed6c4076a2a383b3b001936bf0eb6e23c78fbec1ee36f19c6a9d24d75e9e081c"]' -H "Content-Type: application/json" http://127.0.0.1:3040/
+    ///
+    /// The other methods:
+    /// - getStateKeysBatch: a list of the parameters of getStateKeys, the responses are in the same order
+    /// - getEpoch: `[blockNumber]`, the block number is optional
+    /// - getSelectedWorker and getWorkerGroup: `[contractAddress, blockNumber]`, the block number is optional
+    /// - getSigningAddress and health: no parameters
     pub fn start(&self) {
         let mut io = IoHandler::default();
         let epoch_provider = Arc::clone(&self.epoch_provider);
-        io.add_method(METHOD_GET_STATE_KEYS, move |params: Params| {
-            let epoch_provider = epoch_provider.clone();
+        io.add_method(METHOD_GET_STATE_KEYS, move |params: Params| -> Result<Value, ServerError> {
             let request = params.parse::<StateKeyRequest>()?;
-            let body = match Self::get_state_keys(epoch_provider, request) {
-                Ok(body) => body,
-                Err(err) => {
-                    return Err(ServerError {
-                        code: ErrorCode::InternalError,
-                        message: format!("Unable to get keys: {:?}", err),
-                        data: None,
-                    })
-                }
-            };
-            Ok(body)
+            Ok(Self::get_state_keys(epoch_provider.clone(), request)?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_GET_STATE_KEYS_BATCH, move |params: Params| -> Result<Value, ServerError> {
+            let requests = params.parse::<Vec<StateKeyRequest>>()?;
+            Ok(Self::get_state_keys_batch(epoch_provider.clone(), requests)?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_GET_EPOCH, move |params: Params| -> Result<Value, ServerError> {
+            let request = Self::parse_optional::<EpochRequest>(params)?;
+            Ok(Self::get_epoch(epoch_provider.clone(), request)?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_GET_SELECTED_WORKER, move |params: Params| -> Result<Value, ServerError> {
+            let request = params.parse::<SelectedWorkerRequest>()?;
+            Ok(Self::get_selected_worker(epoch_provider.clone(), request)?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_GET_WORKER_GROUP, move |params: Params| -> Result<Value, ServerError> {
+            let request = params.parse::<SelectedWorkerRequest>()?;
+            Ok(Self::get_worker_group(epoch_provider.clone(), request)?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_GET_SIGNING_ADDRESS, move |_: Params| -> Result<Value, ServerError> {
+            Ok(Self::get_signing_address(epoch_provider.clone())?)
+        });
+        let epoch_provider = Arc::clone(&self.epoch_provider);
+        io.add_method(METHOD_HEALTH, move |_: Params| -> Result<Value, ServerError> {
+            Ok(Self::health(epoch_provider.clone())?)
         });
         let server =
             ServerBuilder::new(io).start_http(&format!("0.0.0.0:{}", self.port).parse().unwrap()).expect("Unable to start RPC server");
@@ -141,32 +361,64 @@ mod test {
 
     use self::ethereum_types::{H160, U256};
     use super::*;
+    use enigma_tools_m::keeper_types::InputWorkerParams;
+    use enigma_tools_u::web3_utils::fake_chain::FakeChain;
+    use epoch_u::epoch_storage::EpochStorage;
     use enigma_types::ContractAddress;
     use epoch_u::epoch_types::ConfirmedEpochState;
     use rustc_hex::FromHex;
-    use std::collections::HashMap;
-    use web3::types::Bytes;
+    use std::{collections::HashMap, sync::Mutex};
+    use web3::types::{Address, Bytes};
 
     #[test]
     pub fn test_find_epoch_contract_addresses() {
         let msg = vec![132, 164, 100, 97, 116, 97, 129, 167, 82, 101, 113, 117, 101, 115, 116, 192, 162, 105, 100, 156, 75, 52, 85, 204, 160, 204, 254, 16, 9, 204, 130, 50, 81, 204, 252, 204, 231, 166, 112, 114, 101, 102, 105, 120, 158, 69, 110, 105, 103, 109, 97, 32, 77, 101, 115, 115, 97, 103, 101, 166, 112, 117, 98, 107, 101, 121, 220, 0, 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let sig = sign_message(&msg).unwrap();
-        let request = StateKeyRequest { data: StringWrapper(msg.to_hex()), sig: StringWrapper(sig.to_hex()) };
+        let sig = sign_message(&PrincipalMessage::from_message(&msg).unwrap().to_sign().unwrap()).unwrap();
+        let request = StateKeyRequest { data: StringWrapper(msg.to_hex()), sig: StringWrapper(sig.to_hex()), block_number: None };
         let address = ContractAddress::from([0u8; 32]);
 
         let mut selected_workers: HashMap<ContractAddress, H160> = HashMap::new();
         selected_workers.insert(address, H160(WORKER_SIGN_ADDRESS));
         let block_number = U256::from(1);
-        let confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number });
+        let confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number, workers: vec![], stakes: vec![] });
         let seed = U256::from(1);
         let sig = Bytes::from(sig.to_vec());
         let nonce = U256::from(0);
-        let epoch_state = EpochState { seed, sig, nonce, confirmed_state };
-        let results = PrincipalHttpServer::find_epoch_contract_addresses(&request, &epoch_state).unwrap();
+        let epoch_state = EpochState { seed, sig, nonce, confirmed_state, worker_params_block: block_number, group_size: 1 };
+        let workers = PrincipalHttpServer::recover_workers(&[request]).unwrap();
+        assert_eq!(workers, vec![WORKER_SIGN_ADDRESS]);
+        let results = PrincipalHttpServer::find_epoch_contract_addresses(workers[0], &epoch_state).unwrap();
         println!("Found contract addresses: {:?}", results);
         assert_eq!(results, vec![address])
     }
 
+    #[test]
+    pub fn test_recover_workers_batch() {
+        // Built the way the worker's `ecall_ptt_req_internal` builds them
+        let addrs = vec![ContractAddress::from([1u8; 32]), ContractAddress::from([2u8; 32])];
+        let mut requests: Vec<StateKeyRequest> = vec![PrincipalMessageType::Request(None), PrincipalMessageType::Request(Some(addrs))]
+            .into_iter()
+            .map(|data| {
+                let req = PrincipalMessage::new(data, KeyPair::new().unwrap().get_pubkey()).unwrap();
+                let sig = sign_message(&req.to_sign().unwrap()).unwrap();
+                StateKeyRequest { data: StringWrapper(req.into_message().unwrap().to_hex()), sig: StringWrapper(sig.to_hex()), block_number: None }
+            })
+            .collect();
+        assert_eq!(PrincipalHttpServer::recover_workers(&requests).unwrap(), vec![WORKER_SIGN_ADDRESS; 2]);
+
+        // A single malformed signature rejects the whole batch
+        requests[1].sig = StringWrapper([0u8; 65].to_hex());
+        assert!(PrincipalHttpServer::recover_workers(&requests).is_err());
+        let err = PrincipalHttpServer::get_state_keys_batch(mock_epoch_provider(vec![]), requests.clone()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidParams);
+
+        // So does a signature that isn't 65 bytes
+        requests[1].sig = StringWrapper([1u8; 64].to_hex());
+        assert!(PrincipalHttpServer::recover_workers(&requests).is_err());
+        let err = PrincipalHttpServer::get_state_keys_batch(mock_epoch_provider(vec![]), requests).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidParams);
+    }
+
     pub const WORKER_SIGN_ADDRESS: [u8; 20] =
         [95, 53, 26, 193, 96, 206, 55, 206, 15, 120, 191, 101, 13, 44, 28, 237, 80, 151, 54, 182];
     pub(crate) fn sign_message(msg: &Vec<u8>) -> Result<[u8; 65], Error> {
@@ -178,6 +430,109 @@ mod test {
         Ok(sig)
     }
 
+    #[test]
+    pub fn test_state_key_request_block_number() {
+        let request: StateKeyRequest = serde_json::from_str(r#"["00", "11"]"#).unwrap();
+        assert_eq!(request.block_number, None);
+        let request: StateKeyRequest = serde_json::from_str(r#"["00", "11", 42]"#).unwrap();
+        assert_eq!(request.block_number, Some(42));
+    }
+
     #[test]
     pub fn test_get_state_keys() {}
+
+    fn mock_epoch_provider(epoch_states: Vec<EpochState>) -> Arc<EpochProvider> {
+        let contract = Arc::new(FakeChain::new(Address::from([1u8; 20]), Address::from([2u8; 20])));
+        let storage = EpochStorage::new(0, false).unwrap();
+        Arc::new(EpochProvider { contract, epoch_state: Arc::new(Mutex::new(epoch_states)), eid: Arc::new(0), group_size: 1, storage })
+    }
+
+    fn confirmed_epoch_state(nonce: u64, block_number: u64) -> EpochState {
+        let block_number = U256::from(block_number);
+        let worker_params =
+            InputWorkerParams { block_number, workers: vec![H160(WORKER_SIGN_ADDRESS)], stakes: vec![U256::from(1)] };
+        let mut epoch_state = EpochState::new(U256::from(nonce), Bytes::from(vec![0u8; 65]), U256::from(nonce), block_number, 1);
+        epoch_state.confirm(block_number, &worker_params, vec![]).unwrap();
+        epoch_state
+    }
+
+    #[test]
+    pub fn test_get_epoch() {
+        let epoch_provider = mock_epoch_provider(vec![confirmed_epoch_state(0, 10), confirmed_epoch_state(1, 20)]);
+        let value = |x: u64| serde_json::to_value(U256::from(x)).unwrap();
+
+        let response = PrincipalHttpServer::get_epoch(epoch_provider.clone(), EpochRequest { block_number: Some(15) }).unwrap();
+        assert_eq!(response["nonce"], value(0));
+        assert_eq!(response["firstBlockNumber"], value(10));
+        assert_eq!(response["lastBlockNumber"], value(19));
+        assert_eq!(response["workers"], serde_json::to_value(vec![H160(WORKER_SIGN_ADDRESS)]).unwrap());
+        assert_eq!(response["groupSize"], 1);
+
+        let response = PrincipalHttpServer::get_epoch(epoch_provider.clone(), EpochRequest::default()).unwrap();
+        assert_eq!(response["nonce"], value(1));
+        assert_eq!(response["lastBlockNumber"], Value::Null);
+
+        let err = PrincipalHttpServer::get_epoch(epoch_provider, EpochRequest { block_number: Some(5) }).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ServerError(-32001));
+    }
+
+    #[test]
+    pub fn test_get_selected_worker() {
+        let epoch_provider = mock_epoch_provider(vec![confirmed_epoch_state(0, 10)]);
+        let request = |address: &str| SelectedWorkerRequest { contract_address: StringWrapper(address.to_string()), block_number: None };
+
+        // The contract was not deployed when the epoch was confirmed, the worker is selected again
+        let address = format!("0x{}", [7u8; 32].to_hex());
+        let response = PrincipalHttpServer::get_selected_worker(epoch_provider.clone(), request(&address)).unwrap();
+        assert_eq!(response, serde_json::to_value(H160(WORKER_SIGN_ADDRESS)).unwrap());
+
+        let err = PrincipalHttpServer::get_selected_worker(epoch_provider.clone(), request("0x1234")).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidParams);
+
+        let epoch_provider = mock_epoch_provider(vec![]);
+        let err = PrincipalHttpServer::get_selected_worker(epoch_provider, request(&address)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ServerError(-32001));
+    }
+
+    #[test]
+    pub fn test_get_worker_group() {
+        let block_number = U256::from(10);
+        let workers = vec![H160(WORKER_SIGN_ADDRESS), H160([1u8; 20])];
+        let worker_params = InputWorkerParams { block_number, workers: workers.clone(), stakes: vec![U256::from(1), U256::from(2)] };
+        let mut epoch_state = EpochState::new(U256::from(0), Bytes::from(vec![0u8; 65]), U256::from(0), block_number, 2);
+        let address = ContractAddress::from([7u8; 32]);
+        epoch_state.confirm(block_number, &worker_params, vec![address]).unwrap();
+        let epoch_provider = mock_epoch_provider(vec![epoch_state.clone()]);
+        let request = SelectedWorkerRequest { contract_address: StringWrapper(address.to_hex()), block_number: None };
+
+        let response = PrincipalHttpServer::get_worker_group(epoch_provider.clone(), request.clone()).unwrap();
+        let group: Vec<H160> = serde_json::from_value(response).unwrap();
+        assert_eq!(group.len(), 2);
+        assert!(workers.iter().all(|worker| group.contains(worker)));
+        let response = PrincipalHttpServer::get_selected_worker(epoch_provider, request).unwrap();
+        assert_eq!(response, serde_json::to_value(group[0]).unwrap());
+        // Both members get the state keys of the contract
+        for worker in &workers {
+            assert_eq!(epoch_state.get_contract_addresses(worker).unwrap(), vec![address]);
+        }
+    }
+
+    #[test]
+    pub fn test_health() {
+        let response = PrincipalHttpServer::health(mock_epoch_provider(vec![])).unwrap();
+        assert_eq!(response["status"], "ok");
+        assert_eq!(response["epochConfirmed"], false);
+
+        let response = PrincipalHttpServer::health(mock_epoch_provider(vec![confirmed_epoch_state(3, 10)])).unwrap();
+        assert_eq!(response["epochNonce"], serde_json::to_value(U256::from(3)).unwrap());
+        assert_eq!(response["epochConfirmed"], true);
+    }
+
+    #[test]
+    pub fn test_rpc_error_codes() {
+        let err: ServerError = PrincipalRpcError::WorkerNotFound(ContractAddress::from([0u8; 32])).into();
+        assert_eq!(err.code, ErrorCode::ServerError(-32002));
+        let err: ServerError = PrincipalRpcError::Internal("enclave".to_string()).into();
+        assert_eq!(err.code, ErrorCode::ServerError(-32000));
+    }
 }
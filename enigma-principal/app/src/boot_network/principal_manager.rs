@@ -1,10 +1,11 @@
-use boot_network::{deploy_scripts, keys_provider_http::PrincipalHttpServer, principal_utils::Principal};
+use boot_network::{deploy_scripts, keys_provider_http::PrincipalHttpServer, principal_utils::watch_blocks};
+use enigma_tools_m::keeper_types::DEFAULT_GROUP_SIZE;
 use enigma_tools_u::{
     attestation_service::service,
     esgx::equote::retry_quote,
-    web3_utils::enigma_contract::{ContractFuncs, ContractQueries, EnigmaContract},
+    web3_utils::enigma_contract::{ContractFuncs, ContractQueries, EnigmaContractApi},
 };
-use epoch_u::epoch_provider::EpochProvider;
+use epoch_u::{epoch_provider::EpochProvider, epoch_storage::EpochStorage};
 use esgx;
 use failure::Error;
 use rustc_hex::ToHex;
@@ -13,7 +14,6 @@ use serde_json;
 use sgx_types::sgx_enclave_id_t;
 use std::{fs::File, io::prelude::*, str, sync::Arc, thread};
 use web3::{
-    futures::Future,
     transports::Http,
     types::{Address, H160, H256, U256},
     Web3,
@@ -36,8 +36,16 @@ pub struct PrincipalConfig {
     pub attestation_service_url: String,
     pub http_port: u16,
     pub confirmations: u64,
+    /// The number of workers selected for each secret contract, every member gets the state keys
+    #[serde(default = "default_worker_group_size")]
+    pub worker_group_size: u64,
+    /// Seal the stored epoch state with the enclave key, an existing unsealed state is sealed with `--seal-epoch-state`
+    #[serde(default)]
+    pub seal_epoch_state: bool,
 }
 
+fn default_worker_group_size() -> u64 { DEFAULT_GROUP_SIZE }
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegistrationParams {
     pub signing_address: String,
@@ -53,7 +61,7 @@ pub struct ReportManager {
 
 pub struct PrincipalManager {
     pub config: PrincipalConfig,
-    pub contract: Arc<EnigmaContract>,
+    pub contract: Arc<dyn EnigmaContractApi>,
     pub report_manager: ReportManager,
     pub eid: sgx_enclave_id_t,
 }
@@ -107,14 +115,12 @@ impl PrincipalManager {
 
         Ok(serde_json::from_str(&contents)?)
     }
-
-    pub fn get_web3(&self) -> Arc<Web3<Http>> { Arc::clone(&self.contract.web3) }
 }
 
 // General interface of a Sampler == The entity that manages the principal node logic.
 pub trait Sampler {
     /// load with config from file
-    fn new(config: PrincipalConfig, contract: Arc<EnigmaContract>, report_manager: ReportManager) -> Result<Self, Error>
+    fn new(config: PrincipalConfig, contract: Arc<dyn EnigmaContractApi>, report_manager: ReportManager) -> Result<Self, Error>
     where Self: Sized;
 
     fn get_signing_address(&self) -> Result<String, Error>;
@@ -136,7 +142,7 @@ pub trait Sampler {
 }
 
 impl Sampler for PrincipalManager {
-    fn new(config: PrincipalConfig, contract: Arc<EnigmaContract>, report_manager: ReportManager) -> Result<Self, Error> {
+    fn new(config: PrincipalConfig, contract: Arc<dyn EnigmaContractApi>, report_manager: ReportManager) -> Result<Self, Error> {
         let eid = report_manager.eid;
         //        let registration_params = report_manager.get_registration_params()?;
         Ok(PrincipalManager { config, contract, report_manager, eid })
@@ -147,12 +153,12 @@ impl Sampler for PrincipalManager {
     fn get_contract_address(&self) -> Address { self.contract.address() }
 
     // noinspection RsBorrowChecker
-    fn get_account_address(&self) -> Address { self.contract.account }
+    fn get_account_address(&self) -> Address { self.contract.account() }
 
     fn get_network_url(&self) -> String { self.config.url.clone() }
 
     fn get_block_number(&self) -> Result<U256, Error> {
-        let block_number = match self.get_web3().eth().block_number().wait() {
+        let block_number = match self.contract.block_number() {
             Ok(block_number) => block_number,
             Err(err) => bail!("Current block number not available: {:?}", err),
         };
@@ -166,7 +172,7 @@ impl Sampler for PrincipalManager {
             signing_address,
             registration_params.report,
             registration_params.signature,
-            gas_limit,
+            gas_limit.into(),
             self.config.confirmations as usize,
         )?;
         Ok(receipt.transaction_hash)
@@ -210,7 +216,8 @@ impl Sampler for PrincipalManager {
         // get enigma contract
         // Start the WorkerParameterized Web3 log filter
         let eid: Arc<sgx_enclave_id_t> = Arc::new(self.eid);
-        let epoch_provider = Arc::new(EpochProvider::new(eid, self.contract.clone())?);
+        let storage = EpochStorage::new(self.eid, self.config.seal_epoch_state)?;
+        let epoch_provider = Arc::new(EpochProvider::new(eid, self.contract.clone(), self.config.worker_group_size, storage)?);
         if reset_epoch {
             epoch_provider.reset_epoch_state()?;
         }
@@ -227,7 +234,7 @@ impl Sampler for PrincipalManager {
         // watch blocks
         let polling_interval = self.config.polling_interval;
         let epoch_size = self.config.epoch_size;
-        self.contract.watch_blocks(
+        watch_blocks(
             epoch_size,
             polling_interval,
             epoch_provider,
@@ -251,9 +258,10 @@ pub fn run_miner(account: Address, w3: Arc<Web3<Http>>, interval: u64) -> thread
 #[cfg(test)]
 mod test {
     use super::*;
-    use enigma_tools_u::web3_utils::{enigma_contract::EnigmaContract, w3utils};
-    use epoch_u::epoch_types::WorkersParameterizedEvent;
-    use esgx::general::init_enclave_wrapper;
+    use enigma_tools_u::web3_utils::{enigma_contract::EnigmaContract, fake_chain::FakeChain, w3utils};
+    use enigma_types::ContractAddress;
+    use epoch_u::{epoch_provider::EpochTransition, epoch_types::WorkersParameterizedEvent};
+    use esgx::{epoch_keeper_u::get_epoch, general::init_enclave_wrapper};
     use std::{env, path::Path, sync::Arc, thread, time};
     use web3::{
         futures::{stream::Stream, Future},
@@ -282,7 +290,7 @@ mod test {
         Ok(config)
     }
 
-    pub fn init_no_deploy(eid: u64) -> Result<PrincipalManager, Error> {
+    pub fn init_no_deploy(eid: u64) -> Result<(PrincipalManager, Arc<EnigmaContract>), Error> {
         let mut config = get_config()?;
         let enclave_manager = ReportManager::new(config.clone(), eid)?;
         println!("The Principal node signing address: {:?}", enclave_manager.get_signing_address().unwrap());
@@ -295,13 +303,80 @@ mod test {
         )?);
         let _gas_limit = 5_999_999;
         config.max_epochs = None;
-        let principal: PrincipalManager = PrincipalManager::new(config.clone(), contract, enclave_manager).unwrap();
+        let principal: PrincipalManager = PrincipalManager::new(config.clone(), contract.clone(), enclave_manager).unwrap();
         println!(
             "Connected to the Enigma contract: {:?} with account: {:?}",
             &config.enigma_contract_address,
             principal.get_account_address()
         );
-        Ok(principal)
+        Ok((principal, contract))
+    }
+
+    /// Runs the epoch cycle against the in-process `FakeChain`, including a reorg
+    /// dropping the worker parameters of the latest epoch
+    #[test]
+    fn test_epoch_cycle_fake_chain() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let eid = enclave.geteid();
+        let chain = Arc::new(FakeChain::new(Address::from([1u8; 20]), Address::from([2u8; 20])));
+        let worker = H160([3u8; 20]);
+        let address = ContractAddress::from([4u8; 32]);
+        chain.add_worker(worker, U256::from(1));
+        chain.add_secret_contract(address);
+        // The epoch is sealed, so the nonce carries on from the previous runs
+        let nonce = get_epoch(eid).map(|(_, nonce)| nonce + 1).unwrap_or_else(|_| U256::from(0));
+        chain.set_epoch_nonce(nonce);
+
+        let epoch_provider = EpochProvider::new(Arc::new(eid), chain.clone(), 1, EpochStorage::new(eid, false).unwrap()).unwrap();
+        epoch_provider.reset_epoch_state().unwrap();
+        let epoch_size = 10;
+        let first_block_number = chain.block_number().unwrap();
+        assert_eq!(epoch_provider.step(epoch_size, 0, 0).unwrap(), EpochTransition::NewEpoch(first_block_number));
+        let epoch_state = epoch_provider.get_state().unwrap();
+        assert_eq!(epoch_state.nonce, nonce);
+        assert_eq!(epoch_state.get_contract_addresses(&worker).unwrap(), vec![address]);
+        assert_eq!(epoch_provider.step(epoch_size, 0, 0).unwrap(), EpochTransition::Active);
+
+        chain.mine(epoch_size);
+        let block_number = chain.block_number().unwrap();
+        assert_eq!(epoch_provider.step(epoch_size, 0, 0).unwrap(), EpochTransition::NewEpoch(block_number));
+        assert_eq!(epoch_provider.get_state().unwrap().nonce, nonce + 1);
+
+        // The setWorkersParams transaction of the latest epoch is dropped and submitted again
+        chain.reorg(1);
+        assert_eq!(epoch_provider.step(epoch_size, 0, 0).unwrap(), EpochTransition::Reorged);
+        assert_eq!(epoch_provider.get_confirmed().unwrap().block_number, block_number);
+        assert_eq!(epoch_provider.step(epoch_size, 0, 0).unwrap(), EpochTransition::Active);
+        enclave.destroy();
+    }
+
+    /// Every member of the worker group is authorized for the contract
+    #[test]
+    fn test_epoch_group_fake_chain() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let eid = enclave.geteid();
+        let chain = Arc::new(FakeChain::new(Address::from([1u8; 20]), Address::from([2u8; 20])));
+        let workers = [H160([3u8; 20]), H160([5u8; 20])];
+        let address = ContractAddress::from([4u8; 32]);
+        chain.add_worker(workers[0], U256::from(1));
+        chain.add_worker(workers[1], U256::from(2));
+        chain.add_secret_contract(address);
+        let nonce = get_epoch(eid).map(|(_, nonce)| nonce + 1).unwrap_or_else(|_| U256::from(0));
+        chain.set_epoch_nonce(nonce);
+
+        let epoch_provider = EpochProvider::new(Arc::new(eid), chain.clone(), 2, EpochStorage::new(eid, false).unwrap()).unwrap();
+        epoch_provider.reset_epoch_state().unwrap();
+        let block_number = chain.block_number().unwrap();
+        assert_eq!(epoch_provider.step(10, 0, 0).unwrap(), EpochTransition::NewEpoch(block_number));
+        let epoch_state = epoch_provider.get_state().unwrap();
+        assert_eq!(epoch_state.group_size, 2);
+        let group = epoch_state.get_selected_workers(address).unwrap();
+        assert_eq!(group.len(), 2);
+        assert_eq!(epoch_state.get_selected_worker(address).unwrap(), Some(group[0]));
+        for worker in &workers {
+            assert_eq!(epoch_state.get_contract_addresses(worker).unwrap(), vec![address]);
+        }
+        enclave.destroy();
     }
 
     // TODO: The two tests below require the Enigma contract to be deployed
@@ -312,12 +387,13 @@ mod test {
         let gas_limit: U256 = 5999999.into();
         let enclave = init_enclave_wrapper().unwrap();
         let eid = enclave.geteid();
-        let principal = init_no_deploy(eid).unwrap();
+        let (principal, _) = init_no_deploy(eid).unwrap();
         principal.verify_identity_or_register(gas_limit).unwrap();
 
         let block_number = principal.get_block_number().unwrap();
         let eid_safe = Arc::new(eid);
-        let epoch_provider = EpochProvider::new(eid_safe, principal.contract.clone()).unwrap();
+        let storage = EpochStorage::new(eid, principal.config.seal_epoch_state).unwrap();
+        let epoch_provider = EpochProvider::new(eid_safe, principal.contract.clone(), principal.config.worker_group_size, storage).unwrap();
         epoch_provider.reset_epoch_state().unwrap();
         epoch_provider.set_worker_params(block_number, gas_limit, 0).unwrap();
     }
@@ -336,13 +412,12 @@ mod test {
         let _gas_limit: U256 = 5999999.into();
         let enclave = init_enclave_wrapper().unwrap();
         let eid = enclave.geteid();
-        let principal = init_no_deploy(eid).unwrap();
+        let (principal, contract) = init_no_deploy(eid).unwrap();
         let account = principal.get_account_address();
 
         // run simulated miner
-        run_miner(account, Arc::clone(&principal.contract.web3), 1);
+        run_miner(account, Arc::clone(&contract.web3), 1);
 
-        let contract = Arc::clone(&principal.contract);
         let child = thread::spawn(move || {
             let event = WorkersParameterizedEvent::new();
             let event_sig = event.0.signature();
@@ -1,57 +1,80 @@
 use enigma_tools_u::web3_utils::enigma_contract::EnigmaContract;
-use epoch_u::epoch_provider::EpochProvider;
+use epoch_u::epoch_provider::{EpochProvider, EpochTransition};
 use failure::Error;
 use std::{sync::Arc, thread, time};
-use web3::{futures::Future, types::U256};
+use web3::types::U256;
+
+/// The longest delay between two polls after failed epoch transitions, in seconds
+const MAX_RETRY_INTERVAL: u64 = 300;
 
 // this trait should extend the EnigmaContract into Principal specific functions.
 pub trait Principal {
     fn new(address: &str, path: String, account: &str, url: &str) -> Result<Self, Error>
     where Self: Sized;
-
-    fn watch_blocks<G: Into<U256>>(&self, epoch_size: usize, polling_interval: u64, epoch_provider: Arc<EpochProvider>, gas_limit: G,
-                                   confirmations: usize, max_epochs: Option<usize>);
 }
 
 impl Principal for EnigmaContract {
     fn new(address: &str, path: String, account: &str, url: &str) -> Result<Self, Error> {
         Ok(Self::from_deployed(address, path, Some(account), url)?)
     }
+}
 
-    /// Watches the blocks for new epoch using the epoch size and the previous epoch block number.
-    /// For each new epoch, set the worker parameters.
-    #[logfn(INFO)]
-    fn watch_blocks<G: Into<U256>>(&self, epoch_size: usize, polling_interval: u64, epoch_provider: Arc<EpochProvider>, gas_limit: G,
+/// Watches the blocks for new epoch using the epoch size and the previous epoch block number.
+/// For each new epoch, set the worker parameters.
+/// Failed transitions, like an unavailable Ethereum node, are retried with an increasing delay
+/// and reorgs dropping the worker parameters are handled by submitting them again.
+#[logfn(INFO)]
+pub fn watch_blocks<G: Into<U256>>(epoch_size: usize, polling_interval: u64, epoch_provider: Arc<EpochProvider>, gas_limit: G,
                                    confirmations: usize, max_epochs: Option<usize>) {
-        let gas_limit: U256 = gas_limit.into();
-        let max_epochs = max_epochs.unwrap_or(0);
-        let mut epoch_counter = 0;
-        loop {
-            let block_number = self.web3.eth().block_number().wait().unwrap();
-            let curr_block = block_number.low_u64() as usize;
-            let prev_block = match epoch_provider.get_state() {
-                Ok(state) => match state.confirmed_state {
-                    Some(state) => state.block_number,
-                    None => U256::from(0),
-                },
-                Err(_) => U256::from(0),
-            };
-            let prev_block_ref = prev_block.low_u64() as usize;
-            println!("[\u{1F50A} ] Blocks @ previous: {}, current: {}, next: {} [\u{1F50A} ]", prev_block_ref, curr_block, (prev_block_ref + epoch_size));
-            if prev_block_ref == 0 || curr_block >= (prev_block_ref + epoch_size) {
-                println!("[\u{263C} ] New epoch found [\u{263C} ]");
-                epoch_provider
-                    .set_worker_params(block_number, gas_limit, confirmations)
-                    .expect("Unable to set worker params. Please recover manually.");
-            } else {
+    let gas_limit: U256 = gas_limit.into();
+    let max_epochs = max_epochs.unwrap_or(0);
+    let mut epoch_counter = 0;
+    let mut failures = 0;
+    loop {
+        match epoch_provider.step(epoch_size, gas_limit, confirmations) {
+            Ok(EpochTransition::Active) => {
+                failures = 0;
                 println!("[\u{23f3} ] Epoch still active [\u{23f3} ]");
             }
-            thread::sleep(time::Duration::from_secs(polling_interval));
-            epoch_counter += 1;
-            if max_epochs != 0 && epoch_counter == max_epochs {
-                println!("[+] Principal: reached max_epochs {} , stopping.", max_epochs);
-                break;
+            Ok(transition) => {
+                failures = 0;
+                println!("[\u{263C} ] Epoch transition: {:?} [\u{263C} ]", transition);
             }
+            Err(err) => {
+                failures += 1;
+                eprintln!("[-] Epoch transition failed, attempt: {}, error: {:?}", failures, err);
+            }
+        }
+        thread::sleep(time::Duration::from_secs(retry_interval(polling_interval, failures)));
+        epoch_counter += 1;
+        if max_epochs != 0 && epoch_counter == max_epochs {
+            println!("[+] Principal: reached max_epochs {} , stopping.", max_epochs);
+            break;
         }
     }
 }
+
+/// Returns the delay in seconds before the next poll, doubling the polling interval for each consecutive failure
+fn retry_interval(polling_interval: u64, failures: u32) -> u64 {
+    if failures == 0 {
+        return polling_interval;
+    }
+    let backoff = polling_interval.max(1).saturating_mul(1 << failures.min(16));
+    backoff.min(MAX_RETRY_INTERVAL.max(polling_interval))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_interval() {
+        assert_eq!(retry_interval(5, 0), 5);
+        assert_eq!(retry_interval(5, 1), 10);
+        assert_eq!(retry_interval(5, 3), 40);
+        assert_eq!(retry_interval(5, 100), MAX_RETRY_INTERVAL);
+        assert_eq!(retry_interval(0, 2), 4);
+        // Never shorter than the polling interval
+        assert_eq!(retry_interval(1000, 1), 1000);
+    }
+}
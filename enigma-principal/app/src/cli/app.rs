@@ -4,8 +4,9 @@ use boot_network::{
 };
 use cli;
 use enigma_tools_u::{esgx::general::storage_dir, web3_utils::enigma_contract::EnigmaContract};
-use epoch_u::epoch_provider::EpochProvider;
-use esgx::general::ENCLAVE_DIR;
+use enigma_types::ContractAddress;
+use epoch_u::{epoch_provider::EpochProvider, epoch_storage::EpochStorage};
+use esgx::{general::ENCLAVE_DIR, keys_keeper_u};
 use failure::Error;
 use sgx_types::sgx_enclave_id_t;
 use std::{fs::File, io::prelude::*, path::Path, sync::Arc};
@@ -27,6 +28,26 @@ pub fn start(eid: sgx_enclave_id_t) -> Result<(), Error> {
         let prefixed_signing_address = format!("0x{}", signing_address);
         file.write_all(prefixed_signing_address.as_bytes())?;
         println!("Wrote signing address: {:?} in file: {:?}", prefixed_signing_address, path);
+    } else if let Some(addrs) = opt.rotate_state_keys {
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                let addr = addr.trim().trim_start_matches("0x");
+                ContractAddress::from_hex(addr).map_err(|e| format_err!("Invalid contract address {}: {:?}", addr, e))
+            })
+            .collect::<Result<Vec<ContractAddress>, Error>>()?;
+        keys_keeper_u::rotate_state_keys(eid, &addrs)?;
+        println!("Rotated the state keys of {} contracts", addrs.len());
+    } else if opt.recover_epoch_state {
+        let storage = EpochStorage::new(eid, principal_config.seal_epoch_state)?;
+        match storage.recover()? {
+            Some(path) => println!("Moved the corrupt epoch state to: {:?}, a new history starts with the next epoch", path),
+            None => println!("The epoch state in {:?} is valid, nothing to recover", storage.path()),
+        }
+    } else if opt.seal_epoch_state {
+        let storage = EpochStorage::new(eid, true)?;
+        let epochs = storage.migrate_to_sealed()?;
+        println!("Sealed the epoch state of {} epochs in {:?}", epochs, storage.path());
     } else if opt.deploy {
         unimplemented!("Self-deploy mode not yet implemented. Fix issues with linked libraries in the Enigma contract.");
     } else {
@@ -45,18 +66,19 @@ pub fn start(eid: sgx_enclave_id_t) -> Result<(), Error> {
 
         let gas_limit = 5_999_999;
 
-        let principal: PrincipalManager = PrincipalManager::new(principal_config.clone(), enigma_contract, report_manager)?;
+        let principal: PrincipalManager = PrincipalManager::new(principal_config.clone(), enigma_contract.clone(), report_manager)?;
         println!("Connected to the Enigma contract: {:?} with account: {:?}", &contract_address, principal.get_account_address());
 
         // step2 optional - run miner to simulate blocks
         let join_handle = if opt.mine > 0 {
-            Some(principal_manager::run_miner(principal.get_account_address(), principal.get_web3(), opt.mine as u64))
+            Some(principal_manager::run_miner(principal.get_account_address(), Arc::clone(&enigma_contract.web3), opt.mine as u64))
         } else {
             None
         };
 
         let eid_safe = Arc::new(eid);
-        let epoch_provider = EpochProvider::new(eid_safe, principal.contract.clone())?;
+        let storage = EpochStorage::new(eid, principal_config.seal_epoch_state)?;
+        let epoch_provider = EpochProvider::new(eid_safe, principal.contract.clone(), principal_config.worker_group_size, storage)?;
         if opt.reset_epoch_state {
             epoch_provider.reset_epoch_state()?;
         }
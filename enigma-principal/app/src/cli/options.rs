@@ -32,6 +32,10 @@ pub struct Opt {
     #[structopt(short = "k", long = "get-state-keys")]
     pub get_state_keys: Option<String>,
 
+    /// Rotate the state keys of the comma separated contract addresses and shutdown
+    #[structopt(short = "t", long = "rotate-state-keys")]
+    pub rotate_state_keys: Option<String>,
+
     /// Optional: The Enigma contract address, use the config if not provided
     #[structopt(short = "c", long = "contract-address")]
     pub contract_address: Option<String>,
@@ -40,6 +44,14 @@ pub struct Opt {
     #[structopt(short = "s", long = "reset-epoch-state")]
     pub reset_epoch_state: bool,
 
+    /// Set a corrupt Epoch state aside so that the node starts a new history, and shutdown
+    #[structopt(short = "e", long = "recover-epoch-state")]
+    pub recover_epoch_state: bool,
+
+    /// Seal an unsealed Epoch state once `seal_epoch_state` is turned on, and shutdown
+    #[structopt(short = "l", long = "seal-epoch-state")]
+    pub seal_epoch_state: bool,
+
     /// Optional: Deploy the Enigma contract related infrastructure
     #[structopt(short = "d", long = "deploy")]
     pub deploy: bool,
@@ -1,6 +1,7 @@
 #![allow(dead_code, unused_assignments, unused_variables)]
 
 use sgx_types::*;
+use std::path::PathBuf;
 
 // error while requesting to produce a quote (registration)
 #[derive(Fail, Debug)]
@@ -30,3 +31,10 @@ pub struct EnclaveFailError {
     pub err: enigma_types::EnclaveReturn,
     pub status: sgx_status_t,
 }
+
+#[derive(Fail, Debug)]
+#[fail(display = "The epoch state file {:?} is corrupt: {}. Run with --recover-epoch-state to set it aside and start over", path, reason)]
+pub struct EpochStateCorruptError {
+    pub path: PathBuf,
+    pub reason: String,
+}
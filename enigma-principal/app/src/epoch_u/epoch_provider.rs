@@ -1,108 +1,207 @@
 use std::{
-    fs::{self, File},
-    io::{self, prelude::*},
     mem,
-    ops::Deref,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
 };
 
-use enigma_tools_m::keeper_types::InputWorkerParams;
+use enigma_tools_m::keeper_types::{find_epoch_at, InputWorkerParams, EPOCH_CAPACITY};
 use ethabi::{Log, RawLog};
 use failure::Error;
-use serde::{Deserialize, Serialize};
 use sgx_types::sgx_enclave_id_t;
-use web3::types::{H256, TransactionReceipt, U256};
+use web3::types::{H256, Log as Web3Log, TransactionReceipt, U256};
 
-use enigma_tools_u::{
-    esgx::general::storage_dir,
-    web3_utils::enigma_contract::{ContractFuncs, ContractQueries, EnigmaContract},
+use enigma_tools_u::web3_utils::enigma_contract::{ContractFuncs, ContractQueries, EnigmaContractApi};
+use epoch_u::{
+    epoch_storage::EpochStorage,
+    epoch_types::{ConfirmedEpochState, EpochState, WorkersParameterizedEvent},
 };
-use epoch_u::epoch_types::{ConfirmedEpochState, EpochState, WorkersParameterizedEvent};
-use esgx::{epoch_keeper_u::set_worker_params, general::ENCLAVE_DIR};
-use rmp_serde::{Deserializer, Serializer};
+use esgx::epoch_keeper_u::{get_epoch, set_worker_params};
+
+/// The chain queries driving the epoch transitions
+pub trait EpochChain {
+    /// Returns the current block number
+    fn block_number(&self) -> Result<U256, Error>;
+
+    /// Returns the most recent `WorkersParameterized` event emitted by the Enigma contract from the block number on
+    fn last_worker_parameterized(&self, from_block: U256) -> Result<Option<Log>, Error>;
+}
+
+impl<T: EnigmaContractApi + ?Sized> EpochChain for T {
+    fn block_number(&self) -> Result<U256, Error> { EnigmaContractApi::block_number(self) }
+
+    fn last_worker_parameterized(&self, from_block: U256) -> Result<Option<Log>, Error> {
+        let event = WorkersParameterizedEvent::new();
+        let logs = self.get_logs(event.0.signature().into(), from_block)?;
+        match logs.last() {
+            Some(log) => Ok(Some(EpochProvider::parse_worker_parameterized_log(log.clone())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The transitions of the epoch watcher, from the latest `EpochState` and the chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum EpochTransition {
+    /// The latest epoch is confirmed and still active
+    Active,
+    /// No epoch was started or the latest one expired, the worker parameters must be set at the block number
+    NewEpoch(U256),
+    /// The signed worker parameters are not on-chain yet, the `setWorkersParams` transaction must be re-submitted
+    Pending,
+    /// The signed worker parameters were included in the chain at the first block number of the epoch,
+    /// but the epoch is not confirmed yet
+    Mined(U256),
+    /// A reorg dropped the `WorkersParameterized` event of the latest confirmed epoch,
+    /// the same worker parameters must be re-submitted
+    Reorged,
+}
 
 pub struct EpochProvider {
-    pub contract: Arc<EnigmaContract>,
-    pub epoch_state: Arc<Mutex<Option<EpochState>>>,
+    pub contract: Arc<dyn EnigmaContractApi>,
+    /// The `EpochState` history, the latest last
+    pub epoch_state: Arc<Mutex<Vec<EpochState>>>,
     pub eid: Arc<sgx_enclave_id_t>,
+    /// The number of workers selected for each contract in the new epochs
+    pub group_size: u64,
+    pub storage: EpochStorage,
 }
 
 impl EpochProvider {
-    pub fn new(eid: Arc<sgx_enclave_id_t>, contract: Arc<EnigmaContract>) -> Result<EpochProvider, Error> {
-        let epoch_state_val = Self::read_epoch_state()?;
-        // TODO: If the state is not empty, get the active workers and prove them to the enclave
-        println!("Initializing EpochProvider with EpochState: {:?}", epoch_state_val);
+    /// Fails with an `EpochStateCorruptError` if the stored history is corrupt, see `EpochStorage::recover`
+    pub fn new(
+        eid: Arc<sgx_enclave_id_t>, contract: Arc<dyn EnigmaContractApi>, group_size: u64, storage: EpochStorage,
+    ) -> Result<EpochProvider, Error> {
+        let epoch_state_val = storage.read()?;
+        println!("Initializing EpochProvider with EpochState history: {:?}", epoch_state_val);
         let epoch_state = Arc::new(Mutex::new(epoch_state_val));
-        Ok(Self { contract, epoch_state, eid })
+        let epoch_provider = Self { contract, epoch_state, eid, group_size, storage };
+        epoch_provider.verify_epoch_state()?;
+        Ok(epoch_provider)
     }
 
-    fn get_state_file_path() -> Result<PathBuf, Error> {
-        let mut path = storage_dir(ENCLAVE_DIR)?;
-        match fs::create_dir(&path) {
-            Ok(_) => (),
-            Err(e) => match e.kind() {
-                io::ErrorKind::AlreadyExists => (),
-                _ => return Err(e.into())
-            }
+    /// Check the latest stored `EpochState` against the epoch sealed in the enclave and
+    /// the last `WorkersParameterized` event emitted by the Enigma contract.
+    /// An inconsistent history is discarded, it will be replaced by the next epochs.
+    fn verify_epoch_state(&self) -> Result<(), Error> {
+        let epoch_state = match self.get_state() {
+            Ok(epoch_state) => epoch_state,
+            Err(_) => return Ok(()),
         };
-        path.push("epoch-state.msgpack");
-        Ok(path)
+        if let Err(err) = self.check_epoch_state(&epoch_state) {
+            eprintln!("Discarding the stored EpochState: {:?}", err);
+            self.reset_epoch_state()?;
+        }
+        Ok(())
     }
 
-    /// Reset the `EpochState` stores in memory
-    pub fn reset_epoch_state(&self) -> Result<(), Error> {
-        self.set_epoch_state(None)?;
+    fn check_epoch_state(&self, epoch_state: &EpochState) -> Result<(), Error> {
+        let (seed, nonce) = get_epoch(*self.eid)?;
+        if seed != epoch_state.seed || nonce != epoch_state.nonce {
+            bail!("The EpochState does not match the sealed epoch with seed: {:?}, nonce: {:?}", seed, nonce);
+        }
+        if epoch_state.confirmed_state.is_none() {
+            // The pending worker parameters are submitted again by the epoch watcher
+            return Ok(());
+        }
+        match self.contract.last_worker_parameterized(epoch_state.worker_params_block)? {
+            Some(log) => Self::check_worker_parameterized(epoch_state, &log),
+            None => bail!("No WorkersParameterized event found for the EpochState"),
+        }
+    }
+
+    /// Verify that the `WorkersParameterized` event was emitted for the `EpochState`
+    fn check_worker_parameterized(epoch_state: &EpochState, log: &Log) -> Result<(), Error> {
+        let (seed, nonce) = (Self::log_param(log, "seed")?, Self::log_param(log, "nonce")?);
+        if seed != epoch_state.seed || nonce != epoch_state.nonce {
+            bail!("The EpochState does not match the WorkersParameterized event with seed: {:?}, nonce: {:?}", seed, nonce);
+        }
         Ok(())
     }
 
-    #[logfn(DEBUG)]
-    fn read_epoch_state() -> Result<Option<EpochState>, Error> {
-        let path = Self::get_state_file_path()?;
-        let epoch_state = match File::open(path) {
-            Ok(mut f) => {
-                let mut buf = Vec::new();
-                f.read_to_end(&mut buf)?;
-                let mut des = Deserializer::new(&buf[..]);
-                let epoch_state: Option<EpochState> = match Deserialize::deserialize(&mut des) {
-                    Ok(value) => Some(value),
-                    Err(err) => {
-                        eprintln!("Unable to read block state file: {:?}", err);
-                        None
-                    }
-                };
-                epoch_state
+    fn log_param(log: &Log, name: &str) -> Result<U256, Error> {
+        match log.params.iter().find(|x| x.name == name).and_then(|x| x.value.clone().to_uint()) {
+            Some(value) => Ok(value),
+            None => bail!("{} not found in the WorkersParameterized log", name),
+        }
+    }
+
+    /// Returns the next transition of the epoch watcher
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` - The chain queried for the current block and the last `WorkersParameterized` event
+    /// * `epoch_state` - The latest `EpochState`, if any
+    /// * `epoch_size` - The number of blocks in an epoch
+    pub fn transition<C: EpochChain + ?Sized>(chain: &C, epoch_state: Option<&EpochState>, epoch_size: usize) -> Result<EpochTransition, Error> {
+        let block_number = chain.block_number()?;
+        let epoch_state = match epoch_state {
+            Some(epoch_state) => epoch_state,
+            None => return Ok(EpochTransition::NewEpoch(block_number)),
+        };
+        // The worker parameters of the latest epoch can't be mined before the block they were signed at,
+        // so the logs are only scanned from there.
+        // The nonce is incremented for each epoch, an older event means that the latest epoch is not on the chain
+        let log = match chain.last_worker_parameterized(epoch_state.worker_params_block)? {
+            Some(ref log) if Self::log_param(log, "nonce")? < epoch_state.nonce => None,
+            Some(log) => {
+                Self::check_worker_parameterized(epoch_state, &log)?;
+                Some(log)
             }
-            Err(_) => {
-                println!("No existing epoch state, starting with block 0");
-                None
+            None => None,
+        };
+        let transition = match (&epoch_state.confirmed_state, log) {
+            (None, Some(log)) => EpochTransition::Mined(Self::log_param(&log, "firstBlockNumber")?),
+            (None, None) => EpochTransition::Pending,
+            (Some(_), None) => EpochTransition::Reorged,
+            (Some(confirmed_state), Some(_)) if block_number >= confirmed_state.block_number + U256::from(epoch_size) => {
+                EpochTransition::NewEpoch(block_number)
             }
+            (Some(_), Some(_)) => EpochTransition::Active,
         };
-        Ok(epoch_state)
+        println!("Epoch transition at block {:?}: {:?}", block_number, transition);
+        Ok(transition)
     }
 
-    fn write_epoch_state(epoch_state: Option<EpochState>) -> Result<(), Error> {
-        let path = Self::get_state_file_path()?;
-        match epoch_state {
-            Some(epoch_state) => {
-                let mut file = File::create(path)?;
-                let mut buf = Vec::new();
-                epoch_state.serialize(&mut Serializer::new(&mut buf))?;
-                file.write_all(&buf)?;
+    /// Run the next transition of the epoch watcher, submitting or confirming the worker parameters as needed
+    ///
+    /// # Arguments
+    ///
+    /// * `epoch_size` - The number of blocks in an epoch
+    /// * `gas_limit` - The gas limit of the `setWorkersParams` transaction
+    /// * `confirmations` - The number of blocks required to confirm the `setWorkersParams` transaction
+    pub fn step<G: Into<U256>>(&self, epoch_size: usize, gas_limit: G, confirmations: usize) -> Result<EpochTransition, Error> {
+        let epoch_state = self.lock_epoch_state()?.last().cloned();
+        let transition = Self::transition(&*self.contract, epoch_state.as_ref(), epoch_size)?;
+        match &transition {
+            EpochTransition::Active => (),
+            EpochTransition::NewEpoch(block_number) => {
+                self.set_worker_params(*block_number, gas_limit, confirmations)?;
             }
-            None => {
-                match fs::remove_file(path) {
-                    Ok(res) => println!("Epoch state file removed: {:?}", res),
-                    Err(_err) => eprintln!("No epoch state file to remove"),
-                }
+            EpochTransition::Pending => {
+                self.submit_worker_params(gas_limit, confirmations)?;
             }
-        };
+            EpochTransition::Mined(block_number) => self.confirm_latest(*block_number)?,
+            EpochTransition::Reorged => {
+                self.unconfirm_latest()?;
+                self.submit_worker_params(gas_limit, confirmations)?;
+            }
+        }
+        Ok(transition)
+    }
+
+    /// Reset the `EpochState` history stored in memory and on disk
+    pub fn reset_epoch_state(&self) -> Result<(), Error> {
+        let mut guard = self.lock_epoch_state()?;
+        guard.clear();
+        self.storage.write(&guard)?;
         Ok(())
     }
 
     #[logfn(DEBUG)]
     fn parse_worker_parameterized(&self, receipt: &TransactionReceipt) -> Result<Log, Error> {
-        let log = receipt.logs[0].clone();
+        Self::parse_worker_parameterized_log(receipt.logs[0].clone())
+    }
+
+    fn parse_worker_parameterized_log(log: Web3Log) -> Result<Log, Error> {
         let raw_log = RawLog { topics: log.topics, data: log.data.0 };
         let event = WorkersParameterizedEvent::new();
         let result = match event.0.parse_log(raw_log) {
@@ -113,13 +212,17 @@ impl EpochProvider {
         Ok(result)
     }
 
-    /// Returns the `EpochState` stored in memory
-    pub fn get_state(&self) -> Result<EpochState, Error> {
-        let guard = match self.epoch_state.try_lock() {
-            Ok(guard) => guard,
+    fn lock_epoch_state(&self) -> Result<MutexGuard<Vec<EpochState>>, Error> {
+        match self.epoch_state.try_lock() {
+            Ok(guard) => Ok(guard),
             Err(_) => bail!("Unable to lock Epoch Marker Mutex."),
-        };
-        let epoch_state = match guard.deref() {
+        }
+    }
+
+    /// Returns the latest `EpochState` stored in memory
+    pub fn get_state(&self) -> Result<EpochState, Error> {
+        let guard = self.lock_epoch_state()?;
+        let epoch_state = match guard.last() {
             Some(epoch_state) => epoch_state.clone(),
             None => bail!("EpochState not set."),
         };
@@ -127,31 +230,93 @@ impl EpochProvider {
         Ok(epoch_state)
     }
 
-    #[logfn(DEBUG)]
-    fn set_epoch_state(&self, epoch_state: Option<EpochState>) -> Result<(), Error> {
-        println!("Replacing EpochMaker mutex: {:?}", epoch_state);
-        let mut guard = match self.epoch_state.try_lock() {
-            Ok(guard) => guard,
-            Err(_) => bail!("Unable to lock Epoch Marker Mutex"),
+    /// Returns the `EpochState` active at the block number
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - The block number of the task
+    pub fn get_state_for_block(&self, block_number: U256) -> Result<EpochState, Error> {
+        let guard = self.lock_epoch_state()?;
+        let epoch_state = match Self::find_epoch_state(&guard, block_number) {
+            Some(epoch_state) => epoch_state.clone(),
+            None => bail!("No confirmed EpochState for block number: {:?}", block_number),
+        };
+        mem::drop(guard);
+        Ok(epoch_state)
+    }
+
+    /// Returns the confirmed `EpochState` active at the block number with the first block number
+    /// of the next epoch, if it already started
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - The block number, the latest confirmed epoch is returned if missing
+    pub fn get_epoch_range(&self, block_number: Option<U256>) -> Result<(EpochState, Option<U256>), Error> {
+        let block_number = block_number.unwrap_or_else(U256::max_value);
+        let guard = self.lock_epoch_state()?;
+        let epoch_state = match Self::find_epoch_state(&guard, block_number) {
+            Some(epoch_state) => epoch_state.clone(),
+            None => bail!("No confirmed EpochState for block number: {:?}", block_number),
         };
-        let prev = mem::replace(&mut *guard, epoch_state.clone());
-        println!("Replaced EpochMaker: {:?} with: {:?}", prev, epoch_state);
+        let next_block_number = Self::find_next_epoch_block(&guard, block_number);
         mem::drop(guard);
-        match Self::write_epoch_state(epoch_state) {
+        Ok((epoch_state, next_block_number))
+    }
+
+    /// Find the confirmed `EpochState` of the block number, the same way the enclave does (see `find_epoch_at`)
+    fn find_epoch_state(epoch_states: &[EpochState], block_number: U256) -> Option<&EpochState> {
+        let confirmed = epoch_states.iter().filter(|epoch_state| epoch_state.confirmed_state.is_some());
+        find_epoch_at(confirmed, block_number, |epoch_state| (epoch_state.worker_params_block, epoch_state.nonce))
+    }
+
+    /// Find the first block number of the earliest confirmed epoch starting after the block number
+    fn find_next_epoch_block(epoch_states: &[EpochState], block_number: U256) -> Option<U256> {
+        epoch_states
+            .iter()
+            .filter(|epoch_state| epoch_state.confirmed_state.is_some())
+            .map(|epoch_state| epoch_state.worker_params_block)
+            .find(|&first_block_number| first_block_number > block_number)
+    }
+
+    /// Add the `EpochState` of a new epoch to the history, dropping the oldest beyond `EPOCH_CAPACITY`
+    #[logfn(DEBUG)]
+    fn push_epoch_state(&self, epoch_state: EpochState) -> Result<(), Error> {
+        println!("Adding EpochState to the history: {:?}", epoch_state);
+        let mut guard = self.lock_epoch_state()?;
+        guard.push(epoch_state);
+        let expired = guard.len().saturating_sub(EPOCH_CAPACITY);
+        guard.drain(..expired);
+        match self.storage.write(&guard) {
             Ok(_) => println!("Stored the Epoch Marker to disk"),
             Err(err) => bail!(err),
         };
         Ok(())
     }
 
-    /// Get the confirmed state if available. Bail if not.
+    /// Replace the latest `EpochState` of the history and store it to disk
+    fn replace_latest_epoch_state(&self, epoch_state: EpochState) -> Result<(), Error> {
+        let mut guard = self.lock_epoch_state()?;
+        match guard.last_mut() {
+            Some(latest) => *latest = epoch_state,
+            None => bail!("EpochState not set."),
+        }
+        self.storage.write(&guard)?;
+        Ok(())
+    }
+
+    /// Mark the latest `EpochState` as pending after its `WorkersParameterized` event was dropped from the chain
+    fn unconfirm_latest(&self) -> Result<(), Error> {
+        let mut epoch_state = self.get_state()?;
+        println!("The WorkersParameterized event of the latest epoch was dropped: {:?}", epoch_state);
+        epoch_state.confirmed_state = None;
+        self.replace_latest_epoch_state(epoch_state)
+    }
+
+    /// Get the confirmed state of the latest epoch if available. Bail if not.
     /// The confirmed state contains the selected worker cache.
     pub fn get_confirmed(&self) -> Result<ConfirmedEpochState, Error> {
-        let guard = match self.epoch_state.try_lock() {
-            Ok(guard) => guard,
-            Err(_) => bail!("Unable to lock Epoch Marker Mutex."),
-        };
-        let confirmed_state = match guard.deref() {
+        let guard = self.lock_epoch_state()?;
+        let confirmed_state = match guard.last() {
             Some(epoch_state) => match &epoch_state.confirmed_state {
                 Some(confirmed_state) => confirmed_state.clone(),
                 None => bail!("Epoch Marker not confirmed yet."),
@@ -181,26 +346,58 @@ impl EpochProvider {
     /// * `gas_limit` - The gas limit of the `setWorkersParams` transaction
     /// * `confirmations` - The number of blocks required to confirm the `setWorkersParams` transaction
     pub fn set_worker_params<G: Into<U256>>(&self, block_number: U256, gas_limit: G, confirmations: usize) -> Result<H256, Error> {
-        let result = self.contract.get_active_workers(block_number)?;
-        let worker_params: InputWorkerParams = InputWorkerParams { block_number, workers: result.0, stakes: result.1 };
+        let worker_params = self.get_worker_params(block_number)?;
         println!("The active workers: {:?}", worker_params);
-        let mut epoch_state = set_worker_params(*self.eid, &worker_params)?;
+        let epoch_state = set_worker_params(*self.eid, &worker_params, self.group_size)?;
+        // The enclave nonce is already incremented, keep the signed parameters until they are confirmed on-chain
+        self.push_epoch_state(epoch_state)?;
+        self.submit_worker_params(gas_limit, confirmations)
+    }
+
+    /// Submit the worker parameters of the latest pending `EpochState` to the Enigma contract
+    /// and confirm the epoch with the resulting `WorkersParameterized` event
+    ///
+    /// # Arguments
+    ///
+    /// * `gas_limit` - The gas limit of the `setWorkersParams` transaction
+    /// * `confirmations` - The number of blocks required to confirm the `setWorkersParams` transaction
+    pub fn submit_worker_params<G: Into<U256>>(&self, gas_limit: G, confirmations: usize) -> Result<H256, Error> {
+        let epoch_state = self.get_state()?;
+        if epoch_state.confirmed_state.is_some() {
+            bail!("The latest EpochState is already confirmed");
+        }
+        let block_number = epoch_state.worker_params_block;
         println!("Waiting for setWorkerParams({:?}, {:?}, {:?})", block_number, epoch_state.seed, epoch_state.sig);
-        // TODO: Consider a retry mechanism, either store the EpochSeed or add a getter ecall
-        let receipt =
-            self.contract.set_workers_params(block_number, epoch_state.seed, epoch_state.sig.clone(), gas_limit, confirmations)?;
+        let receipt = self.contract.set_workers_params(
+            block_number,
+            epoch_state.seed,
+            epoch_state.sig.clone(),
+            gas_limit.into(),
+            confirmations,
+        )?;
         println!("Got the receipt: {:?}", receipt);
         let log = self.parse_worker_parameterized(&receipt)?;
-        match log.params.into_iter().find(|x| x.name == "firstBlockNumber") {
-            Some(param) => {
-                println!("Caching selected workers");
-                let block_number = param.value.to_uint().unwrap();
-                self.confirm_epoch(&mut epoch_state, block_number, worker_params)?;
-                self.set_epoch_state(Some(epoch_state))?;
-                Ok(receipt.transaction_hash)
-            }
-            None => bail!("firstBlockNumber not found in receipt log"),
-        }
+        Self::check_worker_parameterized(&epoch_state, &log)?;
+        self.confirm_latest(Self::log_param(&log, "firstBlockNumber")?)?;
+        Ok(receipt.transaction_hash)
+    }
+
+    /// Confirm the latest `EpochState` once its worker parameters are on-chain
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - The first block number of the epoch
+    fn confirm_latest(&self, block_number: U256) -> Result<(), Error> {
+        let mut epoch_state = self.get_state()?;
+        let worker_params = self.get_worker_params(epoch_state.worker_params_block)?;
+        println!("Caching selected workers");
+        self.confirm_epoch(&mut epoch_state, block_number, worker_params)?;
+        self.replace_latest_epoch_state(epoch_state)
+    }
+
+    fn get_worker_params(&self, block_number: U256) -> Result<InputWorkerParams, Error> {
+        let (workers, stakes) = self.contract.get_active_workers(block_number)?;
+        Ok(InputWorkerParams { block_number, workers, stakes })
     }
 
     /// Build a local mapping of smart contract address => selected worker for the epoch
@@ -227,6 +424,7 @@ mod test {
     use web3::types::{Bytes, H160};
     use std::collections::HashMap;
     use enigma_types::ContractAddress;
+    use ethabi::{LogParam, Token};
 
     use super::*;
 
@@ -239,15 +437,115 @@ mod test {
         let mock_address: [u8; 32] = [1; 32];
         selected_workers.insert(ContractAddress::from(mock_address), H160(WORKER_SIGN_ADDRESS));
         let block_number = U256::from(1);
-        let confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number });
+        let confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number, workers: vec![], stakes: vec![] });
         let seed = U256::from(1);
         let mock_sig: [u8; 65] = [1; 65];
         let sig = Bytes::from(mock_sig.to_vec());
         let nonce = U256::from(0);
-        let epoch_state = EpochState { seed, sig, nonce, confirmed_state };
-        EpochProvider::write_epoch_state(Some(epoch_state.clone())).unwrap();
+        let epoch_state = EpochState { seed, sig, nonce, confirmed_state, worker_params_block: block_number, group_size: 2 };
+        let storage = EpochStorage::new(0, false).unwrap();
+        storage.write(&[epoch_state.clone()]).unwrap();
+
+        let saved_epoch_state = storage.read().unwrap();
+        assert_eq!(format!("{:?}", saved_epoch_state), format!("{:?}", vec![epoch_state]));
+    }
+
+    #[test]
+    fn test_find_epoch_state() {
+        let epoch_state = |nonce: u64, block_number: Option<u64>| {
+            let confirmed_state = block_number.map(|b| ConfirmedEpochState {
+                selected_workers: HashMap::new(),
+                block_number: U256::from(b),
+                workers: vec![],
+                stakes: vec![],
+            });
+            EpochState {
+                seed: U256::from(nonce),
+                sig: Bytes::from(vec![0u8; 65]),
+                nonce: U256::from(nonce),
+                confirmed_state,
+                worker_params_block: U256::from(block_number.unwrap_or(30)),
+                group_size: 1,
+            }
+        };
+        let epoch_states = vec![epoch_state(0, Some(10)), epoch_state(1, Some(20)), epoch_state(2, None)];
+        let nonce_at = |block_number: u64| {
+            EpochProvider::find_epoch_state(&epoch_states, U256::from(block_number)).map(|epoch_state| epoch_state.nonce.low_u64())
+        };
+        assert_eq!(nonce_at(5), None);
+        assert_eq!(nonce_at(10), Some(0));
+        assert_eq!(nonce_at(15), Some(0));
+        // The unconfirmed epoch is never selected
+        assert_eq!(nonce_at(100), Some(1));
+
+        let next_at = |block_number: u64| EpochProvider::find_next_epoch_block(&epoch_states, U256::from(block_number));
+        assert_eq!(next_at(15), Some(U256::from(20)));
+        assert_eq!(next_at(20), None);
+    }
+
+    /// A chain with the (seed, firstBlockNumber, nonce) of its last `WorkersParameterized` event
+    struct MockChain {
+        block_number: u64,
+        last_event: Option<(u64, u64, u64)>,
+    }
+
+    impl EpochChain for MockChain {
+        fn block_number(&self) -> Result<U256, Error> { Ok(U256::from(self.block_number)) }
 
-        let saved_epoch_state = EpochProvider::read_epoch_state().unwrap();
-        assert_eq!(format!("{:?}", saved_epoch_state.unwrap()), format!("{:?}", epoch_state));
+        fn last_worker_parameterized(&self, _from_block: U256) -> Result<Option<Log>, Error> {
+            let param = |name: &str, value: u64| LogParam { name: name.to_string(), value: Token::Uint(U256::from(value)) };
+            Ok(self.last_event.map(|(seed, first_block_number, nonce)| Log {
+                params: vec![param("seed", seed), param("firstBlockNumber", first_block_number), param("nonce", nonce)],
+            }))
+        }
+    }
+
+    fn mock_epoch_state(nonce: u64, block_number: Option<u64>) -> EpochState {
+        let mut epoch_state = EpochState::new(U256::from(nonce), Bytes::from(vec![0u8; 65]), U256::from(nonce), U256::from(1), 1);
+        epoch_state.confirmed_state = block_number.map(|b| ConfirmedEpochState {
+            selected_workers: HashMap::new(),
+            block_number: U256::from(b),
+            workers: vec![],
+            stakes: vec![],
+        });
+        epoch_state
+    }
+
+    #[test]
+    fn test_epoch_transitions() {
+        let transition = |block_number: u64, last_event: Option<(u64, u64, u64)>, epoch_state: Option<EpochState>| {
+            let chain = MockChain { block_number, last_event };
+            EpochProvider::transition(&chain, epoch_state.as_ref(), 10)
+        };
+        // The first epoch
+        assert_eq!(transition(5, None, None).unwrap(), EpochTransition::NewEpoch(U256::from(5)));
+        // The confirmed epoch is active until its last block
+        assert_eq!(transition(19, Some((1, 10, 1)), Some(mock_epoch_state(1, Some(10)))).unwrap(), EpochTransition::Active);
+        assert_eq!(
+            transition(20, Some((1, 10, 1)), Some(mock_epoch_state(1, Some(10)))).unwrap(),
+            EpochTransition::NewEpoch(U256::from(20))
+        );
+        // The signed worker parameters are submitted until mined
+        assert_eq!(transition(20, Some((1, 10, 1)), Some(mock_epoch_state(2, None))).unwrap(), EpochTransition::Pending);
+        assert_eq!(
+            transition(21, Some((2, 20, 2)), Some(mock_epoch_state(2, None))).unwrap(),
+            EpochTransition::Mined(U256::from(20))
+        );
+        // The event of the confirmed epoch was dropped by a reorg
+        assert_eq!(transition(21, Some((1, 10, 1)), Some(mock_epoch_state(2, Some(20)))).unwrap(), EpochTransition::Reorged);
+        assert_eq!(transition(21, None, Some(mock_epoch_state(2, Some(20)))).unwrap(), EpochTransition::Reorged);
+    }
+
+    #[test]
+    fn test_epoch_transitions_inconsistent_event() {
+        let transition = |last_event: Option<(u64, u64, u64)>, epoch_state: EpochState| {
+            let chain = MockChain { block_number: 25, last_event };
+            EpochProvider::transition(&chain, Some(&epoch_state), 10)
+        };
+        // An epoch was set without the local EpochState
+        assert!(transition(Some((3, 20, 3)), mock_epoch_state(2, Some(20))).is_err());
+        assert!(transition(Some((3, 20, 3)), mock_epoch_state(2, None)).is_err());
+        // The event does not match the seed signed by the enclave
+        assert!(transition(Some((4, 20, 2)), mock_epoch_state(2, Some(20))).is_err());
     }
 }
@@ -0,0 +1,292 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+};
+
+use enigma_crypto::hash::Sha256;
+use failure::Error;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use sgx_types::sgx_enclave_id_t;
+
+use common_u::errors::EpochStateCorruptError;
+use enigma_tools_u::esgx::general::storage_dir;
+use epoch_u::epoch_types::EpochState;
+use esgx::{
+    epoch_keeper_u::{seal_epoch_state, unseal_epoch_state},
+    general::ENCLAVE_DIR,
+};
+
+const EPOCH_STATE_FILE: &str = "epoch-state.msgpack";
+/// The file header: magic (4 bytes) || format version (1 byte) || flags (1 byte) || sha256 of the body (32 bytes)
+const MAGIC: [u8; 4] = *b"EPST";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 38;
+/// The body is sealed by the principal enclave
+const FLAG_SEALED: u8 = 1;
+
+/// The `EpochState` history stored on disk.
+/// The history is written to a temporary file which is synced and renamed over the previous one,
+/// so that a crash leaves either the previous or the new history, never a partial one.
+pub struct EpochStorage {
+    path: PathBuf,
+    eid: sgx_enclave_id_t,
+    /// Seal the history with the principal enclave key, a sealed history is read regardless.
+    /// When set, an unsealed history is refused, see `migrate_to_sealed`.
+    seal: bool,
+}
+
+impl EpochStorage {
+    /// The storage of the history in the enclave directory
+    pub fn new(eid: sgx_enclave_id_t, seal: bool) -> Result<Self, Error> {
+        let mut path = storage_dir(ENCLAVE_DIR)?;
+        match fs::create_dir(&path) {
+            Ok(_) => (),
+            Err(e) => match e.kind() {
+                io::ErrorKind::AlreadyExists => (),
+                _ => return Err(e.into()),
+            },
+        };
+        path.push(EPOCH_STATE_FILE);
+        Ok(Self::with_path(path, eid, seal))
+    }
+
+    pub fn with_path(path: PathBuf, eid: sgx_enclave_id_t, seal: bool) -> Self { Self { path, eid, seal } }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    /// Read the history, empty if it was never written.
+    /// Fails with an `EpochStateCorruptError` if the file doesn't hold a valid history.
+    #[logfn(DEBUG)]
+    pub fn read(&self) -> Result<Vec<EpochState>, Error> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                println!("No existing epoch state, starting with block 0");
+                return Ok(Vec::new());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let payload = self.decode(&data)?;
+        let mut des = Deserializer::new(&payload[..]);
+        Deserialize::deserialize(&mut des).map_err(|err| self.corrupt(format!("Unable to deserialize the history: {:?}", err)))
+    }
+
+    /// Replace the history on disk, the file is removed if the history is empty
+    pub fn write(&self, epoch_state: &[EpochState]) -> Result<(), Error> {
+        if epoch_state.is_empty() {
+            match fs::remove_file(&self.path) {
+                Ok(res) => println!("Epoch state file removed: {:?}", res),
+                Err(_err) => eprintln!("No epoch state file to remove"),
+            }
+            return Ok(());
+        }
+        let mut payload = Vec::new();
+        epoch_state.serialize(&mut Serializer::new(&mut payload))?;
+        let (flags, body) = if self.seal { (FLAG_SEALED, seal_epoch_state(self.eid, &payload)?) } else { (0, payload) };
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + body.len());
+        data.extend_from_slice(&MAGIC);
+        data.push(FORMAT_VERSION);
+        data.push(flags);
+        data.extend_from_slice(&body.sha256()[..]);
+        data.extend_from_slice(&body);
+        write_atomic(&self.path, &self.sibling(".tmp"), &data)?;
+        Ok(())
+    }
+
+    /// Rewrite an unsealed history, with or without a header, as a sealed one. Returns the number of epochs in it.
+    /// This is the one-time migration for turning sealing on, it trusts the file as is, so only run it on a history
+    /// written by this node.
+    pub fn migrate_to_sealed(&self) -> Result<usize, Error> {
+        let history = Self::with_path(self.path.clone(), self.eid, false).read()?;
+        Self::with_path(self.path.clone(), self.eid, true).write(&history)?;
+        Ok(history.len())
+    }
+
+    /// Set a corrupt history aside, next to the original file with a `.corrupt` suffix, so that a new history is started.
+    /// A valid history is kept as is. Returns the path of the corrupt file if it was moved.
+    pub fn recover(&self) -> Result<Option<PathBuf>, Error> {
+        let err = match self.read() {
+            Ok(_) => return Ok(None),
+            Err(err) => err.downcast::<EpochStateCorruptError>()?,
+        };
+        eprintln!("Recovering from: {}", err);
+        let corrupt_path = self.sibling(".corrupt");
+        fs::rename(&self.path, &corrupt_path)?;
+        Ok(Some(corrupt_path))
+    }
+
+    /// Returns the payload of the file, checking the header and unsealing the body if needed
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            if self.seal {
+                return Err(self.corrupt("The history has no header, it must be migrated before it's read sealed"));
+            }
+            // Written before the header existed, the next write adds it
+            return Ok(data.to_vec());
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(self.corrupt(format!("Truncated header of {} bytes", data.len())));
+        }
+        let (version, flags) = (data[4], data[5]);
+        if version != FORMAT_VERSION {
+            return Err(self.corrupt(format!("Unsupported format version: {}", version)));
+        }
+        let body = &data[HEADER_SIZE..];
+        if body.sha256()[..] != data[6..HEADER_SIZE] {
+            return Err(self.corrupt("Checksum mismatch"));
+        }
+        if flags & FLAG_SEALED == 0 {
+            // Otherwise anyone who can write the file could replace a sealed history with an unsealed one
+            if self.seal {
+                return Err(self.corrupt("The history is not sealed, it must be migrated before it's read sealed"));
+            }
+            return Ok(body.to_vec());
+        }
+        unseal_epoch_state(self.eid, body).map_err(|err| self.corrupt(format!("Unable to unseal the history: {}", err)))
+    }
+
+    fn corrupt<S: Into<String>>(&self, reason: S) -> Error {
+        EpochStateCorruptError { path: self.path.clone(), reason: reason.into() }.into()
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut file_name = self.path.file_name().map(OsString::from).unwrap_or_default();
+        file_name.push(suffix);
+        self.path.with_file_name(file_name)
+    }
+}
+
+/// Write the data to the temporary path then rename it over the path, syncing both the file and the directory
+fn write_atomic(path: &Path, tmp_path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    // The rename itself is only durable once the directory is synced
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use esgx::general::init_enclave_wrapper;
+    use std::{env, process};
+    use web3::types::{Bytes, U256};
+
+    fn test_storage(name: &str, eid: sgx_enclave_id_t, seal: bool) -> EpochStorage {
+        let path = env::temp_dir().join(format!("epoch-state-{}-{}.msgpack", name, process::id()));
+        let _ = fs::remove_file(&path);
+        EpochStorage::with_path(path, eid, seal)
+    }
+
+    fn mock_history() -> Vec<EpochState> {
+        (0..3u64).map(|i| EpochState::new(U256::from(i), Bytes::from(vec![i as u8; 65]), U256::from(i), U256::from(10 * i), 1)).collect()
+    }
+
+    fn assert_corrupt(storage: &EpochStorage) {
+        match storage.read() {
+            Err(err) => assert!(err.downcast_ref::<EpochStateCorruptError>().is_some(), "Unexpected error: {:?}", err),
+            Ok(history) => panic!("Expected a corrupt history, got: {:?}", history),
+        }
+    }
+
+    #[test]
+    fn test_epoch_storage_write_read() {
+        let storage = test_storage("write-read", 0, false);
+        assert!(storage.read().unwrap().is_empty());
+        let history = mock_history();
+        storage.write(&history).unwrap();
+        assert_eq!(format!("{:?}", storage.read().unwrap()), format!("{:?}", history));
+        assert_eq!(fs::read(storage.path()).unwrap()[..4], MAGIC);
+        assert!(!storage.sibling(".tmp").exists());
+
+        storage.write(&[]).unwrap();
+        assert!(!storage.path().exists());
+    }
+
+    #[test]
+    fn test_epoch_storage_corruption() {
+        let storage = test_storage("corruption", 0, false);
+        storage.write(&mock_history()).unwrap();
+        let data = fs::read(storage.path()).unwrap();
+
+        let mut flipped = data.clone();
+        *flipped.last_mut().unwrap() ^= 0xff;
+        fs::write(storage.path(), &flipped).unwrap();
+        assert_corrupt(&storage);
+
+        fs::write(storage.path(), &data[..HEADER_SIZE - 1]).unwrap();
+        assert_corrupt(&storage);
+
+        // Without a header the file is read as a legacy history
+        fs::write(storage.path(), &data[HEADER_SIZE..HEADER_SIZE + 5]).unwrap();
+        assert_corrupt(&storage);
+
+        let corrupt_path = storage.recover().unwrap().unwrap();
+        assert!(corrupt_path.exists());
+        assert!(storage.read().unwrap().is_empty());
+        assert_eq!(storage.recover().unwrap(), None);
+        fs::remove_file(corrupt_path).unwrap();
+    }
+
+    #[test]
+    fn test_epoch_storage_legacy() {
+        let storage = test_storage("legacy", 0, false);
+        let history = mock_history();
+        let mut payload = Vec::new();
+        history.serialize(&mut Serializer::new(&mut payload)).unwrap();
+        fs::write(storage.path(), &payload).unwrap();
+        assert_eq!(format!("{:?}", storage.read().unwrap()), format!("{:?}", history));
+        // A valid history is not moved aside
+        assert_eq!(storage.recover().unwrap(), None);
+        storage.write(&[]).unwrap();
+    }
+
+    #[test]
+    fn test_epoch_storage_sealed() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let storage = test_storage("sealed", enclave.geteid(), true);
+        let history = mock_history();
+        storage.write(&history).unwrap();
+        let data = fs::read(storage.path()).unwrap();
+        assert_eq!(data[5], FLAG_SEALED);
+
+        // The sealed history is read even if sealing was turned off since
+        let unsealed = EpochStorage::with_path(storage.path().to_path_buf(), enclave.geteid(), false);
+        assert_eq!(format!("{:?}", unsealed.read().unwrap()), format!("{:?}", history));
+        storage.write(&[]).unwrap();
+        enclave.destroy();
+    }
+
+    #[test]
+    fn test_epoch_storage_sealed_rejects_unsealed() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let storage = test_storage("sealed-rejects", enclave.geteid(), true);
+        let unsealed = EpochStorage::with_path(storage.path().to_path_buf(), enclave.geteid(), false);
+        let history = mock_history();
+
+        // An unsealed history with a valid checksum
+        unsealed.write(&history).unwrap();
+        assert_corrupt(&storage);
+
+        // A history written before the header existed
+        let mut payload = Vec::new();
+        history.serialize(&mut Serializer::new(&mut payload)).unwrap();
+        fs::write(storage.path(), &payload).unwrap();
+        assert_corrupt(&storage);
+
+        // The explicit migration seals it
+        assert_eq!(storage.migrate_to_sealed().unwrap(), history.len());
+        assert_eq!(fs::read(storage.path()).unwrap()[5], FLAG_SEALED);
+        assert_eq!(format!("{:?}", storage.read().unwrap()), format!("{:?}", history));
+        storage.write(&[]).unwrap();
+        enclave.destroy();
+    }
+}
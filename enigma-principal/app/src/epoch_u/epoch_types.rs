@@ -1,12 +1,12 @@
 use std::collections::HashMap;
 
-use enigma_tools_m::keeper_types::InputWorkerParams;
-use ethabi::{Event, EventParam, ParamType};
+use enigma_tools_m::keeper_types::{InputWorkerParams, DEFAULT_GROUP_SIZE};
 use failure::Error;
 pub use rlp::{decode, Encodable, encode, RlpStream};
 use serde::{Deserialize, Serialize};
 use web3::types::{Address, Bytes, H160, U256};
 
+pub use enigma_tools_u::web3_utils::enigma_contract::WorkersParameterizedEvent;
 use enigma_types::ContractAddress;
 use enigma_types::Hash256;
 
@@ -14,6 +14,11 @@ use enigma_types::Hash256;
 pub struct ConfirmedEpochState {
     pub selected_workers: HashMap<Hash256, H160>,
     pub block_number: U256,
+    /// The active workers and their stakes, used to select the workers of the contracts deployed during the epoch
+    #[serde(default)]
+    pub workers: Vec<H160>,
+    #[serde(default)]
+    pub stakes: Vec<U256>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -22,10 +27,20 @@ pub struct EpochState {
     pub sig: Bytes,
     pub nonce: U256,
     pub confirmed_state: Option<ConfirmedEpochState>,
+    /// The block number of the worker parameters signed by the enclave, kept to re-submit them
+    #[serde(default)]
+    pub worker_params_block: U256,
+    /// The number of workers selected for each contract, as sealed by the enclave
+    #[serde(default = "default_group_size")]
+    pub group_size: u64,
 }
 
+fn default_group_size() -> u64 { DEFAULT_GROUP_SIZE }
+
 impl EpochState {
-    pub fn new(seed: U256, sig: Bytes, nonce: U256) -> Self { Self { seed, sig, nonce, confirmed_state: None } }
+    pub fn new(seed: U256, sig: Bytes, nonce: U256, worker_params_block: U256, group_size: u64) -> Self {
+        Self { seed, sig, nonce, confirmed_state: None, worker_params_block, group_size }
+    }
 
     /// Build a local mapping of smart contract address => selected worker for the epoch
     ///
@@ -54,11 +69,53 @@ impl EpochState {
                 }
             }
         }
-        self.confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number });
+        let (workers, stakes) = (worker_params.workers.clone(), worker_params.stakes.clone());
+        self.confirmed_state = Some(ConfirmedEpochState { selected_workers, block_number, workers, stakes });
         Ok(())
     }
 
-    /// Returns the contract address that the worker is selected to work on during this epoch
+    /// Returns the worker selected to work on the contract during this epoch, if any.
+    /// The worker selection runs again for the contracts deployed after the epoch was confirmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `sc_address` - The Secret Contract address
+    pub fn get_selected_worker(&self, sc_address: ContractAddress) -> Result<Option<H160>, Error> {
+        let state = match &self.confirmed_state {
+            Some(state) => state,
+            None => bail!("Cannot get the selected worker until the EpochState is confirmed."),
+        };
+        if let Some(worker) = state.selected_workers.get(&sc_address) {
+            return Ok(Some(*worker));
+        }
+        Ok(Self::worker_params(state, self.worker_params_block).get_selected_worker(sc_address, self.seed))
+    }
+
+    /// Returns the group of workers selected to work on the contract during this epoch,
+    /// the first one is the worker returned by `get_selected_worker`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sc_address` - The Secret Contract address
+    pub fn get_selected_workers(&self, sc_address: ContractAddress) -> Result<Vec<H160>, Error> {
+        let state = match &self.confirmed_state {
+            Some(state) => state,
+            None => bail!("Cannot get the selected workers until the EpochState is confirmed."),
+        };
+        // The cache only holds the first worker of each group
+        if self.group_size <= 1 {
+            return Ok(self.get_selected_worker(sc_address)?.into_iter().collect());
+        }
+        let worker_params = Self::worker_params(state, self.worker_params_block);
+        Ok(worker_params.get_selected_workers(sc_address, self.seed, Some(self.group_size)))
+    }
+
+    fn worker_params(state: &ConfirmedEpochState, block_number: U256) -> InputWorkerParams {
+        InputWorkerParams { block_number, workers: state.workers.clone(), stakes: state.stakes.clone() }
+    }
+
+    /// Returns the contract address that the worker is selected to work on during this epoch,
+    /// as any member of the contract group
     ///
     /// # Arguments
     ///
@@ -68,8 +125,8 @@ impl EpochState {
         let addrs = match &self.confirmed_state {
             Some(state) => {
                 let mut addrs: Vec<ContractAddress> = Vec::new();
-                for (&addr, account) in &state.selected_workers {
-                    if account == worker {
+                for &addr in state.selected_workers.keys() {
+                    if self.get_selected_workers(addr)?.contains(worker) {
                         addrs.push(addr);
                     }
                 }
@@ -80,23 +137,3 @@ impl EpochState {
         Ok(addrs)
     }
 }
-
-#[derive(Debug, Clone)]
-pub struct WorkersParameterizedEvent(pub Event);
-
-impl WorkersParameterizedEvent {
-    pub fn new() -> Self {
-        WorkersParameterizedEvent(Event {
-            name: "WorkersParameterized".to_string(),
-            inputs: vec![
-                EventParam { name: "seed".to_string(), kind: ParamType::Uint(256), indexed: false },
-                EventParam { name: "firstBlockNumber".to_string(), kind: ParamType::Uint(256), indexed: false },
-                EventParam { name: "inclusionBlockNumber".to_string(), kind: ParamType::Uint(256), indexed: false },
-                EventParam { name: "workers".to_string(), kind: ParamType::Array(Box::new(ParamType::Address)), indexed: false },
-                EventParam { name: "stakes".to_string(), kind: ParamType::Array(Box::new(ParamType::Uint(256))), indexed: false },
-                EventParam { name: "nonce".to_string(), kind: ParamType::Uint(256), indexed: false },
-            ],
-            anonymous: false,
-        })
-    }
-}
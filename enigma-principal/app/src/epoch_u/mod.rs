@@ -1,2 +1,3 @@
 pub mod epoch_provider;
+pub mod epoch_storage;
 pub mod epoch_types;
@@ -8,20 +8,28 @@ use web3::types::{Bytes, U256};
 
 extern "C" {
     fn ecall_set_worker_params(
-        eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, worker_params_rlp: *const u8, worker_params_rlp_len: usize,
+        eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, worker_params_rlp: *const u8, worker_params_rlp_len: usize, group_size: u64,
         rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32], sig_out: &mut [u8; 65],
     ) -> sgx_status_t;
+    fn ecall_get_epoch(eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, seed_out: &mut [u8; 32], nonce_out: &mut [u8; 32]) -> sgx_status_t;
+    fn ecall_seal_epoch_state(
+        eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, epoch_state: *const u8, epoch_state_len: usize, serialized_ptr: *mut u64,
+    ) -> sgx_status_t;
+    fn ecall_unseal_epoch_state(
+        eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, sealed: *mut u8, sealed_len: usize, serialized_ptr: *mut u64,
+    ) -> sgx_status_t;
 }
 
 /// Returns an EpochState object containing the 32 bytes signed random seed and an incremented account nonce.
+/// The enclave seals the group size with the epoch, each contract is assigned to a group of `group_size` workers.
 /// # Examples
 /// ```
 /// let enclave = esgx::general::init_enclave().unwrap();
 /// let result = self.contract.get_active_workers(block_number)?;
 /// let worker_params: InputWorkerParams = InputWorkerParams { block_number, workers: result.0, stakes: result.1 };
-/// let sig = set_worker_params(enclave.geteid(), worker_params).unwrap();
+/// let sig = set_worker_params(enclave.geteid(), &worker_params, 1).unwrap();
 /// ```
-pub fn set_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParams) -> Result<EpochState, Error> {
+pub fn set_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParams, group_size: u64) -> Result<EpochState, Error> {
     let mut retval: EnclaveReturn = EnclaveReturn::Success;
     let mut nonce_out: [u8; 32] = [0; 32];
     let mut rand_out: [u8; 32] = [0; 32];
@@ -34,6 +42,7 @@ pub fn set_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParam
             &mut retval,
             worker_params_rlp.as_c_ptr() as *const u8,
             worker_params_rlp.len(),
+            group_size,
             &mut rand_out,
             &mut nonce_out,
             &mut sig_out,
@@ -45,7 +54,49 @@ pub fn set_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParam
     let seed = U256::from_big_endian(&rand_out);
     let sig = Bytes(sig_out.to_vec());
     let nonce = U256::from_big_endian(&nonce_out);
-    Ok(EpochState::new(seed, sig, nonce))
+    Ok(EpochState::new(seed, sig, nonce, worker_params.block_number, group_size))
+}
+
+/// Returns the seed and nonce of the latest epoch sealed in the enclave.
+/// Fails if the enclave never sealed an epoch.
+pub fn get_epoch(eid: sgx_enclave_id_t) -> Result<(U256, U256), Error> {
+    let mut retval: EnclaveReturn = EnclaveReturn::Success;
+    let mut seed_out: [u8; 32] = [0; 32];
+    let mut nonce_out: [u8; 32] = [0; 32];
+    let status = unsafe { ecall_get_epoch(eid, &mut retval, &mut seed_out, &mut nonce_out) };
+    if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: retval, status }.into());
+    }
+    Ok((U256::from_big_endian(&seed_out), U256::from_big_endian(&nonce_out)))
+}
+
+/// Seals the serialized `EpochState` history with the enclave sealing key, only this enclave can unseal it
+pub fn seal_epoch_state(eid: sgx_enclave_id_t, epoch_state: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut retval: EnclaveReturn = EnclaveReturn::Success;
+    let mut sealed_ptr = 0u64;
+    let status = unsafe {
+        ecall_seal_epoch_state(eid, &mut retval, epoch_state.as_c_ptr() as *const u8, epoch_state.len(), &mut sealed_ptr as *mut u64)
+    };
+    if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: retval, status }.into());
+    }
+    let sealed = unsafe { Box::from_raw(sealed_ptr as *mut Box<[u8]>) };
+    Ok(sealed.to_vec())
+}
+
+/// Unseals the `EpochState` history sealed by `seal_epoch_state`
+pub fn unseal_epoch_state(eid: sgx_enclave_id_t, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut retval: EnclaveReturn = EnclaveReturn::Success;
+    let mut epoch_state_ptr = 0u64;
+    let mut sealed = sealed.to_vec();
+    let status = unsafe {
+        ecall_unseal_epoch_state(eid, &mut retval, sealed.as_mut_ptr(), sealed.len(), &mut epoch_state_ptr as *mut u64)
+    };
+    if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: retval, status }.into());
+    }
+    let epoch_state = unsafe { Box::from_raw(epoch_state_ptr as *mut Box<[u8]>) };
+    Ok(epoch_state.to_vec())
 }
 
 #[cfg(test)]
@@ -62,15 +113,18 @@ pub mod tests {
             workers: vec![Address::from("f25186B5081Ff5cE73482AD761DB0eB0d25abfBF")],
             stakes: vec![U256::from(1)],
         };
-        set_worker_params(eid, &worker_params).unwrap()
+        set_worker_params(eid, &worker_params, 1).unwrap()
     }
 
     #[test]
     fn test_set_mock_worker_params() {
         let enclave = init_enclave_wrapper().unwrap();
+        // The epoch is sealed, so the nonce carries on from the previous runs
+        let expected_nonce = get_epoch(enclave.geteid()).map(|(_, nonce)| nonce + 1).unwrap_or_else(|_| Uint::from(0));
         let epoch_seed = set_mock_worker_params(enclave.geteid());
         println!("Got epoch seed params: {:?}", epoch_seed);
-        assert_eq!(epoch_seed.nonce, Uint::from(0));
+        assert_eq!(epoch_seed.nonce, expected_nonce);
+        assert_eq!(get_epoch(enclave.geteid()).unwrap(), (epoch_seed.seed, epoch_seed.nonce));
 
         enclave.destroy();
     }
@@ -3,13 +3,14 @@ use common_u::errors::EnclaveFailError;
 use enigma_types::{traits::SliceCPtr, ContractAddress, EnclaveReturn};
 use failure::Error;
 use sgx_types::{sgx_enclave_id_t, sgx_status_t};
-use std::{convert::TryInto, mem};
+use std::{convert::TryInto, mem, ptr};
 
 extern "C" {
     fn ecall_get_enc_state_keys(
         eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, msg: *const u8, msg_len: usize, addrs: *const u8, addrs_len: usize,
-        sig: &[u8; 65], serialized_ptr: *mut u64, sig_out: &mut [u8; 65],
+        sig: &[u8; 65], block_number: *const u64, serialized_ptr: *mut u64, sig_out: &mut [u8; 65],
     ) -> sgx_status_t;
+    fn ecall_rotate_state_keys(eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, addrs: *const u8, addrs_len: usize) -> sgx_status_t;
 }
 
 /// Returns the signed encrypted keys.
@@ -19,7 +20,7 @@ extern "C" {
 /// let enclave = esgx::general::init_enclave().unwrap();
 /// let msg = StringWrapper("84a46461746181a75265717565737493dc0020cca7cc937b64ccb8cccacca5cc8f03721bccb6ccbacccf5c78cccb235fccebcce0cce70b1bcc84cccdcc99541461cca0cc8edc002016367accacccb67a4a017ccc8dcca8ccabcc95682ccccb390863780f7114ccddcca0cca0cce0ccc55644ccc7ccc4dc0020ccb1cce9cc9324505bccd32dcca0cce1ccf85dcccf5e19cca0cc9dccb0481ecc8a15ccf62c41cceb320304cca8cce927a269649c1363ccb3301c101f33cce1cc9a0524a67072656669789e456e69676d61204d657373616765a67075626b6579dc0040cce5ccbe28cc9dcc9a2eccbd08ccc0457a5f16ccdfcc9fccdc256c5d5f6c3514cccdcc95ccb47c11ccc4cccd3e31ccf0cce4ccefccc83ccc80cce8121c3939ccbb2561cc80ccec48ccbecca8ccc569ccd2cca3ccda6bcce415ccfa20cc9bcc98ccda".to_string());
 /// let sig = StringWrapper("43f19586b0a0ae626b9418fe8355888013be1c9b4263a4b3a27953de641991e936ed6c4076a2a383b3b001936bf0eb6e23c78fbec1ee36f19c6a9d24d75e9e081c".to_string());
-/// let request = StateKeyRequest { data: msg, sig: sig };
+/// let request = StateKeyRequest { data: msg, sig: sig, block_number: None };
 /// let response = get_enc_state_keys(enclave.geteid(), request, None).unwrap();
 /// ```
 #[logfn(DEBUG)]
@@ -28,6 +29,8 @@ pub fn get_enc_state_keys(eid: sgx_enclave_id_t, request: StateKeyRequest, epoch
     let mut sig_out: [u8; 65] = [0; 65];
     let mut response_ptr = 0u64;
     let epoch_addrs = epoch_addrs.unwrap_or_default();
+    // The enclave uses the latest epoch if the block number is null
+    let block_number = request.block_number.as_ref().map_or(ptr::null(), |b| b as *const u64);
 
     let msg_bytes: Vec<u8> = request.data.try_into()?;
     let status = unsafe {
@@ -39,6 +42,7 @@ pub fn get_enc_state_keys(eid: sgx_enclave_id_t, request: StateKeyRequest, epoch
             epoch_addrs.as_c_ptr() as *const u8,
             mem::size_of_val(epoch_addrs),
             &request.sig.try_into()?,
+            block_number,
             &mut response_ptr as *mut u64,
             &mut sig_out,
         )
@@ -51,6 +55,18 @@ pub fn get_enc_state_keys(eid: sgx_enclave_id_t, request: StateKeyRequest, epoch
     Ok(StateKeyResponse { data: StringWrapper::from(&response[..]), sig: StringWrapper::from(&sig_out[..]) })
 }
 
+/// Issues a new version of the state keys of the given contracts.
+/// The workers get the new version with their next state keys request and re-encrypt the contract state with it.
+#[logfn(DEBUG)]
+pub fn rotate_state_keys(eid: sgx_enclave_id_t, addrs: &[ContractAddress]) -> Result<(), Error> {
+    let mut retval: EnclaveReturn = EnclaveReturn::Success;
+    let status = unsafe { ecall_rotate_state_keys(eid, &mut retval, addrs.as_c_ptr() as *const u8, mem::size_of_val(addrs)) };
+    if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: retval, status }.into());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -87,7 +103,7 @@ pub mod tests {
         println!("The mock message: {:?}", msg);
         println!("The mock sig: {:?}", sig);
 
-        let request = StateKeyRequest { data: msg, sig };
+        let request = StateKeyRequest { data: msg, sig, block_number: None };
         let response = get_enc_state_keys(enclave.geteid(), request, None).unwrap();
         println!("Got response: {:?}", response);
         enclave.destroy();
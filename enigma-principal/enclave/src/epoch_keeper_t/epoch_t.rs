@@ -1,12 +1,17 @@
-use enigma_tools_m::keeper_types::{InputWorkerParams, RawEncodable};
-use enigma_tools_t::common::errors_t::{
-    EnclaveError::{self, SystemError},
-    EnclaveSystemError,
+use enigma_tools_m::keeper_types::{
+    Decodable, DecoderError, Encodable, InputWorkerParams, RawEncodable, RlpStream, UntrustedRlp, DEFAULT_GROUP_SIZE,
+};
+use enigma_tools_t::common::{
+    errors_t::{
+        EnclaveError::{self, SystemError},
+        EnclaveSystemError,
+    },
+    ToHex,
 };
 use enigma_types::ContractAddress;
-use ethabi::{encode, Bytes, Token};
+use ethabi::Bytes;
 use ethereum_types::{H160, U256};
-use std::string::ToString;
+use std::{string::{String, ToString}, vec::Vec};
 
 pub type EpochNonce = [u8; 32];
 
@@ -15,25 +20,70 @@ pub struct Epoch {
     pub nonce: U256,
     pub seed: U256,
     pub worker_params: InputWorkerParams,
+    /// The number of workers selected for each contract, all of them get the state keys
+    pub group_size: u64,
 }
 
 impl Epoch {
     pub fn get_selected_worker(&self, sc_addr: ContractAddress) -> Result<H160, EnclaveError> {
-        self.worker_params
-            .get_selected_worker(sc_addr, self.seed)
-            .ok_or_else(|| SystemError(EnclaveSystemError::WorkerAuthError { err: "Worker selection returns nothing.".to_string() }))
+        Ok(self.get_selected_workers(sc_addr)?[0])
+    }
+
+    /// The group of workers selected for the contract, the first one is the worker returned by `get_selected_worker`
+    pub fn get_selected_workers(&self, sc_addr: ContractAddress) -> Result<Vec<H160>, EnclaveError> {
+        let workers = self.worker_params.get_selected_workers(sc_addr, self.seed, Some(self.group_size));
+        if workers.is_empty() {
+            return Err(SystemError(EnclaveSystemError::WorkerAuthError { err: "Worker selection returns nothing.".to_string() }));
+        }
+        Ok(workers)
+    }
+
+    /// Verify that `worker` is in the selected group of every contract in `sc_addrs`.
+    /// All the addresses are checked so that the error lists every contract the worker isn't authorized for.
+    pub fn verify_selected_worker(&self, worker: H160, sc_addrs: &[ContractAddress]) -> Result<(), EnclaveError> {
+        let mut unauthorized: Vec<String> = Vec::new();
+        for &sc_addr in sc_addrs {
+            if !self.get_selected_workers(sc_addr)?.contains(&worker) {
+                unauthorized.push(sc_addr.to_hex());
+            }
+        }
+        if unauthorized.is_empty() {
+            Ok(())
+        } else {
+            Err(SystemError(EnclaveSystemError::WorkerAuthError {
+                err: format!("Worker {:?} is not selected in epoch {:?} for the contracts: {:?}", worker, self.nonce, unauthorized),
+            }))
+        }
     }
 }
 
 impl RawEncodable for Epoch {
     /// Encode the Epoch as Ethereum ABI parameters
-    fn raw_encode(&self) -> Bytes {
-        let tokens = vec![
-            Token::Uint(self.seed),
-            Token::Uint(self.nonce),
-            Token::Array(self.worker_params.workers.iter().map(|a| Token::Address(*a)).collect()),
-            Token::Array(self.worker_params.stakes.iter().map(|s| Token::Uint(*s)).collect()),
-        ];
-        encode(&tokens)
+    fn raw_encode(&self) -> Bytes { self.worker_params.encode_epoch(self.seed, self.nonce, self.group_size) }
+}
+
+/// The RLP encoding used to seal the Epoch
+impl Encodable for Epoch {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        let seed: [u8; 32] = self.seed.into();
+        let nonce: EpochNonce = self.nonce.into();
+        s.begin_list(4);
+        s.append(&seed.to_vec());
+        s.append(&nonce.to_vec());
+        s.append(&self.worker_params);
+        s.append(&self.group_size);
+    }
+}
+
+impl Decodable for Epoch {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        // The epochs sealed before the worker groups have no group size
+        let group_size = if rlp.item_count() > 3 { rlp.val_at(3)? } else { DEFAULT_GROUP_SIZE };
+        Ok(Self {
+            seed: U256::from(&rlp.val_at::<Vec<u8>>(0)?[..]),
+            nonce: U256::from(&rlp.val_at::<Vec<u8>>(1)?[..]),
+            worker_params: rlp.val_at(2)?,
+            group_size,
+        })
     }
 }
@@ -1,5 +1,5 @@
 use crate::SIGNING_KEY;
-use enigma_tools_m::keeper_types::{decode, InputWorkerParams, RawEncodable};
+use enigma_tools_m::keeper_types::{decode, find_epoch_at, Encodable, InputWorkerParams, RawEncodable, UntrustedRlp, EPOCH_CAPACITY};
 use enigma_tools_t::{
     common::{
         errors_t::{
@@ -9,7 +9,7 @@ use enigma_tools_t::{
         utils_t::LockExpectMutex,
         ToHex,
     },
-    document_storage_t::{is_document, load_sealed_document, save_sealed_document, SealedDocumentStorage, SEAL_LOG_SIZE},
+    document_storage_t::{is_document, load_document, save_document, seal_document, unseal_document, LegacyFormat, SealedDocumentType},
 };
 use enigma_types::ContractAddress;
 use epoch_keeper_t::epoch_t::{Epoch, EpochNonce};
@@ -17,13 +17,16 @@ use ethereum_types::{H160, U256};
 use ocalls_t;
 use sgx_trts::trts::rsgx_read_rand;
 use sgx_types::*;
-use std::{collections::HashMap, path, str, string::ToString, sync::SgxMutex};
+use std::{collections::HashMap, path, str, string::ToString, sync::SgxMutex, untrusted::fs::remove_file};
 
 pub mod epoch_t;
 
 const INIT_NONCE: uint32_t = 0;
 const EPOCH_DIR: &str = "epoch";
-
+const EPOCH_DOCUMENT: SealedDocumentType = 2;
+const EPOCH_MARKER_DOCUMENT: SealedDocumentType = 3;
+/// The `EpochState` history of the untrusted app, sealed by the enclave and stored by the app
+const EPOCH_STATE_DOCUMENT: SealedDocumentType = 4;
 // The epoch seed contains the seeds + a nonce that must match the Ethereum tx
 lazy_static! {
     pub static ref EPOCH: SgxMutex<HashMap<U256, Epoch>> = SgxMutex::new(HashMap::new());
@@ -38,66 +41,106 @@ fn get_epoch_root_path() -> path::PathBuf {
 
 fn get_epoch_nonce_path() -> path::PathBuf { get_epoch_root_path().join("nonce.sealed") }
 
-fn get_epoch(epoch_map: &HashMap<U256, Epoch>, block_number: Option<U256>) -> Result<Option<Epoch>, EnclaveError> {
+fn get_epoch_path(nonce: U256) -> path::PathBuf {
+    get_epoch_root_path().join(format!("epoch-{}.sealed", EpochNonce::from(nonce).to_hex()))
+}
+
+/// Unseal the epoch of the nonce, returns None if there is no sealed epoch for it
+fn unseal_epoch(nonce: U256) -> Result<Option<Epoch>, EnclaveError> {
+    let path = get_epoch_path(nonce);
+    println!("Unsealing epoch: {:?}", path);
+    match load_document(&path, EPOCH_DOCUMENT, Some(LegacyFormat::Bytes))? {
+        Some(data) => {
+            let epoch: Epoch = UntrustedRlp::new(&data).as_val().map_err(|err| {
+                SystemError(StateError { err: format!("Unable to decode the sealed epoch: {:?}", err) })
+            })?;
+            Ok(Some(epoch))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Unseal the latest epochs, up to `EPOCH_CAPACITY`, into the cache after a restart
+fn load_epochs(epoch_map: &mut HashMap<U256, Epoch>) -> Result<(), EnclaveError> {
+    println!("Unsealing epoch nonce");
+    let mut nonce = match load_document(&get_epoch_nonce_path(), EPOCH_MARKER_DOCUMENT, Some(LegacyFormat::Storage))? {
+        Some(ref data) if data.len() == 32 => U256::from(&data[..]),
+        Some(data) => return Err(SystemError(StateError { err: format!("Invalid epoch marker of {} bytes", data.len()) })),
+        None => return Ok(()),
+    };
+    println!("found epoch marker: {:?}", nonce);
+    for _ in 0..EPOCH_CAPACITY {
+        match unseal_epoch(nonce)? {
+            Some(epoch) => epoch_map.insert(nonce, epoch),
+            None => break,
+        };
+        if nonce.is_zero() {
+            break;
+        }
+        nonce = nonce - 1;
+    }
+    Ok(())
+}
+
+/// Get the epoch active at the block number (None == latest) from the cache,
+/// or from the sealed documents after a restart.
+/// An epoch is active from the block number of its worker parameters until the next epoch, see `find_epoch_at`.
+fn get_epoch(epoch_map: &mut HashMap<U256, Epoch>, block_number: Option<U256>) -> Result<Option<Epoch>, EnclaveError> {
     println!("Getting epoch for block number: {:?}", block_number);
-    if block_number.is_some() {
-        Err(SystemError(WorkerAuthError { err: "Epoch lookup by block number not implemented.".to_string() }))
-    } else if epoch_map.is_empty() {
+    if epoch_map.is_empty() {
         println!("Epoch not found");
-        let nonce_path = get_epoch_nonce_path();
-        if is_document(&nonce_path) {
-            println!("Unsealing epoch nonce");
-            let mut sealed_log_out = [0u8; SEAL_LOG_SIZE];
-            load_sealed_document(&nonce_path, &mut sealed_log_out)?;
-            let doc = SealedDocumentStorage::<EpochNonce>::unseal(&mut sealed_log_out)?;
-            if let Some(doc) = doc {
-                let nonce = Some(doc.data);
-                println!("found epoch marker: {:?}", nonce);
-                // TODO: unseal the epoch
-            }
-        }
-        Ok(None)
-    } else {
-        // The epoch map cannot be empty here
-        let nonce = epoch_map.keys().max().unwrap();
-        let epoch: Epoch = epoch_map.get(nonce).unwrap().clone();
-        Ok(Some(epoch))
+        load_epochs(epoch_map)?;
     }
+    let epoch = match block_number {
+        Some(block_number) => find_epoch_at(epoch_map.values(), block_number, |epoch| (epoch.worker_params.block_number, epoch.nonce)),
+        None => epoch_map.values().max_by_key(|epoch| epoch.nonce),
+    };
+    Ok(epoch.cloned())
 }
 
 /// Creates new epoch both in the cache and as sealed documents
-fn new_epoch(nonce_map: &mut HashMap<U256, Epoch>, worker_params:
-            &InputWorkerParams, nonce: U256, seed: U256) -> Result<Epoch, EnclaveError> {
-    let mut marker_doc: SealedDocumentStorage<EpochNonce> = SealedDocumentStorage {
-        version: 0x1234, // TODO: what's this?
-        data: [0; 32],
-    };
-    marker_doc.data = nonce.into();
-    let mut sealed_log_in = [0u8; SEAL_LOG_SIZE];
-    marker_doc.seal(&mut sealed_log_in)?;
-    // Save sealed_log to file
+fn new_epoch(nonce_map: &mut HashMap<U256, Epoch>, worker_params: &InputWorkerParams,
+             nonce: U256, seed: U256, group_size: u64) -> Result<Epoch, EnclaveError> {
+    let epoch = Epoch { nonce, seed, worker_params: worker_params.clone(), group_size };
+    // The epoch is sealed before the marker so that the marker never points to a missing epoch
+    let epoch_path = get_epoch_path(nonce);
+    save_document(&epoch_path, EPOCH_DOCUMENT, &epoch.rlp_bytes())?;
+    println!("Sealed the epoch: {:?}", epoch_path);
+
     let marker_path = get_epoch_nonce_path();
-    save_sealed_document(&marker_path, &sealed_log_in)?;
+    save_document(&marker_path, EPOCH_MARKER_DOCUMENT, &EpochNonce::from(nonce))?;
     println!("Sealed the epoch marker: {:?}", marker_path);
 
-    let epoch = Epoch { nonce, seed, worker_params: worker_params.clone() };
-    // TODO: seal the epoch
     println!("Storing epoch: {:?}", epoch);
     match nonce_map.insert(nonce, epoch.clone()) {
         Some(prev) => println!("New epoch stored successfully, previous epoch: {:?}", prev),
         None => println!("Initial epoch stored successfully"),
     }
+    // Only the latest epochs are kept, the keys of older tasks are not provisioned anymore
+    nonce_map.retain(|&prev, _| prev > nonce || nonce - prev < U256::from(EPOCH_CAPACITY as u64));
+    if nonce >= U256::from(EPOCH_CAPACITY as u64) {
+        let expired_path = get_epoch_path(nonce - EPOCH_CAPACITY as u64);
+        if is_document(&expired_path) {
+            match remove_file(&expired_path) {
+                Ok(_) => println!("Removed the expired epoch: {:?}", expired_path),
+                Err(err) => println!("Unable to remove the expired epoch: {:?}", err),
+            }
+        }
+    }
     Ok(epoch)
 }
 
-pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], rand_out: &mut [u8; 32],
+pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], group_size: u64, rand_out: &mut [u8; 32],
                                                nonce_out: &mut [u8; 32], sig_out: &mut [u8; 65]) -> Result<(), EnclaveError> {
+    if group_size == 0 {
+        return Err(SystemError(WorkerAuthError { err: "The worker group size must be at least 1".to_string() }));
+    }
     // RLP decoding the necessary data
     let worker_params = decode(worker_params_rlp);
     println!("Successfully decoded RLP worker parameters");
     let mut guard = EPOCH.lock_expect("Epoch");
 
-    let nonce: U256 = get_epoch(&*guard, None)?.map_or_else(|| INIT_NONCE.into(), |_| guard.keys().max().unwrap() + 1);
+    let nonce: U256 = get_epoch(&mut guard, None)?.map_or_else(|| INIT_NONCE.into(), |epoch| epoch.nonce + 1);
 
     println!("Generated a nonce by incrementing the previous by 1 {:?}", nonce);
     *nonce_out = EpochNonce::from(nonce);
@@ -106,7 +149,7 @@ pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], rand_ou
 
     let seed = U256::from(rand_out.as_ref());
     println!("Generated random seed: {:?}", seed);
-    let epoch = new_epoch(&mut guard, &worker_params, nonce, seed)?;
+    let epoch = new_epoch(&mut guard, &worker_params, nonce, seed, group_size)?;
 
     let msg = epoch.raw_encode();
     *sig_out = SIGNING_KEY.sign(&msg)?;
@@ -114,24 +157,55 @@ pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], rand_ou
     Ok(())
 }
 
+pub(crate) fn ecall_seal_epoch_state_internal(epoch_state: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    seal_document(EPOCH_STATE_DOCUMENT, epoch_state)
+}
+
+pub(crate) fn ecall_unseal_epoch_state_internal(sealed: &mut [u8]) -> Result<Vec<u8>, EnclaveError> {
+    Ok(unseal_document(EPOCH_STATE_DOCUMENT, None, sealed)?.payload)
+}
+
+/// Get the epoch for the block number (None == latest), failing if there is none
+fn get_existing_epoch(epoch_map: &mut HashMap<U256, Epoch>, block_number: Option<U256>) -> Result<Epoch, EnclaveError> {
+    match get_epoch(epoch_map, block_number)? {
+        Some(epoch) => Ok(epoch),
+        None => Err(SystemError(WorkerAuthError {
+            err: format!("No epoch found for block number (None == latest): {:?}", block_number),
+        })),
+    }
+}
+
 pub(crate) fn ecall_get_epoch_worker_internal(sc_addr: ContractAddress, block_number: Option<U256>) -> Result<H160, EnclaveError> {
-    let guard = EPOCH.lock_expect("Epoch");
-    let epoch = match get_epoch(&guard, block_number)? {
-        Some(epoch) => epoch,
-        None => {
-            return Err(SystemError(WorkerAuthError {
-                err: format!("No epoch found for block number (None == latest): {:?}", block_number),
-            }));
-        }
-    };
+    let mut guard = EPOCH.lock_expect("Epoch");
+    let epoch = get_existing_epoch(&mut guard, block_number)?;
     println!("Running worker selection using Epoch: {:?}", epoch);
     let worker = epoch.get_selected_worker(sc_addr)?;
     Ok(worker)
 }
 
+pub(crate) fn ecall_get_epoch_internal(seed_out: &mut [u8; 32], nonce_out: &mut [u8; 32]) -> Result<(), EnclaveError> {
+    let mut guard = EPOCH.lock_expect("Epoch");
+    let epoch = get_existing_epoch(&mut guard, None)?;
+    *seed_out = epoch.seed.into();
+    *nonce_out = EpochNonce::from(epoch.nonce);
+    Ok(())
+}
+
+/// Verify that the worker is in the selected group of all the contracts in the epoch of the block number (None == latest)
+/// Returns the block number of the worker parameters of the epoch the worker was verified with.
+pub(crate) fn verify_worker_selection(worker: H160, sc_addrs: &[ContractAddress], block_number: Option<U256>) -> Result<U256, EnclaveError> {
+    let mut guard = EPOCH.lock_expect("Epoch");
+    let epoch = get_existing_epoch(&mut guard, block_number)?;
+    println!("Verifying worker selection using Epoch: {:?}", epoch);
+    epoch.verify_selected_worker(worker, sc_addrs)?;
+    Ok(epoch.worker_params.block_number)
+}
+
 pub mod tests {
     use super::*;
+    use enigma_tools_m::keeper_types::RlpStream;
     use ethereum_types::{H160, U256};
+    use std::vec::Vec;
 
     // noinspection RsTypeCheck
     pub fn test_get_epoch_worker_internal() {
@@ -140,10 +214,132 @@ pub mod tests {
             workers: vec![H160::from(0), H160::from(1), H160::from(2), H160::from(3)],
             stakes: vec![U256::from(1), U256::from(1), U256::from(1), U256::from(1)],
         };
-        let epoch = Epoch { nonce: U256::from(0), seed: U256::from(1), worker_params };
+        let epoch = Epoch { nonce: U256::from(0), seed: U256::from(1), worker_params, group_size: 1 };
         println!("The epoch: {:?}", epoch);
         let sc_addr = ContractAddress::from([1u8; 32]);
         let worker = epoch.get_selected_worker(sc_addr).unwrap();
         println!("The selected workers: {:?}", worker);
     }
+
+    pub fn test_epoch_sealing() {
+        let worker_params = InputWorkerParams {
+            block_number: U256::from(1),
+            workers: vec![H160::from(0), H160::from(1), H160::from(2), H160::from(3)],
+            stakes: vec![U256::from(1), U256::from(2), U256::from(3), U256::from(4)],
+        };
+        let mut epoch_map: HashMap<U256, Epoch> = HashMap::new();
+        let epoch = new_epoch(&mut epoch_map, &worker_params, U256::from(7), U256::from(42), 2).unwrap();
+
+        // Clearing the cache to test the recovery of the sealed epoch, like after a restart
+        epoch_map.clear();
+        let unsealed = get_epoch(&mut epoch_map, None).unwrap().unwrap();
+        assert_eq!(unsealed.raw_encode(), epoch.raw_encode());
+        assert_eq!(unsealed.worker_params.block_number, worker_params.block_number);
+        assert_eq!(unsealed.group_size, 2);
+        assert!(epoch_map.contains_key(&epoch.nonce));
+    }
+
+    pub fn test_get_epoch_by_block_number() {
+        let mut epoch_map: HashMap<U256, Epoch> = HashMap::new();
+        for i in 0..3u64 {
+            let worker_params = InputWorkerParams {
+                block_number: U256::from(10 * (i + 1)),
+                workers: vec![H160::from(i)],
+                stakes: vec![U256::from(1)],
+            };
+            epoch_map.insert(U256::from(i), Epoch { nonce: U256::from(i), seed: U256::from(i), worker_params, group_size: 1 });
+        }
+        let nonce_at = |map: &mut HashMap<U256, Epoch>, block_number: Option<u64>| {
+            get_epoch(map, block_number.map(U256::from)).unwrap().map(|epoch| epoch.nonce.low_u64())
+        };
+        assert_eq!(nonce_at(&mut epoch_map, Some(5)), None);
+        assert_eq!(nonce_at(&mut epoch_map, Some(10)), Some(0));
+        assert_eq!(nonce_at(&mut epoch_map, Some(25)), Some(1));
+        assert_eq!(nonce_at(&mut epoch_map, Some(100)), Some(2));
+        assert_eq!(nonce_at(&mut epoch_map, None), Some(2));
+    }
+
+    pub fn test_epoch_retention() {
+        let worker_params = InputWorkerParams { block_number: U256::from(1), workers: vec![H160::from(0)], stakes: vec![U256::from(1)] };
+        let mut epoch_map: HashMap<U256, Epoch> = HashMap::new();
+        let last_nonce = EPOCH_CAPACITY as u64 + 2;
+        for nonce in 0..=last_nonce {
+            new_epoch(&mut epoch_map, &worker_params, U256::from(nonce), U256::from(nonce), 1).unwrap();
+        }
+        assert_eq!(epoch_map.len(), EPOCH_CAPACITY);
+        assert!(!epoch_map.contains_key(&U256::from(last_nonce - EPOCH_CAPACITY as u64)));
+        assert!(epoch_map.contains_key(&U256::from(last_nonce - EPOCH_CAPACITY as u64 + 1)));
+
+        // The same history is unsealed after a restart
+        epoch_map.clear();
+        get_epoch(&mut epoch_map, None).unwrap();
+        assert_eq!(epoch_map.len(), EPOCH_CAPACITY);
+    }
+
+    pub fn test_verify_selected_worker() {
+        let worker_params = InputWorkerParams {
+            block_number: U256::from(1),
+            workers: vec![H160::from(0), H160::from(1), H160::from(2), H160::from(3)],
+            stakes: vec![U256::from(1), U256::from(1), U256::from(1), U256::from(1)],
+        };
+        let epoch = Epoch { nonce: U256::from(0), seed: U256::from(1), worker_params, group_size: 1 };
+        let sc_addrs: Vec<ContractAddress> = (0..16u8).map(|i| ContractAddress::from([i; 32])).collect();
+        let worker = epoch.get_selected_worker(sc_addrs[0]).unwrap();
+        let (allowed, denied): (Vec<ContractAddress>, Vec<ContractAddress>) =
+            sc_addrs.iter().cloned().partition(|&addr| epoch.get_selected_worker(addr).unwrap() == worker);
+        assert!(!denied.is_empty());
+
+        epoch.verify_selected_worker(worker, &allowed).unwrap();
+        epoch.verify_selected_worker(worker, &[]).unwrap();
+        // A mixed batch is rejected as a whole, listing every unauthorized address
+        match epoch.verify_selected_worker(worker, &sc_addrs) {
+            Err(SystemError(WorkerAuthError { err })) => {
+                for addr in &denied {
+                    assert!(err.contains(&addr.to_hex()));
+                }
+                for addr in &allowed {
+                    assert!(!err.contains(&addr.to_hex()));
+                }
+            }
+            other => panic!("Expected a WorkerAuthError, got: {:?}", other),
+        }
+    }
+
+    pub fn test_verify_selected_group() {
+        let worker_params = InputWorkerParams {
+            block_number: U256::from(1),
+            workers: vec![H160::from(0), H160::from(1), H160::from(2), H160::from(3)],
+            stakes: vec![U256::from(1), U256::from(2), U256::from(3), U256::from(4)],
+        };
+        let epoch = Epoch { nonce: U256::from(0), seed: U256::from(1), worker_params, group_size: 2 };
+        let sc_addr = ContractAddress::from([1u8; 32]);
+        let group = epoch.get_selected_workers(sc_addr).unwrap();
+        assert_eq!(group.len(), 2);
+        assert_eq!(epoch.get_selected_worker(sc_addr).unwrap(), group[0]);
+        // Every member of the group is authorized, the other workers are not
+        for worker in &epoch.worker_params.workers {
+            assert_eq!(epoch.verify_selected_worker(*worker, &[sc_addr]).is_ok(), group.contains(worker));
+        }
+
+        // The epochs sealed without a group size select a single worker
+        let (seed, nonce): ([u8; 32], EpochNonce) = (epoch.seed.into(), epoch.nonce.into());
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&seed.to_vec()).append(&nonce.to_vec()).append(&epoch.worker_params);
+        let legacy: Epoch = UntrustedRlp::new(&stream.out()).as_val().unwrap();
+        assert_eq!(legacy.group_size, 1);
+        assert_eq!(legacy.get_selected_workers(sc_addr).unwrap(), vec![group[0]]);
+    }
+
+    pub fn test_seal_epoch_state() {
+        let epoch_state = b"epoch state history".to_vec();
+        let mut sealed = ecall_seal_epoch_state_internal(&epoch_state).unwrap();
+        assert_eq!(ecall_unseal_epoch_state_internal(&mut sealed.clone()).unwrap(), epoch_state);
+
+        // Another kind of sealed document is rejected
+        let mut other = seal_document(EPOCH_DOCUMENT, &epoch_state).unwrap();
+        assert!(ecall_unseal_epoch_state_internal(&mut other).is_err());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+        assert!(ecall_unseal_epoch_state_internal(&mut sealed).is_err());
+    }
 }
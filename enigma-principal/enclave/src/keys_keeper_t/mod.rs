@@ -1,7 +1,8 @@
 use crate::SIGNING_KEY;
+use epoch_keeper_t::verify_worker_selection;
 use enigma_crypto::{asymmetric::KeyPair, Encryption};
 use enigma_tools_m::{
-    primitives::km_primitives::{PrincipalMessage, PrincipalMessageType},
+    primitives::km_primitives::{PrincipalMessage, PrincipalMessageType, PrincipalResponseKey, KEY_VERSIONS_WINDOW},
     utils::EthereumAddress,
 };
 use enigma_tools_t::{
@@ -13,17 +14,75 @@ use enigma_tools_t::{
         utils_t::LockExpectMutex,
         ToHex,
     },
-    document_storage_t::{is_document, load_sealed_document, save_sealed_document, SealedDocumentStorage, SEAL_LOG_SIZE},
+    document_storage_t::{load_document, save_document, LegacyFormat, SealedDocumentType},
 };
-use enigma_types::{ContractAddress, Hash256, StateKey};
+use enigma_types::{ContractAddress, Hash256, KeyVersion, StateKey};
+use ethereum_types::{H160, U256};
 use ocalls_t;
 use sgx_trts::trts::rsgx_read_rand;
-use std::{collections::HashMap, path, sync::SgxMutex, vec::Vec};
+use std::{path, sync::SgxMutex, vec::Vec};
 
 const STATE_KEYS_DIR: &str = "state-keys";
+const STATE_KEY_VERSIONS_DOCUMENT: SealedDocumentType = 1;
 
+// The state keys aren't cached, they are read from their sealed documents on every request:
+// `--rotate-state-keys` rotates them from another instance of the enclave and the running principal must serve the new versions.
+// The lock serializes the updates of the documents within this instance.
 lazy_static! {
-    pub static ref STATE_KEY_STORE: SgxMutex<HashMap<ContractAddress, StateKey>> = SgxMutex::new(HashMap::new());
+    pub static ref STATE_KEYS_LOCK: SgxMutex<()> = SgxMutex::new(());
+}
+
+/// The versions of a contract's state key that are still served.
+/// The keys are kept in a ring indexed by the version so the struct has a fixed size and can be sealed as is.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StateKeyVersions {
+    pub latest: KeyVersion,
+    keys: [StateKey; KEY_VERSIONS_WINDOW],
+}
+
+impl StateKeyVersions {
+    pub fn new(key: StateKey) -> Self {
+        let mut keys = [[0u8; 32]; KEY_VERSIONS_WINDOW];
+        keys[0] = key;
+        StateKeyVersions { latest: 0, keys }
+    }
+
+    pub fn latest_key(&self) -> StateKey { self.keys[self.latest as usize % KEY_VERSIONS_WINDOW] }
+
+    /// Replaces the oldest version with `key` as the new latest version.
+    pub fn rotate(&mut self, key: StateKey) {
+        self.latest += 1;
+        self.keys[self.latest as usize % KEY_VERSIONS_WINDOW] = key;
+    }
+
+    /// The sealed payload: the latest version followed by the keys in the order of the ring.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(4 + 32 * KEY_VERSIONS_WINDOW);
+        res.extend_from_slice(&self.latest.to_le_bytes());
+        for key in self.keys.iter() {
+            res.extend_from_slice(key);
+        }
+        res
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, EnclaveError> {
+        if bytes.len() != 4 + 32 * KEY_VERSIONS_WINDOW {
+            return Err(SystemError(KeyProvisionError { err: format!("Invalid sealed state key versions of {} bytes", bytes.len()) }));
+        }
+        let mut latest = [0u8; 4];
+        latest.copy_from_slice(&bytes[..4]);
+        let mut versions = StateKeyVersions { latest: KeyVersion::from_le_bytes(latest), ..Default::default() };
+        for (key, chunk) in versions.keys.iter_mut().zip(bytes[4..].chunks(32)) {
+            key.copy_from_slice(chunk);
+        }
+        Ok(versions)
+    }
+
+    /// The versions that are served, from the oldest to the latest.
+    pub fn versions(&self) -> Vec<(KeyVersion, StateKey)> {
+        let first = self.latest.saturating_sub(KEY_VERSIONS_WINDOW as KeyVersion - 1);
+        (first..=self.latest).map(|version| (version, self.keys[version as usize % KEY_VERSIONS_WINDOW])).collect()
+    }
 }
 
 /// The state keys root path is guaranteed to exist of the enclave was initialized
@@ -33,108 +92,126 @@ fn get_state_keys_root_path() -> path::PathBuf {
     path_buf
 }
 
-fn get_document_path(sc_addr: &ContractAddress) -> path::PathBuf {
+/// Where the state key of a contract was sealed before keys could be rotated, it's read as the first version.
+fn get_legacy_document_path(sc_addr: &ContractAddress) -> path::PathBuf {
     get_state_keys_root_path().join(format!("{}.{}", sc_addr.to_hex(), "sealed"))
 }
 
-/// Read state keys from the cache and sealed documents.
-/// Adds keys to the cache after unsealing.
-fn get_state_keys(keys_map: &mut HashMap<ContractAddress, StateKey>,
-                  sc_addrs: &[ContractAddress]) -> Result<Vec<Option<StateKey>>, EnclaveError> {
-    let mut results: Vec<Option<StateKey>> = Vec::new();
-    for &addr in sc_addrs {
-        let key = match keys_map.get(&addr) {
-            Some(&key) => Some(key),
-            None => {
-                println!("State key not found in cache, fetching sealed document.");
-                let path = get_document_path(&addr);
-                if is_document(&path) {
-                    println!("Unsealing state key.");
-                    let mut sealed_log_out = [0u8; SEAL_LOG_SIZE];
-                    load_sealed_document(&path, &mut sealed_log_out)?;
-                    let doc = SealedDocumentStorage::<StateKey>::unseal(&mut sealed_log_out)?;
-                    match doc {
-                        Some(doc) => {
-                            keys_map.insert(addr, doc.data);
-                            Some(doc.data)
-                        }
-                        None => {
-                            println!("State key {:?} does not exist", addr);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                }
-            }
-        };
+fn get_document_path(sc_addr: &ContractAddress) -> path::PathBuf {
+    get_state_keys_root_path().join(format!("{}.{}", sc_addr.to_hex(), "keys.sealed"))
+}
+
+fn seal_state_key_versions(sc_addr: &ContractAddress, versions: StateKeyVersions) -> Result<(), EnclaveError> {
+    save_document(&get_document_path(sc_addr), STATE_KEY_VERSIONS_DOCUMENT, &versions.to_bytes())
+}
+
+fn unseal_state_key_versions(sc_addr: &ContractAddress) -> Result<Option<StateKeyVersions>, EnclaveError> {
+    if let Some(payload) = load_document(&get_document_path(sc_addr), STATE_KEY_VERSIONS_DOCUMENT, Some(LegacyFormat::Storage))? {
+        println!("Unsealed state key versions.");
+        return Ok(Some(StateKeyVersions::from_bytes(&payload)?));
+    }
+    if let Some(payload) = load_document(&get_legacy_document_path(sc_addr), STATE_KEY_VERSIONS_DOCUMENT, Some(LegacyFormat::Storage))? {
+        println!("Unsealed state key.");
+        if payload.len() != 32 {
+            return Err(SystemError(KeyProvisionError { err: format!("Invalid sealed state key of {} bytes", payload.len()) }));
+        }
+        let mut key: StateKey = [0; 32];
+        key.copy_from_slice(&payload);
+        // Moving the key to the versioned document so it can be rotated
+        let versions = StateKeyVersions::new(key);
+        seal_state_key_versions(sc_addr, versions)?;
+        return Ok(Some(versions));
+    }
+    Ok(None)
+}
+
+/// Read state keys from the sealed documents.
+fn get_state_keys(sc_addrs: &[ContractAddress]) -> Result<Vec<Option<StateKeyVersions>>, EnclaveError> {
+    let mut results: Vec<Option<StateKeyVersions>> = Vec::new();
+    for addr in sc_addrs {
+        let key = unseal_state_key_versions(addr)?;
+        if key.is_none() {
+            println!("State key {:?} does not exist", addr);
+        }
         results.push(key);
     }
     Ok(results)
 }
 
-/// Creates new state keys both in the cache and as sealed documents
-fn new_state_keys(keys_map: &mut HashMap<ContractAddress, StateKey>,
-                  sc_addrs: &[ContractAddress]) -> Result<Vec<StateKey>, EnclaveError> {
-    let mut results: Vec<StateKey> = Vec::new();
-    for &addr in sc_addrs {
-        let mut doc: SealedDocumentStorage<StateKey> = SealedDocumentStorage {
-            version: 0x1234, // TODO: what's this?
-            data: [0; 32],
-        };
+/// Creates new state keys as sealed documents
+fn new_state_keys(sc_addrs: &[ContractAddress]) -> Result<Vec<StateKeyVersions>, EnclaveError> {
+    let mut results: Vec<StateKeyVersions> = Vec::new();
+    for addr in sc_addrs {
         // Generate a new key randomly
-        rsgx_read_rand(&mut doc.data)?;
+        let mut key: StateKey = [0; 32];
+        rsgx_read_rand(&mut key)?;
+        let versions = StateKeyVersions::new(key);
+        seal_state_key_versions(addr, versions)?;
+        println!("Initial key stored successfully");
+        results.push(versions);
+    }
+    Ok(results)
+}
 
-        let mut sealed_log_in = [0u8; SEAL_LOG_SIZE];
-        doc.seal(&mut sealed_log_in)?;
-        // Save sealed_log to file
-        let path = get_document_path(&addr);
-        save_sealed_document(&path, &sealed_log_in)?;
-        // Add to cache
-        match keys_map.insert(addr, doc.data) {
-            Some(prev) => println!("New key stored successfully, previous key: {:?}", prev), // TODO: What is that?
-            None => println!("Initial key stored successfully"),
-        }
-        results.push(doc.data);
+/// Issues a new version of the state keys of existing contracts.
+/// The previous versions are still served so the workers can decrypt what was written with them.
+fn rotate_state_keys(sc_addrs: &[ContractAddress]) -> Result<Vec<StateKeyVersions>, EnclaveError> {
+    let mut results: Vec<StateKeyVersions> = Vec::new();
+    let current = get_state_keys(sc_addrs)?;
+    for (&addr, versions) in sc_addrs.iter().zip(current.into_iter()) {
+        let mut versions = versions.ok_or_else(|| {
+            SystemError(KeyProvisionError { err: format!("Cannot rotate a state key that doesn't exist: {:?}", addr.to_hex()) })
+        })?;
+        let mut key: StateKey = [0; 32];
+        rsgx_read_rand(&mut key)?;
+        versions.rotate(key);
+        seal_state_key_versions(&addr, versions)?;
+        println!("Rotated the state key of {:?} to version {}", addr.to_hex(), versions.latest);
+        results.push(versions);
     }
     Ok(results)
 }
 
-fn build_get_state_keys_response(sc_addrs: Vec<ContractAddress>) -> Result<Vec<(ContractAddress, StateKey)>, EnclaveError> {
-    let mut response_data: Vec<(ContractAddress, StateKey)> = Vec::new();
+fn build_get_state_keys_response(sc_addrs: Vec<ContractAddress>) -> Result<Vec<(ContractAddress, KeyVersion, StateKey)>, EnclaveError> {
+    let mut response_data: Vec<(ContractAddress, KeyVersion, StateKey)> = Vec::new();
     if sc_addrs.is_empty() {
         return Ok(response_data);
     }
-    let mut guard = STATE_KEY_STORE.lock_expect("State Key Store");
-    let keys = get_state_keys(&mut guard, &sc_addrs)?;
+    let _guard = STATE_KEYS_LOCK.lock_expect("State Keys Lock");
+    let keys = get_state_keys(&sc_addrs)?;
     // Create the state keys not found in storage
-    let mut new_addrs: Vec<ContractAddress> = Vec::new();
-    for (i, key) in keys.iter().enumerate() {
-        if key.is_none() {
-            new_addrs.push(sc_addrs[i]);
-        }
-    }
-    new_state_keys(&mut guard, &new_addrs)?; // If the vector is empty this won't do anything.
-                                             // Now we have keys for all addresses in cache
-    for addr in sc_addrs {
-        match guard.get(&addr) {
-            Some(&key) => response_data.push((addr, key)),
-            None => {
-                return Err(SystemError(KeyProvisionError { err: format!("State key not found in cache: {:?}", addr.to_hex()) }));
-            }
+    let new_addrs: Vec<ContractAddress> =
+        sc_addrs.iter().zip(keys.iter()).filter(|(_, key)| key.is_none()).map(|(&addr, _)| addr).collect();
+    let mut new_keys = new_state_keys(&new_addrs)?.into_iter(); // If the vector is empty this won't do anything.
+    for (addr, key) in sc_addrs.into_iter().zip(keys.into_iter()) {
+        // The new keys were created in the order of the missing ones
+        let versions = match key.or_else(|| new_keys.next()) {
+            Some(versions) => versions,
+            None => return Err(SystemError(KeyProvisionError { err: format!("State key not found: {:?}", addr.to_hex()) })),
+        };
+        for (version, key) in versions.versions() {
+            response_data.push((addr, version, key));
         }
     }
     Ok(response_data)
 }
 
+pub(crate) fn ecall_rotate_state_keys_internal(sc_addrs: &[ContractAddress]) -> Result<(), EnclaveError> {
+    let _guard = STATE_KEYS_LOCK.lock_expect("State Keys Lock");
+    rotate_state_keys(sc_addrs)?;
+    Ok(())
+}
+
 /// Get encrypted state keys
 
 pub(crate) fn ecall_get_enc_state_keys_internal(
-    msg_bytes: &[u8], addrs_bytes: Vec<ContractAddress>, sig: [u8; 65], sig_out: &mut [u8; 65],
+    msg_bytes: &[u8], addrs_bytes: Vec<ContractAddress>, sig: [u8; 65], block_number: Option<U256>, sig_out: &mut [u8; 65],
 ) -> Result<Vec<u8>, EnclaveError> {
     let msg = PrincipalMessage::from_message(msg_bytes)?;
     let user_pubkey = msg.get_pubkey();
     let msg_id = msg.get_id();
+    // The worker signs the hash of the request (`to_sign`), not its serialization.
+    let worker = H160::from(KeyPair::recover_address(&msg.to_sign()?, sig)?);
     let sc_addrs: Vec<ContractAddress> = match msg.data {
         PrincipalMessageType::Request(Some(addrs)) => addrs,
         PrincipalMessageType::Request(None) => addrs_bytes,
@@ -142,19 +219,20 @@ pub(crate) fn ecall_get_enc_state_keys_internal(
             return Err(SystemError(KeyProvisionError { err: format!("Unable to deserialize message: {:?}", msg_bytes) }));
         }
     };
-    let recovered = KeyPair::recover(&msg_bytes, sig)?;
-    println!("Recovered signer address from the message signature: {:?}", recovered.address());
-    // TODO: Verify that the worker is selected for all addresses or throw
+    println!("Recovered signer address from the message signature: {:?}", worker);
+    // Only the selected worker of a contract is allowed to get its state key
+    let epoch = verify_worker_selection(worker, &sc_addrs, block_number)?;
     let response_data = build_get_state_keys_response(sc_addrs)?;
 
     // Generate the encryption key material
     let key_pair = KeyPair::new()?;
-    let derived_key = key_pair.derive_key(&user_pubkey)?;
+    let derived_key = PrincipalResponseKey::derive(&key_pair, &user_pubkey)?;
 
     // Create the response message
-    let response_msg_data = PrincipalMessageType::Response(response_data);
+    let response_msg_data = PrincipalMessageType::VersionedResponse(response_data);
     let pubkey = key_pair.get_pubkey();
-    let response_msg = PrincipalMessage::new_id(response_msg_data, msg_id, pubkey);
+    // The epoch is signed along with the keys, the worker drops the keys of previous epochs when it gets them.
+    let response_msg = PrincipalMessage::new_id(response_msg_data, msg_id, pubkey).with_epoch(epoch.low_u64());
     // Generate the iv from the first 12 bytes of a new random number
     let response = response_msg.encrypt(&derived_key)?.into_message()?;
     println!("The partially encrypted response: {:?}", response.to_hex());
@@ -166,7 +244,9 @@ pub(crate) fn ecall_get_enc_state_keys_internal(
 
 pub mod tests {
     use super::*;
+    use enigma_tools_m::keeper_types::InputWorkerParams;
     use enigma_tools_t::common::FromHex;
+    use epoch_keeper_t::{epoch_t::Epoch, EPOCH};
 
     // noinspection RsTypeCheck
     pub fn test_state_keys_storage() {
@@ -180,24 +260,116 @@ pub mod tests {
             a.copy_from_slice(addr);
             sc_addrs.push(a);
         }
-        let mut guard = STATE_KEY_STORE.lock_expect("State Key Store");
-        let new_keys = new_state_keys(&mut guard, &sc_addrs).expect("Unable to store state keys");
-
-        let cached_keys = get_state_keys(&mut guard, &sc_addrs)
-            .expect("Unable to get state keys from cache")
-            .iter()
-            .map(|k| k.unwrap())
-            .collect::<Vec<StateKey>>();
-        assert_eq!(new_keys, cached_keys);
-
-        // Clearing the cache to test retrieval of sealed keys form disk
-        guard.clear();
+        let _guard = STATE_KEYS_LOCK.lock_expect("State Keys Lock");
+        let new_keys = new_state_keys(&sc_addrs).expect("Unable to store state keys");
 
-        let stored_keys = get_state_keys(&mut guard, &sc_addrs)
+        let stored_keys = get_state_keys(&sc_addrs)
             .expect("Unable to get state keys from sealed files")
             .iter()
             .map(|k| k.unwrap())
-            .collect::<Vec<StateKey>>();
+            .collect::<Vec<StateKeyVersions>>();
         assert_eq!(new_keys, stored_keys);
     }
+
+    pub fn test_legacy_state_key_migration() {
+        use enigma_tools_t::document_storage_t::{save_sealed_document, SealedDocumentStorage, SEAL_LOG_SIZE};
+        use std::untrusted::fs::remove_file;
+
+        let sc_addr = ContractAddress::from([0xcc; 32]);
+        let legacy = SealedDocumentStorage { version: 0x1234, data: [0xdd; 32] };
+        let mut sealed_log_in = [0u8; SEAL_LOG_SIZE];
+        legacy.seal(&mut sealed_log_in).unwrap();
+        save_sealed_document(&get_legacy_document_path(&sc_addr), &sealed_log_in).unwrap();
+        let _ = remove_file(&get_document_path(&sc_addr));
+
+        let _guard = STATE_KEYS_LOCK.lock_expect("State Keys Lock");
+        let versions = get_state_keys(&[sc_addr]).unwrap()[0].unwrap();
+        assert_eq!(versions, StateKeyVersions::new(legacy.data));
+        // The key can be rotated after the migration
+        rotate_state_keys(&[sc_addr]).unwrap();
+        let rotated = get_state_keys(&[sc_addr]).unwrap()[0].unwrap();
+        assert_eq!(rotated.versions()[0], (0, legacy.data));
+        assert_eq!(rotated.latest, 1);
+    }
+
+    pub fn test_rotate_state_keys() {
+        let sc_addr = ContractAddress::from([0xaa; 32]);
+        let guard = STATE_KEYS_LOCK.lock_expect("State Keys Lock");
+        let first = new_state_keys(&[sc_addr]).unwrap()[0];
+        assert_eq!(first.versions(), vec![(0, first.latest_key())]);
+
+        let mut rotated = first;
+        for _ in 0..KEY_VERSIONS_WINDOW {
+            rotated = rotate_state_keys(&[sc_addr]).unwrap()[0];
+        }
+        let latest = KEY_VERSIONS_WINDOW as KeyVersion;
+        assert_eq!(rotated.latest, latest);
+        assert_ne!(rotated.latest_key(), first.latest_key());
+        let versions = rotated.versions();
+        assert_eq!(versions.len(), KEY_VERSIONS_WINDOW);
+        assert_eq!(versions.last().unwrap(), &(latest, rotated.latest_key()));
+        // The first version is out of the window
+        assert!(versions.iter().all(|&(version, key)| version != 0 && key != first.latest_key()));
+
+        // The rotated keys are sealed
+        assert_eq!(get_state_keys(&[sc_addr]).unwrap(), vec![Some(rotated)]);
+
+        let unknown = ContractAddress::from([0xbb; 32]);
+        assert!(rotate_state_keys(&[unknown]).is_err());
+
+        // A rotation by another instance of the enclave is served right away, as it's read from the sealed document
+        drop(guard);
+        ecall_rotate_state_keys_internal(&[sc_addr]).unwrap();
+        let response = build_get_state_keys_response(vec![sc_addr]).unwrap();
+        assert_eq!(response.last().unwrap().1, latest + 1);
+    }
+
+    pub fn test_get_enc_state_keys_worker_auth() {
+        let worker = KeyPair::new().unwrap();
+        let worker_addr = H160::from(worker.get_pubkey().address());
+        let worker_params = InputWorkerParams {
+            block_number: U256::from(1),
+            workers: vec![worker_addr, H160::from(1)],
+            stakes: vec![U256::from(1), U256::from(1)],
+        };
+        let epoch = Epoch { nonce: U256::max_value(), seed: U256::from(1), worker_params, group_size: 1 };
+        let sc_addrs: Vec<ContractAddress> = (0..8u8).map(|i| ContractAddress::from([i; 32])).collect();
+        let (allowed, denied): (Vec<ContractAddress>, Vec<ContractAddress>) =
+            sc_addrs.iter().cloned().partition(|&addr| epoch.get_selected_worker(addr).unwrap() == worker_addr);
+        assert!(!allowed.is_empty() && !denied.is_empty());
+        EPOCH.lock_expect("Epoch").insert(epoch.nonce, epoch.clone());
+
+        // Built the way the worker's `ecall_ptt_req_internal` builds it: a new DH key, signed over `to_sign`
+        let request = |addrs: &[ContractAddress]| {
+            let dh_keys = KeyPair::new().unwrap();
+            let req = PrincipalMessage::new(PrincipalMessageType::Request(Some(addrs.to_vec())), dh_keys.get_pubkey()).unwrap();
+            let sig = worker.sign(&req.to_sign().unwrap()).unwrap();
+            let msg = req.into_message().unwrap();
+            (msg, sig)
+        };
+        let (allowed_msg, allowed_sig) = request(&allowed);
+        let allowed_res = ecall_get_enc_state_keys_internal(&allowed_msg, Vec::new(), allowed_sig, None, &mut [0u8; 65]);
+        let (mixed_msg, mixed_sig) = request(&sc_addrs);
+        let mixed_res = ecall_get_enc_state_keys_internal(&mixed_msg, Vec::new(), mixed_sig, None, &mut [0u8; 65]);
+        // A signature over the serialized message isn't the worker's signature of the request
+        let raw_sig = worker.sign(&allowed_msg).unwrap();
+        let raw_res = ecall_get_enc_state_keys_internal(&allowed_msg, Vec::new(), raw_sig, None, &mut [0u8; 65]);
+        EPOCH.lock_expect("Epoch").remove(&epoch.nonce);
+
+        let response = allowed_res.expect("The selected worker should get the state keys");
+        match raw_res {
+            Err(SystemError(WorkerAuthError { .. })) => (),
+            other => panic!("Expected a WorkerAuthError, got: {:?}", other),
+        }
+        // The response says which epoch the keys were issued for
+        assert_eq!(PrincipalMessage::from_message(&response).unwrap().get_epoch(), Some(1));
+        match mixed_res {
+            Err(SystemError(WorkerAuthError { err })) => {
+                for addr in &denied {
+                    assert!(err.contains(&addr.to_hex()));
+                }
+            }
+            other => panic!("Expected a WorkerAuthError, got: {:?}", other),
+        }
+    }
 }
@@ -5,6 +5,7 @@
 #![cfg_attr(not(feature = "std"), feature(alloc))]
 #![feature(tool_lints)]
 #![feature(try_from)]
+#![feature(int_to_from_bytes)]
 #![deny(unused_extern_crates)]
 
 extern crate enigma_crypto;
@@ -21,11 +22,17 @@ extern crate sgx_tstd as std;
 extern crate sgx_tunittest;
 extern crate sgx_types;
 
-use crate::{epoch_keeper_t::ecall_set_worker_params_internal, keys_keeper_t::ecall_get_enc_state_keys_internal};
+use crate::{
+    epoch_keeper_t::{
+        ecall_get_epoch_internal, ecall_seal_epoch_state_internal, ecall_set_worker_params_internal, ecall_unseal_epoch_state_internal,
+    },
+    keys_keeper_t::{ecall_get_enc_state_keys_internal, ecall_rotate_state_keys_internal},
+};
 use enigma_crypto::asymmetric;
 use enigma_tools_m::utils::EthereumAddress;
 use enigma_tools_t::{esgx::ocalls_t, quote_t, storage_t};
 use enigma_types::{ContractAddress, EnclaveReturn};
+use ethereum_types::U256;
 use sgx_types::{sgx_report_t, sgx_status_t, sgx_target_info_t};
 use std::{mem, slice};
 
@@ -58,26 +65,67 @@ fn get_sealed_keys_wrapper() -> asymmetric::KeyPair {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_set_worker_params(worker_params_rlp: *const u8, worker_params_rlp_len: usize,
+pub unsafe extern "C" fn ecall_set_worker_params(worker_params_rlp: *const u8, worker_params_rlp_len: usize, group_size: u64,
                                                  rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32],
                                                  sig_out: &mut [u8; 65]) -> EnclaveReturn {
     // Assembling byte arrays with the RLP data
     let worker_params_rlp = slice::from_raw_parts(worker_params_rlp, worker_params_rlp_len);
 
-    match ecall_set_worker_params_internal(worker_params_rlp, rand_out, nonce_out, sig_out) {
+    match ecall_set_worker_params_internal(worker_params_rlp, group_size, rand_out, nonce_out, sig_out) {
         Ok(_) => println!("Worker parameters set successfully"),
         Err(err) => return err.into(),
     };
     EnclaveReturn::Success
 }
 
+#[no_mangle]
+pub extern "C" fn ecall_get_epoch(seed_out: &mut [u8; 32], nonce_out: &mut [u8; 32]) -> EnclaveReturn {
+    match ecall_get_epoch_internal(seed_out, nonce_out) {
+        Ok(_) => EnclaveReturn::Success,
+        Err(err) => err.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_seal_epoch_state(epoch_state: *const u8, epoch_state_len: usize, serialized_ptr: *mut u64) -> EnclaveReturn {
+    let epoch_state = slice::from_raw_parts(epoch_state, epoch_state_len);
+    let sealed = match ecall_seal_epoch_state_internal(epoch_state) {
+        Ok(sealed) => sealed,
+        Err(err) => return err.into(),
+    };
+    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&sealed) {
+        Ok(ptr) => ptr,
+        Err(e) => return e.into(),
+    };
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_unseal_epoch_state(sealed: *mut u8, sealed_len: usize, serialized_ptr: *mut u64) -> EnclaveReturn {
+    let sealed = slice::from_raw_parts_mut(sealed, sealed_len);
+    let epoch_state = match ecall_unseal_epoch_state_internal(sealed) {
+        Ok(epoch_state) => epoch_state,
+        Err(err) => {
+            println!("{:?}", err);
+            return err.into();
+        }
+    };
+    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&epoch_state) {
+        Ok(ptr) => ptr,
+        Err(e) => return e.into(),
+    };
+    EnclaveReturn::Success
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn ecall_get_enc_state_keys(msg: *const u8, msg_len: usize,
-                                                  addrs: *const u8, addrs_len: usize, sig: &[u8; 65],
+                                                  addrs: *const u8, addrs_len: usize, sig: &[u8; 65], block_number: *const u64,
                                                   serialized_ptr: *mut u64, sig_out: &mut [u8; 65]) -> EnclaveReturn {
     let msg_bytes = slice::from_raw_parts(msg, msg_len);
     let addrs_bytes = slice::from_raw_parts(addrs as *const ContractAddress, addrs_len / mem::size_of::<ContractAddress>()).to_vec();
-    let response = match ecall_get_enc_state_keys_internal(msg_bytes, addrs_bytes, *sig, sig_out) {
+    // A null block number means the latest epoch
+    let block_number = if block_number.is_null() { None } else { Some(U256::from(*block_number)) };
+    let response = match ecall_get_enc_state_keys_internal(msg_bytes, addrs_bytes, *sig, block_number, sig_out) {
         Ok(response) => response,
         Err(err) => {
             println!("{:?}", err);
@@ -92,6 +140,18 @@ pub unsafe extern "C" fn ecall_get_enc_state_keys(msg: *const u8, msg_len: usize
     EnclaveReturn::Success
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn ecall_rotate_state_keys(addrs: *const u8, addrs_len: usize) -> EnclaveReturn {
+    let addrs = slice::from_raw_parts(addrs as *const ContractAddress, addrs_len / mem::size_of::<ContractAddress>());
+    match ecall_rotate_state_keys_internal(addrs) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(err) => {
+            println!("{:?}", err);
+            err.into()
+        }
+    }
+}
+
 pub mod tests {
 
     use crate::{epoch_keeper_t::tests::*, keys_keeper_t::tests::*};
@@ -104,8 +164,20 @@ pub mod tests {
         rsgx_unit_tests!(
             test_full_sealing_storage,
             test_document_sealing_storage,
+            test_bytes_sealing_storage,
+            test_versioned_document_sealing,
+            test_legacy_document_migration,
             test_get_epoch_worker_internal,
-            test_state_keys_storage
+            test_epoch_sealing,
+            test_get_epoch_by_block_number,
+            test_epoch_retention,
+            test_verify_selected_worker,
+            test_verify_selected_group,
+            test_seal_epoch_state,
+            test_state_keys_storage,
+            test_rotate_state_keys,
+            test_legacy_state_key_migration,
+            test_get_enc_state_keys_worker_auth
         );
     }
 }
@@ -1,7 +1,8 @@
+use data::{open, prefix_key_version, seal, split_key_version, EncryptedData};
 use enigma_tools_t::common::errors_t::EnclaveError;
 use enigma_crypto::hash::Keccak256;
-use enigma_crypto::{symmetric, Encryption};
-use enigma_types::{Hash256, ContractAddress, StateKey};
+use enigma_crypto::Encryption;
+use enigma_types::{Hash256, ContractAddress, KeyVersion, StateKey};
 use json_patch;
 use rmps::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
@@ -22,11 +23,34 @@ pub struct EncryptedPatch {
     pub data: Vec<u8>,
     pub contract_address: ContractAddress,
     pub index: u32,
+    /// The version of the state key the patch is encrypted with.
+    #[serde(default)]
+    pub key_version: KeyVersion,
+    /// Whether the patch is bound to its contract and index through the AAD, patches written before that aren't.
+    #[serde(default)]
+    pub aad_bound: bool,
 }
 
 impl EncryptedPatch {
+    /// The hash of the patch as it's stored, so it commits to the key version too.
+    /// Patches written before the key version prefix existed keep the hash of their ciphertext,
+    /// which is the one their successors and the Enigma contract refer to.
     pub fn keccak256_patch(&self) -> Hash256 {
-        self.data.keccak256()
+        if self.aad_bound {
+            self.to_stored_bytes().keccak256()
+        } else {
+            self.data.keccak256()
+        }
+    }
+
+    /// The patch as it's saved in the DB and published to other workers.
+    pub fn to_stored_bytes(&self) -> Vec<u8> {
+        prefix_key_version(self.key_version, self.aad_bound, &self.data)
+    }
+
+    pub fn from_stored_bytes(stored: &[u8], contract_address: ContractAddress, index: u32) -> Result<Self, EnclaveError> {
+        let (key_version, aad_bound, data) = split_key_version(stored)?;
+        Ok(EncryptedPatch { data, contract_address, index, key_version, aad_bound })
     }
 }
 
@@ -34,14 +58,16 @@ impl<'a> Encryption<&'a StateKey, EnclaveError, EncryptedPatch, [u8; 12]> for St
     fn encrypt_with_nonce(self, key: &StateKey, _iv: Option<[u8; 12]>) -> Result<EncryptedPatch, EnclaveError> {
         let mut buf = Vec::new();
         self.serialize(&mut Serializer::new(&mut buf))?;
-        let data = symmetric::encrypt_with_nonce(&buf, key, _iv)?;
         let contract_address = self.contract_address;
         let index = self.index;
-        Ok(EncryptedPatch { data, contract_address, index })
+        let aad = EncryptedData::Delta(index).aad(&contract_address);
+        let data = seal(&buf, key, &aad, _iv)?;
+        Ok(EncryptedPatch { data, contract_address, index, key_version: 0, aad_bound: true })
     }
 
     fn decrypt(enc: EncryptedPatch, key: &StateKey) -> Result<Self, EnclaveError> {
-        let dec = symmetric::decrypt(&enc.data, key)?;
+        let aad = EncryptedData::Delta(enc.index).aad(&enc.contract_address);
+        let dec = open(&enc.data, key, enc.aad_bound, &aad)?;
         let mut des = Deserializer::new(&dec[..]);
         let mut back: Self = Deserialize::deserialize(&mut des)?;
         back.contract_address = enc.contract_address;
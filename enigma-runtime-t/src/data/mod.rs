@@ -3,8 +3,38 @@ mod state;
 
 pub use data::delta::{EncryptedPatch, StatePatch};
 pub use data::state::{ContractState, EncryptedContractState};
+use enigma_crypto::{symmetric::{self, Cipher}, CryptoError};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*};
+use enigma_types::{ContractAddress, KeyVersion, StateKey, AAD_BOUND_FLAG};
 use serde::Deserialize;
 use serde_json::{Error, Value};
+use std::string::ToString;
+use std::vec::Vec;
+
+const KEY_VERSION_SIZE: usize = 4;
+
+/// What a ciphertext is bound to, so it can't be swapped with another one encrypted under the same key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EncryptedData {
+    /// A state, with the index of the last delta applied to it.
+    State(u32),
+    Delta(u32),
+}
+
+impl EncryptedData {
+    /// `type (1) || contract address (32) || delta index (4, big endian)`.
+    pub(crate) fn aad(self, contract_address: &ContractAddress) -> Vec<u8> {
+        let (data_type, index) = match self {
+            EncryptedData::State(index) => (0u8, index),
+            EncryptedData::Delta(index) => (1u8, index),
+        };
+        let mut aad = Vec::with_capacity(1 + contract_address.len() + 4);
+        aad.push(data_type);
+        aad.extend_from_slice(&contract_address[..]);
+        aad.extend_from_slice(&index.to_be_bytes());
+        aad
+    }
+}
 
 pub trait IOInterface<E, U> {
     fn read_key<T>(&self, key: &str) -> Result<T, Error> where for<'de> T: Deserialize<'de>;
@@ -17,10 +47,54 @@ pub trait DeltasInterface<E, T, K> {
     fn generate_delta_and_update_state(old: &Self, new: &mut Self, key: K) -> Result<T, E> where Self: Sized;
 }
 
+/// Encrypted states and deltas are stored (and shared with other workers) as the big endian key version
+/// followed by the ciphertext, so the key that decrypts them can be found after a rotation.
+/// The top bit of the version marks a ciphertext bound through the AAD, clearing it doesn't turn a bound ciphertext
+/// into an unbound one since it then fails to decrypt without its AAD. The unbound data written before the binding
+/// can still be swapped, which is why the `reject-unbound` feature refuses it once it was all rewritten.
+pub(crate) fn prefix_key_version(key_version: KeyVersion, aad_bound: bool, ciphertext: &[u8]) -> Vec<u8> {
+    let key_version = if aad_bound { key_version | AAD_BOUND_FLAG } else { key_version };
+    let mut res = Vec::with_capacity(KEY_VERSION_SIZE + ciphertext.len());
+    res.extend_from_slice(&key_version.to_be_bytes());
+    res.extend_from_slice(ciphertext);
+    res
+}
+
+/// Returns the key version, whether the ciphertext is bound through the AAD and the ciphertext.
+pub(crate) fn split_key_version(stored: &[u8]) -> Result<(KeyVersion, bool, Vec<u8>), EnclaveError> {
+    if stored.len() < KEY_VERSION_SIZE {
+        return Err(SystemError(StateError { err: "The stored data is too short to contain a key version".to_string() }));
+    }
+    let (version, ciphertext) = stored.split_at(KEY_VERSION_SIZE);
+    let mut version_bytes = [0u8; KEY_VERSION_SIZE];
+    version_bytes.copy_from_slice(version);
+    let key_version = KeyVersion::from_be_bytes(version_bytes);
+    Ok((key_version & !AAD_BOUND_FLAG, key_version & AAD_BOUND_FLAG != 0, ciphertext.to_vec()))
+}
+
+/// Decrypts a state or a delta, with its AAD if it's bound and without one if it was written before the binding.
+pub(crate) fn open(ciphertext: &[u8], key: &StateKey, aad_bound: bool, aad: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    if aad_bound {
+        Ok(symmetric::decrypt_with_aad(ciphertext, key, aad)?)
+    } else if cfg!(feature = "reject-unbound") {
+        Err(SystemError(StateError { err: "The data isn't bound to its contract and index through the AAD".to_string() }))
+    } else {
+        Ok(symmetric::decrypt(ciphertext, key)?)
+    }
+}
+
+/// Encrypts a state or a delta with the default cipher, a fixed nonce (for tests) is always used with AES-256-GCM.
+pub(crate) fn seal(buf: &[u8], key: &StateKey, aad: &[u8], iv: Option<[u8; 12]>) -> Result<Vec<u8>, CryptoError> {
+    match iv {
+        Some(iv) => symmetric::seal_with_cipher(Cipher::Aes256Gcm, buf, key, aad, Some(&iv)),
+        None => symmetric::seal(buf, key, aad, None),
+    }
+}
+
 #[cfg(debug_assertions)]
 pub mod tests {
     use crate::data::*;
-    use enigma_crypto::hash::Sha256;
+    use enigma_crypto::hash::{Keccak256, Sha256};
     use enigma_crypto::Encryption;
     use enigma_types::ContractAddress;
     use json_patch;
@@ -37,16 +111,21 @@ pub mod tests {
         let key = b"EnigmaMPC".sha256();
         let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
 
-        let enc_data = vec![197, 53, 186, 61, 17, 116, 238, 226, 187, 179, 66, 18, 156, 95, 182, 135, 157, 171, 159, 207, 39, 197, 204, 188, 170, 147, 3, 1, 22, 218, 163, 31, 219, 245, 18, 247, 68, 87, 160, 229, 125, 146, 160, 230, 154, 246, 169, 129, 162, 171, 195, 133, 120, 163, 23, 63, 162, 223, 160, 47, 195, 219, 14, 21, 182, 120, 195, 100, 170, 65, 203, 10, 7, 215, 228, 226, 110, 152, 175, 120, 234, 107, 79, 30, 205, 4, 253, 116, 236, 45, 189, 65, 97, 167, 218, 142, 21, 248, 238, 145, 206, 202, 148, 71, 163, 17, 251, 83, 255, 137, 33, 101, 112, 137, 139, 247, 211, 110, 253, 59, 19, 3, 173, 193, 148, 132, 196, 254, 190, 35, 51, 20, 157, 119, 201, 122, 175, 165, 99, 232, 37, 3, 168, 150, 165, 246, 226, 227, 100, 132, 142, 102, 65, 69, 92, 44, 226, 189, 117, 239, 54, 17, 156, 236, 224, 164, 6, 224, 38, 96, 166, 91, 172, 56, 80, 97, 142, 89, 176, 72, 18, 141, 174, 26, 108, 103, 239, 236, 174, 7, 151, 177, 57, 218, 16, 214, 248, 35, 165, 35, 201, 138, 77, 88, 189, 7, 13, 108, 64, 177, 214, 227, 205, 49, 245, 53, 16, 39, 44, 66, 201, 15, 104, 246, 187, 221, 238, 183, 14, 128, 47, 73, 207, 133, 152, 186, 61, 197, 73, 71, 98, 179, 136, 83, 28, 188, 226, 9, 216, 163, 42, 61, 135, 94, 235, 100, 71, 154, 102, 153, 217, 171, 73, 254, 52, 113, 183, 122, 237, 49, 150, 8, 124, 132, 107, 65, 140, 220, 53, 110, 220, 128, 136, 7, 52, 174, 144, 242, 66, 145, 250, 210, 169, 213, 240, 139, 164, 170, 196, 155, 240, 121, 73, 124, 166, 64, 52, 84, 55, 213, 146, 82, 150, 222, 8, 163, 215, 45, 220, 166, 28, 177, 136, 253, 239, 248, 196, 119, 148, 10, 185, 223, 53, 216, 242, 152, 215, 60, 235, 22, 212, 254, 99, 139, 251, 238, 174, 82, 115, 171, 239, 45, 99, 161, 133, 187, 118, 253, 174, 13, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
+        let enc_data = vec![236, 1, 197, 53, 186, 61, 17, 116, 238, 226, 187, 179, 66, 18, 156, 95, 182, 135, 157, 171, 159, 207, 39, 197, 204, 188, 170, 147, 3, 1, 22, 218, 163, 31, 219, 245, 18, 247, 68, 87, 160, 229, 125, 146, 160, 230, 154, 246, 169, 129, 162, 171, 195, 133, 120, 163, 23, 63, 162, 223, 160, 47, 195, 219, 14, 21, 182, 120, 195, 100, 170, 65, 203, 10, 7, 215, 228, 226, 110, 152, 175, 120, 234, 107, 79, 30, 205, 4, 253, 116, 236, 45, 189, 65, 97, 167, 218, 142, 21, 248, 238, 145, 206, 202, 148, 71, 163, 17, 251, 83, 255, 137, 33, 101, 112, 137, 139, 247, 211, 110, 253, 59, 19, 3, 173, 193, 148, 132, 196, 254, 190, 35, 51, 20, 157, 119, 201, 122, 175, 165, 99, 232, 37, 3, 168, 150, 165, 246, 226, 227, 100, 132, 142, 102, 65, 69, 92, 44, 226, 189, 117, 239, 54, 17, 156, 236, 224, 164, 6, 224, 38, 96, 166, 91, 172, 56, 80, 97, 142, 89, 176, 72, 18, 141, 174, 26, 108, 103, 239, 236, 174, 7, 151, 177, 57, 218, 16, 214, 248, 35, 165, 35, 201, 138, 77, 88, 189, 7, 13, 108, 64, 177, 214, 227, 205, 49, 245, 53, 16, 39, 44, 66, 201, 15, 104, 246, 187, 221, 238, 183, 14, 128, 47, 73, 207, 133, 152, 186, 61, 197, 73, 71, 98, 179, 136, 83, 28, 188, 226, 9, 216, 163, 42, 61, 135, 94, 235, 100, 71, 154, 102, 153, 217, 171, 73, 254, 52, 113, 183, 122, 237, 49, 150, 8, 124, 132, 107, 65, 140, 220, 53, 110, 220, 128, 136, 7, 52, 174, 144, 242, 66, 145, 250, 210, 169, 213, 240, 139, 164, 170, 196, 155, 240, 121, 73, 124, 166, 64, 52, 84, 55, 213, 146, 82, 150, 222, 8, 163, 215, 45, 220, 166, 28, 177, 136, 253, 239, 248, 196, 119, 148, 10, 185, 223, 53, 216, 242, 152, 215, 60, 235, 22, 212, 254, 99, 139, 4, 107, 227, 102, 185, 163, 203, 39, 203, 224, 13, 214, 174, 119, 18, 220, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
         let enc_contract = con.encrypt_with_nonce(&key, Some(iv)).unwrap();
-        assert_eq!(EncryptedContractState { contract_address, json: enc_data }, enc_contract)
+        assert_eq!(EncryptedContractState { contract_address, json: enc_data, key_version: 0, aad_bound: true, delta_index: 0 }, enc_contract)
     }
 
     pub fn test_decrypt_state() {
         let key = b"EnigmaMPC".sha256();
         let enc_data = vec![197, 53, 186, 61, 17, 116, 238, 226, 187, 179, 66, 18, 156, 95, 182, 135, 157, 171, 159, 207, 39, 197, 204, 188, 170, 147, 3, 1, 22, 218, 163, 31, 219, 245, 18, 247, 68, 87, 160, 229, 125, 146, 160, 230, 154, 246, 169, 129, 162, 171, 195, 133, 120, 163, 23, 63, 162, 223, 160, 47, 195, 219, 14, 21, 182, 120, 195, 100, 170, 65, 203, 10, 7, 215, 228, 226, 110, 152, 175, 120, 234, 107, 79, 30, 205, 4, 253, 116, 236, 45, 189, 65, 97, 167, 218, 142, 21, 248, 238, 145, 206, 202, 148, 71, 163, 17, 251, 83, 255, 137, 33, 101, 112, 137, 139, 247, 211, 110, 253, 59, 19, 3, 173, 193, 148, 132, 196, 254, 190, 35, 51, 20, 157, 119, 201, 122, 175, 165, 99, 232, 37, 3, 168, 150, 165, 246, 226, 227, 100, 132, 142, 102, 65, 69, 92, 44, 226, 189, 117, 239, 54, 17, 156, 236, 224, 164, 6, 224, 38, 96, 166, 91, 172, 56, 80, 97, 142, 89, 176, 72, 18, 141, 174, 26, 108, 103, 239, 236, 174, 7, 151, 177, 57, 218, 16, 214, 248, 35, 165, 35, 201, 138, 77, 88, 189, 7, 13, 108, 64, 177, 214, 227, 205, 49, 245, 53, 16, 39, 44, 66, 201, 15, 104, 246, 187, 221, 238, 183, 14, 128, 47, 73, 207, 133, 152, 186, 61, 197, 73, 71, 98, 179, 136, 83, 28, 188, 226, 9, 216, 163, 42, 61, 135, 94, 235, 100, 71, 154, 102, 153, 217, 171, 73, 254, 52, 113, 183, 122, 237, 49, 150, 8, 124, 132, 107, 65, 140, 220, 53, 110, 220, 128, 136, 7, 52, 174, 144, 242, 66, 145, 250, 210, 169, 213, 240, 139, 164, 170, 196, 155, 240, 121, 73, 124, 166, 64, 52, 84, 55, 213, 146, 82, 150, 222, 8, 163, 215, 45, 220, 166, 28, 177, 136, 253, 239, 248, 196, 119, 148, 10, 185, 223, 53, 216, 242, 152, 215, 60, 235, 22, 212, 254, 99, 139, 251, 238, 174, 82, 115, 171, 239, 45, 99, 161, 133, 187, 118, 253, 174, 13, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
         let contract_address = b"Enigma".sha256();
-        let enc = EncryptedContractState { contract_address, json: enc_data };
+        // A state encrypted before the binding, without an AAD
+        let enc = EncryptedContractState { contract_address, json: enc_data, key_version: 0, aad_bound: false, delta_index: 0 };
+        if cfg!(feature = "reject-unbound") {
+            assert!(ContractState::decrypt(enc, &key).is_err());
+            return;
+        }
         let result = ContractState {
             contract_address,
             json: json!({"widget":{"debug":"on","window":{"title":"Sample Konfabulator Widget","name":"main_window","width":500,"height":500},"image":{"src":"Images/Sun.png","name":"sun1","hOffset":250,"vOffset":250,"alignment":"center"},"text":{"data":"Click Here","size":36,"style":"bold","name":"text1","hOffset":250,"vOffset":100,"alignment":"center","onMouseUp":"sun1.opacity = (sun1.opacity / 100) * 90;"}}}),
@@ -70,6 +149,27 @@ pub mod tests {
         assert_eq!(ContractState::decrypt(enc, &key).unwrap(), con)
     }
 
+    pub fn test_bound_state_swap() {
+        let contract_address = b"Enigma".sha256();
+        let con = ContractState { contract_address, json: json!({ "a": 1 }), delta_hash: [4u8; 32].into(), delta_index: 7 };
+        let key = b"EnigmaMPC".sha256();
+        let mut enc = con.clone().encrypt(&key).unwrap();
+        enc.key_version = 3;
+        assert_eq!(enc.delta_index, 7);
+
+        let stored = EncryptedContractState::from_stored_bytes(&enc.to_stored_bytes(), contract_address).unwrap();
+        assert_eq!(stored, enc);
+        assert_eq!(ContractState::decrypt(stored, &key).unwrap(), con);
+
+        // The same ciphertext doesn't decrypt as another version of the state, for another contract or as an unbound state
+        let moved = EncryptedContractState { delta_index: 6, ..enc.clone() };
+        assert!(ContractState::decrypt(moved, &key).is_err());
+        let moved = EncryptedContractState { contract_address: [2u8; 32].into(), ..enc.clone() };
+        assert!(ContractState::decrypt(moved, &key).is_err());
+        let unbound = EncryptedContractState { aad_bound: false, ..enc };
+        assert!(ContractState::decrypt(unbound, &key).is_err());
+    }
+
     pub fn test_write_state() {
         let mut con = ContractState::new(b"Enigma".sha256());
         con.write_key("code", &json!(200)).unwrap();
@@ -112,8 +212,8 @@ pub mod tests {
         let key = b"EnigmaMPC".sha256();
         let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
 
-        let enc_data = vec![196, 39, 143, 237, 10, 117, 249, 235, 174, 84, 130, 219, 214, 92, 182, 148, 87, 171, 131, 69, 32, 201, 192, 190, 253, 176, 230, 5, 20, 221, 171, 31, 37, 51, 29, 231, 134, 147, 234, 255, 104, 144, 161, 110, 192, 28, 187, 143, 184, 188, 211, 219, 36, 117, 28, 51, 160, 204, 97, 250, 153, 193, 86, 194, 169, 111, 124, 202, 195, 44, 170, 109, 98, 164, 203, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 246, 97, 112, 71, 240, 162, 35, 176, 216, 26, 97, 90, 218, 197, 244, 94, 225, 184, 235, 75, 198, 205, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
-        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index };
+        let enc_data = vec![236, 1, 196, 39, 143, 237, 10, 117, 249, 235, 174, 84, 130, 219, 214, 92, 182, 148, 87, 171, 131, 69, 32, 201, 192, 190, 253, 176, 230, 5, 20, 221, 171, 31, 37, 51, 29, 231, 134, 147, 234, 255, 104, 144, 161, 110, 192, 28, 187, 143, 184, 188, 211, 219, 36, 117, 28, 51, 160, 204, 97, 250, 153, 193, 86, 194, 169, 111, 124, 202, 195, 44, 170, 109, 98, 164, 203, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 246, 97, 112, 71, 240, 162, 23, 157, 64, 220, 24, 242, 199, 70, 10, 52, 36, 93, 207, 114, 213, 82, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index, key_version: 0, aad_bound: true };
         let a = patch.encrypt_with_nonce(&key, Some(iv)).unwrap();
         assert_eq!(a, enc_patch)
     }
@@ -126,7 +226,12 @@ pub mod tests {
         let key = b"EnigmaMPC".sha256();
         let enc_data = vec![196, 39, 143, 237, 10, 117, 249, 235, 174, 84, 130, 219, 214, 92, 182, 148, 87, 171, 131, 69, 32, 201, 192, 190, 253, 176, 230, 5, 20, 221, 171, 31, 37, 51, 29, 231, 134, 147, 234, 255, 104, 144, 161, 110, 192, 28, 187, 143, 184, 188, 211, 219, 36, 117, 28, 51, 160, 204, 97, 250, 153, 193, 86, 194, 169, 111, 124, 202, 195, 44, 170, 109, 98, 164, 203, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 246, 97, 112, 71, 240, 162, 35, 176, 216, 26, 97, 90, 218, 197, 244, 94, 225, 184, 235, 75, 198, 205, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
 
-        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index: 0 };
+        // A patch encrypted before the binding, without an AAD
+        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index: 0, key_version: 0, aad_bound: false };
+        if cfg!(feature = "reject-unbound") {
+            assert!(StatePatch::decrypt(enc_patch, &key).is_err());
+            return;
+        }
         let dec = StatePatch::decrypt(enc_patch, &key).unwrap();
         assert_eq!(patch, dec)
     }
@@ -142,6 +247,27 @@ pub mod tests {
         assert_eq!(patch, StatePatch::decrypt(enc, &key).unwrap())
     }
 
+    pub fn test_bound_patch_swap() {
+        let s = "[{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"}]";
+        let contract_address: ContractAddress = [1u8; 32].into();
+        let patch = StatePatch { patch: serde_json::from_str(s).unwrap(), previous_hash: [0u8; 32].into(), contract_address, index: 5 };
+        let key = b"EnigmaMPC".sha256();
+        let mut enc = patch.clone().encrypt(&key).unwrap();
+        enc.key_version = 3;
+
+        let stored = EncryptedPatch::from_stored_bytes(&enc.to_stored_bytes(), contract_address, 5).unwrap();
+        assert_eq!(stored, enc);
+        assert_eq!(StatePatch::decrypt(stored, &key).unwrap(), patch);
+
+        // The same ciphertext doesn't decrypt at another index, for another contract or as an unbound patch
+        let moved = EncryptedPatch { index: 6, ..enc.clone() };
+        assert!(StatePatch::decrypt(moved, &key).is_err());
+        let moved = EncryptedPatch { contract_address: [2u8; 32].into(), ..enc.clone() };
+        assert!(StatePatch::decrypt(moved, &key).is_err());
+        let unbound = EncryptedPatch { aad_bound: false, ..enc };
+        assert!(StatePatch::decrypt(unbound, &key).is_err());
+    }
+
     pub fn test_apply_delta() {
         let p = "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]";
         let contract_address = b"Enigma".sha256();
@@ -155,7 +281,7 @@ pub mod tests {
             delta_hash: [4u8; 32].into(),
             delta_index: 0,
         };
-        contract.apply_delta(enc_patch, &key).unwrap();
+        contract.apply_delta(enc_patch, (0, &key)).unwrap();
         assert_eq!(
             contract,
             ContractState {
@@ -185,8 +311,26 @@ pub mod tests {
             delta_index: 0,
         };
 
-        let delta = ContractState::generate_delta_and_update_state(&before, &mut after, &key).unwrap();
+        let delta = ContractState::generate_delta_and_update_state(&before, &mut after, (0, &key)).unwrap();
         let delta = StatePatch::decrypt(delta, &key).unwrap();
         assert_eq!(delta, result);
     }
+
+    pub fn test_delta_hash_key_version() {
+        let contract_address = b"Enigma".sha256();
+        let key = [1u8; 32];
+        let before = ContractState { contract_address, json: json!({ "a": 1 }), ..Default::default() };
+        let mut after = ContractState { contract_address, json: json!({ "a": 2 }), ..Default::default() };
+        let delta = ContractState::generate_delta_and_update_state(&before, &mut after, (7, &key)).unwrap();
+        assert_eq!(delta.key_version, 7);
+        // The hash covers the stored delta, key version included
+        assert_eq!(after.delta_hash, delta.to_stored_bytes().keccak256());
+        assert_ne!(EncryptedPatch { key_version: 8, ..delta.clone() }.keccak256_patch(), after.delta_hash);
+
+        // A delta is only applied with the version of the key it's encrypted with
+        let mut state = before.clone();
+        assert!(state.apply_delta(delta.clone(), (8, &key)).is_err());
+        state.apply_delta(delta, (7, &key)).unwrap();
+        assert_eq!(state, after);
+    }
 }
@@ -1,7 +1,7 @@
 use crate::data::{DeltasInterface, IOInterface, StatePatch};
 use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*};
-use enigma_types::{ContractAddress, StateKey};
-use enigma_crypto::{symmetric, Encryption};
+use enigma_types::{ContractAddress, KeyVersion, StateKey};
+use enigma_crypto::Encryption;
 use enigma_types::Hash256;
 use json_patch;
 use rmps::{Deserializer, Serializer};
@@ -9,7 +9,9 @@ use serde::{Deserialize, Serialize};
 use serde_json::{from_value, Error, Value};
 use std::string::ToString;
 use std::vec::Vec;
-use data::EncryptedPatch;
+use data::{open, prefix_key_version, seal, split_key_version, EncryptedData, EncryptedPatch};
+
+const DELTA_INDEX_SIZE: usize = 4;
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
 pub struct ContractState {
@@ -24,6 +26,40 @@ pub struct ContractState {
 pub struct EncryptedContractState<T> {
     pub contract_address: ContractAddress,
     pub json: Vec<T>,
+    /// The version of the state key the state is encrypted with.
+    pub key_version: KeyVersion,
+    /// Whether the state is bound to its contract and delta index through the AAD, states written before that aren't.
+    pub aad_bound: bool,
+    /// The index of the last delta applied to the state, only known for a bound state.
+    pub delta_index: u32,
+}
+
+impl EncryptedContractState<u8> {
+    /// The state as it's saved in the DB, a bound state has its big endian delta index before the ciphertext.
+    pub fn to_stored_bytes(&self) -> Vec<u8> {
+        if !self.aad_bound {
+            return prefix_key_version(self.key_version, false, &self.json);
+        }
+        let mut data = Vec::with_capacity(DELTA_INDEX_SIZE + self.json.len());
+        data.extend_from_slice(&self.delta_index.to_be_bytes());
+        data.extend_from_slice(&self.json);
+        prefix_key_version(self.key_version, true, &data)
+    }
+
+    pub fn from_stored_bytes(stored: &[u8], contract_address: ContractAddress) -> Result<Self, EnclaveError> {
+        let (key_version, aad_bound, mut json) = split_key_version(stored)?;
+        let mut delta_index = 0;
+        if aad_bound {
+            if json.len() < DELTA_INDEX_SIZE {
+                return Err(SystemError(StateError { err: "The stored state is too short to contain a delta index".to_string() }));
+            }
+            let mut index_bytes = [0u8; DELTA_INDEX_SIZE];
+            index_bytes.copy_from_slice(&json[..DELTA_INDEX_SIZE]);
+            delta_index = u32::from_be_bytes(index_bytes);
+            json.drain(..DELTA_INDEX_SIZE);
+        }
+        Ok(EncryptedContractState { contract_address, json, key_version, aad_bound, delta_index })
+    }
 }
 
 impl ContractState {
@@ -55,8 +91,14 @@ impl IOInterface<EnclaveError, u8> for ContractState {
     }
 }
 
-impl<'a> DeltasInterface<EnclaveError, EncryptedPatch, &'a StateKey> for ContractState {
-    fn apply_delta(&mut self, delta: EncryptedPatch, key: &'a StateKey) -> Result<(), EnclaveError> {
+/// The deltas are generated and applied with a state key and its version.
+impl<'a> DeltasInterface<EnclaveError, EncryptedPatch, (KeyVersion, &'a StateKey)> for ContractState {
+    fn apply_delta(&mut self, delta: EncryptedPatch, (key_version, key): (KeyVersion, &'a StateKey)) -> Result<(), EnclaveError> {
+        if delta.key_version != key_version {
+            return Err(SystemError(StateError {
+                err: format!("The delta is encrypted with version {} of the state key, not {}", delta.key_version, key_version),
+            }));
+        }
         let delta_hash = delta.keccak256_patch();
         let dec_delta = StatePatch::decrypt(delta.clone(), key)?;
         if dec_delta.previous_hash != self.delta_hash {
@@ -68,7 +110,7 @@ impl<'a> DeltasInterface<EnclaveError, EncryptedPatch, &'a StateKey> for Contrac
         Ok(())
     }
 
-    fn generate_delta_and_update_state(old: &Self, new: &mut Self, key: &'a StateKey) -> Result<EncryptedPatch, EnclaveError> {
+    fn generate_delta_and_update_state(old: &Self, new: &mut Self, (key_version, key): (KeyVersion, &'a StateKey)) -> Result<EncryptedPatch, EnclaveError> {
         if old.delta_hash.is_zero() {
             new.delta_index = 0;
         } else {
@@ -80,7 +122,9 @@ impl<'a> DeltasInterface<EnclaveError, EncryptedPatch, &'a StateKey> for Contrac
             contract_address: old.contract_address,
             index: new.delta_index,
         };
-        let enc_delta = delta.encrypt(key)?;
+        let mut enc_delta = delta.encrypt(key)?;
+        // The hash of the delta covers its key version, so it's set before hashing
+        enc_delta.key_version = key_version;
         new.delta_hash = enc_delta.keccak256_patch();
         Ok(enc_delta)
     }
@@ -90,12 +134,15 @@ impl<'a> Encryption<&'a StateKey, EnclaveError, EncryptedContractState<u8>, [u8;
     fn encrypt_with_nonce(self, key: &StateKey, _iv: Option<[u8; 12]>) -> Result<EncryptedContractState<u8>, EnclaveError> {
         let mut buf = Vec::new();
         self.serialize(&mut Serializer::new(&mut buf))?;
-        let enc = symmetric::encrypt_with_nonce(&buf, key, _iv)?;
-        Ok(EncryptedContractState { contract_address: self.contract_address, json: enc })
+        let delta_index = self.delta_index;
+        let aad = EncryptedData::State(delta_index).aad(&self.contract_address);
+        let enc = seal(&buf, key, &aad, _iv)?;
+        Ok(EncryptedContractState { contract_address: self.contract_address, json: enc, key_version: 0, aad_bound: true, delta_index })
     }
 
     fn decrypt(enc: EncryptedContractState<u8>, key: &StateKey) -> Result<ContractState, EnclaveError> {
-        let dec = symmetric::decrypt(&enc.json, key)?;
+        let aad = EncryptedData::State(enc.delta_index).aad(&enc.contract_address);
+        let dec = open(&enc.json, key, enc.aad_bound, &aad)?;
         let mut des = Deserializer::new(&dec[..]);
         let mut state: ContractState = Deserialize::deserialize(&mut des)?;
         state.contract_address = enc.contract_address;
@@ -4,65 +4,21 @@ extern crate wasmi;
 use std::borrow::ToOwned;
 use std::cell::RefCell;
 
+use enigma_types::wasm::{self, signatures::{StaticSignature, ValueType}};
+use enigma_tools_t::common::errors_t::{EnclaveError::FailedTaskError, FailedTaskError::ResourceLimitError, WasmError};
+use std::boxed::Box;
+use std::string::ToString;
+use std::vec::Vec;
 use wasmi::{memory_units, Error, FuncInstance, FuncRef, MemoryDescriptor, MemoryInstance, MemoryRef, ModuleImportResolver, Signature};
 
-pub mod ids {
-    pub const RET_FUNC: usize = 1;
-    pub const WRITE_STATE_FUNC: usize = 2;
-    pub const READ_STATE_FUNC: usize = 3;
-    pub const READ_STATE_LEN_FUNC: usize = 4;
-    pub const EPRINT_FUNC: usize = 5;
-    pub const NAME_LENGTH_FUNC: usize = 6;
-    pub const NAME_FUNC: usize = 7;
-    pub const ARGS_FUNC: usize = 8;
-    pub const ARGS_LENGTH_FUNC: usize = 9;
-    pub const TYPES_LENGTH_FUNC: usize = 10;
-    pub const TYPES_FUNC: usize = 11;
-    pub const WRITE_ETH_BRIDGE_FUNC: usize = 12;
-    pub const REMOVE_STATE_FUNC: usize = 13;
-    pub const GAS_FUNC: usize = 14;
-    pub const RAND_FUNC: usize = 15;
-}
-
-pub mod signatures {
-    use wasmi::ValueType::*;
-    use wasmi::{self, ValueType};
-
-    pub struct StaticSignature(pub &'static [ValueType], pub Option<ValueType>);
-
-    pub const RET: StaticSignature = StaticSignature(&[I32, I32], None);
-
-    pub const WRITE_STATE: StaticSignature = StaticSignature(&[I32, I32, I32, I32], None);
-
-    pub const READ_STATE_LEN: StaticSignature = StaticSignature(&[I32, I32], Some(I32));
-
-    pub const READ_STATE: StaticSignature = StaticSignature(&[I32, I32, I32], None);
-
-    pub const REMOVE_STATE: StaticSignature = StaticSignature(&[I32, I32], None);
-
-    pub const EPRINT: StaticSignature = StaticSignature(&[I32, I32], None);
-
-    pub const NAME_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
-
-    pub const NAME: StaticSignature = StaticSignature(&[I32], None);
+pub use enigma_types::wasm::ids;
 
-    pub const ARGS_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
-
-    pub const ARGS: StaticSignature = StaticSignature(&[I32], None);
-
-    pub const TYPES_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
-
-    pub const WRITE_ETH_BRIDGE: StaticSignature = StaticSignature(&[I32, I32, I32], None);
-
-    pub const TYPES: StaticSignature = StaticSignature(&[I32], None);
-
-    pub const GAS: StaticSignature = StaticSignature(&[I32], None);
-
-    pub const RAND: StaticSignature = StaticSignature(&[I32, I32], None);
-
-    impl Into<wasmi::Signature> for StaticSignature {
-        fn into(self) -> wasmi::Signature { wasmi::Signature::new(self.0, self.1) }
-    }
+fn signature(sig: StaticSignature) -> Signature {
+    let value_type = |value: ValueType| match value {
+        ValueType::I32 => wasmi::ValueType::I32,
+        ValueType::I64 => wasmi::ValueType::I64,
+    };
+    Signature::new(sig.0.iter().cloned().map(value_type).collect::<Vec<_>>(), sig.1.map(value_type))
 }
 
 /// Import resolver for wasmi
@@ -103,33 +59,19 @@ impl ImportResolver {
 
 impl ModuleImportResolver for ImportResolver {
     fn resolve_func(&self, field_name: &str, _signature: &Signature) -> Result<FuncRef, Error> {
-        let func_ref = match field_name {
-            "ret" => FuncInstance::alloc_host(signatures::RET.into(), ids::RET_FUNC),
-            "write_state" => FuncInstance::alloc_host(signatures::WRITE_STATE.into(), ids::WRITE_STATE_FUNC),
-            "read_state_len" => FuncInstance::alloc_host(signatures::READ_STATE_LEN.into(), ids::READ_STATE_LEN_FUNC),
-            "read_state" => FuncInstance::alloc_host(signatures::READ_STATE.into(), ids::READ_STATE_FUNC),
-            "remove_from_state" => FuncInstance::alloc_host(signatures::REMOVE_STATE.into(), ids::REMOVE_STATE_FUNC),
-            "eprint" => FuncInstance::alloc_host(signatures::EPRINT.into(), ids::EPRINT_FUNC),
-            "fetch_function_name_length" => FuncInstance::alloc_host(signatures::NAME_LENGTH.into(), ids::NAME_LENGTH_FUNC),
-            "fetch_function_name" => FuncInstance::alloc_host(signatures::NAME.into(), ids::NAME_FUNC),
-            "fetch_args_length" => FuncInstance::alloc_host(signatures::ARGS_LENGTH.into(), ids::ARGS_LENGTH_FUNC),
-            "fetch_args" => FuncInstance::alloc_host(signatures::ARGS.into(), ids::ARGS_FUNC),
-            "fetch_types_length" => FuncInstance::alloc_host(signatures::TYPES_LENGTH.into(), ids::TYPES_LENGTH_FUNC),
-            "fetch_types" => FuncInstance::alloc_host(signatures::TYPES.into(), ids::TYPES_FUNC),
-            "write_eth_bridge" => FuncInstance::alloc_host(signatures::WRITE_ETH_BRIDGE.into(), ids::WRITE_ETH_BRIDGE_FUNC),
-            "gas" => FuncInstance::alloc_host(signatures::GAS.into(), ids::GAS_FUNC),
-            "rand" => FuncInstance::alloc_host(signatures::RAND.into(), ids::RAND_FUNC),
-            _ => return Err(wasmi::Error::Instantiation(format!("Export {} not found", field_name))),
-        };
-
-        Ok(func_ref)
+        match wasm::resolve_func(field_name) {
+            Some((sig, id)) => Ok(FuncInstance::alloc_host(signature(sig), id)),
+            None => Err(wasmi::Error::Instantiation(format!("Export {} not found", field_name))),
+        }
     }
 
     fn resolve_memory(&self, field_name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, Error> {
         if field_name == "memory" {
-            let effective_max = descriptor.maximum().unwrap_or(self.max_memory + 1);
-            if descriptor.initial() > self.max_memory || effective_max > self.max_memory {
-                Err(Error::Instantiation("Module requested too much memory".to_owned()))
+            // A memory without a maximum can grow without bound, so it's never within the limit.
+            let requested = descriptor.maximum().unwrap_or(u32::max_value()).max(descriptor.initial());
+            if requested > self.max_memory {
+                let err = FailedTaskError(ResourceLimitError { resource: "memory pages".to_string(), requested, limit: self.max_memory });
+                Err(Error::Host(Box::new(WasmError::EnclaveError(err))))
             } else {
                 let mem = MemoryInstance::alloc(
                     memory_units::Pages(descriptor.initial() as usize),
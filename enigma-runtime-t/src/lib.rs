@@ -1,4 +1,5 @@
 #![no_std]
+#![feature(int_to_from_bytes)]
 #![warn(unused_extern_crates)]
 
 
@@ -21,7 +22,11 @@ extern crate wasmi;
 
 use crate::data::{ContractState, DeltasInterface, IOInterface, EncryptedPatch};
 use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*, WasmError};
-use enigma_types::{ContractAddress, StateKey};
+use enigma_crypto::rand::SeededRng;
+use enigma_crypto::KeyPair;
+use enigma_crypto::hash::Keccak256;
+use enigma_types::{ContractAddress, Hash256, KeyVersion, PubKey, StateKey};
+pub use enigma_types::wasm::RuntimeWasmCosts;
 use std::{str, vec::Vec};
 use std::string::{String, ToString};
 use wasmi::{MemoryRef, RuntimeArgs, RuntimeValue};
@@ -29,6 +34,7 @@ use sgx_trts::trts::rsgx_read_rand;
 
 pub mod data;
 pub mod eng_resolver;
+pub mod limits;
 pub mod ocalls_t;
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -37,6 +43,31 @@ pub struct EthereumData{
     pub ethereum_contract_addr: [u8; 20],
 }
 
+/// What a contract can learn about the task it's running in.
+#[derive(Clone, Copy)]
+pub struct TaskContext {
+    /// The public key of the user that submitted the task.
+    pub user_pubkey: PubKey,
+    /// The block number the task was submitted in, as reported by the worker. It's part of the signed result.
+    pub block_number: u64,
+    /// The inputs hash of the task, which is unique for every task.
+    pub task_id: Hash256,
+}
+
+impl Default for TaskContext {
+    fn default() -> Self { TaskContext { user_pubkey: [0u8; 64], block_number: 0, task_id: Hash256::default() } }
+}
+
+impl ::std::fmt::Debug for TaskContext {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let mut debug_trait_builder = f.debug_struct("TaskContext");
+        debug_trait_builder.field("user_pubkey", &(&self.user_pubkey[..]));
+        debug_trait_builder.field("block_number", &self.block_number);
+        debug_trait_builder.field("task_id", &self.task_id);
+        debug_trait_builder.finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RuntimeResult {
     pub state_delta: Option<EncryptedPatch>,
@@ -46,25 +77,6 @@ pub struct RuntimeResult {
     pub used_gas: u64,
 }
 
-#[derive(Debug, Clone)]
-pub struct RuntimeWasmCosts {
-    write_value: u64,
-    write_additional_byte: u64,
-    deploy_byte: u64,
-    execution: u64,
-}
-
-impl Default for RuntimeWasmCosts {
-    fn default() -> Self {
-        RuntimeWasmCosts {
-            write_value: 10,
-            write_additional_byte: 1,
-            deploy_byte: 1,
-            execution: 10_000,
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct Runtime {
     gas_counter: u64,
@@ -78,7 +90,10 @@ pub struct Runtime {
     pre_execution_state: ContractState,
     post_execution_state: ContractState,
     key: StateKey,
+    key_version: KeyVersion,
     gas_costs: RuntimeWasmCosts,
+    rand: Option<SeededRng>,
+    context: TaskContext,
 }
 
 type Result<T> = ::std::result::Result<T, WasmError>;
@@ -102,7 +117,25 @@ impl Runtime {
             ethereum_bridge: Default::default(),
             used_gas: 0,
         };
-        Runtime { gas_counter: 0, gas_limit, gas_return: 0, memory, function_name, args_types, args, result, pre_execution_state, post_execution_state, key, gas_costs: costs }
+        Runtime { gas_counter: 0, gas_limit, gas_return: 0, memory, function_name, args_types, args, result, pre_execution_state, post_execution_state, key, key_version: 0, gas_costs: costs, rand: None, context: TaskContext::default() }
+    }
+
+    /// Use a deterministic random stream derived from `seed` for `Rand` instead of the SGX randomness.
+    /// Executing the same task with the same seed will produce the same random values.
+    pub fn with_rand_seed(mut self, seed: Hash256) -> Runtime {
+        self.rand = Some(SeededRng::new(seed.into()));
+        self
+    }
+
+    /// The version of the state key, the delta is tagged with it.
+    pub fn with_key_version(mut self, key_version: KeyVersion) -> Runtime {
+        self.key_version = key_version;
+        self
+    }
+
+    pub fn with_task_context(mut self, context: TaskContext) -> Runtime {
+        self.context = context;
+        self
     }
 
     pub fn get_used_gas(&self) -> u64 {
@@ -301,11 +334,22 @@ impl Runtime {
         Ok(())
     }
 
+    /// args:
+    /// * `ptr` - the start address in memory
+    /// * `len` - the length
+    ///
+    /// Fill the memory of length `len` starting at address `ptr` with random bytes.
+    /// If the runtime has a seed the bytes are taken from the seeded stream, otherwise from `rsgx_read_rand`.
     pub fn rand(&mut self, args: RuntimeArgs) -> Result<()> {
         let ptr: u32 = args.nth_checked(0)?;
         let len: u32 = args.nth_checked(1)?;
 
         let mut buf = vec![0u8; len as usize];
+        if let Some(ref mut rng) = self.rand {
+            rng.fill(&mut buf[..]);
+            self.memory.set(ptr, &buf[..])?;
+            return Ok(());
+        }
         match rsgx_read_rand(&mut buf[..]) {
             Ok(_) => {
                 self.memory.set(ptr, &buf[..])?;
@@ -315,6 +359,79 @@ impl Runtime {
         }
     }
 
+    /// args:
+    /// * `ptr` - the start address in memory, must have room for 64 bytes
+    ///
+    /// Copy the public key of the user that submitted the task to `ptr`
+    pub fn fetch_sender_pubkey(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+
+        self.memory.set(ptr, &self.context.user_pubkey[..])?;
+        Ok(())
+    }
+
+    /// args:
+    /// * `ptr` - the start address in memory, must have room for 20 bytes
+    ///
+    /// Copy the Ethereum address of the user that submitted the task to `ptr`,
+    /// that is the last 20 bytes of the keccak256 of the public key.
+    pub fn fetch_sender_address(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+
+        let hash = self.context.user_pubkey[..].keccak256();
+        self.memory.set(ptr, &hash[12..])?;
+        Ok(())
+    }
+
+    /// args:
+    /// * `ptr` - the start address in memory, must have room for 32 bytes
+    ///
+    /// Copy the address of the running contract to `ptr`
+    pub fn fetch_contract_address(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+
+        self.memory.set(ptr, &self.post_execution_state.contract_address[..])?;
+        Ok(())
+    }
+
+    fn fetch_block_number(&mut self) -> RuntimeValue { RuntimeValue::I64(self.context.block_number as i64) }
+
+    /// args:
+    /// * `ptr` - the start address in memory, must have room for 32 bytes
+    ///
+    /// Copy the task id to `ptr`
+    pub fn fetch_task_id(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+
+        self.memory.set(ptr, &self.context.task_id[..])?;
+        Ok(())
+    }
+
+    /// args:
+    /// * `pubkey` - the start address of the user's 64 bytes public key in memory
+    /// * `data` - the start address of the data in memory
+    /// * `data_len` - the length of the data
+    /// * `sealed_holder` - the start address of a buffer of `data_len + SEALED_OVERHEAD` bytes
+    ///
+    /// Encrypt `data` so only the owner of `pubkey` can decrypt it (see `KeyPair::seal_for`),
+    /// and copy the sealed data to `sealed_holder`.
+    pub fn seal_for_user(&mut self, args: RuntimeArgs) -> Result<()> {
+        let pubkey_ptr: u32 = args.nth_checked(0)?;
+        let data_ptr: u32 = args.nth_checked(1)?;
+        let data_len: u32 = args.nth_checked(2)?;
+        let sealed_holder: u32 = args.nth_checked(3)?;
+
+        let cost = self.gas_costs.seal_for_user;
+        self.charge_gas(cost)?;
+        let mut pubkey: PubKey = [0u8; 64];
+        self.memory.get_into(pubkey_ptr, &mut pubkey[..])?;
+        let data = self.memory.get(data_ptr, data_len as usize)?;
+
+        let sealed = KeyPair::seal_for(&pubkey, &data).map_err(EnclaveError::from)?;
+        self.memory.set(sealed_holder, &sealed)?;
+        Ok(())
+    }
+
     /// Destroy the runtime, returning currently recorded result of the execution
     pub fn into_result(mut self) -> ::std::result::Result<RuntimeResult, EnclaveError> {
             if self.gas_counter >= self.gas_return {
@@ -327,7 +444,7 @@ impl Runtime {
             // The delta is always generated after a deployment.
             // The delta is generated after an execution only if there is a state change.
             if (&self.pre_execution_state != &self.post_execution_state) || (self.pre_execution_state.is_initial()) {
-                Some(ContractState::generate_delta_and_update_state(&self.pre_execution_state, &mut self.post_execution_state, &self.key)?)
+                Some(ContractState::generate_delta_and_update_state(&self.pre_execution_state, &mut self.post_execution_state, (self.key_version, &self.key))?)
             } else {
                 None
             }
@@ -451,8 +568,101 @@ mod ext_impl {
                     Ok(None)
                 }
 
+                eng_resolver::ids::SEAL_FOR_USER_FUNC => {
+                    Runtime::seal_for_user(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::SENDER_PUBKEY_FUNC => {
+                    Runtime::fetch_sender_pubkey(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::SENDER_ADDRESS_FUNC => {
+                    Runtime::fetch_sender_address(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::CONTRACT_ADDRESS_FUNC => {
+                    Runtime::fetch_contract_address(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::BLOCK_NUMBER_FUNC => Ok(Some(Runtime::fetch_block_number(self))),
+
+                eng_resolver::ids::TASK_ID_FUNC => {
+                    Runtime::fetch_task_id(self, args)?;
+                    Ok(None)
+                }
+
                 _ => unimplemented!("Unimplemented function at {}", index),
             }
         }
     }
 }
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::{Runtime, TaskContext};
+    use crate::data::ContractState;
+    use enigma_crypto::hash::Keccak256;
+    use enigma_crypto::{KeyPair, asymmetric::SEALED_OVERHEAD};
+    use enigma_types::ContractAddress;
+    use std::string::ToString;
+    use std::vec::Vec;
+    use wasmi::{memory_units::Pages, MemoryInstance, RuntimeArgs, RuntimeValue};
+
+    fn new_runtime(memory: &wasmi::MemoryRef) -> Runtime {
+        let state = ContractState::new(ContractAddress::default());
+        Runtime::new_with_state(100_000, memory.clone(), Vec::new(), state, "flip".to_string(), "".to_string(), [0u8; 32], Default::default())
+    }
+
+    fn draw_rand(seed: Option<[u8; 32]>, len: usize) -> Vec<u8> {
+        let memory = MemoryInstance::alloc(Pages(1), Some(Pages(1))).unwrap();
+        let mut runtime = new_runtime(&memory);
+        if let Some(seed) = seed {
+            runtime = runtime.with_rand_seed(seed.into());
+        }
+        let args = [RuntimeValue::I32(0), RuntimeValue::I32(len as i32)];
+        runtime.rand(RuntimeArgs::from(&args[..])).unwrap();
+        memory.get(0, len).unwrap()
+    }
+
+    pub fn test_seeded_rand() {
+        assert_eq!(draw_rand(Some([3u8; 32]), 64), draw_rand(Some([3u8; 32]), 64));
+        assert_ne!(draw_rand(Some([3u8; 32]), 64), draw_rand(Some([4u8; 32]), 64));
+    }
+
+    pub fn test_unseeded_rand() {
+        assert_ne!(draw_rand(None, 64), draw_rand(None, 64));
+    }
+
+    pub fn test_seal_for_user() {
+        let user = KeyPair::new().unwrap();
+        let data = b"a secret for one user";
+        let memory = MemoryInstance::alloc(Pages(1), Some(Pages(1))).unwrap();
+        memory.set(0, &user.get_pubkey()).unwrap();
+        memory.set(64, data).unwrap();
+
+        let mut runtime = new_runtime(&memory);
+        let args = [RuntimeValue::I32(0), RuntimeValue::I32(64), RuntimeValue::I32(data.len() as i32), RuntimeValue::I32(1024)];
+        runtime.seal_for_user(RuntimeArgs::from(&args[..])).unwrap();
+
+        let sealed = memory.get(1024, data.len() + SEALED_OVERHEAD).unwrap();
+        assert_eq!(user.open_sealed(&sealed).unwrap(), data.to_vec());
+        assert!(KeyPair::new().unwrap().open_sealed(&sealed).is_err());
+    }
+
+    pub fn test_task_context() {
+        let user = KeyPair::new().unwrap();
+        let context = TaskContext { user_pubkey: user.get_pubkey(), block_number: 1234, task_id: [7u8; 32].into() };
+        let memory = MemoryInstance::alloc(Pages(1), Some(Pages(1))).unwrap();
+        let mut runtime = new_runtime(&memory).with_task_context(context);
+
+        runtime.fetch_sender_address(RuntimeArgs::from(&[RuntimeValue::I32(0)][..])).unwrap();
+        assert_eq!(memory.get(0, 20).unwrap(), user.get_pubkey()[..].keccak256()[12..].to_vec());
+        runtime.fetch_task_id(RuntimeArgs::from(&[RuntimeValue::I32(32)][..])).unwrap();
+        assert_eq!(memory.get(32, 32).unwrap(), vec![7u8; 32]);
+        assert_eq!(runtime.fetch_block_number(), RuntimeValue::I64(1234));
+    }
+}
@@ -0,0 +1,71 @@
+//! Resource limits of a single contract, the section they are declared in is read by `enigma_types::limits`.
+
+use enigma_tools_t::common::errors_t::EnclaveError;
+use std::vec::Vec;
+use wasmi::{self, ModuleRef, RuntimeValue, TrapKind};
+
+pub use enigma_types::limits::{find_custom_section, ContractLimits, LimitsError, LIMITS_SECTION, MAX_MEMORY_PAGES, MAX_STACK_HEIGHT};
+
+/// Append the limits section to `code`, unless it already has one.
+pub fn append_to(limits: &ContractLimits, code: &mut Vec<u8>) -> Result<(), EnclaveError> {
+    if find_custom_section(code, LIMITS_SECTION)?.is_none() {
+        code.extend_from_slice(&limits.to_section());
+    }
+    Ok(())
+}
+
+/// The stack height limiter injected by `pwasm_utils` keeps the height in the last global of the module,
+/// and traps with `unreachable` once the height passes the limit. The height tells that trap apart from a
+/// contract that reached `unreachable` by itself, e.g. by panicking.
+pub fn stack_limit_error(instance: &ModuleRef, limits: &ContractLimits, err: &wasmi::Error) -> Option<EnclaveError> {
+    let trap = match err {
+        wasmi::Error::Trap(trap) => trap,
+        _ => return None,
+    };
+    let height = match instance.globals().last().map(|global| global.get()) {
+        Some(RuntimeValue::I32(height)) => height as u32,
+        _ => return None,
+    };
+    match trap.kind() {
+        TrapKind::Unreachable if height > limits.stack_height => Some(limits.stack_exceeded(height).into()),
+        TrapKind::StackOverflow => Some(limits.stack_exceeded(height).into()),
+        _ => None,
+    }
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+
+    // An empty module: only the magic number and the version.
+    const EMPTY_MODULE: [u8; 8] = [0, 97, 115, 109, 1, 0, 0, 0];
+
+    pub fn test_limits_roundtrip() {
+        let mut code = EMPTY_MODULE.to_vec();
+        assert_eq!(ContractLimits::declared_in(&code).unwrap(), None);
+        assert_eq!(ContractLimits::from_code(&code).unwrap(), ContractLimits::default());
+
+        let limits = ContractLimits { memory_pages: 17, stack_height: 1024 };
+        append_to(&limits, &mut code).unwrap();
+        assert_eq!(ContractLimits::declared_in(&code).unwrap(), Some(limits));
+
+        // A second append keeps the section that is already there.
+        let len = code.len();
+        append_to(&ContractLimits::default(), &mut code).unwrap();
+        assert_eq!(code.len(), len);
+        assert_eq!(ContractLimits::from_code(&code).unwrap(), limits);
+    }
+
+    pub fn test_limits_above_maximum() {
+        let mut code = EMPTY_MODULE.to_vec();
+        append_to(&ContractLimits { memory_pages: MAX_MEMORY_PAGES + 1, stack_height: 1024 }, &mut code).unwrap();
+        match ContractLimits::declared_in(&code) {
+            Err(LimitsError::ResourceLimit { requested, limit, .. }) => {
+                assert_eq!(requested, MAX_MEMORY_PAGES + 1);
+                assert_eq!(limit, MAX_MEMORY_PAGES);
+            }
+            other => panic!("Expected a resource limit error, got: {:?}", other),
+        }
+        assert!(ContractLimits::from_code(&EMPTY_MODULE[..4]).is_err());
+    }
+}
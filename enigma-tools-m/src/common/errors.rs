@@ -4,4 +4,10 @@ use failure::Fail;
 pub enum ToolsError {
     #[fail(display = "There's an error with the messaging: {}", err)]
     MessagingError { err: &'static str },
+
+    #[fail(display = "The signature is invalid: {}", err)]
+    SignatureError { err: &'static str },
+
+    #[fail(display = "The message was signed by {:?} instead of {:?}", signer, worker)]
+    WrongSignerError { signer: [u8; 20], worker: [u8; 20] },
 }
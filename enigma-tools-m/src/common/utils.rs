@@ -1,5 +1,5 @@
 use crate::localstd::string::String;
-use enigma_crypto::hash::Keccak256;
+use enigma_crypto::asymmetric::KeyPair;
 use rustc_hex::ToHex;
 
 pub trait EthereumAddress<T, P> {
@@ -13,14 +13,10 @@ impl EthereumAddress<String, [u8; 20]> for [u8; 64] {
     // TODO: Maybe add a checksum address
     fn address_string(&self) -> String {
         let mut result: String = String::from("0x");
-        let hex: String = self.keccak256()[12..32].to_hex();
+        let hex: String = self.address().to_hex();
         result.push_str(&hex);
         result
     }
 
-    fn address(&self) -> [u8; 20] {
-        let mut result = [0u8; 20];
-        result.copy_from_slice(&self.keccak256()[12..32]);
-        result
-    }
+    fn address(&self) -> [u8; 20] { KeyPair::pubkey_to_address(self) }
 }
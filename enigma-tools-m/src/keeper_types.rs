@@ -24,6 +24,22 @@ pub trait RawEncodable {
     fn raw_encode(&self) -> Bytes;
 }
 
+/// The number of workers selected for a contract when the epoch doesn't specify a group size
+pub const DEFAULT_GROUP_SIZE: u64 = 1;
+/// The number of draws allowed for each member of a group before giving up on filling it
+const MAX_DRAWS_PER_MEMBER: u64 = 32;
+/// The number of epochs the principal keeps for lookup by block number, in the enclave and in its untrusted history.
+/// Same as the worker parameters kept by the Enigma contract.
+pub const EPOCH_CAPACITY: usize = 5;
+
+/// Returns the epoch of the block number: the one with the highest nonce among those
+/// whose worker parameters were set at or before the block number.
+/// `key` returns the block number of the worker parameters and the nonce of an epoch.
+pub fn find_epoch_at<T, I, F>(epochs: I, block_number: U256, key: F) -> Option<T>
+where I: IntoIterator<Item = T>, F: Fn(&T) -> (U256, U256) {
+    epochs.into_iter().filter(|epoch| key(epoch).0 <= block_number).max_by_key(|epoch| key(epoch).1)
+}
+
 #[derive(Clone)]
 struct WorkerSelectionToken {
     pub seed: U256,
@@ -47,6 +63,19 @@ pub struct InputWorkerParams {
 }
 
 impl InputWorkerParams {
+    /// Encode the epoch the principal signs as Ethereum ABI parameters.
+    /// Every input of the worker selection is signed, so a worker can verify a selection against the principal's signature.
+    pub fn encode_epoch(&self, seed: U256, nonce: U256, group_size: u64) -> Bytes {
+        let tokens = vec![
+            Token::Uint(seed),
+            Token::Uint(nonce),
+            Token::Array(self.workers.iter().map(|a| Token::Address(*a)).collect()),
+            Token::Array(self.stakes.iter().map(|s| Token::Uint(*s)).collect()),
+            Token::Uint(U256::from(group_size)),
+        ];
+        encode(&tokens)
+    }
+
     /// Run the worker selection algorithm against the current epoch
     ///
     /// # Arguments
@@ -63,8 +92,19 @@ impl InputWorkerParams {
         }
     }
 
+    /// Run the worker selection algorithm for a group of workers, the first one is the worker
+    /// returned by `get_selected_worker`.
+    /// Some workers may never be drawn (e.g. without stake), so the draws are bounded by
+    /// `MAX_DRAWS_PER_MEMBER` for each member and the group can be smaller than requested.
+    ///
+    /// # Arguments
+    ///
+    /// * `sc_addr` - The Secret Contract address
+    /// * `seed` - The random seed for the selected epoch
+    /// * `group_size` - The number of distinct workers to select, `DEFAULT_GROUP_SIZE` if missing
+    ///
     #[logfn(DEBUG)]
-    fn get_selected_workers(&self, sc_addr: ContractAddress, seed: U256, group_size: Option<u64>) -> Vec<Address> {
+    pub fn get_selected_workers(&self, sc_addr: ContractAddress, seed: U256, group_size: Option<u64>) -> Vec<Address> {
         let mut balance_sum = U256::zero();
         for &balance in &self.stakes {
             balance_sum += balance;
@@ -72,9 +112,13 @@ impl InputWorkerParams {
         // Using the same type as the Enigma contract
         let mut nonce = U256::zero();
         let mut selected_workers = Vec::new();
-        let group_size = group_size.unwrap_or(1);
+        if balance_sum.is_zero() {
+            return selected_workers;
+        }
+        let group_size = (group_size.unwrap_or(DEFAULT_GROUP_SIZE) as usize).min(self.workers.len());
+        let max_draws = U256::from(group_size as u64 * MAX_DRAWS_PER_MEMBER);
 
-        while selected_workers.len() < group_size as usize {
+        while selected_workers.len() < group_size && nonce < max_draws {
             let token = WorkerSelectionToken { seed, sc_addr, nonce };
             // This is equivalent to encodePacked in Solidity
             let hash = token.raw_encode().keccak256();
@@ -82,9 +126,10 @@ impl InputWorkerParams {
             debug!("The initial random value: {:?}", rand_val.0);
             let mut selected_worker = self.workers.last().unwrap();
 
+            // `rand_val` is in `[0, balance_sum)`, the worker whose range of stake it falls in is selected
             for (i, worker) in self.workers.iter().enumerate() {
                 let (new_rand, overflow) = rand_val.overflowing_sub(self.stakes[i]);
-                if overflow || new_rand.is_zero() {
+                if overflow {
                     selected_worker = worker;
                     break;
                 }
@@ -119,3 +164,78 @@ impl Encodable for InputWorkerParams {
         s.append_list(&self.stakes.iter().map(|b| bigint::U256(b.0)).collect::<Vec<_>>());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker_params(stakes: &[u64]) -> InputWorkerParams {
+        InputWorkerParams {
+            block_number: U256::from(1),
+            workers: (0..stakes.len()).map(|i| H160([i as u8 + 1; 20])).collect(),
+            stakes: stakes.iter().map(|&stake| U256::from(stake)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_encode_epoch() {
+        let params = worker_params(&[1, 2]);
+        let epoch = params.encode_epoch(U256::from(42), U256::from(3), 2);
+        // The seed, the nonce, the offsets of the workers and the stakes and the group size, then the two arrays
+        assert_eq!(epoch.len(), 32 * (5 + 3 + 3));
+        assert_eq!(U256::from(&epoch[4 * 32..5 * 32]), U256::from(2));
+        assert_ne!(epoch, params.encode_epoch(U256::from(42), U256::from(3), 1));
+    }
+
+    #[test]
+    fn test_selected_group() {
+        let params = worker_params(&[1, 2, 3, 4]);
+        let sc_addr = ContractAddress::from([7u8; 32]);
+        let seed = U256::from(42);
+        let group = params.get_selected_workers(sc_addr, seed, Some(3));
+        assert_eq!(group.len(), 3);
+        assert!(group.iter().all(|worker| params.workers.contains(worker)));
+        assert!(!group[1..].contains(&group[0]));
+        assert_eq!(params.get_selected_worker(sc_addr, seed), Some(group[0]));
+        // The group is a prefix of any larger group
+        assert_eq!(params.get_selected_workers(sc_addr, seed, Some(4))[..3], group[..]);
+    }
+
+    #[test]
+    fn test_selected_group_capped() {
+        // A worker without stake is never selected, the group is capped instead of looping forever
+        let params = worker_params(&[1, 0, 1]);
+        let sc_addr = ContractAddress::from([7u8; 32]);
+        let group = params.get_selected_workers(sc_addr, U256::from(1), Some(10));
+        assert!(!group.is_empty() && group.len() < params.workers.len());
+        assert!(!group.contains(&params.workers[1]));
+
+        let params = worker_params(&[]);
+        assert!(params.get_selected_workers(sc_addr, U256::from(1), Some(2)).is_empty());
+        assert_eq!(params.get_selected_worker(sc_addr, U256::from(1)), None);
+    }
+
+    #[test]
+    fn test_selected_group_last_worker() {
+        // The last worker is drawn even with the smallest stake
+        let params = worker_params(&[1, 1]);
+        let sc_addr = ContractAddress::from([7u8; 32]);
+        let group = params.get_selected_workers(sc_addr, U256::from(1), Some(2));
+        assert_eq!(group.len(), 2);
+        assert!(group.contains(&params.workers[1]));
+    }
+
+    #[test]
+    fn test_find_epoch_at() {
+        // The (worker parameters block number, nonce) of the epochs, in any order
+        let epochs = [(20u64, 1u64), (10, 0), (30, 2)];
+        let nonce_at = |block_number: u64| {
+            find_epoch_at(epochs.iter(), U256::from(block_number), |&&(block, nonce)| (U256::from(block), U256::from(nonce)))
+                .map(|&(_, nonce)| nonce)
+        };
+        assert_eq!(nonce_at(5), None);
+        assert_eq!(nonce_at(10), Some(0));
+        assert_eq!(nonce_at(29), Some(1));
+        assert_eq!(nonce_at(100), Some(2));
+    }
+}
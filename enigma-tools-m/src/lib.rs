@@ -1,3 +1,4 @@
+#![feature(int_to_from_bytes)]
 #![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
 #![deny(unused_extern_crates)]
 
@@ -3,16 +3,21 @@ use crate::localstd::vec::Vec;
 use crate::rmp_serde::{Deserializer, Serializer};
 use crate::serde::{Deserialize, Serialize};
 use crate::serde_json;
-use enigma_crypto::{rand, symmetric, CryptoError, Encryption, hash};
-use enigma_types::{ContractAddress, DhKey, PubKey, StateKey};
+use enigma_crypto::{asymmetric::KeyPair, kdf::KeyPurpose, rand, symmetric, CryptoError, Encryption, hash};
+use enigma_types::{ContractAddress, KeyVersion, PubKey, SessionId, StateKey, SymmetricKey};
 
 pub type MsgID = [u8; 12];
 
+/// How many versions of a contract's state key are served, the latest one and the previous ones
+/// which are still needed to decrypt the deltas that were written before a rotation.
+pub const KEY_VERSIONS_WINDOW: usize = 2;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum PrincipalMessageType {
     Response(Vec<(ContractAddress, StateKey)>),
     Request(Option<Vec<ContractAddress>>),
     EncryptedResponse(Vec<u8>),
+    VersionedResponse(Vec<(ContractAddress, KeyVersion, StateKey)>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -20,6 +25,9 @@ pub struct PrincipalMessage {
     pub data: PrincipalMessageType,
     pub(crate) pubkey: Vec<u8>,
     pub(crate) id: MsgID,
+    /// The block number of the worker parameters of the epoch the keys of a response were issued for.
+    /// It's part of the message the principal signs, so a worker can trust it no matter who delivered the response.
+    pub(crate) epoch: Option<u64>,
 }
 
 impl PrincipalMessage {
@@ -27,12 +35,17 @@ impl PrincipalMessage {
         let mut id = [0u8; 12];
         rand::random(&mut id)?;
         let pubkey = pubkey.to_vec();
-        Ok(Self { data, pubkey, id })
+        Ok(Self { data, pubkey, id, epoch: None })
     }
 
     pub fn new_id(data: PrincipalMessageType, id: [u8; 12], pubkey: PubKey) -> Self {
         let pubkey = pubkey.to_vec();
-        Self { data, pubkey, id }
+        Self { data, pubkey, id, epoch: None }
+    }
+
+    pub fn with_epoch(mut self, epoch: u64) -> Self {
+        self.epoch = Some(epoch);
+        self
     }
 
     pub fn to_sign(&self) -> Result<Vec<u8>, ToolsError> {
@@ -46,7 +59,7 @@ impl PrincipalMessage {
             }
             PrincipalMessageType::EncryptedResponse(v) => to_sign.push(v.clone()),
             PrincipalMessageType::Request(None) => (), // If the request is empty we don't need to sign on it.
-            PrincipalMessageType::Response(_) => unreachable!(), // This can't be reached because we check if it's a response before.
+            PrincipalMessageType::Response(_) | PrincipalMessageType::VersionedResponse(_) => unreachable!(), // This can't be reached because we check if it's a response before.
         }
         to_sign.push(self.pubkey.to_vec());
         to_sign.push(self.id.to_vec());
@@ -79,6 +92,8 @@ impl PrincipalMessage {
 
     pub fn get_id(&self) -> MsgID { self.id }
 
+    pub fn get_epoch(&self) -> Option<u64> { self.epoch }
+
     pub fn is_request(&self) -> bool {
         if let PrincipalMessageType::Request(_) = self.data {
             true
@@ -88,10 +103,9 @@ impl PrincipalMessage {
     }
 
     pub fn is_response(&self) -> bool {
-        if let PrincipalMessageType::Response(_) = self.data {
-            true
-        } else {
-            false
+        match self.data {
+            PrincipalMessageType::Response(_) | PrincipalMessageType::VersionedResponse(_) => true,
+            _ => false,
         }
     }
 
@@ -104,50 +118,91 @@ impl PrincipalMessage {
     }
 }
 
-impl<'a> Encryption<&'a DhKey, CryptoError, Self, [u8; 12]> for PrincipalMessage {
-    fn encrypt_with_nonce(self, key: &DhKey, _iv: Option<[u8; 12]>) -> Result<Self, CryptoError> {
+/// The key a response is encrypted with, it can only be derived as the `KeyPurpose::PrincipalResponse` key
+/// of a DH secret, so neither the secret itself nor a key of another purpose can encrypt a response.
+#[derive(Debug)]
+pub struct PrincipalResponseKey(SymmetricKey);
+
+impl PrincipalResponseKey {
+    pub fn derive(keys: &KeyPair, pubkey: &PubKey) -> Result<Self, CryptoError> {
+        Ok(PrincipalResponseKey(keys.derive_key_for(pubkey, KeyPurpose::PrincipalResponse)?))
+    }
+}
+
+/// The first byte of the plaintext of an encrypted response says which kind of response it is.
+/// A response serialized without it starts with a msgpack array, which is never one of the tags.
+const RESPONSE_TAG: u8 = 0;
+const VERSIONED_RESPONSE_TAG: u8 = 1;
+
+impl<'a> Encryption<&'a PrincipalResponseKey, CryptoError, Self, [u8; 12]> for PrincipalMessage {
+    fn encrypt_with_nonce(self, key: &PrincipalResponseKey, _iv: Option<[u8; 12]>) -> Result<Self, CryptoError> {
+        let key = &key.0;
+        let mut buf = Vec::new();
         match self.data {
             PrincipalMessageType::Response(response) => {
-                let mut buf = Vec::new();
+                buf.push(RESPONSE_TAG);
                 response.serialize(&mut Serializer::new(&mut buf)).map_err(|_| CryptoError::EncryptionError)?;
-                let enc = symmetric::encrypt_with_nonce(&buf, key, _iv)?;
-                Ok(Self { data: PrincipalMessageType::EncryptedResponse(enc), pubkey: self.pubkey, id: self.id })
             }
-            _ => Err(CryptoError::EncryptionError),
+            PrincipalMessageType::VersionedResponse(response) => {
+                buf.push(VERSIONED_RESPONSE_TAG);
+                response.serialize(&mut Serializer::new(&mut buf)).map_err(|_| CryptoError::EncryptionError)?;
+            }
+            _ => return Err(CryptoError::EncryptionError),
         }
+        let enc = symmetric::encrypt_with_nonce(&buf, key, _iv)?;
+        Ok(Self { data: PrincipalMessageType::EncryptedResponse(enc), pubkey: self.pubkey, id: self.id, epoch: self.epoch })
     }
 
-    fn decrypt(enc: Self, key: &DhKey) -> Result<Self, CryptoError> {
+    fn decrypt(enc: Self, key: &PrincipalResponseKey) -> Result<Self, CryptoError> {
+        let key = &key.0;
         match &enc.data {
             PrincipalMessageType::EncryptedResponse(response) => {
                 let dec = symmetric::decrypt(&response, key)?;
-                let mut des = Deserializer::new(&dec[..]);
-                let data =
-                    PrincipalMessageType::Response(Deserialize::deserialize(&mut des).map_err(|_| CryptoError::DecryptionError)?);
-                Ok(Self { data, pubkey: enc.pubkey, id: enc.id })
+                let (tag, payload) = dec.split_first().ok_or(CryptoError::DecryptionError)?;
+                let mut des = Deserializer::new(payload);
+                let data = match *tag {
+                    RESPONSE_TAG => PrincipalMessageType::Response(Deserialize::deserialize(&mut des).map_err(|_| CryptoError::DecryptionError)?),
+                    VERSIONED_RESPONSE_TAG => {
+                        PrincipalMessageType::VersionedResponse(Deserialize::deserialize(&mut des).map_err(|_| CryptoError::DecryptionError)?)
+                    }
+                    _ => return Err(CryptoError::DecryptionError),
+                };
+                Ok(Self { data, pubkey: enc.pubkey, id: enc.id, epoch: enc.epoch })
             }
             _ => Err(CryptoError::EncryptionError),
         }
     }
 }
 
+/// The session of a user who didn't ask for a specific one, like any other session it's good for `uses` tasks.
+pub const DEFAULT_SESSION: SessionId = [0u8; 32];
+
+/// The enclave's half of a user's encryption session, signed by the enclave.
+/// The DH key derived from it decrypts the inputs of up to `uses` tasks that reference the session.
 #[derive(Debug, PartialEq, Clone)]
 pub struct UserMessage {
+    pub(crate) version: u8,
     pub(crate) pubkey: Vec<u8>,
+    pub(crate) session: SessionId,
+    pub(crate) uses: u32,
 }
 
 impl UserMessage {
     // The reason for the prefix is that I(@elichai) don't feel comfortable signing a plain public key.
     // Because ECDSA signature contains multiplication of curve points, so I'm not sure if signing on a valid curve point has any side effect.
     const PREFIX: &'static [u8; 19] = b"Enigma User Message";
+    /// The version of the key exchange, from version 1 the session key is the HKDF `KeyPurpose::UserIo` key
+    /// of the DH secret, before it the secret itself was the key. Messages without a version are version 0.
+    pub const VERSION: u8 = 1;
 
-    pub fn new(pubkey: PubKey) -> Self {
+    pub fn new(pubkey: PubKey, session: SessionId, uses: u32) -> Self {
         let pubkey = pubkey.to_vec();
-        Self { pubkey }
+        Self { version: Self::VERSION, pubkey, session, uses }
     }
 
     pub fn to_sign(&self) -> Vec<u8> {
-        let to_sign = [&Self::PREFIX[..], &self.pubkey];
+        let uses = self.uses.to_be_bytes();
+        let to_sign = [&Self::PREFIX[..], &[self.version][..], &self.pubkey, &self.session[..], &uses[..]];
         hash::prepare_hash_multiple(&to_sign)
     }
 
@@ -172,13 +227,107 @@ impl UserMessage {
         pubkey.copy_from_slice(&self.pubkey[..]);
         pubkey
     }
+
+    pub fn get_session(&self) -> SessionId { self.session }
+
+    pub fn get_uses(&self) -> u32 { self.uses }
+
+    pub fn get_version(&self) -> u8 { self.version }
+}
+
+/// The outcome of rebuilding the state of a single contract from its deltas.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BuildStateStatus {
+    /// Deltas were applied, the state is now at this delta index.
+    Rebuilt(u32),
+    /// No new deltas since the saved state, which is at this delta index.
+    UpToDate(u32),
+    /// The saved state couldn't be decrypted with the contract's keys.
+    DecryptFailure,
+    /// The delta at this index is missing or couldn't be applied, the deltas before it were kept.
+    MissingDelta(u32),
+    /// The enclave has no state keys for the contract.
+    MissingKey,
+    /// The rebuilt state couldn't be encrypted or saved.
+    SaveFailure,
+    /// The deltas from this index couldn't be read from the DB, the deltas before it were kept.
+    ReadFailure(u32),
+}
+
+impl BuildStateStatus {
+    pub fn is_failure(&self) -> bool {
+        match self {
+            BuildStateStatus::Rebuilt(_) | BuildStateStatus::UpToDate(_) => false,
+            _ => true,
+        }
+    }
+
+    fn to_code(&self) -> (u8, u32) {
+        match *self {
+            BuildStateStatus::Rebuilt(index) => (0, index),
+            BuildStateStatus::UpToDate(index) => (1, index),
+            BuildStateStatus::DecryptFailure => (2, 0),
+            BuildStateStatus::MissingDelta(index) => (3, index),
+            BuildStateStatus::MissingKey => (4, 0),
+            BuildStateStatus::SaveFailure => (5, 0),
+            BuildStateStatus::ReadFailure(index) => (6, index),
+        }
+    }
+
+    fn from_code(code: u8, index: u32) -> Option<Self> {
+        match code {
+            0 => Some(BuildStateStatus::Rebuilt(index)),
+            1 => Some(BuildStateStatus::UpToDate(index)),
+            2 => Some(BuildStateStatus::DecryptFailure),
+            3 => Some(BuildStateStatus::MissingDelta(index)),
+            4 => Some(BuildStateStatus::MissingKey),
+            5 => Some(BuildStateStatus::SaveFailure),
+            6 => Some(BuildStateStatus::ReadFailure(index)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BuildStateResult {
+    pub address: ContractAddress,
+    pub status: BuildStateStatus,
+}
+
+impl BuildStateResult {
+    /// address (32 bytes) || status (1 byte) || delta index (4 bytes, big endian)
+    pub const SIZE: usize = 37;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let (code, index) = self.status.to_code();
+        let mut res = [0u8; Self::SIZE];
+        res[..32].copy_from_slice(&self.address[..]);
+        res[32] = code;
+        res[33..].copy_from_slice(&index.to_be_bytes());
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ToolsError> {
+        if bytes.len() != Self::SIZE {
+            return Err(MessagingError { err: "Wrong size of a BuildStateResult" });
+        }
+        let mut address = [0u8; 32];
+        address.copy_from_slice(&bytes[..32]);
+        let mut index = [0u8; 4];
+        index.copy_from_slice(&bytes[33..]);
+        let status = BuildStateStatus::from_code(bytes[32], u32::from_be_bytes(index))
+            .ok_or(MessagingError { err: "Unknown BuildStateStatus" })?;
+        Ok(Self { address: address.into(), status })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{PrincipalMessage, PrincipalMessageType};
+    use super::{BuildStateResult, BuildStateStatus, PrincipalMessage, PrincipalMessageType, PrincipalResponseKey, UserMessage};
+    use crate::rmp_serde::Serializer;
+    use crate::serde::Serialize;
     use enigma_crypto::hash::Sha256;
-    use enigma_crypto::Encryption;
+    use enigma_crypto::{symmetric, Encryption};
 
     #[test]
     fn test_to_message() {
@@ -204,9 +353,21 @@ mod tests {
         assert_eq!(PrincipalMessage::from_message(&msg).unwrap(), res);
     }
 
+    #[test]
+    fn test_response_epoch() {
+        let res = get_response().with_epoch(1234);
+        let enc = encrypt(res.clone());
+        let msg = enc.clone().into_message().unwrap();
+        assert_ne!(msg, encrypt(get_response()).into_message().unwrap());
+        let decoded = PrincipalMessage::from_message(&msg).unwrap();
+        assert_eq!(decoded, enc);
+        assert_eq!(decrypt(decoded).get_epoch(), Some(1234));
+        assert_eq!(PrincipalMessage::from_message(&get_request().into_message().unwrap()).unwrap().get_epoch(), None);
+    }
+
     #[test]
     fn test_encrypt_response() {
-        let enc = vec![195, 38, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 240, 120, 203, 145, 26, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 133, 234, 100, 9, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 101, 20, 147, 103, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 188, 97, 216, 195, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 30, 133, 117, 163, 77, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 152, 92, 202, 63, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 94, 57, 158, 3, 225, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 109, 136, 221, 234, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 156, 210, 190, 118, 13, 68, 47, 138, 4, 130, 174, 77, 76, 232, 70, 181, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
+        let enc = vec![86, 33, 142, 150, 120, 48, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 52, 25, 139, 41, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 99, 54, 23, 177, 59, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 88, 54, 68, 43, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 245, 90, 79, 71, 183, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 98, 189, 248, 224, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 143, 203, 169, 131, 110, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 71, 128, 234, 27, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 200, 119, 66, 35, 197, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 181, 84, 253, 207, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 185, 217, 5, 148, 55, 214, 155, 178, 89, 206, 85, 241, 85, 98, 206, 116, 127, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
         let response = get_response();
         let mut enc_response = response.clone();
         enc_response.data = PrincipalMessageType::EncryptedResponse(enc);
@@ -215,7 +376,7 @@ mod tests {
 
     #[test]
     fn test_decrypt_reponse() {
-        let enc = vec![195, 38, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 240, 120, 203, 145, 26, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 133, 234, 100, 9, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 101, 20, 147, 103, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 188, 97, 216, 195, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 30, 133, 117, 163, 77, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 152, 92, 202, 63, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 94, 57, 158, 3, 225, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 109, 136, 221, 234, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 156, 210, 190, 118, 13, 68, 47, 138, 4, 130, 174, 77, 76, 232, 70, 181, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
+        let enc = vec![86, 33, 142, 150, 120, 48, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 52, 25, 139, 41, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 99, 54, 23, 177, 59, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 88, 54, 68, 43, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 245, 90, 79, 71, 183, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 98, 189, 248, 224, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 143, 203, 169, 131, 110, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 71, 128, 234, 27, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 200, 119, 66, 35, 197, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 181, 84, 253, 207, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 185, 217, 5, 148, 55, 214, 155, 178, 89, 206, 85, 241, 85, 98, 206, 116, 127, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
         let response = get_response();
         let mut enc_response = response.clone();
         enc_response.data = PrincipalMessageType::EncryptedResponse(enc);
@@ -229,6 +390,65 @@ mod tests {
         assert_eq!(res, decrypt(enc))
     }
 
+    #[test]
+    fn test_encrypt_decrypt_versioned_response() {
+        let data = PrincipalMessageType::VersionedResponse(vec![
+            ([0u8; 32].into(), 0, [1u8; 32]),
+            ([0u8; 32].into(), 1, [2u8; 32]),
+            ([1u8; 32].into(), 0, [3u8; 32]),
+        ]);
+        let res = PrincipalMessage::new_id(data, [75, 52, 85, 160, 254, 16, 9, 130, 50, 81, 252, 231], [0u8; 64]);
+        assert!(res.is_response());
+        let enc = encrypt(res.clone());
+        assert!(enc.is_encrypted_response());
+        assert_eq!(res, decrypt(enc))
+    }
+
+    #[test]
+    fn test_decrypt_untagged_response() {
+        // A response encrypted without the tag of its kind
+        let key = PrincipalResponseKey(*b"EnigmaMPC".sha256());
+        let mut buf = Vec::new();
+        vec![([0u8; 32], 0u32, [1u8; 32])].serialize(&mut Serializer::new(&mut buf)).unwrap();
+        let enc = symmetric::encrypt(&buf, &key.0).unwrap();
+        let res = PrincipalMessage::new_id(PrincipalMessageType::EncryptedResponse(enc), [0u8; 12], [0u8; 64]);
+        assert!(PrincipalMessage::decrypt(res, &key).is_err());
+    }
+
+    #[test]
+    fn test_user_message_session() {
+        let msg = UserMessage::new([7u8; 64], [1u8; 32], 3);
+        let decoded = UserMessage::from_message(&msg.clone().into_message().unwrap()).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!((decoded.get_session(), decoded.get_uses(), decoded.get_version()), ([1u8; 32], 3, UserMessage::VERSION));
+
+        // The version, the session and its uses are signed along with the pubkey
+        assert_ne!(msg.to_sign(), UserMessage::new([7u8; 64], [2u8; 32], 3).to_sign());
+        assert_ne!(msg.to_sign(), UserMessage::new([7u8; 64], [1u8; 32], 4).to_sign());
+        assert_ne!(msg.to_sign(), UserMessage { version: 0, ..msg.clone() }.to_sign());
+    }
+
+    #[test]
+    fn test_build_state_result_bytes() {
+        let statuses = [
+            BuildStateStatus::Rebuilt(7),
+            BuildStateStatus::UpToDate(0),
+            BuildStateStatus::DecryptFailure,
+            BuildStateStatus::MissingDelta(u32::max_value()),
+            BuildStateStatus::MissingKey,
+            BuildStateStatus::SaveFailure,
+            BuildStateStatus::ReadFailure(3),
+        ];
+        for (i, status) in statuses.iter().enumerate() {
+            let res = BuildStateResult { address: [i as u8; 32].into(), status: *status };
+            assert_eq!(res, BuildStateResult::from_bytes(&res.to_bytes()).unwrap());
+        }
+        let mut bytes = BuildStateResult { address: [0u8; 32].into(), status: BuildStateStatus::MissingKey }.to_bytes();
+        bytes[32] = 7;
+        assert!(BuildStateResult::from_bytes(&bytes).is_err());
+        assert!(BuildStateResult::from_bytes(&bytes[1..]).is_err());
+    }
+
     fn get_request() -> PrincipalMessage {
         let data = PrincipalMessageType::Request(None);
         let id = [75, 52, 85, 160, 254, 16, 9, 130, 50, 81, 252, 231];
@@ -250,13 +470,13 @@ mod tests {
     }
 
     fn encrypt(msg: PrincipalMessage) -> PrincipalMessage {
-        let key = b"EnigmaMPC".sha256();
+        let key = PrincipalResponseKey(*b"EnigmaMPC".sha256());
         let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
         msg.encrypt_with_nonce(&key, Some(iv)).unwrap()
     }
 
     fn decrypt(msg: PrincipalMessage) -> PrincipalMessage {
-        let key = b"EnigmaMPC".sha256();
+        let key = PrincipalResponseKey(*b"EnigmaMPC".sha256());
         PrincipalMessage::decrypt(msg, &key).unwrap()
     }
 
@@ -1,2 +1,3 @@
 pub mod km_primitives;
+pub mod receipts;
 pub mod serde_impls;
@@ -0,0 +1,220 @@
+//! The receipts a worker signs over the outcome of a task.
+//!
+//! A receipt is signed by the worker's enclave signing key as the keccak256 of
+//! `prepare_hash_multiple` over `RECEIPT_VERSION` followed by its fields, in the order they're listed in each variant.
+//! Integers are big endian and the ethereum fields are empty/zeroed if the task didn't call a contract.
+//! The last field is always the `ResultStatus` as a single byte.
+use crate::common::errors::ToolsError::{self, SignatureError, WrongSignerError};
+use crate::localstd::{mem, vec::Vec};
+use enigma_crypto::{asymmetric::KeyPair, hash::{self, Keccak256}};
+use enigma_types::{ContractAddress, Hash256, KeyVersion, PubKey, ResultStatus, AAD_BOUND_FLAG};
+
+/// The version of the layout documented on `TaskReceipt`, any change to the signed fields bumps it.
+pub const RECEIPT_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TaskReceipt {
+    /// A successful computation on an existing contract.
+    Compute {
+        exe_code_hash: Hash256,
+        inputs_hash: Hash256,
+        /// The hash of the latest delta before the task, zero if the contract has only the initial state
+        prev_delta_hash: Hash256,
+        /// The hash of the delta the task produced, zero if the state wasn't changed
+        delta_hash: Hash256,
+        /// The hash of the encrypted output
+        output_hash: Hash256,
+        used_gas: u64,
+        ethereum_payload: Vec<u8>,
+        ethereum_address: [u8; 20],
+        block_number: u64,
+        rand_seed: Hash256,
+    },
+    /// A successful deployment, the inputs hash is signed before the code hash.
+    Deploy {
+        inputs_hash: Hash256,
+        /// The hash of the deployed code, as returned in the result's output
+        exe_code_hash: Hash256,
+        /// The hash of the first delta of the contract
+        delta_hash: Hash256,
+        used_gas: u64,
+        ethereum_payload: Vec<u8>,
+        ethereum_address: [u8; 20],
+        block_number: u64,
+        rand_seed: Hash256,
+    },
+    /// A failed task, which signs only what was known before the execution:
+    /// `[inputs_hash, exe_code_hash]` for a computation and `[inputs_hash]` for a deployment.
+    Failure {
+        pre_execution_data: Vec<Hash256>,
+        used_gas: u64,
+    },
+}
+
+impl TaskReceipt {
+    /// The inputs hash of a computation, `callable` and `args` as they were sent, encrypted.
+    pub fn compute_inputs_hash(callable: &[u8], args: &[u8], address: &ContractAddress, user_key: &PubKey) -> Hash256 {
+        hash::prepare_hash_multiple(&[callable, args, &address[..], &user_key[..]]).keccak256()
+    }
+
+    /// The inputs hash of a deployment, `constructor` and `args` as they were sent, encrypted.
+    pub fn deploy_inputs_hash(constructor: &[u8], args: &[u8], pre_code_hash: &Hash256, user_key: &PubKey) -> Hash256 {
+        hash::prepare_hash_multiple(&[constructor, args, &pre_code_hash[..], &user_key[..]]).keccak256()
+    }
+
+    /// The hash of a delta as it's stored and published, the same as the enclave's `EncryptedPatch::keccak256_patch`.
+    /// It commits to the key version prefix, so a receipt can't be replayed with the delta relabeled to another key version.
+    /// Deltas written before the prefix existed (without `AAD_BOUND_FLAG`) keep the hash of their ciphertext,
+    /// which is the one the receipts that were already signed refer to.
+    /// An empty delta, of a task that didn't change the state, hashes to zero.
+    pub fn stored_delta_hash(stored_delta: &[u8]) -> Hash256 {
+        let prefix_len = mem::size_of::<KeyVersion>();
+        if stored_delta.len() <= prefix_len {
+            return Hash256::default();
+        }
+        let mut key_version = [0u8; 4];
+        key_version.copy_from_slice(&stored_delta[..prefix_len]);
+        if KeyVersion::from_be_bytes(key_version) & AAD_BOUND_FLAG != 0 {
+            stored_delta.keccak256()
+        } else {
+            stored_delta[prefix_len..].keccak256()
+        }
+    }
+
+    pub fn status(&self) -> ResultStatus {
+        match self {
+            TaskReceipt::Failure { .. } => ResultStatus::Failure,
+            _ => ResultStatus::Ok,
+        }
+    }
+
+    /// The message the worker signs, the signature is over its keccak256.
+    pub fn to_sign(&self) -> Vec<u8> {
+        let version = RECEIPT_VERSION.to_be_bytes();
+        let status = [self.status() as u8];
+        match self {
+            TaskReceipt::Compute { exe_code_hash, inputs_hash, prev_delta_hash, delta_hash, output_hash, used_gas,
+                                   ethereum_payload, ethereum_address, block_number, rand_seed } => {
+                hash::prepare_hash_multiple(&[
+                    &version[..],
+                    &exe_code_hash[..],
+                    &inputs_hash[..],
+                    &prev_delta_hash[..],
+                    &delta_hash[..],
+                    &output_hash[..],
+                    &used_gas.to_be_bytes()[..],
+                    &ethereum_payload[..],
+                    &ethereum_address[..],
+                    &block_number.to_be_bytes()[..],
+                    &rand_seed[..],
+                    &status[..]])
+            }
+            TaskReceipt::Deploy { inputs_hash, exe_code_hash, delta_hash, used_gas, ethereum_payload,
+                                  ethereum_address, block_number, rand_seed } => {
+                hash::prepare_hash_multiple(&[
+                    &version[..],
+                    &inputs_hash[..],
+                    &exe_code_hash[..],
+                    &delta_hash[..],
+                    &used_gas.to_be_bytes()[..],
+                    &ethereum_payload[..],
+                    &ethereum_address[..],
+                    &block_number.to_be_bytes()[..],
+                    &rand_seed[..],
+                    &status[..]])
+            }
+            TaskReceipt::Failure { pre_execution_data, used_gas } => {
+                let used_gas = used_gas.to_be_bytes();
+                let mut to_sign: Vec<&[u8]> = Vec::with_capacity(pre_execution_data.len() + 3);
+                to_sign.push(&version);
+                to_sign.extend(pre_execution_data.iter().map(|data| &data[..]));
+                to_sign.push(&used_gas);
+                to_sign.push(&status);
+                hash::prepare_hash_multiple(&to_sign)
+            }
+        }
+    }
+
+    /// Recovers the signing address of the worker who signed the receipt.
+    pub fn recover_signer(&self, sig: &[u8; 65]) -> Result<[u8; 20], ToolsError> {
+        KeyPair::recover_address(&self.to_sign(), *sig).map_err(|_| SignatureError { err: "Unable to recover the signer" })
+    }
+
+    /// Checks that the receipt was signed by the worker with the given signing address.
+    pub fn verify(&self, sig: &[u8; 65], worker: &[u8; 20]) -> Result<(), ToolsError> {
+        let signer = self.recover_signer(sig)?;
+        if &signer != worker {
+            return Err(WrongSignerError { signer, worker: *worker });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TaskReceipt, RECEIPT_VERSION};
+    use crate::common::errors::ToolsError;
+    use crate::common::utils::EthereumAddress;
+    use enigma_crypto::{asymmetric::KeyPair, hash::Keccak256};
+    use enigma_types::{Hash256, AAD_BOUND_FLAG};
+
+    fn compute_receipt() -> TaskReceipt {
+        TaskReceipt::Compute {
+            exe_code_hash: b"code"[..].keccak256(),
+            inputs_hash: b"inputs"[..].keccak256(),
+            prev_delta_hash: Hash256::default(),
+            delta_hash: b"delta"[..].keccak256(),
+            output_hash: b"output"[..].keccak256(),
+            used_gas: 1000,
+            ethereum_payload: vec![],
+            ethereum_address: [0u8; 20],
+            block_number: 15,
+            rand_seed: b"seed"[..].keccak256(),
+        }
+    }
+
+    #[test]
+    fn test_compute_receipt_layout() {
+        let receipt = compute_receipt();
+        match &receipt {
+            TaskReceipt::Compute { exe_code_hash, inputs_hash, prev_delta_hash, delta_hash, output_hash, rand_seed, .. } => {
+                let expected = enigma_crypto::hash::prepare_hash_multiple(&[
+                    &RECEIPT_VERSION.to_be_bytes()[..], &exe_code_hash[..], &inputs_hash[..], &prev_delta_hash[..],
+                    &delta_hash[..], &output_hash[..], &1000u64.to_be_bytes()[..], &b""[..], &[0u8; 20][..],
+                    &15u64.to_be_bytes()[..], &rand_seed[..], &[1u8][..]]);
+                assert_eq!(receipt.to_sign(), expected);
+            }
+            other => panic!("Expected a compute receipt, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stored_delta_hash() {
+        assert_eq!(TaskReceipt::stored_delta_hash(&[]), Hash256::default());
+        let ciphertext = b"ciphertext";
+        // A delta written before the key version prefix is hashed without it
+        let legacy = [&[0u8; 4][..], &ciphertext[..]].concat();
+        assert_eq!(TaskReceipt::stored_delta_hash(&legacy), ciphertext[..].keccak256());
+        let bound = [&(AAD_BOUND_FLAG | 1).to_be_bytes()[..], &ciphertext[..]].concat();
+        assert_eq!(TaskReceipt::stored_delta_hash(&bound), bound[..].keccak256());
+    }
+
+    #[test]
+    fn test_verify_receipt() {
+        let worker = KeyPair::new().unwrap();
+        let receipt = compute_receipt();
+        let sig = worker.sign(&receipt.to_sign()).unwrap();
+        assert_eq!(receipt.recover_signer(&sig).unwrap(), worker.get_pubkey().address());
+        receipt.verify(&sig, &worker.get_pubkey().address()).unwrap();
+
+        let other = KeyPair::new().unwrap().get_pubkey().address();
+        match receipt.verify(&sig, &other) {
+            Err(ToolsError::WrongSignerError { signer, .. }) => assert_eq!(signer, worker.get_pubkey().address()),
+            res => panic!("Expected the wrong signer, got: {:?}", res),
+        }
+
+        let failure = TaskReceipt::Failure { pre_execution_data: vec![b"inputs"[..].keccak256()], used_gas: 0 };
+        assert!(failure.verify(&sig, &worker.get_pubkey().address()).is_err());
+        assert!(receipt.verify(&[0u8; 65], &worker.get_pubkey().address()).is_err());
+    }
+}
@@ -4,14 +4,17 @@ use crate::primitives::km_primitives::*;
 use crate::serde::{de::{EnumAccess, Error, IgnoredAny, MapAccess, SeqAccess, Unexpected, VariantAccess, Visitor},
                    ser::SerializeStruct,
                    Deserialize, Deserializer, Serialize, Serializer};
-use enigma_types::{ContractAddress, StateKey};
+use enigma_types::{ContractAddress, KeyVersion, SessionId, StateKey};
 // The main reason why we need to implement Serialize/Deserialize ourselves is because the derive macro
 // contains `extern crate serde as _serde` but we renamed serde. so that's invalid. https://github.com/serde-rs/serde/pull/1499
 impl Serialize for UserMessage {
     fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        let mut state = Serializer::serialize_struct(ser, "UserMessage", 1)?;
+        let mut state = Serializer::serialize_struct(ser, "UserMessage", 4)?;
+        state.serialize_field("version", &self.version)?;
         state.serialize_field("pubkey", &self.pubkey)?;
+        state.serialize_field("session", &self.session)?;
+        state.serialize_field("uses", &self.uses)?;
         state.end()
     }
 }
@@ -19,7 +22,47 @@ impl Serialize for UserMessage {
 impl<'de> Deserialize<'de> for UserMessage {
     fn deserialize<D>(des: D) -> Result<Self, D::Error>
     where D: Deserializer<'de> {
+        #[allow(non_camel_case_types)]
+        enum UserMessageFields {
+            version,
+            pubkey,
+            session,
+            uses,
+            __ignore,
+        }
+        struct FieldsVisitor;
         struct UserMessageVisitor;
+        const FIELDS: &[&str] = &["version", "pubkey", "session", "uses"];
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = UserMessageFields;
+            fn expecting(&self, fmt: &mut Formatter) -> fmt::Result { fmt.write_str("field identifier") }
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where E: Error {
+                match value {
+                    "version" => Ok(UserMessageFields::version),
+                    "pubkey" => Ok(UserMessageFields::pubkey),
+                    "session" => Ok(UserMessageFields::session),
+                    "uses" => Ok(UserMessageFields::uses),
+                    _ => Ok(UserMessageFields::__ignore),
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for UserMessageFields {
+            #[inline]
+            fn deserialize<D>(des: D) -> Result<Self, D::Error>
+            where D: Deserializer<'de> {
+                des.deserialize_identifier(FieldsVisitor)
+            }
+        }
+
+        fn check_pubkey<E: Error>(pubkey: &[u8]) -> Result<(), E> {
+            if pubkey.len() != 64 {
+                return Err(Error::invalid_value(Unexpected::Bytes(pubkey), &"The pubkey should be 64 bytes"));
+            }
+            Ok(())
+        }
 
         impl<'de> Visitor<'de> for UserMessageVisitor {
             type Value = UserMessage;
@@ -27,16 +70,70 @@ impl<'de> Deserialize<'de> for UserMessage {
 
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
             where A: SeqAccess<'de> {
-                let err_msg = "struct UserMessage with 1 elements";
-                let pubkey = seq.next_element::<Vec<u8>>()?.ok_or_else(|| Error::invalid_length(0, &err_msg))?;
-                if pubkey.len() != 64 {
-                    return Err(Error::invalid_value(Unexpected::Bytes(&pubkey), &"The pubkey should be 64 bytes"));
+                let err_msg = "struct UserMessage with 4 elements";
+                let version = seq.next_element::<u8>()?.ok_or_else(|| Error::invalid_length(0, &err_msg))?;
+                let pubkey = seq.next_element::<Vec<u8>>()?.ok_or_else(|| Error::invalid_length(1, &err_msg))?;
+                check_pubkey(&pubkey)?;
+                let session = seq.next_element::<SessionId>()?.ok_or_else(|| Error::invalid_length(2, &err_msg))?;
+                let uses = seq.next_element::<u32>()?.ok_or_else(|| Error::invalid_length(3, &err_msg))?;
+                Ok(UserMessage { version, pubkey, session, uses })
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where A: MapAccess<'de> {
+                let mut version: Option<u8> = None;
+                let mut pubkey: Option<Vec<u8>> = None;
+                let mut session: Option<SessionId> = None;
+                let mut uses: Option<u32> = None;
+
+                while let Some(key) = map.next_key::<UserMessageFields>()? {
+                    match key {
+                        UserMessageFields::version => {
+                            if version.is_some() {
+                                return Err(<A::Error as Error>::duplicate_field("version"));
+                            } else {
+                                version = Some(map.next_value()?);
+                            }
+                        }
+                        UserMessageFields::pubkey => {
+                            if pubkey.is_some() {
+                                return Err(<A::Error as Error>::duplicate_field("pubkey"));
+                            } else {
+                                pubkey = Some(map.next_value()?);
+                            }
+                        }
+                        UserMessageFields::session => {
+                            if session.is_some() {
+                                return Err(<A::Error as Error>::duplicate_field("session"));
+                            } else {
+                                session = Some(map.next_value()?);
+                            }
+                        }
+                        UserMessageFields::uses => {
+                            if uses.is_some() {
+                                return Err(<A::Error as Error>::duplicate_field("uses"));
+                            } else {
+                                uses = Some(map.next_value()?);
+                            }
+                        }
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
                 }
-                Ok(UserMessage { pubkey })
+
+                let pubkey = pubkey.ok_or_else(|| Error::missing_field("pubkey"))?;
+                check_pubkey(&pubkey)?;
+                let session = session.ok_or_else(|| Error::missing_field("session"))?;
+                let uses = uses.ok_or_else(|| Error::missing_field("uses"))?;
+                // Messages from before the key exchange was versioned are version 0
+                let version = version.unwrap_or(0);
+
+                Ok(UserMessage { version, pubkey, session, uses })
             }
         }
 
-        des.deserialize_newtype_struct("UserMessage", UserMessageVisitor)
+        des.deserialize_struct("UserMessage", FIELDS, UserMessageVisitor)
     }
 }
 
@@ -49,6 +146,9 @@ impl Serialize for PrincipalMessageType {
             PrincipalMessageType::EncryptedResponse(ref f) => {
                 ser.serialize_newtype_variant("PrincipalMessageType", 2, "EncryptedResponse", f)
             }
+            PrincipalMessageType::VersionedResponse(ref f) => {
+                ser.serialize_newtype_variant("PrincipalMessageType", 3, "VersionedResponse", f)
+            }
         }
     }
 }
@@ -61,11 +161,12 @@ impl<'de> Deserialize<'de> for PrincipalMessageType {
             Response,
             Request,
             EncryptedResponse,
+            VersionedResponse,
         }
         struct FieldsVisitor;
 
         struct PrincipalMessageTypeVisitor;
-        const VARIANTS: &[&str] = &["Response", "Request", "EncryptedResponse"];
+        const VARIANTS: &[&str] = &["Response", "Request", "EncryptedResponse", "VersionedResponse"];
 
         impl<'de> Visitor<'de> for FieldsVisitor {
             type Value = PrincipalMessageTypeFields;
@@ -76,6 +177,7 @@ impl<'de> Deserialize<'de> for PrincipalMessageType {
                     "Response" => Ok(PrincipalMessageTypeFields::Response),
                     "Request" => Ok(PrincipalMessageTypeFields::Request),
                     "EncryptedResponse" => Ok(PrincipalMessageTypeFields::EncryptedResponse),
+                    "VersionedResponse" => Ok(PrincipalMessageTypeFields::VersionedResponse),
                     _ => Err(Error::unknown_variant(value, VARIANTS)),
                 }
             }
@@ -104,6 +206,9 @@ impl<'de> Deserialize<'de> for PrincipalMessageType {
                     (PrincipalMessageTypeFields::EncryptedResponse, var) => {
                         var.newtype_variant::<Vec<u8>>().map(PrincipalMessageType::EncryptedResponse)
                     }
+                    (PrincipalMessageTypeFields::VersionedResponse, var) => {
+                        var.newtype_variant::<Vec<(ContractAddress, KeyVersion, StateKey)>>().map(PrincipalMessageType::VersionedResponse)
+                    }
                 }
             }
         }
@@ -115,10 +220,15 @@ impl<'de> Deserialize<'de> for PrincipalMessageType {
 impl Serialize for PrincipalMessage {
     fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        let mut state = Serializer::serialize_struct(ser, "PrincipalMessage", 3)?;
+        // Only responses carry an epoch, so requests are serialized just like before it was added.
+        let mut state = Serializer::serialize_struct(ser, "PrincipalMessage", 3 + self.epoch.is_some() as usize)?;
         state.serialize_field("data", &self.data)?;
         state.serialize_field("pubkey", &self.pubkey)?;
         state.serialize_field("id", &self.id)?;
+        match self.epoch {
+            Some(ref epoch) => state.serialize_field("epoch", epoch)?,
+            None => state.skip_field("epoch")?,
+        }
         state.end()
     }
 }
@@ -131,11 +241,12 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
             data,
             pubkey,
             id,
+            epoch,
             __ignore,
         }
         struct FieldsVisitor;
         struct PrincipalMessageVisitor;
-        const VARIANTS: &[&str] = &["prefix", "data", "pubkey", "id"];
+        const VARIANTS: &[&str] = &["prefix", "data", "pubkey", "id", "epoch"];
 
         impl<'de> Visitor<'de> for FieldsVisitor {
             type Value = PrincipalMessageFields;
@@ -146,6 +257,7 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
                     "data" => Ok(PrincipalMessageFields::data),
                     "pubkey" => Ok(PrincipalMessageFields::pubkey),
                     "id" => Ok(PrincipalMessageFields::id),
+                    "epoch" => Ok(PrincipalMessageFields::epoch),
                     _ => Ok(PrincipalMessageFields::__ignore),
                 }
             }
@@ -165,15 +277,16 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
 
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
             where A: SeqAccess<'de> {
-                let err_msg = "struct PrincipalMessage with 3 elements";
+                let err_msg = "struct PrincipalMessage with 3 or 4 elements";
                 let data = seq.next_element::<PrincipalMessageType>()?.ok_or_else(|| Error::invalid_length(0, &err_msg))?;
                 let pubkey = seq.next_element::<Vec<u8>>()?.ok_or_else(|| Error::invalid_length(1, &err_msg))?;
                 if pubkey.len() != 64 {
                     return Err(Error::invalid_value(Unexpected::Bytes(&pubkey), &"The pubkey should be 64 bytes"));
                 }
                 let id = seq.next_element::<MsgID>()?.ok_or_else(|| Error::invalid_length(2, &err_msg))?;
+                let epoch = seq.next_element::<u64>()?;
 
-                Ok(PrincipalMessage { data, pubkey, id })
+                Ok(PrincipalMessage { data, pubkey, id, epoch })
             }
 
             fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
@@ -181,6 +294,7 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
                 let mut data: Option<PrincipalMessageType> = None;
                 let mut pubkey: Option<Vec<u8>> = None;
                 let mut id: Option<MsgID> = None;
+                let mut epoch: Option<u64> = None;
 
                 while let Some(key) = map.next_key::<PrincipalMessageFields>()? {
                     match key {
@@ -205,6 +319,13 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
                                 id = Some(map.next_value()?);
                             }
                         }
+                        PrincipalMessageFields::epoch => {
+                            if epoch.is_some() {
+                                return Err(<A::Error as Error>::duplicate_field("epoch"));
+                            } else {
+                                epoch = Some(map.next_value()?);
+                            }
+                        }
                         _ => {
                             map.next_value::<IgnoredAny>()?;
                         }
@@ -215,7 +336,7 @@ impl<'de> Deserialize<'de> for PrincipalMessage {
                 let pubkey = pubkey.ok_or_else(|| Error::missing_field("pubkey"))?;
                 let id = id.ok_or_else(|| Error::missing_field("id"))?;
 
-                Ok(PrincipalMessage { data, pubkey, id })
+                Ok(PrincipalMessage { data, pubkey, id, epoch })
             }
         }
 
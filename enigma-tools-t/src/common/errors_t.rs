@@ -1,4 +1,4 @@
-use enigma_types::{EnclaveReturn, ResultToEnclaveReturn};
+use enigma_types::{limits::LimitsError, EnclaveReturn, ResultToEnclaveReturn};
 use enigma_tools_m::ToolsError;
 use json_patch;
 use pwasm_utils as wasm_utils;
@@ -96,6 +96,13 @@ impl From<wasmi::Error> for EnclaveError{
                     TrapKind::StackOverflow => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError{ err: "stack overflow".to_string() }),
                 }
             }
+            // Raised by the import resolver while the module is instantiated, e.g. when it requests too much memory.
+            wasmi::Error::Host(host) => {
+                match (*host).downcast_ref::<WasmError>() {
+                    Some(WasmError::EnclaveError(err)) => err.clone(),
+                    _ => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: format!("{}", host) }),
+                }
+            }
             _ => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: e.to_string() })
         }
     }
@@ -135,6 +142,9 @@ pub enum FailedTaskError {
     #[fail(display = "Invocation resulted in gas limit violated")]
     GasLimitError,
 
+    #[fail(display = "The contract requested {} {}, the limit is {}", requested, resource, limit)]
+    ResourceLimitError { resource: String, requested: u32, limit: u32 },
+
     #[fail(display = "Error in EVM:  {}", err)]
     EvmError { err: String },
 }
@@ -162,8 +172,29 @@ pub enum EnclaveSystemError {
     #[fail(display = "Failed to authenticate the worker: {}", err)]
     WorkerAuthError { err: String },
 
+    #[fail(display = "Failed to authenticate the principal: {}", err)]
+    PrincipalAuthError { err: String },
+
     #[fail(display = "Failed to provide state key: {}", err)]
     KeyProvisionError { err: String },
+
+    #[fail(display = "No such encryption session: {}", err)]
+    UnknownSessionError { err: String },
+
+    #[fail(display = "The encryption session was already used up: {}", err)]
+    ConsumedSessionError { err: String },
+
+    #[fail(display = "Invalid sealed document: {}", err)]
+    SealedDocumentError { err: String },
+}
+
+impl From<LimitsError> for EnclaveError {
+    fn from(err: LimitsError) -> EnclaveError {
+        match err {
+            LimitsError::Malformed(err) => EnclaveError::FailedTaskError(FailedTaskError::WasmModuleCreationError { code: "reading the contract limits".to_string(), err: err.to_string() }),
+            LimitsError::ResourceLimit { resource, requested, limit } => EnclaveError::FailedTaskError(FailedTaskError::ResourceLimitError { resource: resource.to_string(), requested, limit }),
+        }
+    }
 }
 
 impl From<CryptoError> for EnclaveError {
@@ -175,7 +206,10 @@ impl From<CryptoError> for EnclaveError {
 impl From<ToolsError> for EnclaveError {
     fn from(err: ToolsError) -> Self {
         match err {
-            ToolsError::MessagingError {err} => EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: err.to_string() })
+            ToolsError::MessagingError {err} => EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: err.to_string() }),
+            // Signatures verified by the tools are the ones of other workers' receipts.
+            e @ ToolsError::SignatureError { .. } | e @ ToolsError::WrongSignerError { .. } =>
+                EnclaveError::SystemError(EnclaveSystemError::WorkerAuthError { err: e.to_string() }),
         }
     }
 }
@@ -236,7 +270,11 @@ impl Into<EnclaveReturn> for EnclaveError {
                         ParsingError { ..} | RecoveryError { .. } => EnclaveReturn::EncryptionError,
                     }
                     WorkerAuthError { .. } => EnclaveReturn::WorkerAuthError,
+                    PrincipalAuthError { .. } => EnclaveReturn::PrincipalAuthError,
                     KeyProvisionError { .. } => EnclaveReturn::KeyProvisionError,
+                    UnknownSessionError { .. } => EnclaveReturn::UnknownSessionError,
+                    ConsumedSessionError { .. } => EnclaveReturn::ConsumedSessionError,
+                    SealedDocumentError { .. } => EnclaveReturn::SealedDocumentError,
                  }
 
              }
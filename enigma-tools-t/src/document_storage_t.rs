@@ -4,6 +4,7 @@ use sgx_types::marker::ContiguousMemory;
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::string::*;
+use std::vec::Vec;
 use std::untrusted::fs;
 use std::untrusted::fs::{File, remove_file};
 
@@ -11,6 +12,69 @@ use common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*};
 
 pub const SEAL_LOG_SIZE: usize = 2048;
 
+/// The sealed document format written by `seal_document`, documents with a newer version are rejected.
+pub const SEALED_DOCUMENT_VERSION: u16 = 1;
+const SEALED_DOCUMENT_MAGIC: [u8; 4] = *b"ESDC";
+/// The `version` that every `SealedDocumentStorage` document was written with.
+const LEGACY_DOCUMENT_VERSION: u32 = 0x1234;
+const HEADER_SIZE: usize = 12;
+
+/// Identifies the payload of a sealed document so that one kind of document can't be read as another.
+pub type SealedDocumentType = u16;
+
+/// The header sealed together with the payload:
+/// magic (4 bytes) || format version (u16) || payload type (u16) || payload length (u32), all big endian.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SealedDocumentHeader {
+    pub format_version: u16,
+    pub payload_type: SealedDocumentType,
+    pub payload_len: u32,
+}
+
+impl SealedDocumentHeader {
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut res = [0u8; HEADER_SIZE];
+        res[..4].copy_from_slice(&SEALED_DOCUMENT_MAGIC);
+        res[4..6].copy_from_slice(&self.format_version.to_be_bytes());
+        res[6..8].copy_from_slice(&self.payload_type.to_be_bytes());
+        res[8..].copy_from_slice(&self.payload_len.to_be_bytes());
+        res
+    }
+
+    /// Returns None if the data doesn't start with a header, i.e. it was sealed before the header existed.
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[..4] != SEALED_DOCUMENT_MAGIC {
+            return None;
+        }
+        let (mut version, mut payload_type, mut payload_len) = ([0u8; 2], [0u8; 2], [0u8; 4]);
+        version.copy_from_slice(&data[4..6]);
+        payload_type.copy_from_slice(&data[6..8]);
+        payload_len.copy_from_slice(&data[8..HEADER_SIZE]);
+        Some(SealedDocumentHeader {
+            format_version: u16::from_be_bytes(version),
+            payload_type: u16::from_be_bytes(payload_type),
+            payload_len: u32::from_be_bytes(payload_len),
+        })
+    }
+}
+
+/// How a type of document was sealed before the header existed, documents of the other types must have a header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LegacyFormat {
+    /// A `SealedDocumentStorage`, read as its data.
+    Storage,
+    /// The raw payload of `seal_bytes`.
+    Bytes,
+}
+
+/// The payload of an unsealed document.
+/// `migrated` is set if the document was sealed in a format older than `SEALED_DOCUMENT_VERSION`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsealedDocument {
+    pub payload: Vec<u8>,
+    pub migrated: bool,
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 pub struct SealedDocumentStorage<T: ?Sized> {
     pub version: u32,
@@ -68,6 +132,117 @@ impl<T> SealedDocumentStorage<T> where
     }
 }
 
+/// Seal a variable length document, the returned sealed log is sized to fit the data
+/// param: data : clear text to be sealed
+pub fn seal_bytes(data: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let additional: [u8; 0] = [0_u8; 0];
+    let attribute_mask = sgx_attributes_t { flags: 0xffff_ffff_ffff_fff3, xfrm: 0 };
+    let sealed_data = SgxSealedData::<[u8]>::seal_data_ex(
+        0x0001, //key policy
+        attribute_mask,
+        0, //misc mask
+        &additional,
+        data,
+    )?;
+    let sealed_log_size = SgxSealedData::<[u8]>::calc_raw_sealed_data_size(additional.len() as u32, data.len() as u32);
+    let mut sealed_log = vec![0u8; sealed_log_size as usize];
+    let raw = unsafe { sealed_data.to_raw_sealed_data_t(sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t, sealed_log_size) };
+    if raw.is_none() {
+        return Err(SystemError(OcallError { command: "seal".to_string(), err: "Unable to write the sealed log".to_string() }));
+    }
+    Ok(sealed_log)
+}
+
+/// Unseal a sealed log created by `seal_bytes`
+/// A log that fails the MAC check was sealed by another enclave or tampered with, and is an error like any other.
+/// param: sealed_log_in : the encrypted blob
+pub fn unseal_bytes(sealed_log_in: &mut [u8]) -> Result<Vec<u8>, EnclaveError> {
+    let sealed_log_size = sealed_log_in.len() as u32;
+    let sealed_data = match unsafe {
+        SgxSealedData::<[u8]>::from_raw_sealed_data_t(sealed_log_in.as_mut_ptr() as *mut sgx_sealed_data_t, sealed_log_size)
+    } {
+        Some(data) => data,
+        None => {
+            return Err(SystemError(OcallError { command: "unseal".to_string(), err: "No data in sealed log".to_string() }));
+        }
+    };
+    match sealed_data.unseal_data() {
+        Ok(unsealed_data) => Ok(unsealed_data.get_decrypt_txt().to_vec()),
+        Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH) => {
+            Err(SystemError(SealedDocumentError { err: "MAC mismatch, the document was not sealed by this enclave".to_string() }))
+        }
+        Err(err) => Err(SystemError(OcallError { command: "unseal".to_string(), err: format!("{:?}", err) })),
+    }
+}
+
+/// Seal a payload of any size together with a versioned header
+pub fn seal_document(payload_type: SealedDocumentType, payload: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let header = SealedDocumentHeader { format_version: SEALED_DOCUMENT_VERSION, payload_type, payload_len: payload.len() as u32 };
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&header.to_bytes());
+    data.extend_from_slice(payload);
+    seal_bytes(&data)
+}
+
+/// Unseal a document and check its header.
+/// A document without a header is accepted as migrated only if its type was sealed in the `legacy` format before the header existed.
+pub fn unseal_document(payload_type: SealedDocumentType, legacy: Option<LegacyFormat>,
+                       sealed_log_in: &mut [u8]) -> Result<UnsealedDocument, EnclaveError> {
+    let data = unseal_bytes(sealed_log_in)?;
+    let header = match SealedDocumentHeader::from_bytes(&data) {
+        Some(header) => header,
+        None => {
+            let payload = match legacy {
+                // `SealedDocumentStorage` was sealed as is, so its version is in the native (little endian) byte order
+                Some(LegacyFormat::Storage) if data.len() >= 4 && data[..4] == LEGACY_DOCUMENT_VERSION.to_le_bytes() => data[4..].to_vec(),
+                Some(LegacyFormat::Bytes) => data,
+                _ => {
+                    return Err(SystemError(SealedDocumentError {
+                        err: format!("A document of type {} without a header in the expected format", payload_type),
+                    }))
+                }
+            };
+            return Ok(UnsealedDocument { payload, migrated: true });
+        }
+    };
+    if header.format_version == 0 || header.format_version > SEALED_DOCUMENT_VERSION {
+        return Err(SystemError(SealedDocumentError { err: format!("Unsupported format version: {}", header.format_version) }));
+    }
+    if header.payload_type != payload_type {
+        return Err(SystemError(SealedDocumentError {
+            err: format!("Expected a document of type {}, found type {}", payload_type, header.payload_type),
+        }));
+    }
+    let payload = &data[HEADER_SIZE..];
+    if payload.len() != header.payload_len as usize {
+        return Err(SystemError(SealedDocumentError {
+            err: format!("The payload is {} bytes but the header says {}", payload.len(), header.payload_len),
+        }));
+    }
+    Ok(UnsealedDocument { payload: payload.to_vec(), migrated: header.format_version < SEALED_DOCUMENT_VERSION })
+}
+
+/// Seal the payload and save it to the path
+pub fn save_document(path: &PathBuf, payload_type: SealedDocumentType, payload: &[u8]) -> Result<(), EnclaveError> {
+    let sealed = seal_document(payload_type, payload)?;
+    save_sealed_document(path, &sealed)
+}
+
+/// Load and unseal the payload of the document in the path, None if there is no such document.
+/// A document in an older format is sealed again in the current one.
+pub fn load_document(path: &PathBuf, payload_type: SealedDocumentType, legacy: Option<LegacyFormat>) -> Result<Option<Vec<u8>>, EnclaveError> {
+    if !is_document(path) {
+        return Ok(None);
+    }
+    let mut sealed = read_sealed_document(path)?;
+    let doc = unseal_document(payload_type, legacy, &mut sealed)?;
+    if doc.migrated {
+        println!("Migrating the sealed document: {:?}", path);
+        save_document(path, payload_type, &doc.payload)?;
+    }
+    Ok(Some(doc.payload))
+}
+
 fn to_sealed_log<T: Copy + ContiguousMemory>(sealed_data: &SgxSealedData<T>, sealed_log: *mut u8,
                                              sealed_log_size: u32, ) -> Option<*mut sgx_sealed_data_t> {
     unsafe { sealed_data.to_raw_sealed_data_t(sealed_log as *mut sgx_sealed_data_t, sealed_log_size) }
@@ -103,6 +278,24 @@ pub fn is_document(path: &PathBuf) -> bool {
     }
 }
 
+/// Load all the bytes of a variable length sealed document
+pub fn read_sealed_document(path: &PathBuf) -> Result<Vec<u8>, EnclaveError> {
+    let mut file = match File::open(path) {
+        Ok(opt) => opt,
+        Err(err) => {
+            return Err(SystemError(OcallError { command: "read_sealed_document".to_string(), err: format!("{:?}", err) }));
+        }
+    };
+    let mut sealed_document = Vec::new();
+    match file.read_to_end(&mut sealed_document) {
+        Ok(_) => println!("Sealed document: {:?} loaded successfully.", path),
+        Err(err) => {
+            return Err(SystemError(OcallError { command: "read_sealed_document".to_string(), err: format!("{:?}", err) }));
+        }
+    };
+    Ok(sealed_document)
+}
+
 /// Load bytes of a sealed document in the provided mutable byte array
 pub fn load_sealed_document(path: &PathBuf, sealed_document: &mut [u8]) -> Result<(), EnclaveError> {
     let mut file = match File::open(path) {
@@ -150,4 +343,74 @@ pub mod tests {
         let f = remove_file(&p);
         assert!(f.is_ok());
     }
+
+    pub fn test_versioned_document_sealing() {
+        let payload: Vec<u8> = (0..3 * SEAL_LOG_SIZE).map(|i| i as u8).collect();
+        let mut sealed = seal_document(7, &payload).expect("Unable to seal document");
+        let doc = unseal_document(7, None, &mut sealed).expect("Unable to unseal document");
+        assert_eq!(doc, UnsealedDocument { payload: payload.clone(), migrated: false });
+
+        // A document can't be read as another type
+        match unseal_document(8, None, &mut sealed) {
+            Err(SystemError(SealedDocumentError { .. })) => (),
+            other => panic!("Expected a SealedDocumentError, got: {:?}", other),
+        }
+
+        // Unknown versions are rejected
+        let header = SealedDocumentHeader { format_version: SEALED_DOCUMENT_VERSION + 1, payload_type: 7, payload_len: 0 };
+        let mut sealed = seal_bytes(&header.to_bytes()).unwrap();
+        match unseal_document(7, None, &mut sealed) {
+            Err(SystemError(SealedDocumentError { .. })) => (),
+            other => panic!("Expected a SealedDocumentError, got: {:?}", other),
+        }
+        // A payload length that doesn't match
+        let header = SealedDocumentHeader { format_version: SEALED_DOCUMENT_VERSION, payload_type: 7, payload_len: 1 };
+        let mut sealed = seal_bytes(&header.to_bytes()).unwrap();
+        assert!(unseal_document(7, None, &mut sealed).is_err());
+    }
+
+    pub fn test_legacy_document_migration() {
+        let legacy = SealedDocumentStorage { version: LEGACY_DOCUMENT_VERSION, data: [b'l'; 32] };
+        let mut sealed_log_in = [0u8; SEAL_LOG_SIZE];
+        legacy.seal(&mut sealed_log_in).unwrap();
+        let p = PathBuf::from("seal_legacy_test.sealed");
+        save_sealed_document(&p, &sealed_log_in).unwrap();
+
+        // Only the types that were sealed before the header existed can be read without it
+        match load_document(&p, 7, None) {
+            Err(SystemError(SealedDocumentError { .. })) => (),
+            other => panic!("Expected a SealedDocumentError, got: {:?}", other),
+        }
+        assert_eq!(load_document(&p, 7, Some(LegacyFormat::Storage)).unwrap().unwrap(), legacy.data.to_vec());
+        // The document was migrated to the current format
+        let mut sealed = read_sealed_document(&p).unwrap();
+        assert_eq!(unseal_document(7, None, &mut sealed).unwrap(), UnsealedDocument { payload: legacy.data.to_vec(), migrated: false });
+        assert!(remove_file(&p).is_ok());
+
+        // Documents sealed with `seal_bytes` have no header at all
+        let mut sealed = seal_bytes(b"raw document").unwrap();
+        assert_eq!(unseal_document(7, Some(LegacyFormat::Bytes), &mut sealed).unwrap(),
+                   UnsealedDocument { payload: b"raw document".to_vec(), migrated: true });
+        assert!(unseal_document(7, Some(LegacyFormat::Storage), &mut sealed).is_err());
+    }
+
+    pub fn test_bytes_sealing_storage() {
+        // Larger than SEAL_LOG_SIZE to make sure the sealed log grows with the data
+        let data: Vec<u8> = (0..3 * SEAL_LOG_SIZE).map(|i| i as u8).collect();
+        let sealed_log_in = seal_bytes(&data).expect("Unable to seal bytes");
+        let p = PathBuf::from("seal_bytes_test.sealed");
+        save_sealed_document(&p, &sealed_log_in).expect("Unable to save sealed document");
+        let mut sealed_log_out = read_sealed_document(&p).expect("Unable to read sealed document");
+        let unsealed = unseal_bytes(&mut sealed_log_out).expect("Unable to unseal bytes");
+        assert_eq!(data, unsealed);
+        assert!(remove_file(&p).is_ok());
+
+        // A log that fails the MAC check is an error
+        let mac_offset = sealed_log_out.len() - data.len() - 16;
+        sealed_log_out[mac_offset] ^= 1;
+        match unseal_bytes(&mut sealed_log_out) {
+            Err(SystemError(SealedDocumentError { .. })) => (),
+            other => panic!("Expected a SealedDocumentError, got: {:?}", other),
+        }
+    }
 }
@@ -2,6 +2,7 @@
 #![crate_type = "lib"]
 #![feature(core_intrinsics)]
 #![feature(try_from)]
+#![feature(int_to_from_bytes)]
 #![warn(unused_extern_crates)]
 
 extern crate enigma_types;
@@ -107,6 +107,21 @@ pub fn load_sealed_key(path: &str, sealed_key: &mut [u8]) {
 
 // TODO:: handle failure and return a result including the empty match
 pub fn get_sealed_keys(sealed_path: &str) -> Result<asymmetric::KeyPair, EnclaveError> {
+    let privkey = load_or_create_sealed(sealed_path, || Ok(asymmetric::KeyPair::new()?.get_privkey()))?;
+    Ok(asymmetric::KeyPair::from_slice(&privkey)?)
+}
+
+/// A random 32 bytes secret, sealed at `sealed_path` the first time it's requested.
+pub fn get_sealed_secret(sealed_path: &str) -> Result<[u8; SEALING_KEY_SIZE], EnclaveError> {
+    load_or_create_sealed(sealed_path, || {
+        let mut secret = [0u8; SEALING_KEY_SIZE];
+        enigma_crypto::rand::random(&mut secret)?;
+        Ok(secret)
+    })
+}
+
+fn load_or_create_sealed<F>(sealed_path: &str, create: F) -> Result<[u8; SEALING_KEY_SIZE], EnclaveError>
+    where F: FnOnce() -> Result<[u8; SEALING_KEY_SIZE], EnclaveError> {
     // Open the file
     match File::open(sealed_path) {
         Ok(mut file) => {
@@ -116,10 +131,10 @@ pub fn get_sealed_keys(sealed_path: &str) -> Result<asymmetric::KeyPair, Enclave
                 Err(_e) => {}
             }
             match SecretKeyStorage::unseal_key(&mut sealed) {
-                // If the data is unsealed correctly return this KeyPair.
+                // If the data is unsealed correctly return it.
                 Some(unsealed_data) => {
                     debug_println!("Succeeded reading key from file");
-                    return Ok(asymmetric::KeyPair::from_slice(&unsealed_data.data)?);
+                    return Ok(unsealed_data.data);
                 }
                 // If the data couldn't get unsealed remove the file.
                 None => {
@@ -135,18 +150,16 @@ pub fn get_sealed_keys(sealed_path: &str) -> Result<asymmetric::KeyPair, Enclave
         }
     }
 
-    // Generate a new Keypair and seal it.
-    let keypair = asymmetric::KeyPair::new()?;
-    let data = SecretKeyStorage { version: 0x1, data: keypair.get_privkey() };
+    // Generate new data and seal it.
+    let data = SecretKeyStorage { version: 0x1, data: create()? };
     let mut output: [u8; SEAL_LOG_SIZE] = [0; SEAL_LOG_SIZE];
     data.seal_key(&mut output);
     save_sealed_key(&sealed_path, &output);
 
-    Ok(keypair)
+    Ok(data.data)
 }
 
 
-
 //#[cfg(debug_assertions)]
 pub mod tests {
     use storage_t::*;
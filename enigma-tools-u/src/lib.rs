@@ -3,6 +3,7 @@
 
 extern crate enigma_crypto;
 extern crate enigma_types;
+extern crate ethabi;
 #[macro_use]
 extern crate failure;
 extern crate reqwest;
@@ -2,12 +2,13 @@ use std::path::Path;
 use std::str;
 use std::sync::Arc;
 
+use ethabi::{Event, EventParam, ParamType};
 use failure::Error;
 use hex::{FromHex, ToHex};
 use web3::contract::{Contract, Options};
 use web3::futures::Future;
 use web3::transports::{EventLoopHandle, Http};
-use web3::types::{Address, Bytes, H160, H256, TransactionReceipt, U256};
+use web3::types::{Address, BlockNumber, Bytes, FilterBuilder, H160, H256, Log, TransactionReceipt, U256};
 use web3::Web3;
 
 use enigma_types::ContractAddress;
@@ -76,6 +77,69 @@ impl EnigmaContract {
     pub fn address(&self) -> Address { self.w3_contract.address() }
 }
 
+/// The `WorkersParameterized` event emitted by the Enigma contract for each new epoch
+#[derive(Debug, Clone)]
+pub struct WorkersParameterizedEvent(pub Event);
+
+impl WorkersParameterizedEvent {
+    pub fn new() -> Self {
+        WorkersParameterizedEvent(Event {
+            name: "WorkersParameterized".to_string(),
+            inputs: vec![
+                EventParam { name: "seed".to_string(), kind: ParamType::Uint(256), indexed: false },
+                EventParam { name: "firstBlockNumber".to_string(), kind: ParamType::Uint(256), indexed: false },
+                EventParam { name: "inclusionBlockNumber".to_string(), kind: ParamType::Uint(256), indexed: false },
+                EventParam { name: "workers".to_string(), kind: ParamType::Array(Box::new(ParamType::Address)), indexed: false },
+                EventParam { name: "stakes".to_string(), kind: ParamType::Array(Box::new(ParamType::Uint(256))), indexed: false },
+                EventParam { name: "nonce".to_string(), kind: ParamType::Uint(256), indexed: false },
+            ],
+            anonymous: false,
+        })
+    }
+}
+
+/// The Enigma contract and the chain it is deployed on, as used by the Principal node.
+/// Implemented by `EnigmaContract` and by the in-process `FakeChain` for offline testing.
+pub trait EnigmaContractApi: ContractFuncs<U256> + ContractQueries + Send + Sync {
+    /// The address of the Enigma contract
+    fn address(&self) -> Address;
+
+    /// The account sending the transactions
+    fn account(&self) -> Address;
+
+    /// Returns the current block number
+    fn block_number(&self) -> Result<U256, Error>;
+
+    /// Returns the logs of an event emitted by the Enigma contract from the block number on, the oldest first
+    fn get_logs(&self, event_signature: H256, from_block: U256) -> Result<Vec<Log>, Error>;
+}
+
+impl EnigmaContractApi for EnigmaContract {
+    fn address(&self) -> Address { self.w3_contract.address() }
+
+    fn account(&self) -> Address { self.account }
+
+    fn block_number(&self) -> Result<U256, Error> {
+        match self.web3.eth().block_number().wait() {
+            Ok(block_number) => Ok(block_number),
+            Err(e) => Err(errors::Web3Error { message: format!("Unable to fetch the block number: {:?}", e) }.into()),
+        }
+    }
+
+    fn get_logs(&self, event_signature: H256, from_block: U256) -> Result<Vec<Log>, Error> {
+        let filter = FilterBuilder::default()
+            .address(vec![self.w3_contract.address()])
+            .topics(Some(vec![event_signature]), None, None, None)
+            .from_block(BlockNumber::Number(from_block.low_u64()))
+            .to_block(BlockNumber::Latest)
+            .build();
+        match self.web3.eth().logs(filter).wait() {
+            Ok(logs) => Ok(logs),
+            Err(e) => Err(errors::Web3Error { message: format!("Unable to fetch the logs: {:?}", e) }.into()),
+        }
+    }
+}
+
 pub trait ContractFuncs<G> {
     // register
     // input: _signer: Address, _report: bytes
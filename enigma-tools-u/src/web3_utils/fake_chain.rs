@@ -0,0 +1,315 @@
+//! An in-process fake of the Ethereum chain running the Enigma contract.
+//! Every transaction is mined in its own block with its receipt and events, and the contract storage
+//! is kept for each block so that the Principal node epoch cycle, including reorgs, can be tested
+//! without an Ethereum node.
+//! The enclave signatures are not verified.
+
+use std::sync::{Mutex, MutexGuard};
+
+use ethabi::{self, Token};
+use failure::Error;
+use web3::types::{Address, Bytes, H160, H256, Index, Log, TransactionReceipt, U256};
+
+use enigma_crypto::hash::Keccak256;
+use enigma_types::ContractAddress;
+
+use crate::web3_utils::enigma_contract::{ContractFuncs, ContractQueries, EnigmaContractApi, WorkersParameterizedEvent};
+
+/// The storage of the Enigma contract at the end of a block
+#[derive(Debug, Clone, Default)]
+struct ContractStorage {
+    signing_address: H160,
+    workers: Vec<(H160, U256)>,
+    secret_contracts: Vec<ContractAddress>,
+    epoch_nonce: U256,
+}
+
+#[derive(Debug, Clone)]
+struct FakeBlock {
+    hash: H256,
+    receipts: Vec<TransactionReceipt>,
+    storage: ContractStorage,
+}
+
+#[derive(Debug)]
+struct FakeChainState {
+    blocks: Vec<FakeBlock>,
+    /// The number of reorgs, so that the replaced blocks get new hashes
+    reorgs: u64,
+}
+
+impl FakeChainState {
+    fn latest(&self) -> &FakeBlock { self.blocks.last().expect("The genesis block is never removed") }
+
+    /// Returns the contract storage at the block number, the latest one for future blocks
+    fn storage_at(&self, block_number: U256) -> &ContractStorage {
+        let latest = self.blocks.len() - 1;
+        let index = if block_number > U256::from(latest) { latest } else { block_number.low_u64() as usize };
+        &self.blocks[index].storage
+    }
+
+    fn block_hash(&self, number: usize) -> H256 {
+        let mut preimage = [0u8; 64];
+        U256::from(number).to_big_endian(&mut preimage[..32]);
+        U256::from(self.reorgs).to_big_endian(&mut preimage[32..]);
+        H256::from_slice(&preimage.keccak256()[..])
+    }
+
+    fn push_block(&mut self, storage: ContractStorage) -> usize {
+        let number = self.blocks.len();
+        let hash = self.block_hash(number);
+        self.blocks.push(FakeBlock { hash, receipts: Vec::new(), storage });
+        number
+    }
+}
+
+/// A fake chain with the Enigma contract deployed at `address`, sending the transactions from `account`
+#[derive(Debug)]
+pub struct FakeChain {
+    address: Address,
+    account: Address,
+    state: Mutex<FakeChainState>,
+}
+
+impl FakeChain {
+    pub fn new(address: Address, account: Address) -> Self {
+        let mut state = FakeChainState { blocks: Vec::new(), reorgs: 0 };
+        state.push_block(ContractStorage::default());
+        FakeChain { address, account, state: Mutex::new(state) }
+    }
+
+    fn lock_state(&self) -> MutexGuard<FakeChainState> { self.state.lock().expect("The FakeChain state is poisoned") }
+
+    /// Mine empty blocks
+    pub fn mine(&self, count: usize) {
+        let mut state = self.lock_state();
+        for _ in 0..count {
+            let storage = state.latest().storage.clone();
+            state.push_block(storage);
+        }
+    }
+
+    /// Replace the `depth` latest blocks by as many empty blocks.
+    /// The transactions, events and storage changes of the replaced blocks are dropped.
+    pub fn reorg(&self, depth: usize) {
+        let mut state = self.lock_state();
+        let depth = depth.min(state.blocks.len() - 1);
+        let fork = state.blocks.len() - depth;
+        state.blocks.truncate(fork);
+        state.reorgs += 1;
+        drop(state);
+        self.mine(depth);
+    }
+
+    /// Register a worker with its stake, active from the next block
+    pub fn add_worker(&self, worker: H160, stake: U256) {
+        self.transact(|storage, _| {
+            storage.workers.push((worker, stake));
+            None
+        });
+    }
+
+    /// Deploy a secret contract, counted from the next block
+    pub fn add_secret_contract(&self, address: ContractAddress) {
+        self.transact(|storage, _| {
+            storage.secret_contracts.push(address);
+            None
+        });
+    }
+
+    /// Set the nonce of the next epoch, which must follow the nonce sealed in the Principal enclave
+    pub fn set_epoch_nonce(&self, nonce: U256) {
+        self.transact(|storage, _| {
+            storage.epoch_nonce = nonce;
+            None
+        });
+    }
+
+    /// Mine a transaction in a new block, `call` updates the contract storage at the block number
+    /// and returns the (signature, data) of the event it emits, if any
+    fn transact<F>(&self, call: F) -> TransactionReceipt
+    where F: FnOnce(&mut ContractStorage, U256) -> Option<(H256, Vec<u8>)> {
+        let mut state = self.lock_state();
+        let mut storage = state.latest().storage.clone();
+        let number = state.blocks.len();
+        let event = call(&mut storage, U256::from(number));
+        state.push_block(storage);
+
+        let block_hash = state.latest().hash;
+        let block_number = U256::from(number);
+        let transaction_hash = H256::from_slice(&block_hash[..].keccak256()[..]);
+        let logs = event
+            .into_iter()
+            .enumerate()
+            .map(|(i, (signature, data))| Log {
+                address: self.address,
+                topics: vec![signature],
+                data: Bytes(data),
+                block_hash: Some(block_hash),
+                block_number: Some(block_number),
+                transaction_hash: Some(transaction_hash),
+                transaction_index: Some(Index::from(0)),
+                log_index: Some(U256::from(i)),
+                transaction_log_index: Some(U256::from(i)),
+                log_type: None,
+                removed: Some(false),
+            })
+            .collect();
+        let receipt = TransactionReceipt {
+            transaction_hash,
+            transaction_index: Index::from(0),
+            block_hash: Some(block_hash),
+            block_number: Some(block_number),
+            cumulative_gas_used: U256::from(0),
+            gas_used: Some(U256::from(0)),
+            contract_address: None,
+            logs,
+            status: Some(1.into()),
+        };
+        state.blocks[number].receipts.push(receipt.clone());
+        receipt
+    }
+}
+
+impl<G: Into<U256>> ContractFuncs<G> for FakeChain {
+    fn register(&self, signing_address: String, _report: String, _signature: String, _gas: G, confirmations: usize) -> Result<TransactionReceipt, Error> {
+        let signing_address: H160 = signing_address.parse()?;
+        let receipt = self.transact(|storage, _| {
+            storage.signing_address = signing_address;
+            None
+        });
+        self.mine(confirmations);
+        Ok(receipt)
+    }
+
+    fn set_workers_params(&self, block_number: U256, seed: U256, _sig: Bytes, _gas: G, confirmations: usize) -> Result<TransactionReceipt, Error> {
+        if block_number > self.block_number()? {
+            bail!("The worker parameters block number is in the future: {:?}", block_number);
+        }
+        let workers = self.lock_state().storage_at(block_number).workers.clone();
+        let receipt = self.transact(|storage, inclusion_block_number| {
+            let nonce = storage.epoch_nonce;
+            storage.epoch_nonce = nonce + U256::from(1);
+            let data = ethabi::encode(&[
+                Token::Uint(seed),
+                Token::Uint(block_number),
+                Token::Uint(inclusion_block_number),
+                Token::Array(workers.iter().map(|&(worker, _)| Token::Address(worker)).collect()),
+                Token::Array(workers.iter().map(|&(_, stake)| Token::Uint(stake)).collect()),
+                Token::Uint(nonce),
+            ]);
+            Some((WorkersParameterizedEvent::new().0.signature(), data))
+        });
+        self.mine(confirmations);
+        Ok(receipt)
+    }
+}
+
+impl ContractQueries for FakeChain {
+    fn get_signing_address(&self) -> Result<H160, Error> { Ok(self.lock_state().latest().storage.signing_address) }
+
+    fn get_active_workers(&self, block_number: U256) -> Result<(Vec<H160>, Vec<U256>), Error> {
+        let state = self.lock_state();
+        Ok(state.storage_at(block_number).workers.iter().cloned().unzip())
+    }
+
+    fn count_secret_contracts(&self) -> Result<U256, Error> {
+        Ok(U256::from(self.lock_state().latest().storage.secret_contracts.len()))
+    }
+
+    fn get_secret_contract_addresses(&self, start: U256, stop: U256) -> Result<Vec<ContractAddress>, Error> {
+        let state = self.lock_state();
+        let secret_contracts = &state.latest().storage.secret_contracts;
+        if start > stop || stop > U256::from(secret_contracts.len()) {
+            bail!("Invalid secret contract range: {:?}..{:?}", start, stop);
+        }
+        Ok(secret_contracts[start.low_u64() as usize..stop.low_u64() as usize].to_vec())
+    }
+}
+
+impl EnigmaContractApi for FakeChain {
+    fn address(&self) -> Address { self.address }
+
+    fn account(&self) -> Address { self.account }
+
+    fn block_number(&self) -> Result<U256, Error> { Ok(U256::from(self.lock_state().blocks.len() - 1)) }
+
+    fn get_logs(&self, event_signature: H256, from_block: U256) -> Result<Vec<Log>, Error> {
+        let state = self.lock_state();
+        let logs = state
+            .blocks
+            .iter()
+            .skip(from_block.low_u64() as usize)
+            .flat_map(|block| block.receipts.iter())
+            .flat_map(|receipt| receipt.logs.iter())
+            .filter(|log| log.topics.first() == Some(&event_signature))
+            .cloned()
+            .collect();
+        Ok(logs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethabi::RawLog;
+
+    fn worker_params_nonces(chain: &FakeChain, from_block: u64) -> Vec<U256> {
+        let event = WorkersParameterizedEvent::new();
+        chain
+            .get_logs(event.0.signature(), U256::from(from_block))
+            .unwrap()
+            .into_iter()
+            .map(|log| {
+                let log = event.0.parse_log(RawLog { topics: log.topics, data: log.data.0 }).unwrap();
+                log.params.into_iter().find(|param| param.name == "nonce").unwrap().value.to_uint().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fake_chain_blocks() {
+        let chain = FakeChain::new(Address::from([1u8; 20]), Address::from([2u8; 20]));
+        assert_eq!(chain.block_number().unwrap(), U256::from(0));
+        chain.add_worker(H160::from([3u8; 20]), U256::from(10));
+        chain.mine(5);
+        assert_eq!(chain.block_number().unwrap(), U256::from(6));
+        // The worker is only active after the block it registered in
+        assert_eq!(chain.get_active_workers(U256::from(0)).unwrap(), (vec![], vec![]));
+        assert_eq!(chain.get_active_workers(U256::from(1)).unwrap(), (vec![H160::from([3u8; 20])], vec![U256::from(10)]));
+
+        chain.add_secret_contract(ContractAddress::from([1u8; 32]));
+        chain.add_secret_contract(ContractAddress::from([2u8; 32]));
+        assert_eq!(chain.count_secret_contracts().unwrap(), U256::from(2));
+        assert_eq!(
+            chain.get_secret_contract_addresses(U256::from(1), U256::from(2)).unwrap(),
+            vec![ContractAddress::from([2u8; 32])]
+        );
+        assert!(chain.get_secret_contract_addresses(U256::from(1), U256::from(3)).is_err());
+    }
+
+    #[test]
+    fn test_fake_chain_workers_params() {
+        let chain = FakeChain::new(Address::from([1u8; 20]), Address::from([2u8; 20]));
+        chain.add_worker(H160::from([3u8; 20]), U256::from(10));
+        chain.set_epoch_nonce(U256::from(7));
+        let sig = Bytes(vec![0u8; 65]);
+        let receipt = ContractFuncs::<U256>::set_workers_params(&chain, U256::from(2), U256::from(1), sig.clone(), 0.into(), 2).unwrap();
+        assert_eq!(receipt.block_number, Some(U256::from(3)));
+        assert_eq!(chain.block_number().unwrap(), U256::from(5));
+        assert!(ContractFuncs::<U256>::set_workers_params(&chain, U256::from(6), U256::from(1), sig.clone(), 0.into(), 0).is_err());
+
+        ContractFuncs::<U256>::set_workers_params(&chain, U256::from(5), U256::from(2), sig, 0.into(), 0).unwrap();
+        assert_eq!(worker_params_nonces(&chain, 0), vec![U256::from(7), U256::from(8)]);
+        // Only the logs from the block number on are returned
+        assert_eq!(worker_params_nonces(&chain, 4), vec![U256::from(8)]);
+
+        // The reorg drops the last event and rolls back the epoch nonce
+        chain.reorg(1);
+        assert_eq!(chain.block_number().unwrap(), U256::from(6));
+        assert_eq!(worker_params_nonces(&chain, 0), vec![U256::from(7)]);
+        let receipt = ContractFuncs::<U256>::set_workers_params(&chain, U256::from(5), U256::from(2), Bytes(vec![0u8; 65]), 0.into(), 0).unwrap();
+        assert_eq!(worker_params_nonces(&chain, 0), vec![U256::from(7), U256::from(8)]);
+        assert_eq!(receipt.logs.len(), 1);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod enigma_contract;
+pub mod fake_chain;
 pub mod w3utils;
@@ -1,9 +1,12 @@
 #![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
 #![feature(alloc)]
+#![feature(int_to_from_bytes)]
 #![deny(unused_extern_crates)]
 
 
 pub mod traits;
+pub mod limits;
+pub mod wasm;
 mod types;
 mod hash;
 
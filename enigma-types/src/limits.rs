@@ -0,0 +1,158 @@
+//! Resource limits of a single contract, shared by the enclave runtime and the test harness.
+//!
+//! A contract declares its limits in a custom wasm section named `enigma_limits` (see `eng_wasm::contract_limits!`),
+//! which holds the memory pages and the max stack height as big endian u32s. The section is carried
+//! over into the deployed bytecode, so the limits are covered by the code hash just like the code itself.
+//! Contracts that don't declare limits get the enclave-wide maxima.
+
+pub const LIMITS_SECTION: &str = "enigma_limits";
+/// The maximum memory (in 64kb pages) any contract can use.
+pub const MAX_MEMORY_PAGES: u32 = 128;
+/// The maximum stack height any contract can use.
+pub const MAX_STACK_HEIGHT: u32 = 64 * 1024;
+/// The length of the whole limits section: id, size, name length, the 13 bytes name and the two limits.
+pub const LIMITS_SECTION_LEN: usize = 3 + 13 + 8;
+
+const WASM_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitsError {
+    /// The module or its limits section can't be read.
+    Malformed(&'static str),
+    /// A declared limit is 0 or above the enclave-wide maximum.
+    ResourceLimit { resource: &'static str, requested: u32, limit: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContractLimits {
+    pub memory_pages: u32,
+    pub stack_height: u32,
+}
+
+impl Default for ContractLimits {
+    fn default() -> Self { ContractLimits { memory_pages: MAX_MEMORY_PAGES, stack_height: MAX_STACK_HEIGHT } }
+}
+
+impl ContractLimits {
+    /// The limits `code` declares, or the enclave-wide maxima if it doesn't declare any.
+    pub fn from_code(code: &[u8]) -> Result<ContractLimits, LimitsError> {
+        Ok(Self::declared_in(code)?.unwrap_or_default())
+    }
+
+    /// The limits `code` declares, fails if they are above the enclave-wide maxima.
+    pub fn declared_in(code: &[u8]) -> Result<Option<ContractLimits>, LimitsError> {
+        let payload = match find_custom_section(code, LIMITS_SECTION)? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        if payload.len() != 8 {
+            return Err(LimitsError::Malformed("the limits section must be 8 bytes long"));
+        }
+        let limits = ContractLimits { memory_pages: read_u32_be(&payload[..4]), stack_height: read_u32_be(&payload[4..]) };
+        limits.validate()?;
+        Ok(Some(limits))
+    }
+
+    fn validate(&self) -> Result<(), LimitsError> {
+        if self.memory_pages == 0 || self.memory_pages > MAX_MEMORY_PAGES {
+            return Err(LimitsError::ResourceLimit { resource: "memory pages", requested: self.memory_pages, limit: MAX_MEMORY_PAGES });
+        }
+        if self.stack_height == 0 || self.stack_height > MAX_STACK_HEIGHT {
+            return Err(LimitsError::ResourceLimit { resource: "stack height", requested: self.stack_height, limit: MAX_STACK_HEIGHT });
+        }
+        Ok(())
+    }
+
+    /// The error of a contract that reached a stack height of `height`, which is above its limit.
+    pub fn stack_exceeded(&self, height: u32) -> LimitsError {
+        LimitsError::ResourceLimit { resource: "stack height", requested: height, limit: self.stack_height }
+    }
+
+    /// The custom section that declares these limits, to be appended to the code.
+    pub fn to_section(&self) -> [u8; LIMITS_SECTION_LEN] {
+        let mut section = [0u8; LIMITS_SECTION_LEN];
+        // The id of a custom section is 0, and both lengths are below 128 so they are single byte LEB128s.
+        section[1] = (LIMITS_SECTION_LEN - 2) as u8;
+        section[2] = LIMITS_SECTION.len() as u8;
+        let name_end = 3 + LIMITS_SECTION.len();
+        section[3..name_end].copy_from_slice(LIMITS_SECTION.as_bytes());
+        section[name_end..name_end + 4].copy_from_slice(&self.memory_pages.to_be_bytes());
+        section[name_end + 4..].copy_from_slice(&self.stack_height.to_be_bytes());
+        section
+    }
+}
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+    let mut arr = [0u8; 4];
+    arr.copy_from_slice(bytes);
+    u32::from_be_bytes(arr)
+}
+
+fn read_leb128(code: &[u8], pos: &mut usize) -> Result<u32, LimitsError> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *code.get(*pos).ok_or(LimitsError::Malformed("unexpected end of the module"))?;
+        *pos += 1;
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(LimitsError::Malformed("integer is too long"))
+}
+
+/// Walks over the top level sections of the module and returns the payload of the custom section `name`.
+pub fn find_custom_section<'a>(code: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, LimitsError> {
+    if code.len() < WASM_HEADER_LEN {
+        return Err(LimitsError::Malformed("the module is too short"));
+    }
+    let mut pos = WASM_HEADER_LEN;
+    while pos < code.len() {
+        let id = code[pos];
+        pos += 1;
+        let size = read_leb128(code, &mut pos)? as usize;
+        let end = pos.checked_add(size).filter(|end| *end <= code.len()).ok_or(LimitsError::Malformed("section is out of bounds"))?;
+        if id == 0 {
+            let mut name_pos = pos;
+            let name_len = read_leb128(code, &mut name_pos)? as usize;
+            let name_end = name_pos.checked_add(name_len).filter(|e| *e <= end).ok_or(LimitsError::Malformed("section name is out of bounds"))?;
+            if &code[name_pos..name_end] == name.as_bytes() {
+                return Ok(Some(&code[name_end..end]));
+            }
+        }
+        pos = end;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_with_limits(payload: &[u8]) -> Vec<u8> {
+        let mut code = vec![0, 97, 115, 109, 1, 0, 0, 0];
+        code.push(0);
+        code.push((1 + LIMITS_SECTION.len() + payload.len()) as u8);
+        code.push(LIMITS_SECTION.len() as u8);
+        code.extend_from_slice(LIMITS_SECTION.as_bytes());
+        code.extend_from_slice(payload);
+        code
+    }
+
+    #[test]
+    fn test_declared_limits() {
+        let code = module_with_limits(&[0, 0, 0, 16, 0, 0, 4, 0]);
+        assert_eq!(ContractLimits::from_code(&code).unwrap(), ContractLimits { memory_pages: 16, stack_height: 1024 });
+        assert_eq!(ContractLimits::from_code(&code[..8]).unwrap(), ContractLimits::default());
+        assert_eq!(&code[8..], &ContractLimits { memory_pages: 16, stack_height: 1024 }.to_section()[..]);
+    }
+
+    #[test]
+    fn test_limits_above_maximum() {
+        let code = module_with_limits(&[0, 0, 1, 0, 0, 0, 4, 0]);
+        match ContractLimits::from_code(&code) {
+            Err(LimitsError::ResourceLimit { requested, limit, .. }) => assert_eq!((requested, limit), (256, MAX_MEMORY_PAGES)),
+            other => panic!("Expected a resource limit error, got: {:?}", other),
+        }
+    }
+}
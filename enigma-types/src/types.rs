@@ -6,6 +6,13 @@ pub type StateKey = SymmetricKey;
 pub type DhKey = SymmetricKey;
 pub type ContractAddress = Hash256;
 pub type PubKey = [u8; 64];
+/// The version of a contract's state key, it's bumped every time the principal rotates the key.
+pub type KeyVersion = u32;
+/// The top bit of a stored key version marks a ciphertext that's bound to its place through the AAD.
+/// Data written before the binding doesn't have it, and is decrypted without an AAD unless `reject-unbound` is enabled.
+pub const AAD_BOUND_FLAG: KeyVersion = 1 << 31;
+/// Identifies one of a user's encryption sessions, a user can hold several at once, e.g. one per task.
+pub type SessionId = [u8; 32];
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -24,6 +31,10 @@ pub enum EnclaveReturn {
     MessagingError,
     WorkerAuthError,
     KeyProvisionError,
+    PrincipalAuthError,
+    UnknownSessionError,
+    ConsumedSessionError,
+    SealedDocumentError,
     Other
 }
 
@@ -46,6 +57,17 @@ pub struct ExecuteResult {
     pub ethereum_address: [u8; 20],
     pub signature: [u8; 65],
     pub used_gas: u64,
+    pub rand_seed: [u8; 32],
+}
+
+/// How many DH keys the core enclave holds, and how many it evicted since it started.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DhKeysMetrics {
+    pub user_keys: u64,
+    pub evicted_user_keys: u64,
+    pub ptt_keys: u64,
+    pub evicted_ptt_keys: u64,
 }
 
 #[repr(C)]
@@ -121,6 +143,7 @@ impl fmt::Debug for ExecuteResult {
         debug_trait_builder.field("ethereum_address", &(self.ethereum_address));
         debug_trait_builder.field("signature", &(&self.signature[..]));
         debug_trait_builder.field("used_gas", &(self.used_gas));
+        debug_trait_builder.field("rand_seed", &(self.rand_seed));
         debug_trait_builder.finish()
     }
 }
@@ -147,6 +170,10 @@ impl fmt::Display for EnclaveReturn {
             MessagingError => "EnclaveReturn: MessagingError",
             WorkerAuthError => "EnclaveReturn: WorkerAuthError",
             KeyProvisionError => "EnclaveReturn: KeyProvisionError",
+            PrincipalAuthError => "EnclaveReturn: PrincipalAuthError",
+            UnknownSessionError => "EnclaveReturn: UnknownSessionError",
+            ConsumedSessionError => "EnclaveReturn: ConsumedSessionError",
+            SealedDocumentError => "EnclaveReturn: SealedDocumentError",
             Other => "EnclaveReturn: Other",
         };
         write!(f, "{}", p)
@@ -0,0 +1,196 @@
+//! The host interface of secret contracts, shared by the enclave runtime (`enigma-runtime-t`)
+//! and the host-side test harness (`eng-wasm-test`) so the two can't drift apart.
+//! `gas_rules` needs the `gas-rules` feature, as it pulls in `pwasm-utils`.
+
+pub mod ids {
+    pub const RET_FUNC: usize = 1;
+    pub const WRITE_STATE_FUNC: usize = 2;
+    pub const READ_STATE_FUNC: usize = 3;
+    pub const READ_STATE_LEN_FUNC: usize = 4;
+    pub const EPRINT_FUNC: usize = 5;
+    pub const NAME_LENGTH_FUNC: usize = 6;
+    pub const NAME_FUNC: usize = 7;
+    pub const ARGS_FUNC: usize = 8;
+    pub const ARGS_LENGTH_FUNC: usize = 9;
+    pub const TYPES_LENGTH_FUNC: usize = 10;
+    pub const TYPES_FUNC: usize = 11;
+    pub const WRITE_ETH_BRIDGE_FUNC: usize = 12;
+    pub const REMOVE_STATE_FUNC: usize = 13;
+    pub const GAS_FUNC: usize = 14;
+    pub const RAND_FUNC: usize = 15;
+    pub const SEAL_FOR_USER_FUNC: usize = 16;
+    pub const SENDER_PUBKEY_FUNC: usize = 17;
+    pub const SENDER_ADDRESS_FUNC: usize = 18;
+    pub const CONTRACT_ADDRESS_FUNC: usize = 19;
+    pub const BLOCK_NUMBER_FUNC: usize = 20;
+    pub const TASK_ID_FUNC: usize = 21;
+}
+
+pub mod signatures {
+    use self::ValueType::*;
+
+    /// The wasm value types used by the host functions, each runtime converts them to its `wasmi::ValueType`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ValueType {
+        I32,
+        I64,
+    }
+
+    pub struct StaticSignature(pub &'static [ValueType], pub Option<ValueType>);
+
+    pub const RET: StaticSignature = StaticSignature(&[I32, I32], None);
+
+    pub const WRITE_STATE: StaticSignature = StaticSignature(&[I32, I32, I32, I32], None);
+
+    pub const READ_STATE_LEN: StaticSignature = StaticSignature(&[I32, I32], Some(I32));
+
+    pub const READ_STATE: StaticSignature = StaticSignature(&[I32, I32, I32], None);
+
+    pub const REMOVE_STATE: StaticSignature = StaticSignature(&[I32, I32], None);
+
+    pub const EPRINT: StaticSignature = StaticSignature(&[I32, I32], None);
+
+    pub const NAME_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
+
+    pub const NAME: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const ARGS_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
+
+    pub const ARGS: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const TYPES_LENGTH: StaticSignature = StaticSignature(&[], Some(I32));
+
+    pub const WRITE_ETH_BRIDGE: StaticSignature = StaticSignature(&[I32, I32, I32], None);
+
+    pub const TYPES: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const GAS: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const RAND: StaticSignature = StaticSignature(&[I32, I32], None);
+
+    pub const SEAL_FOR_USER: StaticSignature = StaticSignature(&[I32, I32, I32, I32], None);
+
+    pub const SENDER_PUBKEY: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const SENDER_ADDRESS: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const CONTRACT_ADDRESS: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const BLOCK_NUMBER: StaticSignature = StaticSignature(&[], Some(I64));
+
+    pub const TASK_ID: StaticSignature = StaticSignature(&[I32], None);
+}
+
+/// How much longer a value gets when it is sealed to a user with `seal_for_user`,
+/// the format version, the ephemeral public key, the tag and the IV.
+pub const SEALED_OVERHEAD: usize = 1 + 64 + 16 + 12;
+
+/// Maps the name a contract imports a host function under to its signature and id.
+pub fn resolve_func(field_name: &str) -> Option<(signatures::StaticSignature, usize)> {
+    use self::{ids::*, signatures::*};
+    let func = match field_name {
+        "ret" => (RET, RET_FUNC),
+        "write_state" => (WRITE_STATE, WRITE_STATE_FUNC),
+        "read_state_len" => (READ_STATE_LEN, READ_STATE_LEN_FUNC),
+        "read_state" => (READ_STATE, READ_STATE_FUNC),
+        "remove_from_state" => (REMOVE_STATE, REMOVE_STATE_FUNC),
+        "eprint" => (EPRINT, EPRINT_FUNC),
+        "fetch_function_name_length" => (NAME_LENGTH, NAME_LENGTH_FUNC),
+        "fetch_function_name" => (NAME, NAME_FUNC),
+        "fetch_args_length" => (ARGS_LENGTH, ARGS_LENGTH_FUNC),
+        "fetch_args" => (ARGS, ARGS_FUNC),
+        "fetch_types_length" => (TYPES_LENGTH, TYPES_LENGTH_FUNC),
+        "fetch_types" => (TYPES, TYPES_FUNC),
+        "write_eth_bridge" => (WRITE_ETH_BRIDGE, WRITE_ETH_BRIDGE_FUNC),
+        "gas" => (GAS, GAS_FUNC),
+        "rand" => (RAND, RAND_FUNC),
+        "seal_for_user" => (SEAL_FOR_USER, SEAL_FOR_USER_FUNC),
+        "fetch_sender_pubkey" => (SENDER_PUBKEY, SENDER_PUBKEY_FUNC),
+        "fetch_sender_address" => (SENDER_ADDRESS, SENDER_ADDRESS_FUNC),
+        "fetch_contract_address" => (CONTRACT_ADDRESS, CONTRACT_ADDRESS_FUNC),
+        "fetch_block_number" => (BLOCK_NUMBER, BLOCK_NUMBER_FUNC),
+        "fetch_task_id" => (TASK_ID, TASK_ID_FUNC),
+        _ => return None,
+    };
+    Some(func)
+}
+
+/// Wasm cost table, the gas charged by the instructions of a contract.
+#[derive(Debug, Clone)]
+pub struct WasmCosts {
+    /// Default opcode cost
+    pub regular: u32,
+    /// Div operations multiplier.
+    pub div: u32,
+    /// Mul operations multiplier.
+    pub mul: u32,
+    /// Memory (load/store) operations multiplier.
+    pub mem: u32,
+    /// General static query of U256 value from env-info
+    pub static_u256: u32,
+    /// General static query of Address value from env-info
+    pub static_address: u32,
+    /// Memory stipend. Amount of free memory (in 64kb pages) each contract can use for stack.
+    pub initial_mem: u32,
+    /// Grow memory cost, per page (64kb)
+    pub grow_mem: u32,
+    /// Memory copy cost, per byte
+    pub memcpy: u32,
+    /// Cost of wasm opcode is calculated as TABLE_ENTRY_COST * `opcodes_mul` / `opcodes_div`
+    pub opcodes_mul: u32,
+    /// Cost of wasm opcode is calculated as TABLE_ENTRY_COST * `opcodes_mul` / `opcodes_div`
+    pub opcodes_div: u32,
+}
+
+impl Default for WasmCosts {
+    fn default() -> Self {
+        WasmCosts {
+            regular: 1,
+            div: 16,
+            mul: 4,
+            mem: 2,
+            static_u256: 64,
+            static_address: 40,
+            initial_mem: 4096,
+            grow_mem: 8192,
+            memcpy: 1,
+            opcodes_mul: 3,
+            opcodes_div: 8,
+        }
+    }
+}
+
+/// The rules the gas counter is injected into a contract with.
+#[cfg(feature = "gas-rules")]
+pub fn gas_rules(wasm_costs: &WasmCosts) -> pwasm_utils::rules::Set {
+    use pwasm_utils::rules::{InstructionType, Metering, Set};
+    let vals = [
+        (InstructionType::Load, Metering::Fixed(wasm_costs.mem)),
+        (InstructionType::Store, Metering::Fixed(wasm_costs.mem)),
+        (InstructionType::Div, Metering::Fixed(wasm_costs.div)),
+        (InstructionType::Mul, Metering::Fixed(wasm_costs.mul)),
+    ];
+    Set::new(wasm_costs.regular, vals.iter().cloned().collect()).with_grow_cost(wasm_costs.grow_mem)
+}
+
+/// The gas charged by the host functions.
+#[derive(Debug, Clone)]
+pub struct RuntimeWasmCosts {
+    pub write_value: u64,
+    pub write_additional_byte: u64,
+    pub deploy_byte: u64,
+    pub execution: u64,
+    pub seal_for_user: u64,
+}
+
+impl Default for RuntimeWasmCosts {
+    fn default() -> Self {
+        RuntimeWasmCosts {
+            write_value: 10,
+            write_additional_byte: 1,
+            deploy_byte: 1,
+            execution: 10_000,
+            seal_for_user: 1_000,
+        }
+    }
+}
@@ -27,8 +27,7 @@ pub struct Contract;
 
 impl ContractInterface for Contract {
     fn flip() -> bool {
-        let result: u8 = Rand::gen();
-        result % 2 == 1
+        Rand::gen()
     }
 
     fn commit(commitment: bool) {
@@ -22,13 +22,17 @@ static MILLIONAIRES: &str = "millionaires";
 pub struct Millionaire {
     address: H256, // field containing 32 byte hash type for millionaire's address
     net_worth: U256, // field containing 32 byte uint for millionaire's net worth
+    #[serde(default)]
+    pubkey: Vec<u8>, // millionaire's public key, empty if the millionaire doesn't want a private result
 }
 
 // Public-facing secret contract function declarations
 #[pub_interface]
 pub trait ContractInterface{
     fn add_millionaire(address: H256, net_worth: U256);
+    fn add_millionaire_with_key(address: H256, net_worth: U256, pubkey: Vec<u8>);
     fn compute_richest() -> H256;
+    fn compute_private_results() -> Vec<u8>;
 }
 
 pub struct Contract;
@@ -42,21 +46,29 @@ impl Contract {
             None => Vec::new(),
         }
     }
+
+    fn push_millionaire(millionaire: Millionaire) {
+        // Read state to get vector of Millionaires
+        let mut millionaires = Self::get_millionaires();
+        // Append the new Millionaire struct to this vector
+        millionaires.push(millionaire);
+        // Write the updated vector to contract's state
+        write_state!(MILLIONAIRES => millionaires);
+    }
 }
 
 impl ContractInterface for Contract {
     // Add millionaire with 32-byte hash type for address and 32-byte uint for net worth
     #[no_mangle]
     fn add_millionaire(address: H256, net_worth: U256) {
-        // Read state to get vector of Millionaires
-        let mut millionaires = Self::get_millionaires();
-        // Append a new Millionaire struct to this vector
-        millionaires.push(Millionaire {
-            address,
-            net_worth,
-        });
-        // Write the updated vector to contract's state
-        write_state!(MILLIONAIRES => millionaires);
+        Self::push_millionaire(Millionaire { address, net_worth, pubkey: Vec::new() });
+    }
+
+    // Add millionaire together with the 64 byte public key their private result will be sealed to
+    #[no_mangle]
+    fn add_millionaire_with_key(address: H256, net_worth: U256, pubkey: Vec<u8>) {
+        assert_eq!(pubkey.len(), 64, "the public key must be 64 bytes");
+        Self::push_millionaire(Millionaire { address, net_worth, pubkey });
     }
 
     // Compute the richest millionaire by returning the 32-byte hash type for the address
@@ -73,4 +85,20 @@ impl ContractInterface for Contract {
             None => U256::from(0).into(),
         }
     }
+
+    // Tell every millionaire that was added with a key, and only them, whether they are the richest.
+    // The result is the concatenation of one sealed byte (1 for the richest, 0 otherwise) per such
+    // millionaire, in the order they were added. Each one is `1 + SEALED_OVERHEAD` bytes long and can
+    // only be opened with the millionaire's own private key.
+    #[no_mangle]
+    fn compute_private_results() -> Vec<u8> {
+        let millionaires = Self::get_millionaires();
+        let richest = millionaires.iter().max_by_key(|m| m.net_worth).map(|m| m.address);
+        let mut results = Vec::new();
+        for millionaire in millionaires.iter().filter(|m| !m.pubkey.is_empty()) {
+            let is_richest = Some(millionaire.address) == richest;
+            results.extend(seal_for_user(&millionaire.pubkey, &[is_richest as u8]));
+        }
+        results
+    }
 }
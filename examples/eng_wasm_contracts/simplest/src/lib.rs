@@ -18,6 +18,10 @@ pub trait ContractInterface{
     fn addition(x: U256, y: U256) -> U256;
     fn get_last_sum() -> U256;
     fn print_test(x: U256, y: U256);
+    fn get_sender() -> Address;
+    fn get_contract_address() -> H256;
+    fn get_block_number() -> U256;
+    fn get_task_id() -> H256;
     fn construct(param: U256);
 }
 
@@ -121,6 +125,26 @@ impl ContractInterface for Contract {
         eprint!("{:?} {:?}", x.as_u64(), y.as_u64());
     }
 
+    #[no_mangle]
+    fn get_sender() -> Address {
+        Context::sender()
+    }
+
+    #[no_mangle]
+    fn get_contract_address() -> H256 {
+        Context::contract_address()
+    }
+
+    #[no_mangle]
+    fn get_block_number() -> U256 {
+        Context::block_number().into()
+    }
+
+    #[no_mangle]
+    fn get_task_id() -> H256 {
+        Context::task_id()
+    }
+
     #[no_mangle]
     fn construct(param: U256){
         write_state!("1" => param.as_u64());